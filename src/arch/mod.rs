@@ -1,6 +1,6 @@
 use log::debug;
 
-use crate::consts;
+use crate::{consts, executor::hart_local};
 
 /// Returns the current frame pointer or stack base pointer
 #[inline(always)]
@@ -55,6 +55,8 @@ pub fn switch_page_table(new_pgt_addr: usize) -> usize {
     }
 
     // else switch pagetable and flush tlb
+    // Record it first, so that a concurrent TLB shootdown either sees us or is covered by the flush below
+    hart_local::set_curr_active_pgt(new_pgt_addr);
     let new_pgt_ppn = new_pgt_addr >> consts::PAGE_SIZE_BITS;
     unsafe {
         use riscv::register::satp;
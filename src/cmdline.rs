@@ -0,0 +1,100 @@
+//! 内核命令行, 来自设备树的 /chosen/bootargs.
+//!
+//! 认识的参数和 Linux 的写法一致:
+//! - root=: 根文件系统所在的块设备, 可以是 /dev/vda2, PARTUUID=... 或 PARTLABEL=...
+//! - rootfstype=: 根文件系统的类型, ext2/ext3/ext4 或 vfat, 不指定时自动识别
+//! - ro / rw: 只读或读写挂载根文件系统, 默认读写
+//! - init=: 第一个用户程序, -- 之后的参数都传给它
+//! - loglevel=: 0 到 8, 只输出优先级数值小于它的日志
+//! - norandmaps: 关闭 ASLR
+//!
+//! 不认识的参数被忽略.
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use crate::{lazy_init::LazyInit, logging};
+
+#[derive(Debug, Default)]
+pub struct Cmdline {
+    pub root: Option<String>,
+    pub rootfstype: Option<String>,
+    pub read_only: bool,
+    pub init: Option<String>,
+    /// -- 之后的参数
+    pub init_args: Vec<String>,
+    pub loglevel: Option<usize>,
+    pub norandmaps: bool,
+}
+
+impl Cmdline {
+    pub fn parse(bootargs: &str) -> Self {
+        let mut cmdline = Self::default();
+        let mut args = bootargs.split(|c: char| c.is_whitespace() || c == '\0');
+        for arg in args.by_ref().filter(|arg| !arg.is_empty()) {
+            let (key, value) = match arg.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (arg, None),
+            };
+            match (key, value) {
+                ("--", None) => break,
+                ("root", Some(value)) => cmdline.root = Some(value.to_string()),
+                ("rootfstype", Some(value)) => cmdline.rootfstype = Some(value.to_string()),
+                ("ro", None) => cmdline.read_only = true,
+                ("rw", None) => cmdline.read_only = false,
+                ("init", Some(value)) => cmdline.init = Some(value.to_string()),
+                ("loglevel", Some(value)) => match value.parse() {
+                    Ok(level) => cmdline.loglevel = Some(level),
+                    Err(_) => log::warn!("cmdline: bad loglevel {:?}", value),
+                },
+                ("norandmaps", None) => cmdline.norandmaps = true,
+                _ => log::debug!("cmdline: ignore {:?}", arg),
+            }
+        }
+        cmdline.init_args = args.filter(|arg| !arg.is_empty()).map(|s| s.to_string()).collect();
+        cmdline
+    }
+}
+
+static CMDLINE: LazyInit<Cmdline> = LazyInit::new();
+
+/// 解析命令行并应用其中的日志等级
+pub fn init(bootargs: &str) {
+    cmdline_test();
+    let cmdline = Cmdline::parse(bootargs);
+    log::info!("Kernel command line: {:?}", cmdline);
+    if let Some(level) = cmdline.loglevel {
+        // KERN_ERR 是 3, KERN_WARNING 是 4, KERN_INFO 是 6, KERN_DEBUG 是 7
+        logging::set_max_level(match level {
+            0 => "off",
+            1..=4 => "error",
+            5 => "warn",
+            6 | 7 => "info",
+            _ => "debug",
+        });
+    }
+    CMDLINE.init_by(cmdline);
+}
+
+pub fn get() -> &'static Cmdline {
+    &CMDLINE
+}
+
+#[allow(unused)]
+pub fn cmdline_test() {
+    let cmdline = Cmdline::parse("root=/dev/vda2 rootfstype=ext4 loglevel=7\0");
+    debug_assert_eq!(cmdline.root.as_deref(), Some("/dev/vda2"));
+    debug_assert_eq!(cmdline.rootfstype.as_deref(), Some("ext4"));
+    debug_assert_eq!(cmdline.loglevel, Some(7));
+    debug_assert!(!cmdline.read_only);
+    // ro 和 rw 以最后出现的为准
+    debug_assert!(!Cmdline::parse("ro rw").read_only);
+    debug_assert!(Cmdline::parse("rw ro").read_only);
+    // -- 之后的参数即使形如 key=value 也原样交给 init
+    let cmdline = Cmdline::parse("init=/bin/sh ro -- -c  echo rw=1");
+    debug_assert_eq!(cmdline.init.as_deref(), Some("/bin/sh"));
+    debug_assert!(cmdline.read_only);
+    debug_assert_eq!(cmdline.init_args, ["-c", "echo", "rw=1"]);
+}
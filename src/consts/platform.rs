@@ -10,3 +10,7 @@ register_mut_const!(MAX_PHYSICAL_MEMORY, usize, 0);
 register_mut_const!(pub PLATFORM_BOOT_PC, usize, 0);
 
 register_const!(DEVICE_START, usize, 0xc00_0000);
+
+// Physical range of the initrd loaded by the bootloader, empty if none
+register_mut_const!(INITRD_START, usize, 0);
+register_mut_const!(INITRD_END, usize, 0);
@@ -1,8 +1,12 @@
 use fdt::Fdt;
 
 use crate::{
+    cmdline,
     consts::{self, address_space::K_SEG_DTB},
     println,
+    process::user_space::aslr,
+    timer,
+    tools::random,
 };
 
 /// early_parse_device_tree
@@ -15,11 +19,48 @@ pub fn early_parse_device_tree() -> Fdt<'static> {
     let phy_mem = device_tree.memory().regions().next().expect("No memory region found");
     consts::platform::set_phymem_start(phy_mem.starting_address as usize);
     consts::platform::set_max_physical_memory(phy_mem.size.unwrap());
+    // Initrd loaded by the bootloader, must be kept away from the frame allocator
+    if let Some(chosen) = device_tree.find_node("/chosen") {
+        let prop = |name| chosen.property(name).and_then(|p| be_cells(p.value));
+        if let (Some(start), Some(end)) = (prop("linux,initrd-start"), prop("linux,initrd-end")) {
+            println!("Initrd: {:#x} - {:#x}", start, end);
+            consts::platform::set_initrd_start(start);
+            consts::platform::set_initrd_end(end);
+        }
+    }
     device_tree
 }
 
+/// A property of one or two big-endian 32-bit cells
+fn be_cells(value: &[u8]) -> Option<usize> {
+    match value.len() {
+        4 => Some(u32::from_be_bytes(value.try_into().unwrap()) as usize),
+        8 => Some(u64::from_be_bytes(value.try_into().unwrap()) as usize),
+        _ => None,
+    }
+}
+
 pub fn device_init() {
     let device_tree = unsafe { fdt::Fdt::from_ptr(K_SEG_DTB as _).expect("Parse DTB failed") };
     // Init timer frequency
     consts::time::set_clock_freq(device_tree.cpus().next().unwrap().timebase_frequency());
+
+    let mut bootargs = "";
+    if let Some(chosen) = device_tree.find_node("/chosen") {
+        // 随机数种子
+        if let Some(seed) = chosen.property("rng-seed") {
+            random::add_entropy(seed.value);
+        }
+        // 启动参数
+        bootargs = chosen
+            .property("bootargs")
+            .and_then(|p| core::str::from_utf8(p.value).ok())
+            .unwrap_or("");
+    }
+    cmdline::init(bootargs);
+    if cmdline::get().norandmaps {
+        log::info!("ASLR disabled by boot option");
+        aslr::set_randomize(false);
+    }
+    random::add_entropy(&(timer::get_time() as u64).to_le_bytes());
 }
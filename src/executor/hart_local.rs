@@ -1,13 +1,18 @@
 use alloc::sync::Arc;
 
 use crate::process::lproc::LightProcess;
-use core::arch::asm;
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 pub struct HartLocalInfo {
     sum_cnt: usize,
     no_irq_cnt: usize,
     current_lproc: Option<Arc<LightProcess>>,
     curr_fp_belong_to: Option<Arc<LightProcess>>,
+    // 当前核正在使用的页表, 其它核做 TLB shootdown 时会读取, 所以要用原子变量
+    active_pgt: AtomicUsize,
 }
 
 impl HartLocalInfo {
@@ -17,6 +22,7 @@ impl HartLocalInfo {
             no_irq_cnt: 0,
             current_lproc: None,
             curr_fp_belong_to: None,
+            active_pgt: AtomicUsize::new(0),
         }
     }
 
@@ -26,7 +32,7 @@ impl HartLocalInfo {
 }
 
 // hart local 的东西修改肯定也是 hart local 的, 不用锁
-const HART_MAX: usize = 8;
+pub const HART_MAX: usize = 8;
 static mut HART_LOCAL_INFO: [HartLocalInfo; HART_MAX] = [
     // [HartLocalInfo::new(); HART_MAX] needs impl Copy for HartLocalInfo,
     // but we can't impl Copy for HartLocalInfo because of Arc<LightProcess>
@@ -71,6 +77,17 @@ pub fn set_curr_fp_belong_to(lproc: Arc<LightProcess>) {
     get_curr_hart_info().curr_fp_belong_to = Some(lproc);
 }
 
+/// 记录当前核正在使用的页表, 应在写入 satp 之前调用
+pub fn set_curr_active_pgt(pgt_paddr: usize) {
+    get_curr_hart_info().active_pgt.store(pgt_paddr, Ordering::SeqCst);
+}
+
+/// 读取任意一个核正在使用的页表
+pub fn hart_active_pgt(hart_id: usize) -> usize {
+    debug_assert!(hart_id < HART_MAX);
+    unsafe { HART_LOCAL_INFO[hart_id].active_pgt.load(Ordering::SeqCst) }
+}
+
 pub fn no_irq_push() {
     let curr = get_curr_hart_info();
     if curr.no_irq_cnt == 0 {
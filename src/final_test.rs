@@ -194,7 +194,7 @@ fn run_script(name: &str) {
     envp.push(String::from("PATH=/"));
 
     let lproc = LightProcess::new();
-    block_on(lproc.do_exec(busybox, args, envp));
+    block_on(lproc.do_exec(busybox, args, envp)).unwrap();
     lproc.with_mut_procfs_info(|info| info.exe_path = Some(Path::from("/busybox")));
     spawn_proc(lproc);
 }
@@ -206,7 +206,7 @@ fn run_binary(path: &str, args: Vec<String>) {
 
     // Some necessary environment variables.
     let lproc = LightProcess::new();
-    block_on(lproc.do_exec(bin, args, Vec::new()));
+    block_on(lproc.do_exec(bin, args, Vec::new())).unwrap();
     lproc.with_mut_procfs_info(|info| info.exe_path = Some(path));
     spawn_proc(lproc);
 }
@@ -218,7 +218,7 @@ fn run_binary_with_env(path: &str, args: Vec<String>, envp: Vec<String>) {
 
     // Some necessary environment variables.
     let lproc = LightProcess::new();
-    block_on(lproc.do_exec(bin, args, envp));
+    block_on(lproc.do_exec(bin, args, envp)).unwrap();
     lproc.with_mut_procfs_info(|info| info.exe_path = Some(path));
     spawn_proc(lproc);
 }
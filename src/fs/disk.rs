@@ -1,289 +1,308 @@
-use core::cell::Cell;
-use core::fmt::Debug;
-
-use alloc::boxed::Box;
-use alloc::sync::Arc;
-
-/// Disk related
-///
-/// Copyright (C) 2023 by ArceOS
-/// Copyright (C) 2023 by MankorOS
-///
-/// Adapted from ArceOS
-use super::BlockDevice;
-
-use crate::drivers::DevResult;
-
-use super::new_vfs::underlying::ConcreteFile;
-use super::new_vfs::DeviceIDCollection;
-use super::new_vfs::VfsFileAttr;
-use crate::tools::errors::dyn_future;
-use crate::tools::errors::ASysResult;
-use crate::tools::errors::SysError;
-use crate::tools::errors::SysResult;
-
-use super::new_vfs::page_cache::PageCacheFile;
-use super::new_vfs::sync_attr_file::SyncAttrFile;
-use super::new_vfs::top::VfsFileRef;
-use alloc::string::String;
-use alloc::vec::Vec;
+//! /dev 中的块设备文件.
+//!
+//! 启动时为探测到的每个磁盘以及磁盘上的每个 MBR/GPT 分区创建一个 [`Disk`],
+//! 按 Linux 的习惯命名: virtio 磁盘为 vda, vdb, ..., 分区为 vda1, vda2, ...;
+//! SD/MMC 卡为 mmcblk0, mmcblk1, ..., 分区为 mmcblk0p1, ...
+//! 块设备文件直接读写设备, 不经过页缓存.
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use log::{info, warn};
+
+use super::{
+    new_vfs::{
+        top::{
+            DeviceInfo, IOCTLCmd, MmapKind, PollKind, SizeInfo, TimeInfo, TimeInfoChange, VfsFile,
+        },
+        DeviceIDCollection, VfsFileKind,
+    },
+    nfat32::cvt_err,
+    partition::{self, Partition, PartitionInfo},
+};
+use crate::{
+    drivers::{self, AsyncBlockDevice},
+    executor::{block_on, hart_local::get_curr_lproc},
+    impl_vfs_default_non_dir,
+    lazy_init::LazyInit,
+    memory::{address::PhysAddr4K, UserWritePtr},
+    tools::errors::{dyn_future, ASysResult, SysError, SysResult},
+};
 
 pub const BLOCK_SIZE: usize = 512;
 pub const LOG2_BLOCK_SIZE: usize = 9;
 
-/// A disk holds a block device, and can be used to read and write data to that block device.
-pub struct Disk {
-    block_id: Cell<u64>,
-    offset: Cell<usize>,
-    dev: Arc<dyn BlockDevice>,
+/// virtio 块设备的主设备号. Linux 中是动态分配的, 一般就是 254
+pub const VIRTBLK_MAJOR: usize = 254;
+/// 每个 virtio 磁盘占用的次设备号数, 次设备号 0 是整个磁盘
+const VIRTBLK_MINORS: usize = 16;
+pub const MMC_BLOCK_MAJOR: usize = 179;
+/// 每个 MMC 卡占用的次设备号数
+const MMC_BLOCK_MINORS: usize = 8;
+
+impl IOCTLCmd {
+    /// 以 512 字节为单位的大小, 参数为 unsigned long *
+    pub const BLKGETSIZE: Self = Self(0x1260);
+    pub const BLKFLSBUF: Self = Self(0x1261);
+    /// 逻辑扇区大小, 参数为 int *
+    pub const BLKSSZGET: Self = Self(0x1268);
+    /// 以字节为单位的大小, 参数为 u64 *
+    pub const BLKGETSIZE64: Self = Self(0x80081272);
 }
 
-unsafe impl Send for Disk {}
-unsafe impl Sync for Disk {}
-
-impl Debug for Disk {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_struct("Disk")
-            .field("block_id", &self.block_id.get())
-            .field("offset", &self.offset.get())
-            .field("dev", &self.dev.name())
-            .finish()
-            .fmt(f)
-    }
+/// 一个块设备文件, 可以是整个磁盘, 也可以是一个分区
+#[derive(Clone)]
+pub struct Disk {
+    name: String,
+    rdev: usize,
+    dev: Arc<dyn AsyncBlockDevice>,
+    /// 是分区时, 分区表中的信息
+    part: Option<PartitionInfo>,
 }
 
 impl Disk {
-    /// Create a new disk.
-    pub fn new(dev: Arc<dyn BlockDevice>) -> Self {
-        assert_eq!(BLOCK_SIZE, dev.block_size());
+    pub fn new(name: String, rdev: usize, dev: Arc<dyn AsyncBlockDevice>) -> Self {
         Self {
-            block_id: Cell::new(0),
-            offset: Cell::new(0),
+            name,
+            rdev,
             dev,
+            part: None,
         }
     }
-
-    pub fn to_vfs_file(self) -> VfsFileRef {
-        let block_count = self.dev.num_blocks() as usize;
-        let byte_size = block_count * self.dev.block_size();
-        let _attr = VfsFileAttr {
-            kind: super::new_vfs::VfsFileKind::BlockDevice,
-            device_id: 0, // TODO: Device id
-            self_device_id: 0,
-            byte_size,
-            block_count,
-            access_time: 0,
-            modify_time: 0,
-            create_time: 0,
-        };
-        let file = PageCacheFile::new(SyncAttrFile::new(self));
-        VfsFileRef::new(file)
+    pub fn new_partition(name: String, rdev: usize, part: Arc<Partition>) -> Self {
+        Self {
+            name,
+            rdev,
+            part: Some(part.info().clone()),
+            dev: part,
+        }
     }
-
-    /// Get the size of the disk.
-    pub fn size(&self) -> u64 {
-        self.dev.num_blocks() * BLOCK_SIZE as u64
+    pub fn name(&self) -> &str {
+        &self.name
     }
-
-    /// Get the position of the cursor.
-    pub fn position(&self) -> u64 {
-        self.block_id.get() * BLOCK_SIZE as u64 + self.offset.get() as u64
+    pub fn partition(&self) -> Option<&PartitionInfo> {
+        self.part.as_ref()
     }
-
-    /// Set the position of the cursor.
-    pub fn set_position(&self, pos: u64) {
-        self.block_id.set(pos / BLOCK_SIZE as u64);
-        self.offset.set(pos as usize % BLOCK_SIZE);
+    pub fn device(&self) -> Arc<dyn AsyncBlockDevice> {
+        self.dev.clone()
     }
 
-    /// Read within one block, returns the number of bytes read.
-    pub fn read_one(&self, buf: &mut [u8]) -> DevResult<usize> {
-        let read_size = if self.offset.get() == 0 && buf.len() >= BLOCK_SIZE {
-            // whole block
-            self.dev.read_block(self.block_id.get(), &mut buf[0..BLOCK_SIZE])?;
-            self.block_id.set(self.block_id.get() + 1);
-            BLOCK_SIZE
-        } else {
-            // partial block
-            let mut data = [0u8; BLOCK_SIZE];
-            let start = self.offset.get();
-            let count = buf.len().min(BLOCK_SIZE - self.offset.get());
-
-            self.dev.read_block(self.block_id.get(), &mut data)?;
-            buf[..count].copy_from_slice(&data[start..start + count]);
-
-            self.offset.set(self.offset.get() + count);
-            if self.offset.get() >= BLOCK_SIZE {
-                self.block_id.set(self.block_id.get() + 1);
-                self.offset.set(self.offset.get() - BLOCK_SIZE);
-            }
-            count
-        };
-        Ok(read_size)
+    /// Get the size of the disk.
+    pub fn size(&self) -> usize {
+        self.dev.num_blocks() as usize * self.dev.block_size()
     }
 
-    /// Write within one block, returns the number of bytes written.
-    pub fn write_one(&self, buf: &[u8]) -> DevResult<usize> {
-        let write_size = if self.offset.get() == 0 && buf.len() >= BLOCK_SIZE {
-            // whole block
-            self.dev.write_block(self.block_id.get(), &buf[0..BLOCK_SIZE])?;
-            self.block_id.set(self.block_id.get() + 1);
-            BLOCK_SIZE
-        } else {
-            // partial block
-            let mut data = [0u8; BLOCK_SIZE];
-            let start = self.offset.get();
-            let count = buf.len().min(BLOCK_SIZE - self.offset.get());
-
-            self.dev.read_block(self.block_id.get(), &mut data)?;
-            data[start..start + count].copy_from_slice(&buf[..count]);
-            self.dev.write_block(self.block_id.get(), &data)?;
-
-            self.offset.set(self.offset.get() + count);
-            if self.offset.get() >= BLOCK_SIZE {
-                self.block_id.set(self.block_id.get() + 1);
-                self.offset.set(self.offset.get() - BLOCK_SIZE);
+    async fn read(&self, offset: usize, buf: &mut [u8]) -> SysResult<usize> {
+        let size = self.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let end = size.min(offset + buf.len());
+        let bs = self.dev.block_size();
+        let mut block = vec![0; bs];
+        let mut pos = offset;
+        while pos < end {
+            let (id, start) = ((pos / bs) as u64, pos % bs);
+            let n = (bs - start).min(end - pos);
+            let dst = &mut buf[pos - offset..pos - offset + n];
+            if n == bs {
+                self.dev.read_block(id, dst).await.map_err(cvt_err)?;
+            } else {
+                self.dev.read_block(id, &mut block).await.map_err(cvt_err)?;
+                dst.copy_from_slice(&block[start..start + n]);
             }
-            count
-        };
-        Ok(write_size)
-    }
-
-    /// Read the master boot record.
-    pub fn mbr(&mut self) -> mbr_nostd::MasterBootRecord {
-        static mut MBR: [u8; 512] = [0u8; 512];
-        self.set_position(0);
-        unsafe {
-            self.read_one(MBR.as_mut_slice()).expect("Read Master Boot Record failed");
-            mbr_nostd::MasterBootRecord::from_bytes(&MBR).expect("Parse Master Boot Record failed")
+            pos += n;
         }
+        Ok(end - offset)
     }
-}
-
-impl Disk {
-    fn sync_read_at(&self, _offset: u64, buf: &mut [u8]) -> SysResult<usize> {
-        // Offset is ignored
 
+    async fn write(&self, offset: usize, buf: &[u8]) -> SysResult<usize> {
+        let size = self.size();
         if buf.is_empty() {
             return Ok(0);
         }
-        // TODO: implement read
-        Ok(1)
-    }
-    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8]) -> ASysResult<usize> {
-        Box::pin(async move { self.sync_read_at(offset, buf) })
-    }
-
-    fn write_at<'a>(&'a self, offset: u64, buf: &'a [u8]) -> ASysResult<usize> {
-        Box::pin(async move { self.sync_write_at(offset, buf) })
-    }
-    /// 文件属性
-    fn stat(&self) -> SysResult<VfsFileAttr> {
-        let block_count = self.dev.num_blocks() as usize;
-        let byte_size = self.dev.block_size() * block_count;
-
-        Ok(VfsFileAttr {
-            kind: super::new_vfs::VfsFileKind::CharDevice,
-            device_id: DeviceIDCollection::DEV_FS_ID,
-            self_device_id: 0, // TODO: 让每一个 BlockDevice 有一个 id
-            byte_size,
-            block_count,
-            access_time: 0,
-            modify_time: 0,
-            create_time: 0, // TODO: create time
-        })
-    }
-
-    fn sync_write_at(&self, offset: u64, mut buf: &[u8]) -> SysResult<usize> {
-        let mut write_len = 0;
-        self.set_position(offset);
-        while !buf.is_empty() {
-            match self.write_one(buf) {
-                Ok(0) => break,
-                Ok(n) => {
-                    buf = &buf[n..];
-                    write_len += n;
-                }
-                Err(_) => return Err(SysError::EIO),
+        if offset >= size {
+            return Err(SysError::ENOSPC);
+        }
+        let end = size.min(offset + buf.len());
+        let bs = self.dev.block_size();
+        let mut block = vec![0; bs];
+        let mut pos = offset;
+        while pos < end {
+            let (id, start) = ((pos / bs) as u64, pos % bs);
+            let n = (bs - start).min(end - pos);
+            let src = &buf[pos - offset..pos - offset + n];
+            if n == bs {
+                self.dev.write_block(id, src).await.map_err(cvt_err)?;
+            } else {
+                // 不满一块时先读出整块
+                self.dev.read_block(id, &mut block).await.map_err(cvt_err)?;
+                block[start..start + n].copy_from_slice(src);
+                self.dev.write_block(id, &block).await.map_err(cvt_err)?;
             }
+            pos += n;
         }
-        Ok(write_len)
+        Ok(end - offset)
     }
 }
 
-/// A disk can be a dev file
-impl ConcreteFile for Disk {
-    // TODO: async dir read/write
-    fn read_page_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> ASysResult<usize> {
-        dyn_future(async move { self.sync_read_at(offset as u64, buf) })
-    }
-    fn write_page_at<'a>(&'a self, offset: usize, buf: &'a [u8]) -> ASysResult<usize> {
-        dyn_future(async move { self.sync_write_at(offset as u64, buf) })
-    }
+impl VfsFile for Disk {
+    impl_vfs_default_non_dir!(Disk);
 
-    fn attr_kind(&self) -> super::new_vfs::VfsFileKind {
-        super::new_vfs::VfsFileKind::BlockDevice
+    fn attr_kind(&self) -> VfsFileKind {
+        VfsFileKind::BlockDevice
     }
-    fn attr_device(&self) -> super::new_vfs::top::DeviceInfo {
-        super::new_vfs::top::DeviceInfo {
+    fn attr_device(&self) -> DeviceInfo {
+        DeviceInfo {
             device_id: DeviceIDCollection::DEV_FS_ID,
-            self_device_id: 0,
+            self_device_id: self.rdev,
         }
     }
-    fn attr_size(&self) -> ASysResult<super::new_vfs::top::SizeInfo> {
-        dyn_future(async {
-            Ok(super::new_vfs::top::SizeInfo {
-                bytes: self.size() as _,
-                blocks: self.dev.block_size(),
-            })
-        })
+    fn attr_size(&self) -> ASysResult<SizeInfo> {
+        // Linux 的块设备文件 st_size 为 0, 这里报告设备的大小, 这样 lseek(SEEK_END) 才能用
+        dyn_future(async { Ok(SizeInfo::new_bytes_only(self.size())) })
     }
-    fn attr_time(&self) -> ASysResult<super::new_vfs::top::TimeInfo> {
-        dyn_future(async {
-            Ok(super::new_vfs::top::TimeInfo {
-                access: 0,
-                modify: 0,
-                change: 0,
-            })
-        })
+    fn attr_time(&self) -> ASysResult<TimeInfo> {
+        dyn_future(async { Ok(TimeInfo::new_zero()) })
     }
-    fn update_time(&self, _info: super::new_vfs::top::TimeInfoChange) -> ASysResult {
-        todo!()
+    fn update_time(&self, _info: TimeInfoChange) -> ASysResult {
+        dyn_future(async { Ok(()) })
     }
-
-    fn delete(&self) -> ASysResult {
-        todo!()
+    fn sync(&self) -> ASysResult {
+        dyn_future(async { self.dev.flush().await.map_err(cvt_err) })
     }
 
-    fn truncate(&self, _new_size: usize) -> ASysResult {
-        todo!()
+    fn read_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> ASysResult<usize> {
+        dyn_future(self.read(offset, buf))
     }
-
-    fn lookup<'a>(&'a self, _name: &'a str) -> ASysResult<Self> {
-        todo!()
+    fn write_at<'a>(&'a self, offset: usize, buf: &'a [u8]) -> ASysResult<usize> {
+        dyn_future(self.write(offset, buf))
+    }
+    fn get_page(&self, _offset: usize, _kind: MmapKind) -> ASysResult<PhysAddr4K> {
+        dyn_future(async { Err(SysError::ENODEV) })
+    }
+    fn truncate(&self, _length: usize) -> ASysResult {
+        // 和 Linux 一样, 对设备文件的 O_TRUNC 直接忽略
+        dyn_future(async { Ok(()) })
     }
 
-    fn list(&self) -> ASysResult<Vec<(String, Self)>> {
-        todo!()
+    fn poll_ready(&self, _offset: usize, len: usize, _kind: PollKind) -> ASysResult<usize> {
+        dyn_future(async move { Ok(len) })
+    }
+    fn poll_read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        block_on(self.read(offset, buf)).unwrap_or(0)
+    }
+    fn poll_write(&self, offset: usize, buf: &[u8]) -> usize {
+        block_on(self.write(offset, buf)).unwrap_or(0)
     }
 
-    fn create<'a>(
-        &'a self,
-        _name: &'a str,
-        _kind: super::new_vfs::VfsFileKind,
-    ) -> ASysResult<Self> {
-        todo!()
+    fn ioctl(&self, cmd: IOCTLCmd, arg: usize) -> ASysResult<usize> {
+        dyn_future(async move {
+            let lproc = get_curr_lproc().unwrap();
+            match cmd {
+                IOCTLCmd::BLKGETSIZE => {
+                    UserWritePtr::<usize>::from(arg).write(&lproc, self.size() / BLOCK_SIZE)?;
+                }
+                IOCTLCmd::BLKGETSIZE64 => {
+                    UserWritePtr::<u64>::from(arg).write(&lproc, self.size() as u64)?;
+                }
+                IOCTLCmd::BLKSSZGET => {
+                    UserWritePtr::<i32>::from(arg).write(&lproc, self.dev.block_size() as i32)?;
+                }
+                IOCTLCmd::BLKFLSBUF => {
+                    self.dev.flush().await.map_err(cvt_err)?;
+                }
+                _ => {
+                    warn!("unsupported ioctl cmd on block device: {:?}", cmd);
+                    return Err(SysError::ENOTTY);
+                }
+            }
+            Ok(0)
+        })
     }
 
-    fn rename(&self, _file: &Self, _new_name: &str) -> ASysResult {
-        todo!()
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
     }
+}
 
-    fn detach(&self, _file: &Self) -> ASysResult {
-        todo!()
+/// 所有的块设备, 包括分区
+static DISKS: LazyInit<Vec<Disk>> = LazyInit::new();
+
+/// 为探测到的每个磁盘和它上面的分区分配名字和设备号
+pub async fn probe_disks() {
+    let (mut virtio_cnt, mut mmc_cnt) = (0, 0);
+    let mut disks = Vec::new();
+    for dev in drivers::get_device_manager().disks() {
+        // 每个磁盘占用一段次设备号, 第一个是整个磁盘, 之后是分区
+        let (name, major, minor, minors, part_prefix) = match dev.name() {
+            "virtio_blk" if virtio_cnt < 26 => {
+                let name = format!("vd{}", (b'a' + virtio_cnt as u8) as char);
+                let minor = virtio_cnt * VIRTBLK_MINORS;
+                virtio_cnt += 1;
+                (name, VIRTBLK_MAJOR, minor, VIRTBLK_MINORS, "")
+            }
+            "snps,dw_mshc" => {
+                let name = format!("mmcblk{}", mmc_cnt);
+                let minor = mmc_cnt * MMC_BLOCK_MINORS;
+                mmc_cnt += 1;
+                (name, MMC_BLOCK_MAJOR, minor, MMC_BLOCK_MINORS, "p")
+            }
+            name => {
+                warn!("block device {:?} not exposed in /dev", name);
+                continue;
+            }
+        };
+        info!("  block device {}: {}", name, dev.name());
+        disks.push(Disk::new(name.clone(), makedev(major, minor), dev.clone()));
+
+        let parts = match partition::read_partitions(&dev).await {
+            Ok(parts) => parts,
+            Err(e) => {
+                warn!("{}: failed to read partition table: {:?}", name, e);
+                Vec::new()
+            }
+        };
+        for info in parts {
+            let no = info.no;
+            if no >= minors {
+                warn!("{}: too many partitions, partition {} ignored", name, no);
+                continue;
+            }
+            let part_name = format!("{}{}{}", name, part_prefix, no);
+            let part = Partition::new(part_name.clone(), info, dev.clone());
+            let rdev = makedev(major, minor + no);
+            disks.push(Disk::new_partition(part_name, rdev, Arc::new(part)));
+        }
     }
+    DISKS.init_by(disks);
+}
 
-    fn attach<'a>(&'a self, _file: &'a Self, _name: &'a str) -> ASysResult {
-        todo!()
+pub fn disks() -> &'static [Disk] {
+    &DISKS
+}
+
+/// 按名字 (不带 /dev/) 找到块设备
+pub fn find_disk(name: &str) -> Option<&'static Disk> {
+    DISKS.iter().find(|d| d.name() == name)
+}
+
+/// 按 Linux 的 root= 的写法找到块设备: PARTUUID=..., PARTLABEL=..., /dev/vda2 或 vda2
+pub fn find_disk_by_spec(spec: &str) -> Option<&'static Disk> {
+    if let Some(uuid) = spec.strip_prefix("PARTUUID=") {
+        DISKS.iter().find(|d| {
+            d.partition()
+                .and_then(|p| p.uuid.as_deref())
+                .map_or(false, |u| u.eq_ignore_ascii_case(uuid))
+        })
+    } else if let Some(label) = spec.strip_prefix("PARTLABEL=") {
+        DISKS
+            .iter()
+            .find(|d| d.partition().and_then(|p| p.label.as_deref()) == Some(label))
+    } else {
+        find_disk(spec.strip_prefix("/dev/").unwrap_or(spec))
     }
 }
+
+/// 和 glibc 的 makedev 一样编码设备号
+pub const fn makedev(major: usize, minor: usize) -> usize {
+    (minor & 0xff) | (major & 0xfff) << 8 | (minor & !0xff) << 12 | (major & !0xfff) << 32
+}
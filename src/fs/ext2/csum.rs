@@ -0,0 +1,199 @@
+//! ext4 的 metadata_csum 特性: 超级块, 块组描述符, inode, extent 块和目录块都带有 crc32c 校验和.
+//! 除了超级块, 校验和都以文件系统的种子开始计算, inode 相关的还要再混入 inode 号和 generation,
+//! 这样把一个块错放到别的地方也能被发现
+
+use super::{
+    fs::Ext2FS,
+    layout::{
+        read_struct, DirEntryHead, Inode, DIR_ENTRY_HEAD_SIZE, GOOD_OLD_INODE_SIZE,
+        GROUP_DESC_CHECKSUM_OFFSET, INODE_CHECKSUM_HI_OFFSET, INODE_CHECKSUM_LO_OFFSET,
+        INODE_EXTRA_ISIZE_OFFSET,
+    },
+    InodeID,
+};
+use crate::tools::errors::{SysError, SysResult};
+use log::warn;
+
+/// crc32c 的多项式 (按位反转)
+const CRC32C_POLY: u32 = 0x82F6_3B78;
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32C_POLY
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32C_TABLE: [u32; 256] = make_table();
+
+/// 不做最后取反的 crc32c, 和 Linux 的 crc32c_le 一样, 可以分段计算
+pub fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        crc = CRC32C_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// 目录叶子块末尾存放校验和的伪目录项
+const DIR_TAIL_SIZE: usize = 12;
+const DIR_TAIL_FILE_TYPE: u8 = 0xDE;
+
+/// htree 索引块在 count/limit 数组之后的 dx_tail: 4 字节保留, 4 字节校验和
+const DX_TAIL_SIZE: usize = 8;
+
+impl Ext2FS {
+    /// inode 相关的校验和都从这个种子开始计算
+    fn inode_csum_seed(&self, seed: u32, ino: InodeID, inode: &Inode) -> u32 {
+        let crc = crc32c(seed, &ino.to_le_bytes());
+        crc32c(crc, &inode.generation.to_le_bytes())
+    }
+
+    pub(super) fn verify_group_desc(&self, group: usize, raw: &[u8]) -> SysResult {
+        let seed = match self.csum_seed {
+            Some(seed) => seed,
+            None => return Ok(()),
+        };
+        let off = GROUP_DESC_CHECKSUM_OFFSET;
+        let mut crc = crc32c(seed, &(group as u32).to_le_bytes());
+        crc = crc32c(crc, &raw[..off]);
+        crc = crc32c(crc, &[0, 0]);
+        crc = crc32c(crc, &raw[off + 2..]);
+        let expected: u16 = read_struct(&raw[off..]);
+        if crc as u16 != expected {
+            warn!("ext4: bad checksum of group descriptor {}", group);
+            return Err(SysError::EIO);
+        }
+        Ok(())
+    }
+
+    /// raw 是磁盘上完整的 inode, 长度为 inode_size
+    pub(super) fn verify_inode(&self, ino: InodeID, raw: &[u8]) -> SysResult {
+        let seed = match self.csum_seed {
+            Some(seed) => seed,
+            None => return Ok(()),
+        };
+        let inode: Inode = read_struct(raw);
+        let old_size = GOOD_OLD_INODE_SIZE as usize;
+        let lo_off = INODE_CHECKSUM_LO_OFFSET;
+        let hi_off = INODE_CHECKSUM_HI_OFFSET;
+
+        let mut crc = self.inode_csum_seed(seed, ino, &inode);
+        crc = crc32c(crc, &raw[..lo_off]);
+        crc = crc32c(crc, &[0, 0]);
+        crc = crc32c(crc, &raw[lo_off + 2..old_size]);
+        let mut expected = read_struct::<u16>(&raw[lo_off..]) as u32;
+        let mut mask = 0xFFFF;
+        if raw.len() > old_size {
+            // i_checksum_hi 只有在 i_extra_isize 覆盖到它时才存在
+            let extra_isize: u16 = read_struct(&raw[INODE_EXTRA_ISIZE_OFFSET..]);
+            crc = crc32c(crc, &raw[old_size..hi_off]);
+            if old_size + extra_isize as usize >= hi_off + 2 {
+                crc = crc32c(crc, &[0, 0]);
+                crc = crc32c(crc, &raw[hi_off + 2..]);
+                expected |= (read_struct::<u16>(&raw[hi_off..]) as u32) << 16;
+                mask = u32::MAX;
+            } else {
+                crc = crc32c(crc, &raw[hi_off..]);
+            }
+        }
+        if crc & mask != expected {
+            warn!("ext4: bad checksum of inode {}", ino);
+            return Err(SysError::EIO);
+        }
+        Ok(())
+    }
+
+    /// extent 树的非根节点, 校验和放在 eh_max 个项之后
+    pub(super) fn verify_extent_block(
+        &self,
+        ino: InodeID,
+        inode: &Inode,
+        buf: &[u8],
+        max_entries: usize,
+    ) -> SysResult {
+        let seed = match self.csum_seed {
+            Some(seed) => seed,
+            None => return Ok(()),
+        };
+        // 头部和每一项都是 12 字节
+        let tail = 12 + 12 * max_entries;
+        if tail + 4 > buf.len() {
+            warn!("ext4: no space for extent block checksum in inode {}", ino);
+            return Err(SysError::EIO);
+        }
+        let crc = crc32c(self.inode_csum_seed(seed, ino, inode), &buf[..tail]);
+        if crc != read_struct::<u32>(&buf[tail..]) {
+            warn!("ext4: bad checksum of extent block in inode {}", ino);
+            return Err(SysError::EIO);
+        }
+        Ok(())
+    }
+
+    /// 目录的第 idx 块. 普通的目录块末尾有一个存放校验和的伪目录项,
+    /// htree 的索引块则把校验和放在索引项之后
+    pub(super) fn verify_dir_block(
+        &self,
+        ino: InodeID,
+        dir: &Inode,
+        idx: usize,
+        buf: &[u8],
+    ) -> SysResult {
+        let seed = match self.csum_seed {
+            Some(seed) => seed,
+            None => return Ok(()),
+        };
+        let seed = self.inode_csum_seed(seed, ino, dir);
+        let bs = buf.len();
+
+        let tail: DirEntryHead = read_struct(&buf[bs - DIR_TAIL_SIZE..]);
+        if tail.inode == 0
+            && tail.rec_len as usize == DIR_TAIL_SIZE
+            && tail.name_len == 0
+            && tail.file_type == DIR_TAIL_FILE_TYPE
+        {
+            let crc = crc32c(seed, &buf[..bs - DIR_TAIL_SIZE]);
+            if crc != read_struct::<u32>(&buf[bs - DIR_TAIL_SIZE + DIR_ENTRY_HEAD_SIZE..]) {
+                warn!("ext4: bad checksum of dir block {} in inode {}", idx, ino);
+                return Err(SysError::EIO);
+            }
+            return Ok(());
+        }
+
+        let count_offset = match self.dx_count_offset(dir, idx, buf) {
+            Some(offset) if offset + 4 <= bs => offset,
+            _ => {
+                warn!("ext4: no checksum in dir block {} of inode {}", idx, ino);
+                return Err(SysError::EIO);
+            }
+        };
+        let limit = read_struct::<u16>(&buf[count_offset..]) as usize;
+        let count = read_struct::<u16>(&buf[count_offset + 2..]) as usize;
+        let tail = count_offset + limit * 8;
+        if count > limit || tail + DX_TAIL_SIZE > bs {
+            warn!("ext4: no space for htree checksum in inode {}", ino);
+            return Err(SysError::EIO);
+        }
+        // 和 inode 一样, 校验和字段本身按 0 参与计算
+        let crc = crc32c(seed, &buf[..count_offset + count * 8]);
+        let crc = crc32c(crc, &buf[tail..tail + 4]);
+        let crc = crc32c(crc, &[0; 4]);
+        if crc != read_struct::<u32>(&buf[tail + 4..]) {
+            warn!("ext4: bad checksum of htree block {} in inode {}", idx, ino);
+            return Err(SysError::EIO);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+//! 目录的内容是一串变长的目录项, 每一项都不跨块, 块中最后一项的 rec_len 延伸到块尾.
+//! inode 为 0 的目录项是空闲的
+
+use super::{
+    fs::Ext2FS,
+    layout::{
+        dir_rec_len, read_struct, write_struct, DirEntryHead, Inode, DIR_ENTRY_HEAD_SIZE, INDEX_FL,
+    },
+    BlockID, InodeID,
+};
+use crate::tools::errors::{SysError, SysResult};
+use alloc::{
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+pub(super) struct DirEntry {
+    pub ino: InodeID,
+    pub name: String,
+    pub file_type: u8,
+}
+
+impl DirEntry {
+    pub fn is_dot(&self) -> bool {
+        self.name == "." || self.name == ".."
+    }
+}
+
+/// 在一个目录块中依次取出 (偏移, 目录项头部, 名字)
+struct BlockEntries<'a> {
+    buf: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for BlockEntries<'a> {
+    type Item = SysResult<(usize, DirEntryHead, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset + DIR_ENTRY_HEAD_SIZE > self.buf.len() {
+            return None;
+        }
+        let offset = self.offset;
+        let head: DirEntryHead = read_struct(&self.buf[offset..]);
+        let rec_len = head.rec_len as usize;
+        let name_end = offset + DIR_ENTRY_HEAD_SIZE + head.name_len as usize;
+        if rec_len < DIR_ENTRY_HEAD_SIZE
+            || offset + rec_len > self.buf.len()
+            || name_end > offset + rec_len
+        {
+            log::warn!("ext2: corrupted dir entry at {}: {:?}", offset, head);
+            self.offset = self.buf.len();
+            return Some(Err(SysError::EIO));
+        }
+        self.offset += rec_len;
+        let name = &self.buf[offset + DIR_ENTRY_HEAD_SIZE..name_end];
+        Some(Ok((offset, head, name)))
+    }
+}
+
+fn entries(buf: &[u8]) -> BlockEntries {
+    BlockEntries { buf, offset: 0 }
+}
+
+impl Ext2FS {
+    fn write_entry(&self, buf: &mut [u8], ino: InodeID, rec_len: usize, name: &str, file_type: u8) {
+        let head = DirEntryHead {
+            inode: ino,
+            rec_len: rec_len as u16,
+            name_len: name.len() as u8,
+            file_type: if self.has_filetype() { file_type } else { 0 },
+        };
+        write_struct(buf, &head);
+        buf[DIR_ENTRY_HEAD_SIZE..][..name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    fn dir_block_count(&self, dir: &Inode) -> usize {
+        dir.size() / self.block_size
+    }
+
+    /// 读出目录的第 idx 块并检查校验和, 空洞返回 None
+    pub(super) async fn read_dir_block(
+        &self,
+        ino: InodeID,
+        dir: &Inode,
+        idx: usize,
+        buf: &mut [u8],
+    ) -> SysResult<Option<BlockID>> {
+        match self.bmap(ino, dir, idx).await? {
+            Some(bid) => {
+                self.read_block(bid, buf).await?;
+                self.verify_dir_block(ino, dir, idx, buf)?;
+                Ok(Some(bid))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// 列出目录中的所有目录项, 包括 . 和 ..
+    pub(super) async fn read_dir(&self, ino: InodeID, dir: &Inode) -> SysResult<Vec<DirEntry>> {
+        let mut buf = vec![0; self.block_size];
+        let mut ret = Vec::new();
+        for idx in 0..self.dir_block_count(dir) {
+            if self.read_dir_block(ino, dir, idx, &mut buf).await?.is_none() {
+                continue;
+            }
+            for entry in entries(&buf) {
+                let (_, head, name) = entry?;
+                if head.inode != 0 {
+                    ret.push(DirEntry {
+                        ino: head.inode,
+                        name: String::from_utf8_lossy(name).to_string(),
+                        file_type: head.file_type,
+                    });
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// 查找名为 name 的目录项, 有 htree 索引时只需要看索引指向的块
+    pub(super) async fn find_entry(
+        &self,
+        ino: InodeID,
+        dir: &Inode,
+        name: &str,
+    ) -> SysResult<Option<DirEntry>> {
+        let blocks = match self.dx_lookup(ino, dir, name).await? {
+            Some(blocks) => blocks,
+            None => (0..self.dir_block_count(dir)).collect(),
+        };
+        let mut buf = vec![0; self.block_size];
+        for idx in blocks {
+            if self.read_dir_block(ino, dir, idx, &mut buf).await?.is_none() {
+                continue;
+            }
+            for entry in entries(&buf) {
+                let (_, head, entry_name) = entry?;
+                if head.inode != 0 && entry_name == name.as_bytes() {
+                    return Ok(Some(DirEntry {
+                        ino: head.inode,
+                        name: name.to_string(),
+                        file_type: head.file_type,
+                    }));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// 添加一个目录项. 优先利用已有目录项后面的空隙, 都放不下就在目录末尾加一个块.
+    /// 我们不维护 htree 索引, 所以会清除 INDEX_FL. 调用者负责写回 dir
+    pub(super) async fn add_entry(
+        &self,
+        dir_ino: InodeID,
+        dir: &mut Inode,
+        name: &str,
+        ino: InodeID,
+        file_type: u8,
+    ) -> SysResult {
+        let needed = dir_rec_len(name.len());
+        dir.flags &= !INDEX_FL;
+        let mut buf = vec![0; self.block_size];
+        for idx in 0..self.dir_block_count(dir) {
+            let bid = match self.read_dir_block(dir_ino, dir, idx, &mut buf).await? {
+                Some(bid) => bid,
+                None => continue,
+            };
+            let mut slot = None;
+            for entry in entries(&buf) {
+                let (offset, head, _) = entry?;
+                let used = if head.inode == 0 {
+                    0
+                } else {
+                    dir_rec_len(head.name_len as usize)
+                };
+                if head.rec_len as usize - used >= needed {
+                    slot = Some((offset, head, used));
+                    break;
+                }
+            }
+            if let Some((offset, mut head, used)) = slot {
+                let rec_len = head.rec_len as usize - used;
+                if used != 0 {
+                    // 把空隙从前一项中切出来
+                    head.rec_len = used as u16;
+                    write_struct(&mut buf[offset..], &head);
+                }
+                self.write_entry(&mut buf[offset + used..], ino, rec_len, name, file_type);
+                return self.write_block(bid, &buf).await;
+            }
+        }
+
+        let idx = self.dir_block_count(dir);
+        let bid = self.bmap_alloc(dir_ino, dir, idx).await?;
+        buf.fill(0);
+        self.write_entry(&mut buf, ino, self.block_size, name, file_type);
+        self.write_block(bid, &buf).await?;
+        dir.set_size(dir.size() + self.block_size);
+        Ok(())
+    }
+
+    /// 删除名为 name 的指向 ino 的目录项, 空出的空间并入前一项.
+    /// 找不到这个名字时 (例如硬链接的文件被换了名字) 就删除第一个指向 ino 的目录项
+    pub(super) async fn remove_entry(
+        &self,
+        dir_ino: InodeID,
+        dir: &Inode,
+        name: &str,
+        ino: InodeID,
+    ) -> SysResult {
+        for by_name in [true, false] {
+            let mut buf = vec![0; self.block_size];
+            for idx in 0..self.dir_block_count(dir) {
+                let bid = match self.read_dir_block(dir_ino, dir, idx, &mut buf).await? {
+                    Some(bid) => bid,
+                    None => continue,
+                };
+                let mut prev: Option<(usize, DirEntryHead)> = None;
+                let mut found = None;
+                for entry in entries(&buf) {
+                    let (offset, head, entry_name) = entry?;
+                    if head.inode == ino && (!by_name || entry_name == name.as_bytes()) {
+                        found = Some((offset, head, prev));
+                        break;
+                    }
+                    prev = Some((offset, head));
+                }
+                if let Some((offset, mut head, prev)) = found {
+                    match prev {
+                        Some((prev_offset, mut prev)) => {
+                            prev.rec_len += head.rec_len;
+                            write_struct(&mut buf[prev_offset..], &prev);
+                        }
+                        None => {
+                            // 块中的第一项不能合并, 只能标记为空闲
+                            head.inode = 0;
+                            write_struct(&mut buf[offset..], &head);
+                        }
+                    }
+                    return self.write_block(bid, &buf).await;
+                }
+            }
+        }
+        Err(SysError::ENOENT)
+    }
+
+    /// 让名为 name 的目录项指向 ino, 用于目录移动后更新 ..
+    pub(super) async fn set_entry(
+        &self,
+        dir_ino: InodeID,
+        dir: &Inode,
+        name: &str,
+        ino: InodeID,
+    ) -> SysResult {
+        let mut buf = vec![0; self.block_size];
+        for idx in 0..self.dir_block_count(dir) {
+            let bid = match self.read_dir_block(dir_ino, dir, idx, &mut buf).await? {
+                Some(bid) => bid,
+                None => continue,
+            };
+            let mut found = None;
+            for entry in entries(&buf) {
+                let (offset, head, entry_name) = entry?;
+                if head.inode != 0 && entry_name == name.as_bytes() {
+                    found = Some((offset, head));
+                    break;
+                }
+            }
+            if let Some((offset, mut head)) = found {
+                head.inode = ino;
+                write_struct(&mut buf[offset..], &head);
+                return self.write_block(bid, &buf).await;
+            }
+        }
+        Err(SysError::ENOENT)
+    }
+
+    /// 新目录的第一个块, 只有 . 和 ..
+    pub(super) fn init_dir_block(
+        &self,
+        buf: &mut [u8],
+        ino: InodeID,
+        parent: InodeID,
+        file_type: u8,
+    ) {
+        let dot_len = dir_rec_len(1);
+        self.write_entry(buf, ino, dot_len, ".", file_type);
+        self.write_entry(
+            &mut buf[dot_len..],
+            parent,
+            self.block_size - dot_len,
+            "..",
+            file_type,
+        );
+    }
+}
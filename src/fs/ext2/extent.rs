@@ -0,0 +1,77 @@
+//! ext4 的 extent 树. 树根放在 i_block 中, 每个节点由 12 字节的头部和若干 12 字节的项组成,
+//! 内部节点的项指向下一层节点, 叶子的项描述一段连续的磁盘块
+
+use super::{
+    fs::Ext2FS,
+    layout::{
+        read_struct, Extent, ExtentHeader, ExtentIndex, Inode, EXTENT_ENTRY_SIZE, EXTENT_MAGIC,
+        EXT_INIT_MAX_LEN,
+    },
+    BlockID, InodeID,
+};
+use crate::tools::errors::{SysError, SysResult};
+use alloc::vec;
+use log::warn;
+
+impl Ext2FS {
+    /// 在 extent 树中找到文件内第 idx 块对应的磁盘块, 空洞和未写入的 extent 返回 None
+    pub(super) async fn extent_bmap(
+        &self,
+        ino: InodeID,
+        inode: &Inode,
+        idx: usize,
+    ) -> SysResult<Option<BlockID>> {
+        let lblk = match u32::try_from(idx) {
+            Ok(lblk) => lblk,
+            Err(_) => return Ok(None),
+        };
+        let mut node = inode.block_bytes().to_vec();
+        let mut depth = None;
+        loop {
+            let head: ExtentHeader = read_struct(&node);
+            let entries = head.entries as usize;
+            if head.magic != EXTENT_MAGIC
+                || head.entries > head.max
+                || EXTENT_ENTRY_SIZE * (entries + 1) > node.len()
+                || depth.map_or(false, |depth| depth != head.depth)
+            {
+                warn!("ext4: corrupted extent node in inode {}: {:?}", ino, head);
+                return Err(SysError::EIO);
+            }
+            let entry = |i: usize| &node[EXTENT_ENTRY_SIZE * (i + 1)..];
+
+            if head.depth == 0 {
+                for i in 0..entries {
+                    let extent: Extent = read_struct(entry(i));
+                    let (len, init) = if extent.len > EXT_INIT_MAX_LEN {
+                        (extent.len - EXT_INIT_MAX_LEN, false)
+                    } else {
+                        (extent.len, true)
+                    };
+                    if lblk >= extent.block && lblk - extent.block < len as u32 {
+                        let start = extent.start_lo as BlockID | (extent.start_hi as BlockID) << 32;
+                        return Ok(init.then_some(start + (lblk - extent.block) as BlockID));
+                    }
+                }
+                return Ok(None);
+            }
+
+            // 项按起始块号排序, 找最后一个不大于 lblk 的
+            let index = (0..entries)
+                .map(|i| read_struct::<ExtentIndex>(entry(i)))
+                .take_while(|index| index.block <= lblk)
+                .last();
+            let index = match index {
+                Some(index) => index,
+                None => return Ok(None),
+            };
+            let leaf = index.leaf_lo as BlockID | (index.leaf_hi as BlockID) << 32;
+            let mut buf = vec![0; self.block_size];
+            self.read_block(leaf, &mut buf).await?;
+            let child: ExtentHeader = read_struct(&buf);
+            self.verify_extent_block(ino, inode, &buf, child.max as usize)?;
+            depth = Some(head.depth - 1);
+            node = buf;
+        }
+    }
+}
@@ -0,0 +1,481 @@
+use super::{
+    dir::DirEntry,
+    fs::Ext2FS,
+    layout::{
+        file_type_to_kind, kind_to_file_type, kind_to_mode, Inode, FT_DIR, NAME_LEN, N_BLOCKS,
+    },
+    InodeID,
+};
+use crate::{
+    fs::new_vfs::{
+        top::{DeviceInfo, SizeInfo, TimeInfo, TimeInfoChange},
+        underlying::ConcreteFile,
+        VfsFileKind,
+    },
+    timer::get_time_sec,
+    tools::errors::{dyn_future, ASysResult, SysError, SysResult},
+};
+use alloc::{string::String, vec, vec::Vec};
+use core::cell::SyncUnsafeCell;
+
+const NSEC_PER_SEC: usize = 1_000_000_000;
+
+fn now() -> u32 {
+    get_time_sec() as u32
+}
+
+/// 一个 ext2 文件只记录 inode 号, 每次操作都从磁盘读 inode.
+/// 硬链接会让多个 Ext2File 指向同一个 inode, 这样它们看到的总是同一份元数据
+pub struct Ext2File {
+    fs: &'static Ext2FS,
+    ino: InodeID,
+    kind: VfsFileKind,
+    /// 设备文件的设备号
+    rdev: usize,
+    /// 最后一次被找到或 attach 时的名字, 用于 rename 时找到目录项
+    name: SyncUnsafeCell<String>,
+}
+
+impl Ext2File {
+    pub fn new_root(fs: &'static Ext2FS, ino: InodeID) -> Self {
+        Self::new(fs, ino, VfsFileKind::Directory, 0, String::new())
+    }
+
+    fn new(
+        fs: &'static Ext2FS,
+        ino: InodeID,
+        kind: VfsFileKind,
+        rdev: usize,
+        name: String,
+    ) -> Self {
+        Self {
+            fs,
+            ino,
+            kind,
+            rdev,
+            name: SyncUnsafeCell::new(name),
+        }
+    }
+
+    fn name(&self) -> &mut String {
+        unsafe { &mut *self.name.get() }
+    }
+
+    async fn inode(&self) -> SysResult<Inode> {
+        self.fs.read_inode(self.ino).await
+    }
+    async fn write_inode(&self, inode: &Inode) -> SysResult {
+        self.fs.write_inode(self.ino, inode).await
+    }
+
+    /// 根据目录项构造文件. 没有 FILETYPE 特性, 或者是设备文件时需要读 inode
+    async fn into_file(&self, entry: DirEntry) -> SysResult<Self> {
+        let kind = file_type_to_kind(entry.file_type);
+        let (kind, rdev) = match kind {
+            Some(VfsFileKind::CharDevice | VfsFileKind::BlockDevice) | None => {
+                let inode = self.fs.read_inode(entry.ino).await?;
+                (inode.kind(), inode.rdev())
+            }
+            Some(kind) => (kind, 0),
+        };
+        Ok(Self::new(self.fs, entry.ino, kind, rdev, entry.name))
+    }
+
+    fn check_dir(&self) -> SysResult {
+        if self.kind == VfsFileKind::Directory {
+            Ok(())
+        } else {
+            Err(SysError::ENOTDIR)
+        }
+    }
+
+    fn check_name(name: &str) -> SysResult {
+        if name.len() > NAME_LEN {
+            Err(SysError::ENAMETOOLONG)
+        } else if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            Err(SysError::EINVAL)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// 释放 inode 以及它的所有块. 目录会连同其中的文件一起释放
+    fn release(&self, mut inode: Inode) -> ASysResult {
+        // 递归的 async 函数必须 Box
+        dyn_future(async move {
+            if inode.is_dir() {
+                for entry in self.fs.read_dir(self.ino, &inode).await? {
+                    if entry.is_dot() {
+                        continue;
+                    }
+                    let mut child = self.fs.read_inode(entry.ino).await?;
+                    child.links_count = child.links_count.saturating_sub(1);
+                    let child_file = self.into_file(entry).await?;
+                    if child.is_dir() || child.links_count == 0 {
+                        child_file.release(child).await?;
+                    } else {
+                        child_file.write_inode(&child).await?;
+                    }
+                }
+            }
+            if !inode.is_fast_symlink(self.fs.sectors_per_block) {
+                self.fs.truncate_blocks(&mut inode, 0).await?;
+            }
+            inode.links_count = 0;
+            inode.dtime = now();
+            self.write_inode(&inode).await?;
+            self.fs.free_inode(self.ino, inode.is_dir()).await
+        })
+    }
+
+    /// 短符号链接变长之后要搬到数据块里
+    async fn unfast_symlink(&self, inode: &mut Inode) -> SysResult {
+        let mut buf = vec![0; self.fs.block_size];
+        let target = inode.fast_symlink();
+        buf[..target.len()].copy_from_slice(target);
+        inode.block = [0; N_BLOCKS];
+        let bid = self.fs.bmap_alloc(self.ino, inode, 0).await?;
+        self.fs.write_block(bid, &buf).await
+    }
+}
+
+impl ConcreteFile for Ext2File {
+    fn attr_kind(&self) -> VfsFileKind {
+        self.kind
+    }
+    fn attr_device(&self) -> DeviceInfo {
+        DeviceInfo {
+            device_id: self.fs.device_id(),
+            self_device_id: self.rdev,
+        }
+    }
+    fn attr_size(&self) -> ASysResult<SizeInfo> {
+        dyn_future(async move {
+            let inode = self.inode().await?;
+            Ok(SizeInfo {
+                bytes: inode.size(),
+                blocks: inode.blocks as usize,
+            })
+        })
+    }
+    fn attr_time(&self) -> ASysResult<TimeInfo> {
+        dyn_future(async move {
+            let inode = self.inode().await?;
+            Ok(TimeInfo {
+                access: inode.atime as usize * NSEC_PER_SEC,
+                modify: inode.mtime as usize * NSEC_PER_SEC,
+                change: inode.ctime as usize * NSEC_PER_SEC,
+            })
+        })
+    }
+    fn attr_nlink(&self) -> ASysResult<usize> {
+        dyn_future(async move { Ok(self.inode().await?.links_count as usize) })
+    }
+    fn attr_ino(&self) -> usize {
+        self.ino as usize
+    }
+    fn update_time(&self, info: TimeInfoChange) -> ASysResult {
+        dyn_future(async move {
+            self.fs.check_writable()?;
+            let mut inode = self.inode().await?;
+            let mut time = TimeInfo {
+                access: inode.atime as usize * NSEC_PER_SEC,
+                modify: inode.mtime as usize * NSEC_PER_SEC,
+                change: inode.ctime as usize * NSEC_PER_SEC,
+            };
+            time.apply_change(info);
+            inode.atime = (time.access / NSEC_PER_SEC) as u32;
+            inode.mtime = (time.modify / NSEC_PER_SEC) as u32;
+            inode.ctime = now();
+            self.write_inode(&inode).await
+        })
+    }
+
+    fn delete(&self) -> ASysResult {
+        dyn_future(async move {
+            let inode = self.inode().await?;
+            // 还有别的硬链接, 只是少了一个名字. 目录不能有硬链接
+            if !inode.is_dir() && inode.links_count > 0 {
+                return Ok(());
+            }
+            self.release(inode).await
+        })
+    }
+    // inode 和各种元数据都是直接写到磁盘上的, 没有需要写回的缓存
+    fn sync_metadata(&self) -> ASysResult {
+        dyn_future(async { Ok(()) })
+    }
+    fn sync_fs(&self) -> ASysResult {
+        dyn_future(async { Ok(()) })
+    }
+
+    fn read_page_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> ASysResult<usize> {
+        dyn_future(async move {
+            let inode = self.inode().await?;
+            let size = inode.size();
+            if offset >= size {
+                return Ok(0);
+            }
+            let len = buf.len().min(size - offset);
+
+            if inode.is_fast_symlink(self.fs.sectors_per_block) {
+                buf[..len].copy_from_slice(&inode.fast_symlink()[offset..offset + len]);
+                return Ok(len);
+            }
+
+            let bs = self.fs.block_size;
+            let mut block_buf = Vec::new();
+            let mut pos = offset;
+            while pos < offset + len {
+                let (idx, block_off) = (pos / bs, pos % bs);
+                let n = (bs - block_off).min(offset + len - pos);
+                let dst = &mut buf[pos - offset..pos - offset + n];
+                match self.fs.bmap(self.ino, &inode, idx).await? {
+                    // 空洞读出来是 0
+                    None => dst.fill(0),
+                    Some(bid) if n == bs => self.fs.read_block(bid, dst).await?,
+                    Some(bid) => {
+                        // 文件尾之后的部分要保持为 0, 所以不能直接读进 buf
+                        block_buf.resize(bs, 0);
+                        self.fs.read_block(bid, &mut block_buf).await?;
+                        dst.copy_from_slice(&block_buf[block_off..block_off + n]);
+                    }
+                }
+                pos += n;
+            }
+            Ok(len)
+        })
+    }
+
+    fn write_page_at<'a>(&'a self, offset: usize, buf: &'a [u8]) -> ASysResult<usize> {
+        // 同 FAT32, 只写文件长度以内的部分, 文件由 truncate 变长
+        dyn_future(async move {
+            self.fs.check_writable()?;
+            let mut inode = self.inode().await?;
+            let size = inode.size();
+            if offset >= size {
+                return Ok(0);
+            }
+            let len = buf.len().min(size - offset);
+
+            if inode.is_fast_symlink(self.fs.sectors_per_block) {
+                inode.fast_symlink_mut()[offset..offset + len].copy_from_slice(&buf[..len]);
+                self.write_inode(&inode).await?;
+                return Ok(len);
+            }
+
+            let bs = self.fs.block_size;
+            let mut block_buf = Vec::new();
+            let mut pos = offset;
+            while pos < offset + len {
+                let (idx, block_off) = (pos / bs, pos % bs);
+                let n = (bs - block_off).min(offset + len - pos);
+                let src = &buf[pos - offset..pos - offset + n];
+                let bid = self.fs.bmap_alloc(self.ino, &mut inode, idx).await?;
+                if n == bs {
+                    self.fs.write_block(bid, src).await?;
+                } else {
+                    block_buf.resize(bs, 0);
+                    self.fs.read_block(bid, &mut block_buf).await?;
+                    block_buf[block_off..block_off + n].copy_from_slice(src);
+                    self.fs.write_block(bid, &block_buf).await?;
+                }
+                pos += n;
+            }
+
+            inode.mtime = now();
+            inode.ctime = inode.mtime;
+            self.write_inode(&inode).await?;
+            Ok(len)
+        })
+    }
+
+    fn truncate(&self, new_size: usize) -> ASysResult {
+        dyn_future(async move {
+            self.fs.check_writable()?;
+            if self.kind == VfsFileKind::Directory {
+                return Err(SysError::EISDIR);
+            }
+            if new_size > u32::MAX as usize && self.kind != VfsFileKind::RegularFile {
+                return Err(SysError::EFBIG);
+            }
+            let mut inode = self.inode().await?;
+            let old_size = inode.size();
+            let bs = self.fs.block_size;
+
+            if inode.is_fast_symlink(self.fs.sectors_per_block) {
+                if new_size < N_BLOCKS * 4 {
+                    if new_size < old_size {
+                        inode.fast_symlink_mut()[new_size..old_size].fill(0);
+                    }
+                    inode.set_size(new_size);
+                    inode.mtime = now();
+                    inode.ctime = inode.mtime;
+                    return self.write_inode(&inode).await;
+                }
+                self.unfast_symlink(&mut inode).await?;
+            }
+
+            if new_size < old_size {
+                let keep = (new_size + bs - 1) / bs;
+                self.fs.truncate_blocks(&mut inode, keep).await?;
+                // 最后一块中文件尾之后的部分清零, 再变长时才能读到 0
+                if new_size % bs != 0 {
+                    if let Some(bid) = self.fs.bmap(self.ino, &inode, keep - 1).await? {
+                        let mut block_buf = vec![0; bs];
+                        self.fs.read_block(bid, &mut block_buf).await?;
+                        block_buf[new_size % bs..].fill(0);
+                        self.fs.write_block(bid, &block_buf).await?;
+                    }
+                }
+            }
+            // 变长时不分配块, 留下的空洞读出来是 0
+
+            inode.set_size(new_size);
+            inode.mtime = now();
+            inode.ctime = inode.mtime;
+            self.write_inode(&inode).await
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> ASysResult<Self> {
+        dyn_future(async move {
+            self.check_dir()?;
+            // . 和 .. 由 VFS 处理, 不能让它们进入目录缓存
+            if name == "." || name == ".." {
+                return Err(SysError::ENOENT);
+            }
+            let inode = self.inode().await?;
+            match self.fs.find_entry(self.ino, &inode, name).await? {
+                Some(entry) => self.into_file(entry).await,
+                None => Err(SysError::ENOENT),
+            }
+        })
+    }
+
+    fn list(&self) -> ASysResult<Vec<(String, Self)>> {
+        dyn_future(async move {
+            self.check_dir()?;
+            let inode = self.inode().await?;
+            let mut res = Vec::new();
+            for entry in self.fs.read_dir(self.ino, &inode).await? {
+                if entry.is_dot() {
+                    continue;
+                }
+                let name = entry.name.clone();
+                res.push((name, self.into_file(entry).await?));
+            }
+            Ok(res)
+        })
+    }
+
+    fn create<'a>(&'a self, name: &'a str, kind: VfsFileKind) -> ASysResult<Self> {
+        dyn_future(async move {
+            self.fs.check_writable()?;
+            self.check_dir()?;
+            Self::check_name(name)?;
+            let mut dir = self.inode().await?;
+            if self.fs.find_entry(self.ino, &dir, name).await?.is_some() {
+                return Err(SysError::EEXIST);
+            }
+
+            let time = now();
+            let mut inode = Inode::new(kind_to_mode(kind), time);
+            let is_dir = kind == VfsFileKind::Directory;
+            inode.links_count = if is_dir { 2 } else { 1 };
+            let ino = self.fs.alloc_inode(self.ino, &inode).await?;
+            let file = Self::new(self.fs, ino, kind, 0, String::from(name));
+
+            if is_dir {
+                let bid = self.fs.bmap_alloc(ino, &mut inode, 0).await?;
+                let mut block_buf = vec![0; self.fs.block_size];
+                self.fs.init_dir_block(&mut block_buf, ino, self.ino, FT_DIR);
+                self.fs.write_block(bid, &block_buf).await?;
+                inode.set_size(self.fs.block_size);
+                file.write_inode(&inode).await?;
+                // 新目录的 .. 指向这里
+                dir.links_count += 1;
+            }
+
+            self.fs
+                .add_entry(self.ino, &mut dir, name, ino, kind_to_file_type(kind))
+                .await?;
+            dir.mtime = time;
+            dir.ctime = time;
+            self.write_inode(&dir).await?;
+            Ok(file)
+        })
+    }
+
+    fn rename<'a>(&'a self, file: &'a Self, new_name: &'a str) -> ASysResult {
+        dyn_future(async move {
+            self.fs.check_writable()?;
+            Self::check_name(new_name)?;
+            let mut dir = self.inode().await?;
+            if self.fs.find_entry(self.ino, &dir, new_name).await?.is_some() {
+                return Err(SysError::EEXIST);
+            }
+            self.fs.remove_entry(self.ino, &dir, file.name(), file.ino).await?;
+            let file_type = kind_to_file_type(file.kind);
+            self.fs.add_entry(self.ino, &mut dir, new_name, file.ino, file_type).await?;
+            dir.mtime = now();
+            dir.ctime = dir.mtime;
+            self.write_inode(&dir).await?;
+            *file.name() = String::from(new_name);
+            Ok(())
+        })
+    }
+
+    fn detach<'a>(&'a self, file: &'a Self, name: &'a str) -> ASysResult {
+        dyn_future(async move {
+            self.fs.check_writable()?;
+            let mut dir = self.inode().await?;
+            // 有硬链接时 file 可能是通过别的名字找到的, 以调用者给的名字为准
+            self.fs.remove_entry(self.ino, &dir, name, file.ino).await?;
+
+            let time = now();
+            let mut inode = file.inode().await?;
+            inode.links_count -= 1;
+            inode.ctime = time;
+            file.write_inode(&inode).await?;
+            if inode.is_dir() {
+                // 它的 .. 不再算作这里的链接, 在 attach 到新的父目录时会被更新
+                dir.links_count -= 1;
+            }
+            dir.mtime = time;
+            dir.ctime = time;
+            self.write_inode(&dir).await
+        })
+    }
+
+    fn attach<'a>(&'a self, file: &'a Self, name: &'a str) -> ASysResult {
+        dyn_future(async move {
+            self.fs.check_writable()?;
+            Self::check_name(name)?;
+            let mut dir = self.inode().await?;
+            if self.fs.find_entry(self.ino, &dir, name).await?.is_some() {
+                return Err(SysError::EEXIST);
+            }
+            let mut inode = file.inode().await?;
+            if inode.links_count == u16::MAX {
+                return Err(SysError::EMLINK);
+            }
+            let file_type = kind_to_file_type(file.kind);
+            self.fs.add_entry(self.ino, &mut dir, name, file.ino, file_type).await?;
+
+            let time = now();
+            inode.links_count += 1;
+            inode.ctime = time;
+            file.write_inode(&inode).await?;
+            if inode.is_dir() {
+                self.fs.set_entry(file.ino, &inode, "..", self.ino).await?;
+                dir.links_count += 1;
+            }
+            dir.mtime = time;
+            dir.ctime = time;
+            self.write_inode(&dir).await?;
+            *file.name() = String::from(name);
+            Ok(())
+        })
+    }
+}
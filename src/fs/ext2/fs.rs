@@ -0,0 +1,621 @@
+use super::{
+    csum::crc32c,
+    file::Ext2File,
+    layout::{
+        read_struct, write_struct, GroupDesc, Inode, SuperBlock, CHECKSUM_TYPE_CRC32C, EXT2_MAGIC,
+        FEATURE_COMPAT_DIR_INDEX, FEATURE_INCOMPAT_CSUM_SEED, FEATURE_INCOMPAT_FILETYPE,
+        FEATURE_INCOMPAT_RECOVER, FEATURE_INCOMPAT_RO_SUPP, FEATURE_INCOMPAT_SUPP,
+        FEATURE_RO_COMPAT_METADATA_CSUM, FEATURE_RO_COMPAT_SUPP, FLAGS_UNSIGNED_HASH,
+        MIN_DESC_SIZE_64BIT, NAME_LEN, ROOT_INO, SB_CHECKSUM_OFFSET, SB_CHECKSUM_SEED_OFFSET,
+        SUPER_BLOCK_OFFSET, SUPER_BLOCK_SIZE,
+    },
+    BlkDevRef, BlockID, InodeID,
+};
+use crate::{
+    fs::{
+        disk::BLOCK_SIZE,
+        new_vfs::{
+            page_lru,
+            path_cache::PathCacheDir,
+            sync_attr_file::SyncAttrFile,
+            top::{VfsFS, VfsFSAttr, VfsFSKind, VfsFileRef},
+            DeviceIDCollection,
+        },
+        nfat32::cvt_err,
+    },
+    here,
+    sync::{SleepLock, SpinNoIrqLock},
+    timer::get_time_sec,
+    tools::errors::{dyn_future, ASysResult, SysError, SysResult},
+};
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::pin::Pin;
+use log::{debug, info, warn};
+
+/// 块大小的上限, 同 Linux 的 EXT4_MAX_BLOCK_LOG_SIZE
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+/// inode 大小的下限, 即 rev 0 中 inode 的大小
+const MIN_INODE_SIZE: usize = 128;
+
+/// 检查超级块中的几何参数. 它们都来自磁盘, 不合理的值会让后面的计算溢出或者除以 0,
+/// 所以在用 block_size, group_count 等之前先检查一遍
+fn check_geometry(sb: &SuperBlock, dev_bytes: u64) -> SysResult {
+    if sb.log_block_size > MAX_LOG_BLOCK_SIZE {
+        warn!("ext2: bad block size 1024 << {}", sb.log_block_size);
+        return Err(SysError::EINVAL);
+    }
+    let block_size = sb.block_size();
+    // 块位图和 inode 位图都只占一个块
+    let bits_per_block = (block_size * 8) as u32;
+    if sb.blocks_per_group == 0
+        || sb.blocks_per_group > bits_per_block
+        || sb.inodes_per_group == 0
+        || sb.inodes_per_group > bits_per_block
+    {
+        warn!(
+            "ext2: bad group size, {} blocks and {} inodes per group",
+            sb.blocks_per_group, sb.inodes_per_group
+        );
+        return Err(SysError::EINVAL);
+    }
+    let inode_size = sb.inode_size();
+    if !inode_size.is_power_of_two() || inode_size < MIN_INODE_SIZE || inode_size > block_size {
+        warn!("ext2: bad inode size {}", inode_size);
+        return Err(SysError::EINVAL);
+    }
+    let desc_size = sb.desc_size();
+    if sb.is_64bit()
+        && (!desc_size.is_power_of_two()
+            || desc_size < MIN_DESC_SIZE_64BIT
+            || desc_size > block_size)
+    {
+        warn!("ext2: bad group descriptor size {}", desc_size);
+        return Err(SysError::EINVAL);
+    }
+    let fs_bytes = sb.blocks_count().checked_mul(block_size as u64);
+    if sb.blocks_count() <= sb.first_data_block as BlockID
+        || !matches!(fs_bytes, Some(bytes) if bytes <= dev_bytes)
+    {
+        warn!(
+            "ext2: {} blocks (first data block {}) do not fit in the device",
+            sb.blocks_count(),
+            sb.first_data_block
+        );
+        return Err(SysError::EINVAL);
+    }
+    // 同 Linux, 每个块组的 inode 数相同, inode 总数必须正好是它们的和
+    if sb.group_count() as u64 * sb.inodes_per_group as u64 != sb.inodes_count as u64 {
+        warn!(
+            "ext2: {} groups of {} inodes, but {} inodes in total",
+            sb.group_count(),
+            sb.inodes_per_group,
+            sb.inodes_count
+        );
+        return Err(SysError::EINVAL);
+    }
+    Ok(())
+}
+
+/// 内存中的超级块和块组描述符, 修改后立即写回磁盘
+struct Ext2Meta {
+    sb: SuperBlock,
+    /// 整个超级块, 写回时只覆盖 SuperBlock 中的字段
+    sb_raw: Vec<u8>,
+    groups: Vec<GroupDesc>,
+}
+
+pub struct Ext2FS {
+    blk_dev: SleepLock<BlkDevRef>,
+    meta: SpinNoIrqLock<Ext2Meta>,
+    /// 串行化位图和 inode 表的读-改-写, 同一个块里可能有好几个 inode
+    alloc_lock: SleepLock<()>,
+
+    // FS Info
+    device_id: usize,
+    read_only: bool,
+    /// 用到了 ext4 特有的 incompat 特性
+    is_ext4: bool,
+    pub(super) block_size: usize,
+    pub(super) sectors_per_block: u32,
+    inode_size: usize,
+    desc_size: usize,
+    inodes_count: u32,
+    inodes_per_group: u32,
+    blocks_per_group: u32,
+    first_data_block: BlockID,
+    blocks_count: BlockID,
+    has_filetype: bool,
+    pub(super) has_dir_index: bool,
+    pub(super) hash_seed: [u32; 4],
+    /// htree 的哈希按无符号 char 计算
+    pub(super) hash_unsigned: bool,
+    /// 有 metadata_csum 特性时校验和的种子
+    pub(super) csum_seed: Option<u32>,
+}
+
+impl Ext2FS {
+    /// 读超级块, 看看块设备上是不是 ext2
+    pub async fn probe(blk_dev: &BlkDevRef) -> bool {
+        let mut buf = [0; BLOCK_SIZE];
+        let sector = (SUPER_BLOCK_OFFSET / BLOCK_SIZE) as u64;
+        if blk_dev.read_block(sector, &mut buf).await.is_err() {
+            return false;
+        }
+        read_struct::<SuperBlock>(&buf).magic == EXT2_MAGIC
+    }
+
+    pub async fn new(blk_dev: BlkDevRef, read_only: bool) -> SysResult<Self> {
+        let mut sb_raw = vec![0; SUPER_BLOCK_SIZE];
+        let first_sector = (SUPER_BLOCK_OFFSET / BLOCK_SIZE) as u64;
+        for (i, buf) in sb_raw.chunks_mut(BLOCK_SIZE).enumerate() {
+            blk_dev.read_block(first_sector + i as u64, buf).await.map_err(cvt_err)?;
+        }
+        let sb: SuperBlock = read_struct(&sb_raw);
+        if sb.magic != EXT2_MAGIC {
+            warn!("ext2: bad magic {:#x}", sb.magic);
+            return Err(SysError::EINVAL);
+        }
+        let dev_bytes = blk_dev.num_blocks().saturating_mul(blk_dev.block_size() as u64);
+        check_geometry(&sb, dev_bytes)?;
+
+        // 不认识的 incompat 特性意味着我们根本读不懂这个文件系统
+        let known_incompat = FEATURE_INCOMPAT_SUPP | FEATURE_INCOMPAT_RO_SUPP;
+        if sb.has_incompat(FEATURE_INCOMPAT_RECOVER) {
+            warn!("ext2: the journal needs recovery");
+            return Err(SysError::EINVAL);
+        }
+        if sb.has_incompat(!known_incompat) {
+            warn!(
+                "ext2: unsupported incompat features {:#x}",
+                sb.feature_incompat & !known_incompat
+            );
+            return Err(SysError::EINVAL);
+        }
+        // ext4 的 extent 等特性, 以及不认识的 ro_compat 特性只能只读挂载
+        let is_ext4 = sb.has_incompat(FEATURE_INCOMPAT_RO_SUPP);
+        let read_only = read_only || is_ext4 || sb.has_ro_compat(!FEATURE_RO_COMPAT_SUPP);
+        if is_ext4 {
+            warn!(
+                "ext2: ext4 features {:#x}, mount read-only",
+                sb.feature_incompat & FEATURE_INCOMPAT_RO_SUPP
+            );
+        }
+        if sb.has_ro_compat(!FEATURE_RO_COMPAT_SUPP) {
+            warn!(
+                "ext2: unsupported ro_compat features {:#x}, mount read-only",
+                sb.feature_ro_compat & !FEATURE_RO_COMPAT_SUPP
+            );
+        }
+
+        let csum_seed = if sb.has_ro_compat(FEATURE_RO_COMPAT_METADATA_CSUM) {
+            let checksum: u32 = read_struct(&sb_raw[SB_CHECKSUM_OFFSET..]);
+            if sb.checksum_type != CHECKSUM_TYPE_CRC32C
+                || crc32c(!0, &sb_raw[..SB_CHECKSUM_OFFSET]) != checksum
+            {
+                warn!("ext2: bad superblock checksum");
+                return Err(SysError::EINVAL);
+            }
+            if sb.has_incompat(FEATURE_INCOMPAT_CSUM_SEED) {
+                Some(read_struct(&sb_raw[SB_CHECKSUM_SEED_OFFSET..]))
+            } else {
+                Some(crc32c(!0, &sb.uuid))
+            }
+        } else {
+            None
+        };
+
+        let block_size = sb.block_size();
+        let sectors_per_block = (block_size / BLOCK_SIZE) as u32;
+        let group_count = sb.group_count();
+        let desc_size = sb.desc_size();
+        info!(
+            "ext2: {} blocks of {} bytes, {} inodes, {} groups",
+            sb.blocks_count(),
+            block_size,
+            sb.inodes_count,
+            group_count
+        );
+        debug!("ext2: {:?}", sb);
+
+        // 块组描述符表紧跟在超级块所在的块之后
+        let gdt_block = sb.first_data_block as u64 + 1;
+        let gdt_blocks = (group_count * desc_size + block_size - 1) / block_size;
+        let mut gdt = vec![0; gdt_blocks * block_size];
+        for (i, buf) in gdt.chunks_mut(BLOCK_SIZE).enumerate() {
+            let sector = gdt_block * sectors_per_block as u64 + i as u64;
+            blk_dev.read_block(sector, buf).await.map_err(cvt_err)?;
+        }
+
+        let fs = Self {
+            blk_dev: SleepLock::new(blk_dev),
+            meta: SpinNoIrqLock::new(Ext2Meta {
+                sb,
+                sb_raw,
+                groups: Vec::new(),
+            }),
+            alloc_lock: SleepLock::new(()),
+            device_id: DeviceIDCollection::alloc(),
+            read_only,
+            is_ext4,
+            block_size,
+            sectors_per_block,
+            inode_size: sb.inode_size(),
+            desc_size,
+            inodes_count: sb.inodes_count,
+            inodes_per_group: sb.inodes_per_group,
+            blocks_per_group: sb.blocks_per_group,
+            first_data_block: sb.first_data_block as BlockID,
+            blocks_count: sb.blocks_count(),
+            has_filetype: sb.has_incompat(FEATURE_INCOMPAT_FILETYPE),
+            has_dir_index: sb.has_compat(FEATURE_COMPAT_DIR_INDEX),
+            hash_seed: sb.hash_seed,
+            // mke2fs 总会设置有符号或无符号中的一个, 都没有时按有符号处理
+            hash_unsigned: sb.flags & FLAGS_UNSIGNED_HASH != 0,
+            csum_seed,
+        };
+        let mut groups = Vec::with_capacity(group_count);
+        for g in 0..group_count {
+            let raw = &gdt[g * desc_size..(g + 1) * desc_size];
+            fs.verify_group_desc(g, raw)?;
+            groups.push(GroupDesc::from_bytes(raw));
+        }
+        fs.meta.lock(here!()).groups = groups;
+        Ok(fs)
+    }
+
+    pub(super) fn device_id(&self) -> usize {
+        self.device_id
+    }
+
+    pub(super) fn has_filetype(&self) -> bool {
+        self.has_filetype
+    }
+
+    pub(super) fn check_writable(&self) -> SysResult {
+        if self.read_only {
+            Err(SysError::EROFS)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// 间接块中的指针数
+    pub(super) fn ptrs_per_block(&self) -> usize {
+        self.block_size / 4
+    }
+
+    // 块读写
+
+    async fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> SysResult {
+        let blk_dev = self.blk_dev.lock().await;
+        for (i, buf) in buf.chunks_mut(BLOCK_SIZE).enumerate() {
+            blk_dev.read_block(sector + i as u64, buf).await.map_err(cvt_err)?;
+        }
+        Ok(())
+    }
+    async fn write_sectors(&self, sector: u64, buf: &[u8]) -> SysResult {
+        let blk_dev = self.blk_dev.lock().await;
+        for (i, buf) in buf.chunks(BLOCK_SIZE).enumerate() {
+            blk_dev.write_block(sector + i as u64, buf).await.map_err(cvt_err)?;
+        }
+        Ok(())
+    }
+
+    fn first_sector(&self, bid: BlockID) -> u64 {
+        bid as u64 * self.sectors_per_block as u64
+    }
+
+    /// 读一整个块, buf 的长度必须是块大小
+    pub(super) async fn read_block(&self, bid: BlockID, buf: &mut [u8]) -> SysResult {
+        debug_assert!(buf.len() == self.block_size);
+        self.read_sectors(self.first_sector(bid), buf).await
+    }
+    pub(super) async fn write_block(&self, bid: BlockID, buf: &[u8]) -> SysResult {
+        debug_assert!(buf.len() == self.block_size);
+        self.write_sectors(self.first_sector(bid), buf).await
+    }
+
+    /// 读间接块中的第 idx 个指针, 只需要读它所在的扇区
+    pub(super) async fn read_ptr(&self, bid: BlockID, idx: usize) -> SysResult<BlockID> {
+        let mut buf = [0; BLOCK_SIZE];
+        let sector = self.first_sector(bid) + (idx * 4 / BLOCK_SIZE) as u64;
+        self.read_sectors(sector, &mut buf).await?;
+        Ok(read_struct::<u32>(&buf[idx * 4 % BLOCK_SIZE..]) as BlockID)
+    }
+    pub(super) async fn write_ptr(&self, bid: BlockID, idx: usize, ptr: BlockID) -> SysResult {
+        let mut buf = [0; BLOCK_SIZE];
+        let sector = self.first_sector(bid) + (idx * 4 / BLOCK_SIZE) as u64;
+        self.read_sectors(sector, &mut buf).await?;
+        write_struct(&mut buf[idx * 4 % BLOCK_SIZE..], &(ptr as u32));
+        self.write_sectors(sector, &buf).await
+    }
+
+    // inode 读写
+
+    /// inode 所在的扇区以及在扇区内的偏移.
+    /// inode 大小是不小于 128 的 2 的幂, 所以我们关心的前 128 字节不会跨扇区.
+    /// inode 号来自目录项等磁盘上的数据, 超出范围说明文件系统损坏了
+    fn inode_pos(&self, ino: InodeID) -> SysResult<(u64, usize)> {
+        if ino == 0 || ino > self.inodes_count {
+            warn!("ext2: inode number {} out of range", ino);
+            return Err(SysError::EIO);
+        }
+        let group = ((ino - 1) / self.inodes_per_group) as usize;
+        let index = ((ino - 1) % self.inodes_per_group) as usize;
+        let inode_table = self.meta.lock(here!()).groups[group].inode_table();
+        let byte = index * self.inode_size;
+        let sector = self.first_sector(inode_table) + (byte / BLOCK_SIZE) as u64;
+        Ok((sector, byte % BLOCK_SIZE))
+    }
+
+    pub(super) async fn read_inode(&self, ino: InodeID) -> SysResult<Inode> {
+        let (sector, offset) = self.inode_pos(ino)?;
+        // 比扇区大的 inode 按自己的大小对齐, offset 一定是 0
+        let mut buf = vec![0; self.inode_size.max(BLOCK_SIZE)];
+        self.read_sectors(sector, &mut buf).await?;
+        self.verify_inode(ino, &buf[offset..offset + self.inode_size])?;
+        Ok(read_struct(&buf[offset..]))
+    }
+
+    pub(super) async fn write_inode(&self, ino: InodeID, inode: &Inode) -> SysResult {
+        let (sector, offset) = self.inode_pos(ino)?;
+        let mut buf = [0; BLOCK_SIZE];
+        let _guard = self.alloc_lock.lock().await;
+        self.read_sectors(sector, &mut buf).await?;
+        write_struct(&mut buf[offset..], inode);
+        self.write_sectors(sector, &buf).await
+    }
+
+    // 元数据写回
+
+    async fn write_super(&self) -> SysResult {
+        let sb_raw = {
+            let mut meta = self.meta.lock(here!());
+            meta.sb.wtime = get_time_sec() as u32;
+            let sb = meta.sb;
+            write_struct(&mut meta.sb_raw, &sb);
+            meta.sb_raw.clone()
+        };
+        self.write_sectors((SUPER_BLOCK_OFFSET / BLOCK_SIZE) as u64, &sb_raw).await
+    }
+
+    /// 写回第 group 个块组描述符所在的扇区
+    async fn write_group_desc(&self, group: usize) -> SysResult {
+        let ds = self.desc_size;
+        let byte = group * ds;
+        let first = byte / BLOCK_SIZE * BLOCK_SIZE / ds;
+        let mut buf = [0; BLOCK_SIZE];
+        {
+            let meta = self.meta.lock(here!());
+            let groups = meta.groups.iter().skip(first).take(BLOCK_SIZE / ds);
+            for (i, desc) in groups.enumerate() {
+                desc.to_bytes(&mut buf[i * ds..(i + 1) * ds]);
+            }
+        }
+        let gdt_block = self.first_data_block + 1;
+        let sector = self.first_sector(gdt_block) + (byte / BLOCK_SIZE) as u64;
+        self.write_sectors(sector, &buf).await
+    }
+
+    async fn write_group_and_super(&self, group: usize) -> SysResult {
+        self.write_group_desc(group).await?;
+        self.write_super().await
+    }
+
+    // 位图
+
+    /// 在位图块中找到 [0, count) 范围内的第一个空闲位并占用它
+    async fn bitmap_alloc(&self, bitmap: BlockID, count: usize) -> SysResult<Option<usize>> {
+        let mut buf = vec![0; self.block_size];
+        self.read_block(bitmap, &mut buf).await?;
+        let found = (0..count).find(|&i| buf[i / 8] & (1 << (i % 8)) == 0);
+        if let Some(i) = found {
+            buf[i / 8] |= 1 << (i % 8);
+            self.write_block(bitmap, &buf).await?;
+        }
+        Ok(found)
+    }
+
+    async fn bitmap_free(&self, bitmap: BlockID, i: usize) -> SysResult {
+        let mut buf = vec![0; self.block_size];
+        self.read_block(bitmap, &mut buf).await?;
+        if buf[i / 8] & (1 << (i % 8)) == 0 {
+            warn!("ext2: freeing free bit {} in bitmap {}", i, bitmap);
+        }
+        buf[i / 8] &= !(1 << (i % 8));
+        self.write_block(bitmap, &buf).await
+    }
+
+    fn group_of_block(&self, bid: BlockID) -> usize {
+        ((bid - self.first_data_block) / self.blocks_per_group as BlockID) as usize
+    }
+    pub(super) fn group_of_inode(&self, ino: InodeID) -> usize {
+        ((ino - 1) / self.inodes_per_group) as usize
+    }
+
+    /// 块组中的块数, 最后一个块组可能不满
+    fn blocks_in_group(&self, group: usize) -> usize {
+        let first = self.first_data_block as usize + group * self.blocks_per_group as usize;
+        (self.blocks_count as usize - first).min(self.blocks_per_group as usize)
+    }
+
+    /// 分配一个清零的块, 优先从 goal 所在的块组中分配
+    pub(super) async fn alloc_block(&self, goal: BlockID) -> SysResult<BlockID> {
+        self.check_writable()?;
+        let _guard = self.alloc_lock.lock().await;
+        let (group_count, start) = {
+            let meta = self.meta.lock(here!());
+            let goal = goal.clamp(self.first_data_block, self.blocks_count - 1);
+            (meta.groups.len(), self.group_of_block(goal))
+        };
+        for group in (start..group_count).chain(0..start) {
+            let bitmap = {
+                let meta = self.meta.lock(here!());
+                let desc = &meta.groups[group];
+                if desc.free_blocks_count == 0 {
+                    continue;
+                }
+                desc.block_bitmap()
+            };
+            let i = match self.bitmap_alloc(bitmap, self.blocks_in_group(group)).await? {
+                Some(i) => i,
+                None => continue,
+            };
+            {
+                let mut meta = self.meta.lock(here!());
+                meta.groups[group].free_blocks_count -= 1;
+                meta.sb.free_blocks_count -= 1;
+            }
+            self.write_group_and_super(group).await?;
+
+            let bid =
+                self.first_data_block + (group * self.blocks_per_group as usize + i) as BlockID;
+            self.write_block(bid, &vec![0; self.block_size]).await?;
+            return Ok(bid);
+        }
+        Err(SysError::ENOSPC)
+    }
+
+    pub(super) async fn free_block(&self, bid: BlockID) -> SysResult {
+        let _guard = self.alloc_lock.lock().await;
+        let group = self.group_of_block(bid);
+        let bitmap = self.meta.lock(here!()).groups[group].block_bitmap();
+        let i = (bid - self.first_data_block) as usize % self.blocks_per_group as usize;
+        self.bitmap_free(bitmap, i).await?;
+        {
+            let mut meta = self.meta.lock(here!());
+            meta.groups[group].free_blocks_count += 1;
+            meta.sb.free_blocks_count += 1;
+        }
+        self.write_group_and_super(group).await
+    }
+
+    /// 分配一个 inode 并把它初始化成 inode.
+    /// 文件放在父目录的块组中, 目录则放到空闲 inode 最多的块组中, 把目录树摊开
+    pub(super) async fn alloc_inode(&self, parent: InodeID, inode: &Inode) -> SysResult<InodeID> {
+        self.check_writable()?;
+        let is_dir = inode.is_dir();
+        let ino = {
+            let _guard = self.alloc_lock.lock().await;
+            let (group_count, start, first_ino) = {
+                let meta = self.meta.lock(here!());
+                let start = if is_dir {
+                    (0..meta.groups.len())
+                        .max_by_key(|&g| meta.groups[g].free_inodes_count)
+                        .unwrap()
+                } else {
+                    self.group_of_inode(parent)
+                };
+                (meta.groups.len(), start, meta.sb.first_ino())
+            };
+            let mut ino = None;
+            for group in (start..group_count).chain(0..start) {
+                let bitmap = {
+                    let meta = self.meta.lock(here!());
+                    let desc = &meta.groups[group];
+                    if desc.free_inodes_count == 0 {
+                        continue;
+                    }
+                    desc.inode_bitmap()
+                };
+                // 前 first_ino - 1 个 inode 是保留的, 但它们在位图中本来就被标记为占用了
+                let count = self.inodes_per_group as usize;
+                if let Some(i) = self.bitmap_alloc(bitmap, count).await? {
+                    {
+                        let mut meta = self.meta.lock(here!());
+                        let desc = &mut meta.groups[group];
+                        desc.free_inodes_count -= 1;
+                        if is_dir {
+                            desc.used_dirs_count += 1;
+                        }
+                        meta.sb.free_inodes_count -= 1;
+                    }
+                    self.write_group_and_super(group).await?;
+                    let allocated = (group * count + i + 1) as InodeID;
+                    debug_assert!(allocated >= first_ino);
+                    ino = Some(allocated);
+                    break;
+                }
+            }
+            ino.ok_or(SysError::ENOSPC)?
+        };
+
+        // inode 可能比 Inode 结构大, 把后面的扩展部分也清零
+        let (sector, offset) = self.inode_pos(ino)?;
+        let mut buf = [0; BLOCK_SIZE];
+        let _guard = self.alloc_lock.lock().await;
+        self.read_sectors(sector, &mut buf).await?;
+        let len = self.inode_size.min(BLOCK_SIZE - offset);
+        buf[offset..offset + len].fill(0);
+        write_struct(&mut buf[offset..], inode);
+        self.write_sectors(sector, &buf).await?;
+        Ok(ino)
+    }
+
+    pub(super) async fn free_inode(&self, ino: InodeID, is_dir: bool) -> SysResult {
+        let _guard = self.alloc_lock.lock().await;
+        let group = self.group_of_inode(ino);
+        let bitmap = self.meta.lock(here!()).groups[group].inode_bitmap();
+        let i = ((ino - 1) % self.inodes_per_group) as usize;
+        self.bitmap_free(bitmap, i).await?;
+        {
+            let mut meta = self.meta.lock(here!());
+            let desc = &mut meta.groups[group];
+            desc.free_inodes_count += 1;
+            if is_dir {
+                desc.used_dirs_count -= 1;
+            }
+            meta.sb.free_inodes_count += 1;
+        }
+        self.write_group_and_super(group).await
+    }
+
+    /// 一个块组中的第一个数据块, 用作分配块时的 goal
+    pub(super) fn group_first_block(&self, group: usize) -> BlockID {
+        self.first_data_block + (group * self.blocks_per_group as usize) as BlockID
+    }
+}
+
+pub struct Ext2FSWrapper {
+    fs: Pin<Box<Ext2FS>>,
+}
+
+impl Ext2FSWrapper {
+    pub async fn new(blk_dev: BlkDevRef, read_only: bool) -> SysResult<Self> {
+        Ext2FS::new(blk_dev, read_only).await.map(Box::pin).map(|fs| Self { fs })
+    }
+
+    pub fn get(&self) -> &'static Ext2FS {
+        unsafe { &*(&*self.fs as *const Ext2FS) }
+    }
+}
+
+impl VfsFS for Ext2FSWrapper {
+    fn root(&self) -> VfsFileRef {
+        let root = Ext2File::new_root(self.get(), ROOT_INO);
+        VfsFileRef::new(PathCacheDir::new_root(SyncAttrFile::new(root)))
+    }
+
+    fn attr(&self) -> VfsFSAttr {
+        let fs = self.get();
+        let meta = fs.meta.lock(here!());
+        VfsFSAttr {
+            kind: if fs.is_ext4 {
+                VfsFSKind::Ext4
+            } else {
+                VfsFSKind::Ext2
+            },
+            fs_id: fs.device_id(),
+            total_block_size: meta.sb.blocks_count() as usize * fs.block_size,
+            free_block_size: meta.sb.free_blocks_count() as usize * fs.block_size,
+            total_file_count: meta.sb.inodes_count as usize,
+            free_file_count: meta.sb.free_inodes_count as usize,
+            max_file_name_length: NAME_LEN,
+        }
+    }
+
+    fn sync(&self) -> ASysResult {
+        // 元数据都是直接写盘的, 只需要写回页缓存
+        dyn_future(page_lru::write_back_device(self.get().device_id()))
+    }
+}
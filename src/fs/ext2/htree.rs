@@ -0,0 +1,290 @@
+//! ext3/ext4 的 htree 目录索引.
+//!
+//! 目录的第 0 块是索引的根, 在 . 和 .. 之后存放索引信息, .. 的 rec_len 一直延伸到块尾;
+//! 中间的索引节点则伪装成一个 inode 为 0 并占满整块的目录项.
+//! 所以不认识 htree 的代码把它当成普通目录也能正确读出所有目录项, 这里只用索引来加速查找.
+//! 写目录时我们不维护索引, 而是像 Linux 的 ext2 一样清除 INDEX_FL
+
+use super::{
+    fs::Ext2FS,
+    layout::{read_struct, DirEntryHead, DxRootInfo, Inode},
+    InodeID,
+};
+use crate::tools::errors::{SysError, SysResult};
+use alloc::{vec, vec::Vec};
+use log::warn;
+
+const DX_HASH_LEGACY: u8 = 0;
+const DX_HASH_HALF_MD4: u8 = 1;
+const DX_HASH_TEA: u8 = 2;
+const DX_HASH_LEGACY_UNSIGNED: u8 = 3;
+const DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+const DX_HASH_TEA_UNSIGNED: u8 = 5;
+
+/// 32 位哈希中保留给目录尾的值
+const HTREE_EOF_32BIT: u32 = 0x7FFF_FFFF;
+
+/// dx_root_info 在第 0 块中的偏移, 紧跟在 . 和 .. 之后
+const DX_ROOT_INFO_OFFSET: usize = 0x18;
+/// 中间节点的 count/limit 紧跟在伪目录项的头部之后
+const DX_NODE_COUNT_OFFSET: usize = 8;
+/// 索引项中的块号只用低 28 位
+const DX_BLOCK_MASK: u32 = 0x0FFF_FFFF;
+
+fn dx_hack_hash(name: &[u8], signed: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12A3_FE2D_u32, 0x37AB_E8F9_u32);
+    for &c in name {
+        let c = if signed { c as i8 as i32 } else { c as i32 };
+        let mut hash = hash1.wrapping_add(hash0 ^ c.wrapping_mul(7_152_373) as u32);
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7FFF_FFFF);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+/// 把名字按 4 字节一组装进 buf, 不足的部分用长度填充
+fn str2hashbuf(msg: &[u8], buf: &mut [u32], signed: bool) {
+    let mut pad = msg.len() as u32 | (msg.len() as u32) << 8;
+    pad |= pad << 16;
+    let msg = &msg[..msg.len().min(buf.len() * 4)];
+    let mut val = pad;
+    let mut n = 0;
+    for (i, &c) in msg.iter().enumerate() {
+        let c = if signed {
+            c as i8 as i32 as u32
+        } else {
+            c as u32
+        };
+        val = c.wrapping_add(val << 8);
+        if i % 4 == 3 {
+            buf[n] = val;
+            n += 1;
+            val = pad;
+        }
+    }
+    if n < buf.len() {
+        buf[n] = val;
+        n += 1;
+    }
+    buf[n..].fill(pad);
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32]) {
+    const DELTA: u32 = 0x9E37_79B9;
+    let (mut sum, mut b0, mut b1) = (0u32, buf[0], buf[1]);
+    let (a, b, c, d) = (input[0], input[1], input[2], input[3]);
+    for _ in 0..16 {
+        sum = sum.wrapping_add(DELTA);
+        b0 = b0.wrapping_add(
+            ((b1 << 4).wrapping_add(a)) ^ b1.wrapping_add(sum) ^ ((b1 >> 5).wrapping_add(b)),
+        );
+        b1 = b1.wrapping_add(
+            ((b0 << 4).wrapping_add(c)) ^ b0.wrapping_add(sum) ^ ((b0 >> 5).wrapping_add(d)),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32]) {
+    const K2: u32 = 0o13240474631;
+    const K3: u32 = 0o15666365641;
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let round = |a: u32, f: u32, x: u32, s: u32| a.wrapping_add(f).wrapping_add(x).rotate_left(s);
+
+    let [mut a, mut b, mut c, mut d] = *buf;
+    for (i, s) in [
+        (0, 3),
+        (1, 7),
+        (2, 11),
+        (3, 19),
+        (4, 3),
+        (5, 7),
+        (6, 11),
+        (7, 19),
+    ] {
+        match i % 4 {
+            0 => a = round(a, f(b, c, d), input[i], s),
+            1 => d = round(d, f(a, b, c), input[i], s),
+            2 => c = round(c, f(d, a, b), input[i], s),
+            _ => b = round(b, f(c, d, a), input[i], s),
+        }
+    }
+    for (n, (i, s)) in [
+        (1, 3),
+        (3, 5),
+        (5, 9),
+        (7, 13),
+        (0, 3),
+        (2, 5),
+        (4, 9),
+        (6, 13),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let x = input[i].wrapping_add(K2);
+        match n % 4 {
+            0 => a = round(a, g(b, c, d), x, s),
+            1 => d = round(d, g(a, b, c), x, s),
+            2 => c = round(c, g(d, a, b), x, s),
+            _ => b = round(b, g(c, d, a), x, s),
+        }
+    }
+    for (n, (i, s)) in [
+        (3, 3),
+        (7, 9),
+        (2, 11),
+        (6, 15),
+        (1, 3),
+        (5, 9),
+        (0, 11),
+        (4, 15),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let x = input[i].wrapping_add(K3);
+        match n % 4 {
+            0 => a = round(a, h(b, c, d), x, s),
+            1 => d = round(d, h(a, b, c), x, s),
+            2 => c = round(c, h(d, a, b), x, s),
+            _ => b = round(b, h(c, d, a), x, s),
+        }
+    }
+
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
+
+/// 计算名字的哈希值, 最低位总是 0. 不认识的哈希算法返回 None
+fn dirhash(name: &[u8], version: u8, seed: &[u32; 4]) -> Option<u32> {
+    let mut buf = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476];
+    if seed.iter().any(|&s| s != 0) {
+        buf = *seed;
+    }
+    let hash = match version {
+        DX_HASH_LEGACY | DX_HASH_LEGACY_UNSIGNED => dx_hack_hash(name, version == DX_HASH_LEGACY),
+        DX_HASH_HALF_MD4 | DX_HASH_HALF_MD4_UNSIGNED => {
+            let mut input = [0; 8];
+            // 每次取 32 字节, 但填充用的是剩下的全部长度
+            for p in (0..name.len()).step_by(32) {
+                str2hashbuf(&name[p..], &mut input, version == DX_HASH_HALF_MD4);
+                half_md4_transform(&mut buf, &input);
+            }
+            buf[1]
+        }
+        DX_HASH_TEA | DX_HASH_TEA_UNSIGNED => {
+            let mut input = [0; 4];
+            for p in (0..name.len()).step_by(16) {
+                str2hashbuf(&name[p..], &mut input, version == DX_HASH_TEA);
+                tea_transform(&mut buf, &input);
+            }
+            buf[0]
+        }
+        _ => return None,
+    };
+    let hash = hash & !1;
+    if hash == HTREE_EOF_32BIT << 1 {
+        Some((HTREE_EOF_32BIT - 1) << 1)
+    } else {
+        Some(hash)
+    }
+}
+
+/// 在一个索引块中找到哈希值可能落入的子块: 最后一个起始哈希不大于 hash 的项,
+/// 以及之后因为哈希冲突而延续下去的项 (起始哈希的最低位为 1)
+fn dx_candidates(buf: &[u8], count_offset: usize, hash: u32) -> SysResult<Vec<usize>> {
+    let limit = read_struct::<u16>(&buf[count_offset..]) as usize;
+    let count = read_struct::<u16>(&buf[count_offset + 2..]) as usize;
+    if count == 0 || count > limit || count_offset + limit * 8 > buf.len() {
+        warn!(
+            "ext4: corrupted htree node, count {} limit {}",
+            count, limit
+        );
+        return Err(SysError::EIO);
+    }
+    // 第 0 项的哈希位置存放的是 limit 和 count, 它的起始哈希视为 0
+    let hash_at = |i: usize| read_struct::<u32>(&buf[count_offset + i * 8..]);
+    let block_at =
+        |i: usize| (read_struct::<u32>(&buf[count_offset + i * 8 + 4..]) & DX_BLOCK_MASK) as usize;
+    let pos = (1..count).take_while(|&i| hash_at(i) <= hash).last().unwrap_or(0);
+    let mut ret = vec![block_at(pos)];
+    for i in pos + 1..count {
+        if hash_at(i) & !1 != hash {
+            break;
+        }
+        ret.push(block_at(i));
+    }
+    Ok(ret)
+}
+
+impl Ext2FS {
+    /// 如果 buf 是目录 dir 的第 idx 块并且是 htree 的索引块, 返回 count/limit 的偏移
+    pub(super) fn dx_count_offset(&self, dir: &Inode, idx: usize, buf: &[u8]) -> Option<usize> {
+        if !dir.is_indexed() {
+            return None;
+        }
+        if idx == 0 {
+            let info: DxRootInfo = read_struct(&buf[DX_ROOT_INFO_OFFSET..]);
+            return Some(DX_ROOT_INFO_OFFSET + info.info_length as usize);
+        }
+        let head: DirEntryHead = read_struct(buf);
+        (head.inode == 0 && head.rec_len as usize == buf.len()).then_some(DX_NODE_COUNT_OFFSET)
+    }
+
+    /// 用 htree 索引找到可能包含 name 的目录块.
+    /// 目录没有索引, 或者索引是我们不认识的格式时返回 None, 由调用者线性查找
+    pub(super) async fn dx_lookup(
+        &self,
+        ino: InodeID,
+        dir: &Inode,
+        name: &str,
+    ) -> SysResult<Option<Vec<usize>>> {
+        if !self.has_dir_index || !dir.is_indexed() {
+            return Ok(None);
+        }
+        let mut buf = vec![0; self.block_size];
+        if self.read_dir_block(ino, dir, 0, &mut buf).await?.is_none() {
+            return Ok(None);
+        }
+        let info: DxRootInfo = read_struct(&buf[DX_ROOT_INFO_OFFSET..]);
+        if info.reserved_zero != 0 || info.info_length != 8 || info.indirect_levels > 1 {
+            warn!("ext4: unsupported htree root in inode {}: {:?}", ino, info);
+            return Ok(None);
+        }
+        let mut version = info.hash_version;
+        if version <= DX_HASH_TEA && self.hash_unsigned {
+            version += DX_HASH_LEGACY_UNSIGNED;
+        }
+        let hash = match dirhash(name.as_bytes(), version, &self.hash_seed) {
+            Some(hash) => hash,
+            None => {
+                warn!("ext4: unsupported htree hash {} in inode {}", version, ino);
+                return Ok(None);
+            }
+        };
+
+        let count_offset = DX_ROOT_INFO_OFFSET + info.info_length as usize;
+        let mut blocks = dx_candidates(&buf, count_offset, hash)?;
+        for _ in 0..info.indirect_levels {
+            let mut next = Vec::new();
+            for idx in blocks {
+                if self.read_dir_block(ino, dir, idx, &mut buf).await?.is_none() {
+                    warn!("ext4: hole in htree of inode {}", ino);
+                    return Err(SysError::EIO);
+                }
+                next.extend(dx_candidates(&buf, DX_NODE_COUNT_OFFSET, hash)?);
+            }
+            blocks = next;
+        }
+        Ok(Some(blocks))
+    }
+}
@@ -0,0 +1,150 @@
+//! 文件内的块号到磁盘块号的映射: 12 个直接块, 之后是一级, 二级, 三级间接块
+
+use super::{
+    fs::Ext2FS,
+    layout::{read_struct, write_struct, Inode, DIND_BLOCK, IND_BLOCK, NDIR_BLOCKS, TIND_BLOCK},
+    BlockID, InodeID,
+};
+use crate::tools::errors::{dyn_future, ASysResult, SysError, SysResult};
+use alloc::{vec, vec::Vec};
+
+impl Ext2FS {
+    /// 文件内第 idx 块在间接块树中的位置: i_block 的下标, 以及之后各级间接块中的下标
+    fn block_path(&self, idx: usize) -> SysResult<(usize, Vec<usize>)> {
+        let p = self.ptrs_per_block();
+        if idx < NDIR_BLOCKS {
+            return Ok((idx, Vec::new()));
+        }
+        let idx = idx - NDIR_BLOCKS;
+        if idx < p {
+            return Ok((IND_BLOCK, vec![idx]));
+        }
+        let idx = idx - p;
+        if idx < p * p {
+            return Ok((DIND_BLOCK, vec![idx / p, idx % p]));
+        }
+        let idx = idx - p * p;
+        if idx < p * p * p {
+            return Ok((TIND_BLOCK, vec![idx / (p * p), idx / p % p, idx % p]));
+        }
+        Err(SysError::EFBIG)
+    }
+
+    /// 找到文件内第 idx 块对应的磁盘块, 空洞返回 None
+    pub(super) async fn bmap(
+        &self,
+        ino: InodeID,
+        inode: &Inode,
+        idx: usize,
+    ) -> SysResult<Option<BlockID>> {
+        if inode.has_extents() {
+            return self.extent_bmap(ino, inode, idx).await;
+        }
+        let (slot, path) = self.block_path(idx)?;
+        let mut bid = inode.block[slot] as BlockID;
+        for i in path {
+            if bid == 0 {
+                return Ok(None);
+            }
+            bid = self.read_ptr(bid, i).await?;
+        }
+        Ok((bid != 0).then_some(bid))
+    }
+
+    /// 同 bmap, 但会为空洞分配数据块和缺少的间接块, 并更新 inode 的 i_block 和 i_blocks.
+    /// 只用于间接块映射的文件, 有 extent 的文件系统是只读的. 调用者负责写回 inode
+    pub(super) async fn bmap_alloc(
+        &self,
+        ino: InodeID,
+        inode: &mut Inode,
+        idx: usize,
+    ) -> SysResult<BlockID> {
+        let (slot, path) = self.block_path(idx)?;
+        let mut bid = inode.block[slot] as BlockID;
+        if bid == 0 {
+            let goal = self.group_first_block(self.group_of_inode(ino));
+            bid = self.alloc_block(goal).await?;
+            inode.block[slot] = bid as u32;
+            inode.blocks += self.sectors_per_block;
+        }
+        for i in path {
+            let mut next = self.read_ptr(bid, i).await?;
+            if next == 0 {
+                // 尽量让数据块挨着它的间接块
+                next = self.alloc_block(bid).await?;
+                self.write_ptr(bid, i, next).await?;
+                inode.blocks += self.sectors_per_block;
+            }
+            bid = next;
+        }
+        Ok(bid)
+    }
+
+    /// 释放文件内下标不小于 keep 的所有块, 以及因此变空的间接块.
+    /// 调用者负责写回 inode
+    pub(super) async fn truncate_blocks(&self, inode: &mut Inode, keep: usize) -> SysResult {
+        for i in keep.min(NDIR_BLOCKS)..NDIR_BLOCKS {
+            if inode.block[i] != 0 {
+                self.free_block(inode.block[i] as BlockID).await?;
+                inode.block[i] = 0;
+                inode.blocks -= self.sectors_per_block;
+            }
+        }
+
+        let p = self.ptrs_per_block();
+        let mut start = NDIR_BLOCKS;
+        for (slot, depth) in [(IND_BLOCK, 1), (DIND_BLOCK, 2), (TIND_BLOCK, 3)] {
+            let span = p.pow(depth);
+            let bid = inode.block[slot] as BlockID;
+            if bid != 0 && keep < start + span {
+                let keep = keep.saturating_sub(start);
+                let freed = self.free_tree(bid, depth, keep).await?;
+                inode.blocks -= freed as u32 * self.sectors_per_block;
+                if keep == 0 {
+                    inode.block[slot] = 0;
+                }
+            }
+            start += span;
+        }
+        Ok(())
+    }
+
+    /// 释放以 bid 为根, 深度为 depth 的间接块树中下标不小于 keep 的数据块.
+    /// keep 为 0 时 bid 本身也被释放. 返回释放的块数
+    fn free_tree(&self, bid: BlockID, depth: u32, keep: usize) -> ASysResult<usize> {
+        // 递归的 async 函数必须 Box
+        dyn_future(async move {
+            if depth == 0 {
+                debug_assert!(keep == 0);
+                self.free_block(bid).await?;
+                return Ok(1);
+            }
+
+            let span = self.ptrs_per_block().pow(depth - 1);
+            let mut buf = vec![0; self.block_size];
+            self.read_block(bid, &mut buf).await?;
+            let mut freed = 0;
+            let mut dirty = false;
+            for i in keep / span..self.ptrs_per_block() {
+                let ptr: u32 = read_struct(&buf[i * 4..]);
+                if ptr == 0 {
+                    continue;
+                }
+                let child_keep = keep.saturating_sub(i * span);
+                freed += self.free_tree(ptr as BlockID, depth - 1, child_keep).await?;
+                if child_keep == 0 {
+                    write_struct(&mut buf[i * 4..], &0u32);
+                    dirty = true;
+                }
+            }
+
+            if keep == 0 {
+                self.free_block(bid).await?;
+                freed += 1;
+            } else if dirty {
+                self.write_block(bid, &buf).await?;
+            }
+            Ok(freed)
+        })
+    }
+}
@@ -0,0 +1,524 @@
+//! ext2 的磁盘结构
+//!
+//! 所有字段都是小端序的, 和 RISC-V 一致, 所以直接按字节拷贝出来就能用.
+//! 参考 https://www.nongnu.org/ext2-doc/ext2.html
+//! 以及 https://www.kernel.org/doc/html/latest/filesystems/ext4/ondisk.html
+
+use super::{BlockID, InodeID};
+use crate::fs::new_vfs::VfsFileKind;
+use core::mem::size_of;
+
+pub const EXT2_MAGIC: u16 = 0xEF53;
+/// 超级块总是位于分区开头 1024 字节处, 长度为 1024 字节
+pub const SUPER_BLOCK_OFFSET: usize = 1024;
+pub const SUPER_BLOCK_SIZE: usize = 1024;
+
+pub const ROOT_INO: InodeID = 2;
+/// rev 0 的文件系统中第一个可以使用的 inode 和 inode 大小
+pub const GOOD_OLD_FIRST_INO: InodeID = 11;
+pub const GOOD_OLD_INODE_SIZE: u16 = 128;
+
+/// 直接块的数量, 之后依次是一级, 二级, 三级间接块
+pub const NDIR_BLOCKS: usize = 12;
+pub const IND_BLOCK: usize = 12;
+pub const DIND_BLOCK: usize = 13;
+pub const TIND_BLOCK: usize = 14;
+pub const N_BLOCKS: usize = 15;
+
+pub const NAME_LEN: usize = 255;
+
+// s_feature_compat
+pub const FEATURE_COMPAT_DIR_INDEX: u32 = 0x0020;
+
+// s_feature_incompat
+pub const FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002;
+pub const FEATURE_INCOMPAT_RECOVER: u32 = 0x0004;
+pub const FEATURE_INCOMPAT_EXTENTS: u32 = 0x0040;
+pub const FEATURE_INCOMPAT_64BIT: u32 = 0x0080;
+pub const FEATURE_INCOMPAT_FLEX_BG: u32 = 0x0200;
+pub const FEATURE_INCOMPAT_CSUM_SEED: u32 = 0x2000;
+/// 可以读写的 incompat 特性
+pub const FEATURE_INCOMPAT_SUPP: u32 = FEATURE_INCOMPAT_FILETYPE;
+/// 只能读的 incompat 特性, 写的时候我们不会维护 extent 树和 64 位的块组描述符
+pub const FEATURE_INCOMPAT_RO_SUPP: u32 = FEATURE_INCOMPAT_EXTENTS
+    | FEATURE_INCOMPAT_64BIT
+    | FEATURE_INCOMPAT_FLEX_BG
+    | FEATURE_INCOMPAT_CSUM_SEED;
+
+// s_feature_ro_compat
+pub const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+pub const FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+pub const FEATURE_RO_COMPAT_METADATA_CSUM: u32 = 0x0400;
+pub const FEATURE_RO_COMPAT_SUPP: u32 =
+    FEATURE_RO_COMPAT_SPARSE_SUPER | FEATURE_RO_COMPAT_LARGE_FILE;
+
+// s_flags
+pub const FLAGS_UNSIGNED_HASH: u32 = 0x0002;
+
+/// s_checksum_type 只有 crc32c 一种
+pub const CHECKSUM_TYPE_CRC32C: u8 = 1;
+/// 超级块中 s_checksum_seed 和 s_checksum 的偏移
+pub const SB_CHECKSUM_SEED_OFFSET: usize = 0x270;
+pub const SB_CHECKSUM_OFFSET: usize = 0x3FC;
+
+// i_flags
+pub const INDEX_FL: u32 = 0x0000_1000;
+pub const EXTENTS_FL: u32 = 0x0008_0000;
+
+/// inode 中 l_i_checksum_lo, i_extra_isize 和 i_checksum_hi 的偏移
+pub const INODE_CHECKSUM_LO_OFFSET: usize = 0x7C;
+pub const INODE_EXTRA_ISIZE_OFFSET: usize = 0x80;
+pub const INODE_CHECKSUM_HI_OFFSET: usize = 0x82;
+
+// i_mode 的高 4 位
+pub const S_IFMT: u16 = 0o170000;
+pub const S_IFSOCK: u16 = 0o140000;
+pub const S_IFLNK: u16 = 0o120000;
+pub const S_IFREG: u16 = 0o100000;
+pub const S_IFBLK: u16 = 0o060000;
+pub const S_IFDIR: u16 = 0o040000;
+pub const S_IFCHR: u16 = 0o020000;
+pub const S_IFIFO: u16 = 0o010000;
+
+// 目录项中的 file_type
+pub const FT_UNKNOWN: u8 = 0;
+pub const FT_REG_FILE: u8 = 1;
+pub const FT_DIR: u8 = 2;
+pub const FT_CHRDEV: u8 = 3;
+pub const FT_BLKDEV: u8 = 4;
+pub const FT_FIFO: u8 = 5;
+pub const FT_SOCK: u8 = 6;
+pub const FT_SYMLINK: u8 = 7;
+
+/// 从字节中拷贝出一个磁盘结构
+pub fn read_struct<T: Copy>(buf: &[u8]) -> T {
+    assert!(buf.len() >= size_of::<T>());
+    unsafe { core::ptr::read_unaligned(buf.as_ptr() as *const T) }
+}
+
+/// 把一个磁盘结构写回字节中
+pub fn write_struct<T: Copy>(buf: &mut [u8], val: &T) {
+    assert!(buf.len() >= size_of::<T>());
+    unsafe { core::ptr::write_unaligned(buf.as_mut_ptr() as *mut T, *val) }
+}
+
+/// 超级块中到 s_checksum_type 为止的部分, 后面的字段我们用不到, 写回时保持原样
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SuperBlock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub r_blocks_count: u32,
+    pub free_blocks_count: u32,
+    pub free_inodes_count: u32,
+    pub first_data_block: u32,
+    pub log_block_size: u32,
+    pub log_frag_size: u32,
+    pub blocks_per_group: u32,
+    pub frags_per_group: u32,
+    pub inodes_per_group: u32,
+    pub mtime: u32,
+    pub wtime: u32,
+    pub mnt_count: u16,
+    pub max_mnt_count: u16,
+    pub magic: u16,
+    pub state: u16,
+    pub errors: u16,
+    pub minor_rev_level: u16,
+    pub lastcheck: u32,
+    pub checkinterval: u32,
+    pub creator_os: u32,
+    pub rev_level: u32,
+    pub def_resuid: u16,
+    pub def_resgid: u16,
+    // 以下只在 rev 1 中有效
+    pub first_ino: u32,
+    pub inode_size: u16,
+    pub block_group_nr: u16,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    pub uuid: [u8; 16],
+    pub volume_name: [u8; 16],
+    pub last_mounted: [u8; 64],
+    pub algorithm_usage_bitmap: u32,
+    pub prealloc_blocks: u8,
+    pub prealloc_dir_blocks: u8,
+    pub reserved_gdt_blocks: u16,
+    // 以下是 ext3 和 ext4 的扩展
+    pub journal_uuid: [u8; 16],
+    pub journal_inum: u32,
+    pub journal_dev: u32,
+    pub last_orphan: u32,
+    pub hash_seed: [u32; 4],
+    pub def_hash_version: u8,
+    pub jnl_backup_type: u8,
+    /// 64BIT 特性下块组描述符的大小
+    pub desc_size: u16,
+    pub default_mount_opts: u32,
+    pub first_meta_bg: u32,
+    pub mkfs_time: u32,
+    pub jnl_blocks: [u32; 17],
+    pub blocks_count_hi: u32,
+    pub r_blocks_count_hi: u32,
+    pub free_blocks_count_hi: u32,
+    pub min_extra_isize: u16,
+    pub want_extra_isize: u16,
+    pub flags: u32,
+    pub raid_stride: u16,
+    pub mmp_interval: u16,
+    pub mmp_block: u64,
+    pub raid_stripe_width: u32,
+    pub log_groups_per_flex: u8,
+    pub checksum_type: u8,
+    pub reserved_pad: u16,
+}
+
+const _: () = assert!(size_of::<SuperBlock>() == 0x178);
+
+impl SuperBlock {
+    pub fn block_size(&self) -> usize {
+        1024 << self.log_block_size
+    }
+    pub fn first_ino(&self) -> InodeID {
+        if self.rev_level == 0 {
+            GOOD_OLD_FIRST_INO
+        } else {
+            self.first_ino
+        }
+    }
+    pub fn inode_size(&self) -> usize {
+        if self.rev_level == 0 {
+            GOOD_OLD_INODE_SIZE as usize
+        } else {
+            self.inode_size as usize
+        }
+    }
+    pub fn is_64bit(&self) -> bool {
+        self.has_incompat(FEATURE_INCOMPAT_64BIT)
+    }
+    pub fn blocks_count(&self) -> BlockID {
+        let hi = if self.is_64bit() {
+            self.blocks_count_hi
+        } else {
+            0
+        };
+        self.blocks_count as BlockID | (hi as BlockID) << 32
+    }
+    pub fn free_blocks_count(&self) -> BlockID {
+        let hi = if self.is_64bit() {
+            self.free_blocks_count_hi
+        } else {
+            0
+        };
+        self.free_blocks_count as BlockID | (hi as BlockID) << 32
+    }
+    pub fn desc_size(&self) -> usize {
+        if self.is_64bit() {
+            self.desc_size as usize
+        } else {
+            GROUP_DESC_SIZE
+        }
+    }
+    pub fn group_count(&self) -> usize {
+        let data_blocks = (self.blocks_count() - self.first_data_block as BlockID) as usize;
+        let bpg = self.blocks_per_group as usize;
+        (data_blocks + bpg - 1) / bpg
+    }
+    pub fn has_incompat(&self, feature: u32) -> bool {
+        self.rev_level != 0 && self.feature_incompat & feature != 0
+    }
+    pub fn has_ro_compat(&self, feature: u32) -> bool {
+        self.rev_level != 0 && self.feature_ro_compat & feature != 0
+    }
+    pub fn has_compat(&self, feature: u32) -> bool {
+        self.rev_level != 0 && self.feature_compat & feature != 0
+    }
+}
+
+/// 没有 64BIT 特性时块组描述符只有前 32 字节
+pub const GROUP_DESC_SIZE: usize = 32;
+/// 有 64BIT 特性时块组描述符的最小大小, 同 Linux 的 EXT4_MIN_DESC_SIZE_64BIT
+pub const MIN_DESC_SIZE_64BIT: usize = 64;
+/// 块组描述符中 bg_checksum 的偏移
+pub const GROUP_DESC_CHECKSUM_OFFSET: usize = 0x1E;
+
+/// ext4 的块组描述符, 只有 32 字节时后半部分为 0
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GroupDesc {
+    pub block_bitmap_lo: u32,
+    pub inode_bitmap_lo: u32,
+    pub inode_table_lo: u32,
+    pub free_blocks_count: u16,
+    pub free_inodes_count: u16,
+    pub used_dirs_count: u16,
+    pub flags: u16,
+    pub exclude_bitmap_lo: u32,
+    pub block_bitmap_csum_lo: u16,
+    pub inode_bitmap_csum_lo: u16,
+    pub itable_unused_lo: u16,
+    pub checksum: u16,
+    pub block_bitmap_hi: u32,
+    pub inode_bitmap_hi: u32,
+    pub inode_table_hi: u32,
+    pub free_blocks_count_hi: u16,
+    pub free_inodes_count_hi: u16,
+    pub used_dirs_count_hi: u16,
+    pub itable_unused_hi: u16,
+    pub exclude_bitmap_hi: u32,
+    pub block_bitmap_csum_hi: u16,
+    pub inode_bitmap_csum_hi: u16,
+    pub reserved: u32,
+}
+
+impl GroupDesc {
+    /// 从 desc_size 字节的磁盘数据中读出块组描述符
+    pub fn from_bytes(raw: &[u8]) -> Self {
+        let mut buf = [0; size_of::<Self>()];
+        let len = raw.len().min(buf.len());
+        buf[..len].copy_from_slice(&raw[..len]);
+        read_struct(&buf)
+    }
+    /// 把块组描述符的前 desc_size 字节写到 raw 中
+    pub fn to_bytes(&self, raw: &mut [u8]) {
+        let mut buf = [0; size_of::<Self>()];
+        write_struct(&mut buf, self);
+        let len = raw.len().min(buf.len());
+        raw[..len].copy_from_slice(&buf[..len]);
+    }
+
+    pub fn block_bitmap(&self) -> BlockID {
+        self.block_bitmap_lo as BlockID | (self.block_bitmap_hi as BlockID) << 32
+    }
+    pub fn inode_bitmap(&self) -> BlockID {
+        self.inode_bitmap_lo as BlockID | (self.inode_bitmap_hi as BlockID) << 32
+    }
+    pub fn inode_table(&self) -> BlockID {
+        self.inode_table_lo as BlockID | (self.inode_table_hi as BlockID) << 32
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Inode {
+    pub mode: u16,
+    pub uid: u16,
+    pub size: u32,
+    pub atime: u32,
+    pub ctime: u32,
+    pub mtime: u32,
+    pub dtime: u32,
+    pub gid: u16,
+    pub links_count: u16,
+    /// 占用的 512 字节扇区数, 包括间接块
+    pub blocks: u32,
+    pub flags: u32,
+    pub osd1: u32,
+    /// 直接块和间接块的块号, 或者是 extent 树的根, 或者是短符号链接的目标
+    pub block: [u32; N_BLOCKS],
+    pub generation: u32,
+    pub file_acl: u32,
+    /// 普通文件的大小的高 32 位 (LARGE_FILE)
+    pub size_high: u32,
+    pub faddr: u32,
+    pub osd2: [u8; 12],
+}
+
+impl Inode {
+    pub fn new(mode: u16, now: u32) -> Self {
+        Self {
+            mode,
+            uid: 0,
+            size: 0,
+            atime: now,
+            ctime: now,
+            mtime: now,
+            dtime: 0,
+            gid: 0,
+            links_count: 0,
+            blocks: 0,
+            flags: 0,
+            osd1: 0,
+            block: [0; N_BLOCKS],
+            generation: 0,
+            file_acl: 0,
+            size_high: 0,
+            faddr: 0,
+            osd2: [0; 12],
+        }
+    }
+
+    pub fn kind(&self) -> VfsFileKind {
+        match self.mode & S_IFMT {
+            S_IFSOCK => VfsFileKind::SocketFile,
+            S_IFLNK => VfsFileKind::SymbolLink,
+            S_IFREG => VfsFileKind::RegularFile,
+            S_IFBLK => VfsFileKind::BlockDevice,
+            S_IFDIR => VfsFileKind::Directory,
+            S_IFCHR => VfsFileKind::CharDevice,
+            S_IFIFO => VfsFileKind::Pipe,
+            _ => VfsFileKind::Unknown,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn has_extents(&self) -> bool {
+        self.flags & EXTENTS_FL != 0
+    }
+    pub fn is_indexed(&self) -> bool {
+        self.flags & INDEX_FL != 0
+    }
+
+    pub fn size(&self) -> usize {
+        if self.mode & S_IFMT == S_IFREG {
+            self.size as usize | (self.size_high as usize) << 32
+        } else {
+            self.size as usize
+        }
+    }
+    pub fn set_size(&mut self, size: usize) {
+        self.size = size as u32;
+        if self.mode & S_IFMT == S_IFREG {
+            self.size_high = (size >> 32) as u32;
+        }
+    }
+
+    /// 短符号链接的目标直接存放在 i_block 中, 不占用数据块
+    pub fn is_fast_symlink(&self, sectors_per_block: u32) -> bool {
+        let acl_sectors = if self.file_acl != 0 {
+            sectors_per_block
+        } else {
+            0
+        };
+        self.mode & S_IFMT == S_IFLNK && self.blocks == acl_sectors
+    }
+    pub fn fast_symlink(&self) -> &[u8] {
+        let bytes = self.block_bytes();
+        &bytes[..(self.size as usize).min(bytes.len())]
+    }
+    pub fn block_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.block.as_ptr() as *const u8, N_BLOCKS * 4) }
+    }
+    pub fn fast_symlink_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.block.as_mut_ptr() as *mut u8, N_BLOCKS * 4) }
+    }
+
+    /// 设备文件的设备号, 旧格式放在 i_block[0], 新格式放在 i_block[1]
+    pub fn rdev(&self) -> usize {
+        if self.block[0] != 0 {
+            self.block[0] as usize
+        } else {
+            self.block[1] as usize
+        }
+    }
+}
+
+pub fn kind_to_mode(kind: VfsFileKind) -> u16 {
+    match kind {
+        VfsFileKind::SocketFile => S_IFSOCK | 0o755,
+        VfsFileKind::SymbolLink => S_IFLNK | 0o777,
+        VfsFileKind::RegularFile => S_IFREG | 0o644,
+        VfsFileKind::BlockDevice => S_IFBLK | 0o644,
+        VfsFileKind::Directory => S_IFDIR | 0o755,
+        VfsFileKind::CharDevice => S_IFCHR | 0o644,
+        VfsFileKind::Pipe => S_IFIFO | 0o644,
+        VfsFileKind::Unknown => 0o644,
+    }
+}
+
+pub fn kind_to_file_type(kind: VfsFileKind) -> u8 {
+    match kind {
+        VfsFileKind::SocketFile => FT_SOCK,
+        VfsFileKind::SymbolLink => FT_SYMLINK,
+        VfsFileKind::RegularFile => FT_REG_FILE,
+        VfsFileKind::BlockDevice => FT_BLKDEV,
+        VfsFileKind::Directory => FT_DIR,
+        VfsFileKind::CharDevice => FT_CHRDEV,
+        VfsFileKind::Pipe => FT_FIFO,
+        VfsFileKind::Unknown => FT_UNKNOWN,
+    }
+}
+
+pub fn file_type_to_kind(file_type: u8) -> Option<VfsFileKind> {
+    match file_type {
+        FT_REG_FILE => Some(VfsFileKind::RegularFile),
+        FT_DIR => Some(VfsFileKind::Directory),
+        FT_CHRDEV => Some(VfsFileKind::CharDevice),
+        FT_BLKDEV => Some(VfsFileKind::BlockDevice),
+        FT_FIFO => Some(VfsFileKind::Pipe),
+        FT_SOCK => Some(VfsFileKind::SocketFile),
+        FT_SYMLINK => Some(VfsFileKind::SymbolLink),
+        _ => None,
+    }
+}
+
+/// 目录项的头部, 之后紧跟着 name_len 字节的名字
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DirEntryHead {
+    pub inode: InodeID,
+    /// 到下一个目录项的距离, 最后一项延伸到块尾
+    pub rec_len: u16,
+    pub name_len: u8,
+    /// 没有 FILETYPE 特性时是 name_len 的高 8 位, 但名字不会超过 255 字节
+    pub file_type: u8,
+}
+
+pub const DIR_ENTRY_HEAD_SIZE: usize = size_of::<DirEntryHead>();
+
+/// 名字长为 name_len 的目录项至少占用的空间, 按 4 字节对齐
+pub const fn dir_rec_len(name_len: usize) -> usize {
+    (DIR_ENTRY_HEAD_SIZE + name_len + 3) & !3
+}
+
+// ext4 的 extent 树
+
+pub const EXTENT_MAGIC: u16 = 0xF30A;
+/// 长度大于它的 extent 是预分配但未写入的, 读出来是 0
+pub const EXT_INIT_MAX_LEN: u16 = 1 << 15;
+pub const EXTENT_ENTRY_SIZE: usize = 12;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExtentHeader {
+    pub magic: u16,
+    pub entries: u16,
+    pub max: u16,
+    /// 叶子的深度为 0
+    pub depth: u16,
+    pub generation: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExtentIndex {
+    /// 这个子树覆盖的第一个文件内块号
+    pub block: u32,
+    pub leaf_lo: u32,
+    pub leaf_hi: u16,
+    pub unused: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Extent {
+    pub block: u32,
+    pub len: u16,
+    pub start_hi: u16,
+    pub start_lo: u32,
+}
+
+/// htree 索引根中 . 和 .. 之后的信息
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct DxRootInfo {
+    pub reserved_zero: u32,
+    pub hash_version: u8,
+    pub info_length: u8,
+    pub indirect_levels: u8,
+    pub unused_flags: u8,
+}
@@ -0,0 +1,23 @@
+//! ext2 文件系统, 支持读写, 符号链接和硬链接.
+//! ext4 的 extent, flex_bg, 64 位块号, htree 目录索引和元数据校验和只支持读, 用到了就只读挂载.
+//! 不认识的 incompat 特性 (需要恢复的日志, meta_bg, inline data 等) 会拒绝挂载
+
+mod csum;
+mod dir;
+mod extent;
+mod file;
+mod fs;
+mod htree;
+mod inode;
+mod layout;
+
+pub use file::Ext2File;
+pub use fs::Ext2FS;
+pub use fs::Ext2FSWrapper;
+
+// https://www.nongnu.org/ext2-doc/ext2.html
+
+type BlkDevRef = crate::fs::nfat32::BlkDevRef;
+/// ext4 的块号最多有 48 位, ext2 的磁盘结构中仍然是 32 位
+type BlockID = u64;
+type InodeID = u32;
@@ -0,0 +1,240 @@
+//! GPT 分区表.
+//!
+//! 第 1 块是主 GPT 头, 指向紧随其后的分区项数组; 磁盘的最后一块是备份的 GPT 头,
+//! 备份的分区项数组放在它前面. 头和分区项数组都带有 crc32 校验和,
+//! 主 GPT 损坏时改用备份.
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use log::warn;
+
+use super::{nfat32::cvt_err, partition::PartitionInfo};
+use crate::{
+    drivers::AsyncBlockDevice,
+    tools::errors::{SysError, SysResult},
+};
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// GPT 头的最小长度, 也就是 UEFI 规范中定义的字段的长度
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+/// 分区项数组的大小上限, 防止损坏的头让我们读入太多数据. 常见的是 128 项, 每项 128 字节
+const GPT_ENTRIES_MAX_BYTES: usize = 1 << 20;
+/// 分区名最多 36 个 UTF-16 字符
+const GPT_NAME_OFFSET: usize = 56;
+const GPT_NAME_LEN: usize = 72;
+
+/// crc32 (IEEE 802.3) 的多项式 (按位反转)
+const CRC32_POLY: u32 = 0xEDB8_8320;
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32_POLY
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = make_table();
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc = CRC32_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+fn le64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// GUID 的前三段按小端存放, 后两段按大端存放
+fn format_guid(raw: &[u8]) -> String {
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        le32(raw, 0),
+        u16::from_le_bytes([raw[4], raw[5]]),
+        u16::from_le_bytes([raw[6], raw[7]]),
+        raw[8],
+        raw[9],
+        raw[10],
+        raw[11],
+        raw[12],
+        raw[13],
+        raw[14],
+        raw[15],
+    )
+}
+
+/// 分区名是 UTF-16LE, 以 0 结尾或占满整个字段
+fn parse_name(raw: &[u8]) -> String {
+    let units = raw
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0);
+    char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// GPT 头中我们用到的字段
+struct GptHeader {
+    first_usable: u64,
+    last_usable: u64,
+    entries_lba: u64,
+    entry_cnt: usize,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+/// 读取并检查 lba 处的 GPT 头
+async fn read_header(disk: &Arc<dyn AsyncBlockDevice>, lba: u64) -> SysResult<GptHeader> {
+    let mut buf = vec![0; disk.block_size()];
+    disk.read_block(lba, &mut buf).await.map_err(cvt_err)?;
+    if &buf[..8] != GPT_SIGNATURE {
+        return Err(SysError::EINVAL);
+    }
+    let header_size = le32(&buf, 12) as usize;
+    if header_size < GPT_HEADER_MIN_SIZE || header_size > buf.len() {
+        return Err(SysError::EINVAL);
+    }
+    // 计算校验和时校验和字段按 0 处理
+    let expected = le32(&buf, 16);
+    buf[16..20].fill(0);
+    if crc32(&buf[..header_size]) != expected {
+        warn!("gpt: bad header checksum at lba {}", lba);
+        return Err(SysError::EINVAL);
+    }
+    if le64(&buf, 24) != lba {
+        warn!(
+            "gpt: header at lba {} says it is at {}",
+            lba,
+            le64(&buf, 24)
+        );
+        return Err(SysError::EINVAL);
+    }
+
+    let header = GptHeader {
+        first_usable: le64(&buf, 40),
+        last_usable: le64(&buf, 48),
+        entries_lba: le64(&buf, 72),
+        entry_cnt: le32(&buf, 80) as usize,
+        entry_size: le32(&buf, 84) as usize,
+        entries_crc: le32(&buf, 88),
+    };
+    // 头里的数都来自磁盘, 不能相信它们, 算术都要检查溢出
+    let last_lba = disk.num_blocks().checked_sub(1).ok_or(SysError::EINVAL)?;
+    let entries_bytes = header.entry_cnt.checked_mul(header.entry_size);
+    if header.entry_size < GPT_ENTRY_MIN_SIZE
+        || header.entry_size % GPT_ENTRY_MIN_SIZE != 0
+        || !matches!(entries_bytes, Some(bytes) if bytes <= GPT_ENTRIES_MAX_BYTES)
+        || header.first_usable > header.last_usable
+        || header.last_usable > last_lba
+    {
+        warn!("gpt: invalid header at lba {}", lba);
+        return Err(SysError::EINVAL);
+    }
+    Ok(header)
+}
+
+/// 读取并检查 GPT 头指向的分区项数组
+async fn read_entries(disk: &Arc<dyn AsyncBlockDevice>, header: &GptHeader) -> SysResult<Vec<u8>> {
+    let bs = disk.block_size();
+    let len = header.entry_cnt * header.entry_size;
+    let mut buf = vec![0; (len + bs - 1) / bs * bs];
+    match header.entries_lba.checked_add((buf.len() / bs) as u64) {
+        Some(end) if end <= disk.num_blocks() => {}
+        _ => {
+            warn!("gpt: partition entries exceed the end of disk");
+            return Err(SysError::EINVAL);
+        }
+    }
+    for (i, block) in buf.chunks_mut(bs).enumerate() {
+        disk.read_block(header.entries_lba + i as u64, block).await.map_err(cvt_err)?;
+    }
+    buf.truncate(len);
+    if crc32(&buf) != header.entries_crc {
+        warn!("gpt: bad checksum of partition entries");
+        return Err(SysError::EINVAL);
+    }
+    Ok(buf)
+}
+
+/// 先读主 GPT, 不行再读最后一块上的备份.
+/// 两份都坏了返回 EINVAL, 调用者改按 MBR 解析
+pub async fn read_gpt(disk: &Arc<dyn AsyncBlockDevice>) -> SysResult<Vec<PartitionInfo>> {
+    let backup_lba = disk.num_blocks().checked_sub(1).ok_or(SysError::EINVAL)?;
+    let mut found = None;
+    for lba in [1, backup_lba] {
+        let header = match read_header(disk, lba).await {
+            Ok(header) => header,
+            Err(SysError::EINVAL) => continue,
+            Err(e) => return Err(e),
+        };
+        match read_entries(disk, &header).await {
+            Ok(entries) => {
+                if lba != 1 {
+                    warn!(
+                        "{}: primary GPT is corrupted, using the backup",
+                        disk.name()
+                    );
+                }
+                found = Some((header, entries));
+                break;
+            }
+            Err(SysError::EINVAL) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    let (header, entries) = match found {
+        Some(found) => found,
+        None => {
+            warn!("{}: both primary and backup GPT are corrupted", disk.name());
+            return Err(SysError::EINVAL);
+        }
+    };
+
+    let mut ret = Vec::new();
+    for (i, entry) in entries.chunks_exact(header.entry_size).enumerate() {
+        // 类型 GUID 全 0 的是空项
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let (first, last) = (le64(entry, 32), le64(entry, 40));
+        if first > last || first < header.first_usable || last > header.last_usable {
+            warn!("{}: invalid GPT partition {}, ignored", disk.name(), i + 1);
+            continue;
+        }
+        let label = parse_name(&entry[GPT_NAME_OFFSET..GPT_NAME_OFFSET + GPT_NAME_LEN]);
+        ret.push(PartitionInfo {
+            no: i + 1,
+            start: first,
+            blocks: last - first + 1,
+            uuid: Some(format_guid(&entry[16..32])),
+            label: (!label.is_empty()).then_some(label),
+        });
+    }
+    Ok(ret)
+}
+
+#[allow(unused)]
+pub fn gpt_test() {
+    // crc32 的标准校验值
+    debug_assert_eq!(crc32(b""), 0);
+    debug_assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+}
@@ -0,0 +1,273 @@
+//! initramfs: 启动时把 newc 格式的 cpio 归档解到 tmpfs 中, 作为根文件系统.
+//!
+//! 归档有两个来源, 都有时先解内嵌的, 再解引导程序加载的, 后者可以覆盖前者中的文件:
+//! - 打开 initramfs feature 编译时, 内嵌 INITRAMFS 环境变量指定的归档
+//! - 引导程序加载到内存中的 initrd, 位置由设备树的 /chosen/linux,initrd-start 和
+//!   linux,initrd-end 给出, 解完之后它占用的内存还给页帧分配器
+//!
+//! 和 Linux 一样, 一个 initrd 中可以有多个首尾相连的归档, 每个以 TRAILER!!! 结尾.
+//! 支持文件夹, 普通文件, 符号链接和硬链接, 保留权限位; 设备文件等其他类型被忽略.
+
+use alloc::{collections::BTreeMap, string::String};
+use log::{info, warn};
+
+use super::{
+    memfs::tmpdir::TmpDir,
+    new_vfs::{top::VfsFileRef, DeviceIDCollection, VfsFileKind},
+};
+use crate::{
+    consts::platform::{initrd_end, initrd_start},
+    executor::block_on,
+    memory::{address::kernel_phys_to_virt, frame},
+    tools::errors::{SysError, SysResult},
+};
+
+#[cfg(feature = "initramfs")]
+static EMBEDDED: &[u8] = include_bytes!(env!("INITRAMFS"));
+#[cfg(not(feature = "initramfs"))]
+static EMBEDDED: &[u8] = &[];
+
+const NEWC_MAGIC: &[u8; 6] = b"070701";
+/// 魔数之后是 13 个 8 位十六进制数
+const NEWC_HEADER_SIZE: usize = 6 + 13 * 8;
+const TRAILER: &str = "TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// 引导程序加载的 initrd
+fn external() -> &'static [u8] {
+    let (start, end) = (initrd_start(), initrd_end());
+    if start >= end {
+        return &[];
+    }
+    // initrd 所在的页帧在 frame::init 中被保留, 直到 free_initrd
+    unsafe { core::slice::from_raw_parts(kernel_phys_to_virt(start) as *const u8, end - start) }
+}
+
+/// 有没有 initramfs. 有的话根文件系统就是 tmpfs
+pub fn present() -> bool {
+    !EMBEDDED.is_empty() || !external().is_empty()
+}
+
+/// newc 头中我们用到的字段
+struct Header {
+    ino: u32,
+    mode: u32,
+    nlink: u32,
+    dev: (u32, u32),
+    filesize: usize,
+    namesize: usize,
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+fn parse_hex(field: &[u8]) -> SysResult<u32> {
+    let s = core::str::from_utf8(field).map_err(|_| SysError::EINVAL)?;
+    u32::from_str_radix(s, 16).map_err(|_| SysError::EINVAL)
+}
+
+fn parse_header(raw: &[u8]) -> SysResult<Header> {
+    if raw.len() < NEWC_HEADER_SIZE || &raw[..6] != NEWC_MAGIC {
+        return Err(SysError::EINVAL);
+    }
+    let field = |i: usize| parse_hex(&raw[6 + i * 8..6 + (i + 1) * 8]);
+    Ok(Header {
+        ino: field(0)?,
+        mode: field(1)?,
+        nlink: field(4)?,
+        filesize: field(6)? as usize,
+        dev: (field(7)?, field(8)?),
+        namesize: field(11)? as usize,
+    })
+}
+
+/// 解 initramfs 时的状态
+struct Unpacker {
+    root: VfsFileRef,
+    /// 有多个链接的文件, 按 (设备号, inode 号) 记录第一次出现时创建的文件
+    links: BTreeMap<((u32, u32), u32), VfsFileRef>,
+}
+
+impl Unpacker {
+    /// 找到 path 所在的文件夹, 缺少的中间文件夹会被创建
+    async fn parent_of<'a>(&self, path: &'a str) -> SysResult<(VfsFileRef, &'a str)> {
+        let mut dir = self.root.clone();
+        let (parents, name) = match path.rsplit_once('/') {
+            Some((parents, name)) => (Some(parents), name),
+            None => (None, path),
+        };
+        for comp in parents.into_iter().flat_map(|p| p.split('/')) {
+            if comp.is_empty() || comp == "." {
+                continue;
+            }
+            dir = match dir.lookup(comp).await {
+                Ok(file) if file.attr_kind() == VfsFileKind::Directory => file,
+                Ok(_) => return Err(SysError::ENOTDIR),
+                Err(SysError::ENOENT) => dir.create(comp, VfsFileKind::Directory).await?,
+                Err(e) => return Err(e),
+            };
+        }
+        Ok((dir, name))
+    }
+
+    /// 在 dir 中创建 name. 已经有同名的文件时, 文件夹直接沿用, 其他的先删掉
+    async fn create(
+        &self,
+        dir: &VfsFileRef,
+        name: &str,
+        kind: VfsFileKind,
+    ) -> SysResult<VfsFileRef> {
+        match dir.lookup(name).await {
+            Ok(old) if kind == VfsFileKind::Directory && old.attr_kind() == kind => return Ok(old),
+            Ok(_) => {
+                dir.detach(name).await?;
+            }
+            Err(SysError::ENOENT) => {}
+            Err(e) => return Err(e),
+        }
+        dir.create(name, kind).await
+    }
+
+    async fn unpack_entry(&mut self, header: &Header, path: &str, data: &[u8]) -> SysResult<()> {
+        let (dir, name) = self.parent_of(path).await?;
+        let key = (header.dev, header.ino);
+        let kind = match header.mode & S_IFMT {
+            S_IFDIR => VfsFileKind::Directory,
+            S_IFREG => VfsFileKind::RegularFile,
+            S_IFLNK => VfsFileKind::SymbolLink,
+            _ => {
+                warn!(
+                    "initramfs: {} has unsupported type {:#o}, ignored",
+                    path, header.mode
+                );
+                return Ok(());
+            }
+        };
+        // 硬链接中只有最后一个带有数据, 之前的都是空的
+        let linked = match self.links.get(&key) {
+            Some(file) if kind == VfsFileKind::RegularFile => Some(file.clone()),
+            _ => None,
+        };
+        let file = match linked {
+            Some(file) => {
+                if dir.lookup(name).await.is_ok() {
+                    dir.detach(name).await?;
+                }
+                dir.attach(name, file.clone()).await?;
+                file
+            }
+            None => {
+                let file = self.create(&dir, name, kind).await?;
+                if kind == VfsFileKind::RegularFile && header.nlink > 1 {
+                    self.links.insert(key, file.clone());
+                }
+                file
+            }
+        };
+        if kind != VfsFileKind::Directory && !data.is_empty() {
+            file.truncate(0).await?;
+            file.write_at(0, data).await?;
+        }
+        file.set_mode(header.mode & !S_IFMT).await
+    }
+
+    /// 解一段 initramfs, 返回解出的文件数
+    async fn unpack(&mut self, mut archive: &[u8]) -> SysResult<usize> {
+        let mut cnt = 0;
+        loop {
+            // 归档之间可能有用于对齐的 0
+            let skip = archive.iter().take_while(|&&b| b == 0).count();
+            archive = &archive[skip & !3..];
+            if archive.len() < NEWC_HEADER_SIZE {
+                return Ok(cnt);
+            }
+            let header = parse_header(archive)?;
+            let name_end = NEWC_HEADER_SIZE + header.namesize;
+            let data_start = align4(name_end);
+            let data_end = match data_start.checked_add(header.filesize) {
+                Some(end) if header.namesize != 0 && end <= archive.len() => end,
+                _ => return Err(SysError::EINVAL),
+            };
+            // 文件名以 0 结尾
+            let name = core::str::from_utf8(&archive[NEWC_HEADER_SIZE..name_end - 1])
+                .map_err(|_| SysError::EINVAL)?;
+            let data = &archive[data_start..data_end];
+            archive = &archive[align4(data_end).min(archive.len())..];
+
+            if name == TRAILER {
+                // 一个归档结束, 硬链接不会跨越归档
+                self.links.clear();
+                continue;
+            }
+            let path = name.trim_start_matches("./").trim_start_matches('/');
+            if path.is_empty() || path == "." {
+                continue;
+            }
+            match self.unpack_entry(&header, path, data).await {
+                Ok(()) => cnt += 1,
+                Err(e) => warn!("initramfs: failed to unpack {}: {:?}", path, e),
+            }
+        }
+    }
+}
+
+/// 把内嵌的和引导程序加载的 initramfs 依次解到 root 中
+pub async fn unpack(root: VfsFileRef) {
+    let mut unpacker = Unpacker {
+        root,
+        links: BTreeMap::new(),
+    };
+    for (source, archive) in [("built-in", EMBEDDED), ("initrd", external())] {
+        if archive.is_empty() {
+            continue;
+        }
+        let size = humansize::SizeFormatter::new(archive.len(), humansize::BINARY);
+        info!("  unpacking {} initramfs ({})", source, size);
+        match unpacker.unpack(archive).await {
+            Ok(cnt) => info!("  {} files unpacked", cnt),
+            Err(e) => warn!("  initramfs is corrupted, stopped: {:?}", e),
+        }
+    }
+    frame::free_initrd();
+}
+
+#[allow(unused)]
+pub fn initramfs_test() {
+    let header = |mode: u32, filesize: usize, namesize: usize| {
+        // 下标和 parse_header 中的一致, 其他字段为 0
+        let mut fields = [0; 13];
+        (fields[0], fields[1], fields[4]) = (1, mode as usize, 1);
+        (fields[6], fields[11]) = (filesize, namesize);
+        let hex = fields.iter().map(|f| alloc::format!("{:08x}", f));
+        hex.fold(String::from("070701"), |s, f| s + &f)
+    };
+    let raw = header(S_IFREG | 0o644, 5, 2);
+    debug_assert!(matches!(
+        parse_header(raw.as_bytes()),
+        Ok(Header { ino: 1, mode, nlink: 1, filesize: 5, namesize: 2, .. }) if mode == S_IFREG | 0o644
+    ));
+    debug_assert!(parse_header(&raw.as_bytes()[..NEWC_HEADER_SIZE - 1]).is_err());
+    debug_assert!(parse_header(header(0, 0, 0).replace("070701", "070707").as_bytes()).is_err());
+
+    let mut unpacker = Unpacker {
+        root: VfsFileRef::new(TmpDir::new(DeviceIDCollection::alloc())),
+        links: BTreeMap::new(),
+    };
+    // 只有结尾的归档
+    let mut archive = header(0, 0, TRAILER.len() + 1).into_bytes();
+    archive.extend_from_slice(b"TRAILER!!!\0");
+    debug_assert_eq!(block_on(unpacker.unpack(&archive)), Ok(0));
+    // 声明有 5 字节的内容, 实际只有 2 字节
+    let mut archive = raw.into_bytes();
+    archive.extend_from_slice(b"a\0hi");
+    debug_assert_eq!(block_on(unpacker.unpack(&archive)), Err(SysError::EINVAL));
+    // 文件大小远超归档长度
+    let mut archive = header(S_IFREG | 0o644, u32::MAX as usize, 2).into_bytes();
+    archive.extend_from_slice(b"a\0");
+    debug_assert_eq!(block_on(unpacker.unpack(&archive)), Err(SysError::EINVAL));
+}
@@ -1,3 +1,12 @@
+pub mod shmem;
 pub mod tmpdir;
 pub mod tty;
 pub mod zero;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// 分配一个 tmpfs 中的 inode 号. 从 2 开始, 和 ext2 的根目录一样
+fn alloc_ino() -> usize {
+    static NEXT_INO: AtomicUsize = AtomicUsize::new(2);
+    NEXT_INO.fetch_add(1, Ordering::Relaxed)
+}
@@ -0,0 +1,389 @@
+//! 以物理页为存储的内存文件, 用于 tmpfs (包括 /dev/shm) 中的普通文件和 memfd_create
+//!
+//! 文件的每一页都是一个匿名物理页, 共享映射直接映射这些页,
+//! 所以 mmap(MAP_SHARED) 之后对内存的修改和 read/write 看到的是同一份数据.
+
+use crate::{
+    consts::PAGE_SIZE,
+    executor::block_on,
+    fs::new_vfs::{
+        top::{
+            DeviceInfo, MmapKind, PollKind, SizeInfo, TimeInfo, TimeInfoChange, VfsFile, VfsFileRef,
+        },
+        DeviceIDCollection, VfsFileKind,
+    },
+    here, impl_vfs_default_non_dir,
+    memory::{address::PhysAddr4K, frame::alloc_frame},
+    sync::SpinNoIrqLock,
+    timer::get_time_us,
+    tools::errors::{dyn_future, ASysResult, SysError, SysResult},
+};
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+bitflags::bitflags! {
+    /// 文件封印, 见 fcntl(F_ADD_SEALS)
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Seals: u32 {
+        /// 不能再添加新的封印
+        const SEAL = 1 << 0;
+        /// 不能缩小文件
+        const SHRINK = 1 << 1;
+        /// 不能增大文件
+        const GROW = 1 << 2;
+        /// 不能修改文件内容
+        const WRITE = 1 << 3;
+        /// 同 WRITE, 但已有的可写共享映射仍然可以写
+        const FUTURE_WRITE = 1 << 4;
+    }
+}
+
+struct ShmemInner {
+    /// 页号 -> 物理页, 没有的页读出来是 0
+    pages: BTreeMap<usize, PhysAddr4K>,
+    size: usize,
+    seals: Seals,
+    /// 可写的共享映射数, 不为 0 时不能添加 WRITE 封印
+    writable_maps: usize,
+}
+
+impl ShmemInner {
+    /// 取得第 idx 页, 没有就分配一个全 0 的页
+    fn page_or_alloc(&mut self, idx: usize) -> SysResult<PhysAddr4K> {
+        if let Some(&frame) = self.pages.get(&idx) {
+            return Ok(frame);
+        }
+        let frame = alloc_frame().ok_or(SysError::ENOMEM)?;
+        unsafe { frame.as_mut_page_slice().fill(0) };
+        self.pages.insert(idx, frame);
+        Ok(frame)
+    }
+
+    fn check_resize(&self, new_size: usize) -> SysResult {
+        if new_size < self.size && self.seals.contains(Seals::SHRINK) {
+            return Err(SysError::EPERM);
+        }
+        if new_size > self.size && self.seals.contains(Seals::GROW) {
+            return Err(SysError::EPERM);
+        }
+        Ok(())
+    }
+}
+
+pub struct ShmemFile {
+    /// 普通文件, 或者是内容为链接目标的符号链接
+    kind: VfsFileKind,
+    /// 所在的 tmpfs 的设备号. memfd 和匿名共享映射不在任何挂载的 tmpfs 中, 用 TMP_FS_ID
+    device_id: usize,
+    ino: usize,
+    /// 硬链接数, 由所在的 TmpDir 维护
+    nlink: AtomicUsize,
+    /// 权限位, 只记录不检查
+    mode: AtomicU32,
+    time: SpinNoIrqLock<TimeInfo>,
+    inner: SpinNoIrqLock<ShmemInner>,
+}
+
+impl ShmemFile {
+    /// tmpfs 中的文件, 同 Linux, 不允许封印
+    pub fn new(device_id: usize) -> Self {
+        Self::new_with_seals(VfsFileKind::RegularFile, device_id, Seals::SEAL)
+    }
+
+    /// tmpfs 中的符号链接
+    pub fn new_symlink(device_id: usize) -> Self {
+        Self::new_with_seals(VfsFileKind::SymbolLink, device_id, Seals::SEAL)
+    }
+
+    /// memfd_create 创建的文件, 只有带 MFD_ALLOW_SEALING 时才允许封印
+    pub fn new_memfd(allow_sealing: bool) -> Self {
+        let seals = if allow_sealing {
+            Seals::empty()
+        } else {
+            Seals::SEAL
+        };
+        Self::new_with_seals(
+            VfsFileKind::RegularFile,
+            DeviceIDCollection::TMP_FS_ID,
+            seals,
+        )
+    }
+
+    fn new_with_seals(kind: VfsFileKind, device_id: usize, seals: Seals) -> Self {
+        Self {
+            kind,
+            device_id,
+            ino: super::alloc_ino(),
+            nlink: AtomicUsize::new(1),
+            mode: AtomicU32::new(0o777),
+            time: SpinNoIrqLock::new(TimeInfo {
+                access: 0,
+                modify: 0,
+                change: get_time_us() * 1000,
+            }),
+            inner: SpinNoIrqLock::new(ShmemInner {
+                pages: BTreeMap::new(),
+                size: 0,
+                seals,
+                writable_maps: 0,
+            }),
+        }
+    }
+
+    pub(super) fn link(&self) {
+        self.nlink.fetch_add(1, Ordering::Relaxed);
+    }
+    pub(super) fn unlink(&self) {
+        self.nlink.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn touch_modify(&self) {
+        let now = get_time_us() * 1000;
+        let mut time = self.time.lock(here!());
+        time.modify = now;
+        time.change = now;
+    }
+}
+
+impl Drop for ShmemFile {
+    fn drop(&mut self) {
+        // 页可能还被共享映射引用着, 由最后一个使用者释放
+        for frame in self.inner.lock(here!()).pages.values() {
+            frame.page_num().decrease_and_try_dealloc();
+        }
+    }
+}
+
+impl VfsFile for ShmemFile {
+    fn attr_kind(&self) -> VfsFileKind {
+        self.kind
+    }
+    fn attr_device(&self) -> DeviceInfo {
+        DeviceInfo {
+            device_id: self.device_id,
+            self_device_id: 0,
+        }
+    }
+    fn attr_size(&self) -> ASysResult<SizeInfo> {
+        dyn_future(async {
+            let inner = self.inner.lock(here!());
+            Ok(SizeInfo {
+                bytes: inner.size,
+                blocks: inner.pages.len() * (PAGE_SIZE / 512),
+            })
+        })
+    }
+    fn attr_time(&self) -> ASysResult<TimeInfo> {
+        dyn_future(async { Ok(self.time.lock(here!()).clone()) })
+    }
+    fn attr_nlink(&self) -> ASysResult<usize> {
+        dyn_future(async { Ok(self.nlink.load(Ordering::Relaxed)) })
+    }
+    fn attr_mode(&self) -> u32 {
+        self.mode.load(Ordering::Relaxed)
+    }
+    fn attr_ino(&self) -> usize {
+        self.ino
+    }
+    fn update_time(&self, info_change: TimeInfoChange) -> ASysResult {
+        dyn_future(async move {
+            self.time.lock(here!()).apply_change(info_change);
+            Ok(())
+        })
+    }
+    fn set_mode(&self, mode: u32) -> ASysResult {
+        dyn_future(async move {
+            self.mode.store(mode & 0o7777, Ordering::Relaxed);
+            self.time.lock(here!()).change = get_time_us() * 1000;
+            Ok(())
+        })
+    }
+
+    fn read_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> ASysResult<usize> {
+        dyn_future(async move {
+            let inner = self.inner.lock(here!());
+            if offset >= inner.size {
+                return Ok(0);
+            }
+            let len = core::cmp::min(buf.len(), inner.size - offset);
+            let mut done = 0;
+            while done < len {
+                let pos = offset + done;
+                let in_page = pos % PAGE_SIZE;
+                let n = core::cmp::min(len - done, PAGE_SIZE - in_page);
+                let dst = &mut buf[done..done + n];
+                match inner.pages.get(&(pos / PAGE_SIZE)) {
+                    Some(frame) => {
+                        dst.copy_from_slice(unsafe { &frame.as_page_slice()[in_page..in_page + n] })
+                    }
+                    None => dst.fill(0),
+                }
+                done += n;
+            }
+            Ok(len)
+        })
+    }
+
+    fn write_at<'a>(&'a self, offset: usize, buf: &'a [u8]) -> ASysResult<usize> {
+        dyn_future(async move {
+            let mut inner = self.inner.lock(here!());
+            if inner.seals.intersects(Seals::WRITE | Seals::FUTURE_WRITE) {
+                return Err(SysError::EPERM);
+            }
+            let end = offset.checked_add(buf.len()).ok_or(SysError::EFBIG)?;
+            if end > inner.size {
+                inner.check_resize(end)?;
+            }
+
+            let mut done = 0;
+            while done < buf.len() {
+                let pos = offset + done;
+                let in_page = pos % PAGE_SIZE;
+                let n = core::cmp::min(buf.len() - done, PAGE_SIZE - in_page);
+                let frame = match inner.page_or_alloc(pos / PAGE_SIZE) {
+                    Ok(frame) => frame,
+                    Err(_) if done > 0 => break,
+                    Err(e) => return Err(e),
+                };
+                unsafe {
+                    frame.as_mut_page_slice()[in_page..in_page + n]
+                        .copy_from_slice(&buf[done..done + n]);
+                }
+                done += n;
+            }
+            inner.size = inner.size.max(offset + done);
+            drop(inner);
+
+            self.touch_modify();
+            Ok(done)
+        })
+    }
+
+    fn get_page(&self, offset: usize, kind: MmapKind) -> ASysResult<PhysAddr4K> {
+        dyn_future(async move {
+            debug_assert!(offset % PAGE_SIZE == 0);
+            let mut inner = self.inner.lock(here!());
+            match kind {
+                MmapKind::Shared => {
+                    // 映射持有一份引用, 解除映射时释放
+                    let frame = inner.page_or_alloc(offset / PAGE_SIZE)?;
+                    frame.page_num().increase();
+                    Ok(frame)
+                }
+                MmapKind::Private => {
+                    let frame = alloc_frame().ok_or(SysError::ENOMEM)?;
+                    match inner.pages.get(&(offset / PAGE_SIZE)) {
+                        Some(page) => unsafe {
+                            frame.as_mut_page_slice().copy_from_slice(page.as_page_slice())
+                        },
+                        None => unsafe { frame.as_mut_page_slice().fill(0) },
+                    }
+                    Ok(frame)
+                }
+            }
+        })
+    }
+
+    fn truncate(&self, length: usize) -> ASysResult {
+        dyn_future(async move {
+            let mut inner = self.inner.lock(here!());
+            inner.check_resize(length)?;
+
+            // 整页都在新长度之外的页直接丢掉, 已经映射了它们的进程仍然持有引用
+            let first_dropped = (length + PAGE_SIZE - 1) / PAGE_SIZE;
+            let dropped = inner.pages.split_off(&first_dropped);
+            for frame in dropped.values() {
+                frame.page_num().decrease_and_try_dealloc();
+            }
+            // 最后一页中超出长度的部分清零, 之后再变长时读出来是 0
+            if length % PAGE_SIZE != 0 {
+                if let Some(frame) = inner.pages.get(&(length / PAGE_SIZE)) {
+                    unsafe { frame.as_mut_page_slice()[length % PAGE_SIZE..].fill(0) };
+                }
+            }
+            inner.size = length;
+            drop(inner);
+
+            self.touch_modify();
+            Ok(())
+        })
+    }
+
+    fn seals(&self) -> ASysResult<u32> {
+        dyn_future(async { Ok(self.inner.lock(here!()).seals.bits()) })
+    }
+
+    fn add_seals(&self, seals: u32) -> ASysResult {
+        dyn_future(async move {
+            let seals = Seals::from_bits(seals).ok_or(SysError::EINVAL)?;
+            let mut inner = self.inner.lock(here!());
+            if inner.seals.contains(Seals::SEAL) {
+                return Err(SysError::EPERM);
+            }
+            if seals.contains(Seals::WRITE) && inner.writable_maps != 0 {
+                return Err(SysError::EBUSY);
+            }
+            inner.seals |= seals;
+            Ok(())
+        })
+    }
+
+    fn poll_ready(&self, _offset: usize, len: usize, _kind: PollKind) -> ASysResult<usize> {
+        // 内存文件总是可以立即读写
+        dyn_future(async move { Ok(len) })
+    }
+
+    fn poll_read(&self, offset: usize, buf: &mut [u8]) -> usize {
+        block_on(self.read_at(offset, buf)).unwrap_or(0)
+    }
+
+    fn poll_write(&self, offset: usize, buf: &[u8]) -> usize {
+        block_on(self.write_at(offset, buf)).unwrap_or(0)
+    }
+
+    impl_vfs_default_non_dir!(ShmemFile);
+
+    fn as_any(&self) -> &dyn core::any::Any {
+        self
+    }
+}
+
+/// 内存文件的一个可写的共享映射, 和映射它的段一起 fork 或分裂时复制,
+/// 段被删除或者改为只读时释放. 用来在添加 WRITE 封印时检查是否还有人能写这个文件
+pub struct ShmemWritableMap(VfsFileRef);
+
+impl ShmemWritableMap {
+    /// 文件已经有 WRITE 或 FUTURE_WRITE 封印时返回 EPERM
+    pub fn new(file: VfsFileRef) -> SysResult<Self> {
+        if let Some(shmem) = file.as_any().downcast_ref::<ShmemFile>() {
+            let mut inner = shmem.inner.lock(here!());
+            if inner.seals.intersects(Seals::WRITE | Seals::FUTURE_WRITE) {
+                return Err(SysError::EPERM);
+            }
+            inner.writable_maps += 1;
+        }
+        Ok(Self(file))
+    }
+
+    fn shmem(&self) -> Option<&ShmemFile> {
+        self.0.as_any().downcast_ref::<ShmemFile>()
+    }
+}
+
+impl Clone for ShmemWritableMap {
+    fn clone(&self) -> Self {
+        // 已有的映射在 fork 或分裂后仍然可写, 不受 FUTURE_WRITE 的限制
+        if let Some(shmem) = self.shmem() {
+            shmem.inner.lock(here!()).writable_maps += 1;
+        }
+        Self(self.0.clone())
+    }
+}
+
+impl Drop for ShmemWritableMap {
+    fn drop(&mut self) {
+        if let Some(shmem) = self.shmem() {
+            shmem.inner.lock(here!()).writable_maps -= 1;
+        }
+    }
+}
@@ -1,11 +1,12 @@
+use super::shmem::ShmemFile;
 use crate::{
     fs::new_vfs::{
+        mount::MountPoint,
         top::{DeviceInfo, SizeInfo, TimeInfo, VfsFile, VfsFileRef},
-        DeviceIDCollection, VfsFileKind,
+        VfsFileKind,
     },
-    here, impl_vfs_default_non_dir, impl_vfs_default_non_file,
+    here, impl_vfs_default_non_file,
     sync::SpinNoIrqLock,
-    timer::get_time_us,
     tools::errors::{dyn_future, ASysResult, SysError},
 };
 use alloc::{
@@ -13,136 +14,33 @@ use alloc::{
     string::{String, ToString},
     vec::Vec,
 };
-
-pub struct TmpFile {
-    time: SpinNoIrqLock<TimeInfo>,
-    content: SpinNoIrqLock<Vec<u8>>,
-}
-
-impl TmpFile {
-    pub fn new() -> Self {
-        Self {
-            time: SpinNoIrqLock::new(TimeInfo {
-                access: 0,
-                modify: 0,
-                change: get_time_us() * 1000,
-            }),
-            content: SpinNoIrqLock::new(Vec::new()),
-        }
-    }
-}
-
-impl VfsFile for TmpFile {
-    fn attr_kind(&self) -> VfsFileKind {
-        VfsFileKind::RegularFile
-    }
-    fn attr_device(&self) -> DeviceInfo {
-        DeviceInfo {
-            device_id: DeviceIDCollection::TMP_FS_ID,
-            self_device_id: 0,
-        }
-    }
-    fn attr_size(&self) -> ASysResult<SizeInfo> {
-        dyn_future(async {
-            Ok(SizeInfo {
-                bytes: self.content.lock(here!()).len(),
-                blocks: 0,
-            })
-        })
-    }
-    fn attr_time(&self) -> ASysResult<TimeInfo> {
-        dyn_future(async { Ok(self.time.lock(here!()).clone()) })
-    }
-    fn update_time(&self, info_change: crate::fs::new_vfs::top::TimeInfoChange) -> ASysResult {
-        dyn_future(async move {
-            self.time.lock(here!()).apply_change(info_change);
-            Ok(())
-        })
-    }
-
-    fn read_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> ASysResult<usize> {
-        dyn_future(async move {
-            let content = self.content.lock(here!());
-            let len = core::cmp::min(buf.len(), content.len() - offset);
-            buf[..len].copy_from_slice(&content[offset..offset + len]);
-            Ok(len)
-        })
-    }
-
-    fn write_at<'a>(&'a self, offset: usize, buf: &'a [u8]) -> ASysResult<usize> {
-        dyn_future(async move {
-            let mut content = self.content.lock(here!());
-
-            if content.len() < offset {
-                content.resize(offset, 0);
-            }
-
-            // content.len() >= offset
-            let in_content_len = core::cmp::min(buf.len(), content.len() - offset);
-            for i in 0..in_content_len {
-                content[i + offset] = buf[i];
-            }
-
-            let out_content_len = buf.len() - in_content_len;
-            for i in 0..out_content_len {
-                content.push(buf[i + in_content_len]);
-            }
-
-            Ok(buf.len())
-        })
-    }
-
-    fn get_page(
-        &self,
-        _offset: usize,
-        _kind: crate::fs::new_vfs::top::MmapKind,
-    ) -> ASysResult<crate::memory::address::PhysAddr4K> {
-        unimplemented!("mmap for tmpfile")
-    }
-
-    fn truncate(&self, length: usize) -> ASysResult {
-        dyn_future(async move {
-            self.content.lock(here!()).resize(length, 0);
-            Ok(())
-        })
-    }
-
-    fn poll_ready(
-        &self,
-        _offset: usize,
-        _len: usize,
-        _kind: crate::fs::new_vfs::top::PollKind,
-    ) -> ASysResult<usize> {
-        unimplemented!("poll for tmpfile")
-    }
-
-    fn poll_read(&self, _offset: usize, _buf: &mut [u8]) -> usize {
-        unimplemented!("poll for tmpfile")
-    }
-
-    fn poll_write(&self, _offset: usize, _buf: &[u8]) -> usize {
-        unimplemented!("poll for tmpfile")
-    }
-
-    impl_vfs_default_non_dir!(TmpFile);
-
-    fn as_any(&self) -> &dyn core::any::Any {
-        self
-    }
-}
+use core::sync::atomic::{AtomicU32, Ordering};
 
 pub struct TmpDir {
     children: SpinNoIrqLock<BTreeMap<String, VfsFileRef>>,
+    /// 所在的 tmpfs 的设备号, 其中新建的文件都继承它
+    device_id: usize,
+    ino: usize,
+    /// 权限位, 只记录不检查
+    mode: AtomicU32,
 }
 
 impl TmpDir {
-    pub fn new() -> Self {
+    pub fn new(device_id: usize) -> Self {
         Self {
             children: SpinNoIrqLock::new(BTreeMap::new()),
+            device_id,
+            ino: super::alloc_ino(),
+            mode: AtomicU32::new(0o777),
         }
     }
 }
 
+/// tmpfs 中的普通文件和符号链接可以有多个名字, 在 attach/detach 时维护它们的链接数
+fn as_shmem(file: &VfsFileRef) -> Option<&ShmemFile> {
+    file.as_any().downcast_ref::<ShmemFile>()
+}
+
 impl VfsFile for TmpDir {
     impl_vfs_default_non_file!(TmpDir);
 
@@ -151,7 +49,7 @@ impl VfsFile for TmpDir {
     }
     fn attr_device(&self) -> DeviceInfo {
         DeviceInfo {
-            device_id: DeviceIDCollection::TMP_FS_ID,
+            device_id: self.device_id,
             self_device_id: 0,
         }
     }
@@ -161,9 +59,32 @@ impl VfsFile for TmpDir {
     fn attr_time(&self) -> ASysResult<TimeInfo> {
         dyn_future(async { Ok(TimeInfo::new_zero()) })
     }
+    fn attr_nlink(&self) -> ASysResult<usize> {
+        // 自己的 . 和父目录中的名字, 再加上每个子目录的 ..
+        dyn_future(async {
+            let children = self.children.lock(here!());
+            let subdirs = children
+                .values()
+                .filter(|file| file.attr_kind() == VfsFileKind::Directory)
+                .count();
+            Ok(2 + subdirs)
+        })
+    }
+    fn attr_mode(&self) -> u32 {
+        self.mode.load(Ordering::Relaxed)
+    }
+    fn attr_ino(&self) -> usize {
+        self.ino
+    }
     fn update_time(&self, _info: crate::fs::new_vfs::top::TimeInfoChange) -> ASysResult {
         todo!()
     }
+    fn set_mode(&self, mode: u32) -> ASysResult {
+        dyn_future(async move {
+            self.mode.store(mode & 0o7777, Ordering::Relaxed);
+            Ok(())
+        })
+    }
 
     fn create<'a>(&'a self, name: &'a str, kind: VfsFileKind) -> ASysResult<VfsFileRef> {
         dyn_future(async move {
@@ -174,8 +95,9 @@ impl VfsFile for TmpDir {
             }
 
             let new_file = match kind {
-                VfsFileKind::Directory => VfsFileRef::new(Self::new()),
-                VfsFileKind::RegularFile => VfsFileRef::new(TmpFile::new()),
+                VfsFileKind::Directory => VfsFileRef::new(Self::new(self.device_id)),
+                VfsFileKind::RegularFile => VfsFileRef::new(ShmemFile::new(self.device_id)),
+                VfsFileKind::SymbolLink => VfsFileRef::new(ShmemFile::new_symlink(self.device_id)),
                 _ => panic!("unknown kind"),
             };
 
@@ -199,20 +121,29 @@ impl VfsFile for TmpDir {
     fn detach<'a>(&'a self, name: &'a str) -> ASysResult<VfsFileRef> {
         dyn_future(async move {
             let mut children = self.children.lock(here!());
-            match children.remove(name) {
-                Some(file) => Ok(file),
-                None => Err(SysError::ENOENT),
+            let file = children.remove(name).ok_or(SysError::ENOENT)?;
+            if let Some(shmem) = as_shmem(&file) {
+                shmem.unlink();
             }
+            Ok(file)
         })
     }
 
     fn attach<'a>(&'a self, name: &'a str, file: VfsFileRef) -> ASysResult {
         dyn_future(async move {
             let mut children = self.children.lock(here!());
-            match children.insert(name.to_string(), file) {
-                Some(_) => Err(SysError::EEXIST),
-                None => Ok(()),
+            if let Some(old) = children.get(name) {
+                // 和 PathCacheDir 一样, 挂载点可以遮住一个同名的文件夹
+                let is_mount = file.as_any().is::<MountPoint>();
+                if !is_mount || old.attr_kind() != VfsFileKind::Directory {
+                    return Err(SysError::EEXIST);
+                }
             }
+            if let Some(shmem) = as_shmem(&file) {
+                shmem.link();
+            }
+            children.insert(name.to_string(), file);
+            Ok(())
         })
     }
 
@@ -1,25 +1,31 @@
 use alloc::sync::Arc;
-use log::info;
+use log::{info, warn};
 
 use self::new_vfs::{
     mount::GlobalMountManager,
+    path::Path,
     top::{VfsFS, VfsFSAttr, VfsFSKind, VfsFSRef},
     DeviceIDCollection,
 };
 use crate::{
-    drivers::{AsyncBlockDevice, BlockDevice},
+    cmdline,
+    drivers::AsyncBlockDevice,
     executor::block_on,
     fs::{
+        disk::Disk,
         memfs::{tmpdir::TmpDir, tty::TTY, zero::ZeroDev},
         new_vfs::top::VfsFileRef,
         procfs::ProcFS,
     },
-    tools::errors::SysResult,
+    tools::errors::{SysError, SysResult},
 };
 
 pub mod disk;
+pub mod gpt;
+pub mod initramfs;
 pub mod partition;
 
+pub mod ext2;
 pub mod memfs;
 pub mod new_vfs;
 pub mod nfat32;
@@ -27,6 +33,7 @@ pub mod npipe;
 pub mod procfs;
 pub mod stdio;
 
+use crate::fs::ext2::{Ext2FS, Ext2FSWrapper};
 use crate::fs::nfat32::FatFSWrapper;
 use crate::lazy_init::LazyInit;
 
@@ -36,24 +43,120 @@ pub fn get_root_dir() -> VfsFileRef {
     ROOT_DIR.clone()
 }
 
-pub fn init_rootfs(blk_dev: Arc<dyn AsyncBlockDevice>) {
-    static FAT_FS: LazyInit<VfsFSRef> = LazyInit::new();
-    FAT_FS.init_by(VfsFSRef::new(block_on(FatFSWrapper::new(blk_dev)).unwrap()));
-    let main_fs = FAT_FS.clone();
+/// 打开块设备上的文件系统, 用于根文件系统和 mount. fs_type 为空时自动识别:
+/// 有 ext2 的超级块就当作 ext2/ext4, 否则试试 FAT32. 不认识的类型返回 ENODEV
+pub async fn open_fs(
+    blk_dev: Arc<dyn AsyncBlockDevice>,
+    fs_type: &str,
+    read_only: bool,
+) -> SysResult<VfsFSRef> {
+    let is_ext2 = match fs_type {
+        // ext3 和 ext4 也由 ext2 驱动处理, 它会根据特性决定能否写
+        "ext2" | "ext3" | "ext4" => true,
+        "vfat" => false,
+        "" => Ext2FS::probe(&blk_dev).await,
+        _ => return Err(SysError::ENODEV),
+    };
+    if is_ext2 {
+        info!(
+            "  ext2/ext4 on {}, read only: {}",
+            blk_dev.name(),
+            read_only
+        );
+        Ok(VfsFSRef::new(Ext2FSWrapper::new(blk_dev, read_only).await?))
+    } else {
+        info!("  fat32 on {}, read only: {}", blk_dev.name(), read_only);
+        Ok(VfsFSRef::new(FatFSWrapper::new(blk_dev, read_only).await?))
+    }
+}
 
-    let root_dir = GlobalMountManager::register_as_file("/", main_fs);
+/// 挂载根文件系统. 按 root= 和 rootfstype= 打开 blk_dev 失败时,
+/// 和都没有指定时一样, 自动识别 default_dev 上的文件系统
+pub fn init_rootfs(
+    blk_dev: Arc<dyn AsyncBlockDevice>,
+    default_dev: Arc<dyn AsyncBlockDevice>,
+    fs_type: Option<&str>,
+    read_only: bool,
+) {
+    let fs_type = fs_type.unwrap_or("");
+    let root_fs = match block_on(open_fs(blk_dev.clone(), fs_type, read_only)) {
+        Ok(fs) => fs,
+        Err(e) => {
+            warn!(
+                "  failed to open root filesystem {:?} on {}: {:?}, detect it on {}",
+                fs_type,
+                blk_dev.name(),
+                e,
+                default_dev.name()
+            );
+            block_on(open_fs(default_dev, "", read_only)).expect("no usable root filesystem")
+        }
+    };
+
+    let root_dir = GlobalMountManager::register_as_file("/", root_fs);
     ROOT_DIR.init_by(root_dir);
 }
 
-pub fn init_filesystems(blk_dev: Arc<dyn AsyncBlockDevice>) {
+/// 有 initramfs 时根文件系统是解开了 initramfs 的 tmpfs, 否则挂载块设备上的文件系统
+pub fn init_filesystems(blk_dev: Option<Arc<dyn AsyncBlockDevice>>) {
     info!("Filesystem built-in self testing (BIST)...");
     new_vfs::path::path_test();
+    gpt::gpt_test();
+    initramfs::initramfs_test();
 
     info!("Initialize filesystems...");
-    info!("  use block device: {:?}", blk_dev.name());
+    block_on(disk::probe_disks());
+
+    if initramfs::present() {
+        info!("  root filesystem: initramfs");
+        let root = VfsFileRef::new(TmpDir::new(DeviceIDCollection::alloc()));
+        let root_fs = VfsFSRef::new(TmpFS(root.clone()));
+        ROOT_DIR.init_by(GlobalMountManager::register_as_file("/", root_fs));
+        block_on(initramfs::unpack(root));
+    } else {
+        // 根文件系统所在的分区可以由命令行的 root= 指定, 也可以在编译时用
+        // ROOT=PARTUUID=... 或 ROOT=PARTLABEL=... 指定, 都没有时使用整个 blk_dev
+        let cmdline = cmdline::get();
+        let spec = cmdline.root.as_deref().or(option_env!("ROOT")).filter(|s| !s.is_empty());
+        let default_dev =
+            blk_dev.expect("no initramfs and no block device for the root filesystem");
+        let blk_dev = match spec {
+            Some(spec) => match disk::find_disk_by_spec(spec) {
+                Some(disk) => {
+                    info!("  root device: {} ({})", disk.name(), spec);
+                    disk.device()
+                }
+                None => {
+                    warn!(
+                        "  root device {} not found, use {}",
+                        spec,
+                        default_dev.name()
+                    );
+                    default_dev.clone()
+                }
+            },
+            None => default_dev.clone(),
+        };
+        info!("  use block device: {:?}", blk_dev.name());
+        init_rootfs(
+            blk_dev,
+            default_dev,
+            cmdline.rootfstype.as_deref(),
+            cmdline.read_only,
+        );
+    }
 
-    init_rootfs(blk_dev);
     block_on(mount_all_fs()).unwrap();
+    new_vfs::writeback::init();
+}
+
+/// 找到 path 处的块设备文件对应的块设备, 用于挂载
+pub async fn find_block_device(path: &Path) -> SysResult<Arc<dyn AsyncBlockDevice>> {
+    let file = get_root_dir().resolve(path).await?;
+    match file.as_any().downcast_ref::<Disk>() {
+        Some(disk) => Ok(disk.device()),
+        None => Err(SysError::ENOTBLK),
+    }
 }
 
 struct DevFS(VfsFileRef);
@@ -72,7 +175,7 @@ impl VfsFS for TmpFS {
         self.0.clone()
     }
     fn attr(&self) -> VfsFSAttr {
-        VfsFSAttr::default_mem(VfsFSKind::Tmp, DeviceIDCollection::TMP_FS_ID)
+        VfsFSAttr::default_mem(VfsFSKind::Tmp, self.0.attr_device().device_id)
     }
 }
 
@@ -80,12 +183,16 @@ async fn mount_all_fs() -> SysResult<()> {
     let root_dir = get_root_dir();
 
     // Mount devfs
-    let dev_fs = VfsFSRef::new(DevFS(VfsFileRef::new(TmpDir::new())));
+    let dev_fs = VfsFSRef::new(DevFS(VfsFileRef::new(TmpDir::new(
+        DeviceIDCollection::DEV_FS_ID,
+    ))));
     let dev_dir = dev_fs.root();
     dev_dir.attach("null", VfsFileRef::new(ZeroDev)).await?;
     dev_dir.attach("zero", VfsFileRef::new(ZeroDev)).await?;
-    dev_dir.attach("vda2", VfsFileRef::new(ZeroDev)).await?;
     dev_dir.attach("tty", VfsFileRef::new(TTY::new())).await?;
+    for disk in disk::disks() {
+        dev_dir.attach(disk.name(), VfsFileRef::new(disk.clone())).await?;
+    }
     let dev_mp = GlobalMountManager::register_as_file("/dev", dev_fs);
     root_dir.attach("dev", dev_mp).await?;
 
@@ -94,9 +201,18 @@ async fn mount_all_fs() -> SysResult<()> {
     root_dir.attach("proc", proc_mp).await?;
 
     // Mount tmpfs
-    let tmp_fs = VfsFSRef::new(TmpFS(VfsFileRef::new(TmpDir::new())));
+    let tmp_fs = VfsFSRef::new(TmpFS(VfsFileRef::new(TmpDir::new(
+        DeviceIDCollection::alloc(),
+    ))));
     let tmp_mp = GlobalMountManager::register_as_file("/tmp", tmp_fs);
     root_dir.attach("tmp", tmp_mp).await?;
 
+    // Mount tmpfs for POSIX shared memory (shm_open)
+    let shm_fs = VfsFSRef::new(TmpFS(VfsFileRef::new(TmpDir::new(
+        DeviceIDCollection::alloc(),
+    ))));
+    let shm_mp = GlobalMountManager::register_as_file("/dev/shm", shm_fs);
+    dev_dir.attach("shm", shm_mp).await?;
+
     Ok(())
 }
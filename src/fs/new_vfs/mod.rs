@@ -1,12 +1,16 @@
 // pub mod dentry_cache;
 pub mod mount;
 pub mod page_cache;
+pub mod page_lru;
 pub mod path;
 pub mod path_cache;
 pub mod path_file;
 pub mod sync_attr_file;
 pub mod top;
 pub mod underlying;
+pub mod writeback;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 type DeviceID = usize;
 
@@ -69,4 +73,12 @@ impl DeviceIDCollection {
     pub const PROC_FS_ID: DeviceID = 6;
 
     pub const CONCERTE_FS_ID_BEG: DeviceID = 256;
+
+    /// 给新的文件系统实例分配设备号. 每个挂载的文件系统都需要不同的设备号,
+    /// 否则 PathCacheDir 会把别的文件系统的文件当成自己的, linkat 也没法判断是否跨文件系统
+    pub fn alloc() -> DeviceID {
+        static NEXT_DEVICE_ID: AtomicUsize =
+            AtomicUsize::new(DeviceIDCollection::CONCERTE_FS_ID_BEG);
+        NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed)
+    }
 }
@@ -49,7 +49,13 @@ static MGR: SpinNoIrqLock<GlobalMountManager> = SpinNoIrqLock::new(GlobalMountMa
 
 impl GlobalMountManager {
     pub fn register(path: Path, fs: VfsFSRef) -> MountPoint {
-        MGR.lock(here!()).mount_points.push((path, fs.clone()));
+        let mut mgr = MGR.lock(here!());
+        let pos = mgr
+            .mount_points
+            .iter()
+            .position(|(p, _)| p.len() < path.len())
+            .unwrap_or(mgr.mount_points.len());
+        mgr.mount_points.insert(pos, (path, fs.clone()));
         MountPoint::new(fs)
     }
     /// just helper function for [[register]]
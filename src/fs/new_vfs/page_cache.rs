@@ -1,8 +1,9 @@
 use super::{
+    page_lru::{self, EvictResult, LruOwner, PageCacheInfo, PageCacheStat, PageMeta},
     sync_attr_file::SyncAttrFile,
     top::{MmapKind, VfsFile},
     underlying::ConcreteFile,
-    VfsFileKind,
+    writeback, VfsFileKind,
 };
 use crate::{
     consts::PAGE_SIZE,
@@ -13,25 +14,76 @@ use crate::{
         frame::alloc_frame,
     },
     sync::SleepLock,
+    timer::get_time_ms,
     tools::errors::{dyn_future, ASysResult, SysError, SysResult},
 };
-use alloc::collections::BTreeMap;
+use alloc::{
+    collections::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+};
 use core::{
     marker::PhantomData,
-    sync::atomic::{AtomicBool, AtomicU32},
+    ops::Deref,
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize},
 };
 
 pub struct PageCacheFile<F: ConcreteFile> {
+    inner: Arc<PageCacheInner<F>>,
+}
+
+/// 全局 LRU 回收页时需要拿到文件, 所以页缓存本体放在 Arc 里
+pub struct PageCacheInner<F: ConcreteFile> {
     mgr: SleepLock<PageManager<F>>,
     pub(super) file: SyncAttrFile<F>,
+    /// 用于 /proc/pagecache 展示
+    name: String,
+    stat: Arc<PageCacheStat>,
 }
 
 impl<F: ConcreteFile> PageCacheFile<F> {
-    pub fn new(file: SyncAttrFile<F>) -> Self {
-        Self {
-            mgr: SleepLock::new(PageManager::new()),
-            file,
+    pub fn new(name: String, file: SyncAttrFile<F>) -> Self {
+        let stat = Arc::new(PageCacheStat::new());
+        let inner = Arc::new_cyclic(|weak: &Weak<PageCacheInner<F>>| {
+            let owner: Weak<dyn LruOwner> = weak.clone();
+            PageCacheInner {
+                mgr: SleepLock::new(PageManager::new(owner, stat.clone())),
+                file,
+                name,
+                stat,
+            }
+        });
+        let owner: Weak<dyn LruOwner> = Arc::downgrade(&inner);
+        page_lru::register(owner);
+        Self { inner }
+    }
+}
+
+impl<F: ConcreteFile> PageCacheInner<F> {
+    /// 把 [begin, end) 范围内, 在 dirtied_before (毫秒) 之前变脏的页写回底层文件
+    async fn write_back_range(&self, begin: usize, end: usize, dirtied_before: usize) -> SysResult {
+        // 已经删掉的文件没必要写回
+        if self.file.is_deleted() {
+            return Ok(());
+        }
+        let begin = PhysAddr::from(begin).floor().bits();
+        let mgr = self.mgr.lock().await;
+        // 和 try_evict 一样, 先锁页缓存再锁文件
+        let file = self.file.lock().await;
+        for (&offset, page) in mgr.cached_pages.range(begin..end) {
+            if page.is_dirty() && page.dirtied_at() <= dirtied_before {
+                write_back(&*file, offset, page).await?;
+                PageCacheStat::inc(&self.stat.written_back);
+            }
         }
+        Ok(())
+    }
+}
+
+impl<F: ConcreteFile> Deref for PageCacheFile<F> {
+    type Target = PageCacheInner<F>;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
     }
 }
 
@@ -59,11 +111,17 @@ impl<F: ConcreteFile> VfsFile for PageCacheFile<F> {
     fn attr_time(&self) -> ASysResult<super::top::TimeInfo> {
         dyn_future(self.file.attr_time())
     }
+    fn attr_nlink(&self) -> ASysResult<usize> {
+        dyn_future(self.file.attr_nlink())
+    }
+    fn attr_ino(&self) -> usize {
+        self.file.attr_ino()
+    }
 
     fn truncate(&self, new_size: usize) -> ASysResult {
         dyn_future(async move {
             if new_size == 0 {
-                // drop all pages in cache
+                // drop all pages in cache, 页框在 CachedPage 析构时释放
                 let mut mgr = self.mgr.lock().await;
                 mgr.cached_pages.clear();
             } else {
@@ -75,6 +133,13 @@ impl<F: ConcreteFile> VfsFile for PageCacheFile<F> {
     fn update_time(&self, info: super::top::TimeInfoChange) -> ASysResult {
         dyn_future(async move { self.file.update_time(info).await })
     }
+    fn sync(&self) -> ASysResult {
+        dyn_future(async move {
+            self.write_back_range(0, usize::MAX, usize::MAX).await?;
+            self.file.sync_metadata().await?;
+            self.file.sync_fs().await
+        })
+    }
 
     fn read_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> ASysResult<usize> {
         dyn_future(async move {
@@ -104,14 +169,50 @@ impl<F: ConcreteFile> VfsFile for PageCacheFile<F> {
                 // avoid touch the range manager when zero length
                 return Ok(0);
             }
-            let page_addr = PhysAddr::from(offset).floor().bits();
-            let mut mgr = self.mgr.lock().await;
-            mgr.perpare_range(&self.file, page_addr, buf.len()).await?;
-            mgr.cached_write(offset, buf);
+            {
+                let mut mgr = self.mgr.lock().await;
+                // 没有对齐的写可能跨过页边界, 首尾两页都要先从文件读进来
+                mgr.perpare_range(&self.file, offset, buf.len()).await?;
+                mgr.cached_write(offset, buf);
+            }
+            // 脏页太多了, 先把自己的脏页写回, 让写得太快的进程慢下来
+            if writeback::balance_dirty_pages() {
+                self.write_back_range(0, usize::MAX, usize::MAX).await?;
+            }
             Ok(buf.len())
         })
     }
 
+    fn read_page_direct<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> ASysResult<usize> {
+        dyn_future(async move {
+            // 缓存中的内容可能比底层存储的更新
+            let mgr = self.mgr.lock().await;
+            if mgr.cached_pages.contains_key(&offset) {
+                return Ok(mgr.cached_read(offset, buf));
+            }
+            self.file.read_page_at(offset, buf).await
+        })
+    }
+
+    fn sync_range(&self, offset: usize, len: usize) -> ASysResult {
+        dyn_future(async move {
+            let end = match len {
+                0 => usize::MAX,
+                len => offset.saturating_add(len),
+            };
+            self.write_back_range(offset, end, usize::MAX).await
+        })
+    }
+
+    fn write_page_direct<'a>(&'a self, offset: usize, buf: &'a [u8]) -> ASysResult<usize> {
+        dyn_future(async move {
+            // 丢掉旧的缓存页, 否则之后的读会读到过期的内容
+            let mut mgr = self.mgr.lock().await;
+            mgr.cached_pages.remove(&offset);
+            self.file.write_page_at(offset, buf).await
+        })
+    }
+
     fn get_page(&self, offset: usize, kind: MmapKind) -> ASysResult<PhysAddr4K> {
         if kind != MmapKind::Private {
             panic!("SyncPageCacheFile::get_page: only support private mapping")
@@ -136,13 +237,24 @@ impl<F: ConcreteFile> VfsFile for PageCacheFile<F> {
     }
 
     fn poll_read(&self, offset: usize, buf: &mut [u8]) -> usize {
-        let mgr = block_on(self.mgr.lock());
+        let mut mgr = block_on(self.mgr.lock());
+        // poll_ready 准备好的页可能已经被回收了, 重新准备一遍
+        if let Err(e) = block_on(mgr.perpare_range(&self.file, offset, buf.len())) {
+            log::warn!("PageCacheFile::poll_read: {:?}", e);
+            return 0;
+        }
         mgr.cached_read(offset, buf)
     }
 
     fn poll_write(&self, offset: usize, buf: &[u8]) -> usize {
         let mut mgr = block_on(self.mgr.lock());
+        if let Err(e) = block_on(mgr.perpare_range(&self.file, offset, buf.len())) {
+            log::warn!("PageCacheFile::poll_write: {:?}", e);
+            return 0;
+        }
         mgr.cached_write(offset, buf);
+        // 这里不方便等待写回, 只唤醒后台写回
+        writeback::balance_dirty_pages();
         buf.len()
     }
 
@@ -152,17 +264,98 @@ impl<F: ConcreteFile> VfsFile for PageCacheFile<F> {
     }
 }
 
+impl<F: ConcreteFile> LruOwner for PageCacheInner<F> {
+    fn try_evict(&self, offset: usize, meta: &Arc<PageMeta>) -> EvictResult {
+        let mut mgr = match self.mgr.try_lock() {
+            Some(mgr) => mgr,
+            None => return EvictResult::Busy,
+        };
+        match mgr.cached_pages.get(&offset) {
+            // 同一位置可能已经换成了新读入的页
+            Some(page) if Arc::ptr_eq(&page.meta, meta) => {
+                if page.is_dirty() && !self.file.is_deleted() {
+                    let file = match self.file.try_lock() {
+                        Some(file) => file,
+                        None => return EvictResult::Busy,
+                    };
+                    // TODO-PERF: 在分配页框的路径上同步写回
+                    if let Err(e) = block_on(write_back(&*file, offset, page)) {
+                        log::warn!(
+                            "page cache write back failed: {} @ {:#x}: {:?}",
+                            self.name,
+                            offset,
+                            e
+                        );
+                        return EvictResult::Busy;
+                    }
+                    PageCacheStat::inc(&self.stat.written_back);
+                }
+            }
+            _ => return EvictResult::Gone,
+        }
+        mgr.cached_pages.remove(&offset);
+        PageCacheStat::inc(&self.stat.evicted);
+        EvictResult::Evicted
+    }
+
+    fn device_id(&self) -> usize {
+        self.file.attr_device().device_id
+    }
+
+    fn write_back(&self, dirtied_before: usize) -> ASysResult {
+        dyn_future(async move {
+            self.write_back_range(0, usize::MAX, dirtied_before).await?;
+            self.file.sync_metadata().await
+        })
+    }
+
+    fn info(&self) -> PageCacheInfo {
+        // 不能等锁, 拿不到就不统计页数了
+        let pages = self.mgr.try_lock().map(|mgr| {
+            let cached = mgr.cached_pages.len();
+            let dirty = mgr.cached_pages.values().filter(|p| p.is_dirty()).count();
+            (cached, dirty)
+        });
+        PageCacheInfo {
+            device_id: self.file.attr_device().device_id,
+            name: self.name.clone(),
+            pages,
+            hits: PageCacheStat::get(&self.stat.hits),
+            misses: PageCacheStat::get(&self.stat.misses),
+            evicted: PageCacheStat::get(&self.stat.evicted),
+            written_back: PageCacheStat::get(&self.stat.written_back),
+        }
+    }
+}
+
+/// 把脏页写回底层文件, 调用者需持有文件的锁
+async fn write_back<F: ConcreteFile>(file: &F, offset: usize, page: &CachedPage) -> SysResult {
+    let end = offset + page.len();
+    if file.attr_size().await?.bytes < end {
+        file.truncate(end).await?;
+    }
+    // 底层文件系统按块写, 所以直接写整页, 超出文件长度的部分会被忽略
+    file.write_page_at(offset, page.as_page_slice()).await?;
+    page.clear_dirty();
+    Ok(())
+}
+
 // 直接在最外层上大锁好了
 // TODO: 更好的页缓存
 struct PageManager<F: ConcreteFile> {
     cached_pages: BTreeMap<usize, CachedPage>,
+    /// 新页加入全局 LRU 时登记的所属文件
+    owner: Weak<dyn LruOwner>,
+    stat: Arc<PageCacheStat>,
     _phantom: PhantomData<F>,
 }
 
 impl<F: ConcreteFile> PageManager<F> {
-    pub fn new() -> Self {
+    pub fn new(owner: Weak<dyn LruOwner>, stat: Arc<PageCacheStat>) -> Self {
         Self {
             cached_pages: BTreeMap::new(),
+            owner,
+            stat,
             _phantom: PhantomData,
         }
     }
@@ -181,7 +374,11 @@ impl<F: ConcreteFile> PageManager<F> {
 
         let mut total_len = 0;
         for page_begin in (begin..end).step_by(PAGE_SIZE) {
-            if !self.cached_pages.contains_key(&{ page_begin }) {
+            if let Some(page) = self.cached_pages.get(&page_begin) {
+                page.meta.touch();
+                PageCacheStat::inc(&self.stat.hits);
+            } else {
+                PageCacheStat::inc(&self.stat.misses);
                 let page = CachedPage::alloc()?;
 
                 // 如果超过文件长度, 就不用读了
@@ -193,6 +390,7 @@ impl<F: ConcreteFile> PageManager<F> {
 
                 page.set_len(len);
                 total_len += len;
+                page_lru::insert(&self.owner, page_begin, &page.meta);
                 self.cached_pages.insert(page_begin, page);
 
                 // 读到文件尾了
@@ -277,7 +475,12 @@ impl<F: ConcreteFile> PageManager<F> {
     }
 
     fn get_or_alloc(&mut self, idx: usize) -> &CachedPage {
-        self.cached_pages.entry(idx).or_insert_with(|| CachedPage::alloc().unwrap())
+        let owner = &self.owner;
+        self.cached_pages.entry(idx).or_insert_with(|| {
+            let page = CachedPage::alloc().unwrap();
+            page_lru::insert(owner, idx, &page.meta);
+            page
+        })
     }
 
     /// 写入数据到缓存中, 必定能全部写入
@@ -320,6 +523,10 @@ struct CachedPage {
     // 所以为了节省内存, 上一个 u32, 刚好卡住对齐要求
     effective_len: AtomicU32,
     is_dirty: AtomicBool,
+    /// 最近一次从干净变脏的时间 (毫秒), 后台写回只写旧的脏页
+    dirtied_at: AtomicUsize,
+    /// 与全局 LRU 共享
+    meta: Arc<PageMeta>,
 }
 
 impl CachedPage {
@@ -332,8 +539,10 @@ impl CachedPage {
     pub fn new(phys_addr: PhysAddr4K) -> Self {
         Self {
             is_dirty: AtomicBool::new(false),
+            dirtied_at: AtomicUsize::new(0),
             effective_len: AtomicU32::new(0 as u32),
             phys_addr,
+            meta: PageMeta::new(),
         }
     }
 
@@ -363,11 +572,22 @@ impl CachedPage {
     }
 
     pub fn mark_dirty(&self) {
-        self.is_dirty.store(true, core::sync::atomic::Ordering::Relaxed);
+        if !self.is_dirty.swap(true, core::sync::atomic::Ordering::Relaxed) {
+            self.dirtied_at.store(get_time_ms(), core::sync::atomic::Ordering::Relaxed);
+            writeback::page_dirtied();
+        }
     }
     pub fn is_dirty(&self) -> bool {
         self.is_dirty.load(core::sync::atomic::Ordering::Relaxed)
     }
+    pub fn clear_dirty(&self) {
+        if self.is_dirty.swap(false, core::sync::atomic::Ordering::Relaxed) {
+            writeback::page_cleaned();
+        }
+    }
+    pub fn dirtied_at(&self) -> usize {
+        self.dirtied_at.load(core::sync::atomic::Ordering::Relaxed)
+    }
 
     pub fn as_slice(&self) -> &[u8] {
         unsafe { self.phys_addr.as_slice(self.len()) }
@@ -376,4 +596,16 @@ impl CachedPage {
         self.mark_dirty();
         unsafe { self.phys_addr.as_mut_slice(self.len()) }
     }
+    /// 整页, 包括有效长度之后的部分
+    pub fn as_page_slice(&self) -> &[u8] {
+        unsafe { self.phys_addr.as_page_slice() }
+    }
+}
+
+impl Drop for CachedPage {
+    fn drop(&mut self) {
+        self.clear_dirty();
+        self.meta.mark_removed();
+        self.phys_addr.page_num().decrease_and_try_dealloc();
+    }
 }
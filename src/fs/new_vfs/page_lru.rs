@@ -0,0 +1,285 @@
+//! 页缓存的全局 LRU 与回收
+//!
+//! 仿照 Linux, 维护 active 和 inactive 两条链表:
+//! 新读入的页进入 inactive 链表尾; 回收时从 inactive 链表头开始扫描,
+//! 期间被再次访问过 (命中缓存) 的页晋升到 active 链表, 否则写回 (如果脏了) 并释放.
+//! 当 active 链表比 inactive 链表长时, 从 active 链表头降级一些页到 inactive 链表.
+//!
+//! 链表项不持有页本身, 只持有页的元数据与所属文件的弱引用.
+//! 文件自己丢掉某页时 (truncate 等) 只需在元数据上做标记, 扫描到的时候再把链表项扔掉.
+
+use crate::{
+    here,
+    sync::SpinNoIrqLock,
+    tools::errors::{ASysResult, SysResult},
+};
+use alloc::{
+    collections::VecDeque,
+    format,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// 页缓存中一页的元数据, 由文件和 LRU 链表共享
+pub struct PageMeta {
+    referenced: AtomicBool,
+    removed: AtomicBool,
+}
+
+impl PageMeta {
+    pub fn new() -> Arc<Self> {
+        CACHED_PAGES.fetch_add(1, Ordering::Relaxed);
+        Arc::new(Self {
+            referenced: AtomicBool::new(false),
+            removed: AtomicBool::new(false),
+        })
+    }
+    /// 缓存命中时调用
+    pub fn touch(&self) {
+        self.referenced.store(true, Ordering::Relaxed);
+    }
+    fn test_and_clear_referenced(&self) -> bool {
+        self.referenced.swap(false, Ordering::Relaxed)
+    }
+    /// 页从文件的缓存中被删除时调用
+    pub fn mark_removed(&self) {
+        if !self.removed.swap(true, Ordering::Relaxed) {
+            CACHED_PAGES.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+    fn is_removed(&self) -> bool {
+        self.removed.load(Ordering::Relaxed)
+    }
+}
+
+pub enum EvictResult {
+    /// 页已被写回 (如果需要) 并释放
+    Evicted,
+    /// 文件正忙, 过会儿再试
+    Busy,
+    /// 页已经不在缓存中了
+    Gone,
+}
+
+/// 拥有缓存页的文件
+pub trait LruOwner: Send + Sync {
+    /// 尝试回收 offset 处的页, 仅当该页的元数据仍是 meta 时才回收.
+    /// 可能在分配物理页时被调用, 所以不能等待任何锁
+    fn try_evict(&self, offset: usize, meta: &Arc<PageMeta>) -> EvictResult;
+    /// 文件所在的设备
+    fn device_id(&self) -> usize;
+    /// 把在 dirtied_before (毫秒) 之前变脏的页和文件的元数据写回文件系统,
+    /// 用于 sync 和后台写回
+    fn write_back(&self, dirtied_before: usize) -> ASysResult;
+    /// 用于 /proc/pagecache
+    fn info(&self) -> PageCacheInfo;
+}
+
+pub struct PageCacheInfo {
+    pub device_id: usize,
+    pub name: String,
+    /// (缓存页数, 脏页数), 文件正忙时为 None
+    pub pages: Option<(usize, usize)>,
+    pub hits: usize,
+    pub misses: usize,
+    pub evicted: usize,
+    pub written_back: usize,
+}
+
+/// 页缓存的访问统计
+pub struct PageCacheStat {
+    pub hits: AtomicUsize,
+    pub misses: AtomicUsize,
+    pub evicted: AtomicUsize,
+    pub written_back: AtomicUsize,
+}
+
+impl PageCacheStat {
+    pub const fn new() -> Self {
+        Self {
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            evicted: AtomicUsize::new(0),
+            written_back: AtomicUsize::new(0),
+        }
+    }
+    pub fn inc(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn get(counter: &AtomicUsize) -> usize {
+        counter.load(Ordering::Relaxed)
+    }
+}
+
+struct LruEntry {
+    owner: Weak<dyn LruOwner>,
+    offset: usize,
+    meta: Arc<PageMeta>,
+}
+
+struct PageLru {
+    active: VecDeque<LruEntry>,
+    inactive: VecDeque<LruEntry>,
+}
+
+impl PageLru {
+    /// 一次最多降级多少页, 避免持锁太久
+    const BALANCE_BATCH: usize = 32;
+
+    const fn new() -> Self {
+        Self {
+            active: VecDeque::new(),
+            inactive: VecDeque::new(),
+        }
+    }
+
+    /// 让 active 链表不要比 inactive 链表长
+    fn balance(&mut self) {
+        let mut moved = 0;
+        while self.active.len() > self.inactive.len() && moved < Self::BALANCE_BATCH {
+            let entry = self.active.pop_front().unwrap();
+            moved += 1;
+            if entry.meta.is_removed() {
+                continue;
+            }
+            if entry.meta.test_and_clear_referenced() {
+                self.active.push_back(entry);
+            } else {
+                self.inactive.push_back(entry);
+            }
+        }
+    }
+}
+
+static PAGE_LRU: SpinNoIrqLock<PageLru> = SpinNoIrqLock::new(PageLru::new());
+/// 所有带页缓存的文件, 用于 procfs
+static OWNERS: SpinNoIrqLock<Vec<Weak<dyn LruOwner>>> = SpinNoIrqLock::new(Vec::new());
+/// 页缓存中的总页数
+static CACHED_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+pub fn register(owner: Weak<dyn LruOwner>) {
+    let mut owners = OWNERS.lock(here!());
+    owners.retain(|o| o.strong_count() != 0);
+    owners.push(owner);
+}
+
+/// 新读入缓存的页放入 inactive 链表尾
+pub fn insert(owner: &Weak<dyn LruOwner>, offset: usize, meta: &Arc<PageMeta>) {
+    PAGE_LRU.lock(here!()).inactive.push_back(LruEntry {
+        owner: owner.clone(),
+        offset,
+        meta: meta.clone(),
+    });
+}
+
+/// 尝试从页缓存中回收 want 页, 返回实际释放的页数
+pub fn reclaim(want: usize) -> usize {
+    let mut freed = 0;
+    // 每个链表项最多看两遍, 以免大家都在忙的时候原地打转
+    let mut budget = {
+        let lru = PAGE_LRU.lock(here!());
+        2 * (lru.active.len() + lru.inactive.len())
+    };
+    while freed < want && budget > 0 {
+        budget -= 1;
+        let entry = {
+            let mut lru = PAGE_LRU.lock(here!());
+            lru.balance();
+            match lru.inactive.pop_front() {
+                Some(entry) => entry,
+                None => break,
+            }
+        };
+        if entry.meta.is_removed() {
+            continue;
+        }
+        if entry.meta.test_and_clear_referenced() {
+            PAGE_LRU.lock(here!()).active.push_back(entry);
+            continue;
+        }
+        // 回收时不能持有 LRU 的锁, 写回可能很慢
+        let owner = match entry.owner.upgrade() {
+            Some(owner) => owner,
+            None => continue,
+        };
+        match owner.try_evict(entry.offset, &entry.meta) {
+            EvictResult::Evicted => freed += 1,
+            EvictResult::Gone => {}
+            EvictResult::Busy => PAGE_LRU.lock(here!()).inactive.push_back(entry),
+        }
+    }
+    log::debug!("page cache reclaim: want {}, freed {}", want, freed);
+    freed
+}
+
+/// 把某个设备上所有文件的脏页写回
+pub async fn write_back_device(device_id: usize) -> SysResult {
+    write_back_owners(Some(device_id), usize::MAX).await
+}
+
+/// 把所有文件中在 dirtied_before (毫秒) 之前变脏的页写回
+pub async fn write_back_expired(dirtied_before: usize) -> SysResult {
+    write_back_owners(None, dirtied_before).await
+}
+
+/// 一个文件失败了也继续写别的
+async fn write_back_owners(device_id: Option<usize>, dirtied_before: usize) -> SysResult {
+    let owners: Vec<_> = OWNERS.lock(here!()).iter().filter_map(|o| o.upgrade()).collect();
+    let owners = owners.iter().filter(|o| device_id.map_or(true, |id| o.device_id() == id));
+    let mut ret = Ok(());
+    for owner in owners {
+        if let Err(e) = owner.write_back(dirtied_before).await {
+            log::warn!("write back {} failed: {:?}", owner.info().name, e);
+            ret = Err(e);
+        }
+    }
+    ret
+}
+
+/// 页缓存总页数
+pub fn cached_pages() -> usize {
+    CACHED_PAGES.load(Ordering::Relaxed)
+}
+
+/// (active, inactive) 链表长度, 可能包含已被删除但还没扫描到的页
+pub fn lru_len() -> (usize, usize) {
+    let lru = PAGE_LRU.lock(here!());
+    (lru.active.len(), lru.inactive.len())
+}
+
+/// /proc/pagecache 的内容
+pub fn proc_pagecache() -> String {
+    let owners: Vec<_> = OWNERS.lock(here!()).iter().filter_map(|o| o.upgrade()).collect();
+    let (active, inactive) = lru_len();
+    let mut content = format!(
+        "total: {} active: {} inactive: {}\n",
+        cached_pages(),
+        active,
+        inactive
+    );
+    content.push_str(&format!(
+        "{:<6}{:>8}{:>8}{:>10}{:>10}{:>10}{:>10} {}\n",
+        "dev", "cached", "dirty", "hits", "misses", "evicted", "writeback", "path"
+    ));
+    for owner in owners {
+        let info = owner.info();
+        let (cached, dirty) = match info.pages {
+            Some((cached, dirty)) => (format!("{}", cached), format!("{}", dirty)),
+            None => (String::from("-"), String::from("-")),
+        };
+        content.push_str(&format!(
+            "{:<6}{:>8}{:>8}{:>10}{:>10}{:>10}{:>10} {}\n",
+            info.device_id,
+            cached,
+            dirty,
+            info.hits,
+            info.misses,
+            info.evicted,
+            info.written_back,
+            info.name
+        ));
+    }
+    content
+}
@@ -8,7 +8,7 @@ use super::{
 use crate::{
     here, impl_vfs_default_non_file,
     sync::SpinNoIrqLock,
-    tools::errors::{dyn_future, ASysResult, SysError},
+    tools::errors::{dyn_future, ASysResult, SysError, SysResult},
 };
 use alloc::{
     collections::BTreeMap,
@@ -18,6 +18,7 @@ use alloc::{
 
 pub struct PathCacheDir<F: ConcreteFile> {
     file: SyncAttrFile<F>,
+    /// 相对于文件系统根目录的路径
     name: String,
     subdirs: SpinNoIrqLock<SubdirMap>,
 }
@@ -41,6 +42,10 @@ impl SubdirMap {
     pub fn set_all(&mut self) {
         self.is_all = true;
     }
+    /// 缓存中有条目被挂载点遮住了, 之后要重新向具体文件系统查询
+    pub fn clear_all(&mut self) {
+        self.is_all = false;
+    }
 
     pub fn all(&self) -> Vec<(String, VfsFileRef)> {
         self.map.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
@@ -68,33 +73,42 @@ impl<F: ConcreteFile> PathCacheDir<F> {
         }
     }
 
-    fn new_sub(name: &str, file: SyncAttrFile<F>) -> Self {
+    fn new_sub(path: String, file: SyncAttrFile<F>) -> Self {
         Self {
             file,
-            name: name.to_string(),
+            name: path,
             subdirs: SpinNoIrqLock::new(SubdirMap::new()),
         }
     }
 
+    fn sub_path(&self, name: &str) -> String {
+        self.name.clone() + "/" + name
+    }
+
     fn pack_concrete_file(&self, name: &str, file: F) -> VfsFileRef {
         let kind = file.attr_kind();
         let file = SyncAttrFile::new(file);
         match kind {
-            VfsFileKind::Directory => VfsFileRef::new(Self::new_sub(name, file)),
-            VfsFileKind::RegularFile => VfsFileRef::new(PageCacheFile::new(file)),
-            _ => panic!("unsupported file kind from concrete file"),
+            VfsFileKind::Directory => VfsFileRef::new(Self::new_sub(self.sub_path(name), file)),
+            // 符号链接的内容是它的目标, 设备文件等特殊文件没有内容
+            _ => VfsFileRef::new(PageCacheFile::new(self.sub_path(name), file)),
         }
     }
 
     async fn pack_file(&self, name: &str, file: SyncAttrFile<F>) -> VfsFileRef {
         let kind = file.attr_kind();
         match kind {
-            VfsFileKind::Directory => VfsFileRef::new(Self::new_sub(name, file)),
-            VfsFileKind::RegularFile => VfsFileRef::new(PageCacheFile::new(file)),
-            _ => panic!("unsupported file kind from concrete file"),
+            VfsFileKind::Directory => VfsFileRef::new(Self::new_sub(self.sub_path(name), file)),
+            // 符号链接的内容是它的目标, 设备文件等特殊文件没有内容
+            _ => VfsFileRef::new(PageCacheFile::new(self.sub_path(name), file)),
         }
     }
 
+    /// 从目录中 detach 之后文件是否已经没有名字了. 目录不能有硬链接, 删掉的就是唯一的名字
+    async fn is_unlinked(file: &SyncAttrFile<F>) -> SysResult<bool> {
+        Ok(file.attr_kind() == VfsFileKind::Directory || file.attr_nlink().await? == 0)
+    }
+
     async fn extract_file<'a>(&self, file: &'a VfsFileRef) -> Option<&'a SyncAttrFile<F>> {
         let file_dev_id = file.attr_device().device_id;
         let self_dev_id = self.file.attr_device().device_id;
@@ -106,11 +120,10 @@ impl<F: ConcreteFile> PathCacheDir<F> {
                     let file = file.as_any().downcast_ref::<Self>().unwrap();
                     Some(&file.file)
                 }
-                VfsFileKind::RegularFile => {
+                _ => {
                     let file = file.as_any().downcast_ref::<PageCacheFile<F>>().unwrap();
                     Some(&file.file)
                 }
-                _ => panic!("unsupported file kind from concrete file"),
             }
         } else {
             None
@@ -133,12 +146,24 @@ impl<F: ConcreteFile> VfsFile for PathCacheDir<F> {
     fn attr_time(&self) -> ASysResult<super::top::TimeInfo> {
         dyn_future(async move { self.file.lock().await.attr_time().await })
     }
+    fn attr_nlink(&self) -> ASysResult<usize> {
+        dyn_future(self.file.attr_nlink())
+    }
+    fn attr_ino(&self) -> usize {
+        self.file.attr_ino()
+    }
     fn update_time(&self, info: super::top::TimeInfoChange) -> ASysResult {
         dyn_future(async move {
             self.file.lock().await.update_time(info);
             Ok(())
         })
     }
+    fn sync(&self) -> ASysResult {
+        dyn_future(async move {
+            self.file.sync_metadata().await?;
+            self.file.sync_fs().await
+        })
+    }
 
     fn list(&self) -> ASysResult<alloc::vec::Vec<(String, VfsFileRef)>> {
         dyn_future(async move {
@@ -150,7 +175,10 @@ impl<F: ConcreteFile> VfsFile for PathCacheDir<F> {
                     // for (i, c) in name.as_bytes().iter().enumerate() {
                     //     log::debug!("VFS list: '{}' char {}: {}", name, i, c);
                     // }
-                    subdirs.put(name.clone(), self.pack_concrete_file(&name, file));
+                    // 已经缓存的条目可能是挂载点, 不能被覆盖
+                    if !subdirs.exist(&name) {
+                        subdirs.put(name.clone(), self.pack_concrete_file(&name, file));
+                    }
                 }
                 subdirs.set_all();
             }
@@ -198,9 +226,11 @@ impl<F: ConcreteFile> VfsFile for PathCacheDir<F> {
             if let Some(file) = subdirs.pop(name) {
                 if let Some(file) = self.extract_file(&file).await {
                     // 如果是本文件系统中的文件, 则从具体文件系统中删除
-                    self.file.detach(file).await?;
-                    // 同时延迟删
-                    file.mark_deleted();
+                    self.file.detach(file, name).await?;
+                    // 没有别的硬链接了, 则同时延迟删
+                    if Self::is_unlinked(file).await? {
+                        file.mark_deleted();
+                    }
                 }
                 // 否则什么也不做
             } else if subdirs.is_all() {
@@ -209,8 +239,10 @@ impl<F: ConcreteFile> VfsFile for PathCacheDir<F> {
             } else {
                 // 若否, 则向具体文件系统查找并要求删除
                 let file = self.file.lookup(name).await?;
-                self.file.detach(&file).await?;
-                file.mark_deleted();
+                self.file.detach(&file, name).await?;
+                if Self::is_unlinked(&file).await? {
+                    file.mark_deleted();
+                }
             }
             Ok(())
         })
@@ -222,9 +254,11 @@ impl<F: ConcreteFile> VfsFile for PathCacheDir<F> {
             if let Some(file) = subdirs.pop(name) {
                 if let Some(file) = self.extract_file(&file).await {
                     // 如果是本文件系统中的文件, 则从具体文件系统中删除
-                    self.file.detach(file).await?;
+                    self.file.detach(file, name).await?;
+                } else {
+                    // 否则是挂载点, 它可能遮住了具体文件系统中的同名文件夹
+                    subdirs.clear_all();
                 }
-                // 否则什么也不做
                 Ok(file)
             } else if subdirs.is_all() {
                 // 若缓存已经代表了全部文件, 则可以直接返回 ENOENT
@@ -232,7 +266,7 @@ impl<F: ConcreteFile> VfsFile for PathCacheDir<F> {
             } else {
                 // 若否, 则向具体文件系统查找并要求删除
                 let file = self.file.lookup(name).await?;
-                self.file.detach(&file).await?;
+                self.file.detach(&file, name).await?;
                 Ok(self.pack_file(name, file).await)
             }
         })
@@ -241,17 +275,20 @@ impl<F: ConcreteFile> VfsFile for PathCacheDir<F> {
     fn attach<'a>(&'a self, name: &'a str, file: VfsFileRef) -> ASysResult {
         dyn_future(async move {
             let mut subdirs = self.subdirs.lock(here!());
-            if subdirs.exist(name) {
-                Err(SysError::EEXIST)
-            } else {
-                if let Some(file) = self.extract_file(&file).await {
-                    // 如果是本文件系统中的文件, 则向具体文件系统中加入它
-                    self.file.attach(file, name).await?;
+            if let Some(concrete) = self.extract_file(&file).await {
+                if subdirs.exist(name) {
+                    return Err(SysError::EEXIST);
+                }
+                // 如果是本文件系统中的文件, 则向具体文件系统中加入它
+                self.file.attach(concrete, name).await?;
+            } else if let Some(old) = subdirs.get(name) {
+                // 否则是挂载点, 可以遮住一个同名的文件夹, 只替换缓存
+                if !old.is_dir().await? {
+                    return Err(SysError::EEXIST);
                 }
-                // 否则什么也不做
-                subdirs.put(name.to_string(), file);
-                Ok(())
             }
+            subdirs.put(name.to_string(), file);
+            Ok(())
         })
     }
 
@@ -5,7 +5,7 @@ use super::{
 };
 use crate::{
     executor::block_on,
-    sync::{SleepLock, SleepLockFuture},
+    sync::{SleepLock, SleepLockFuture, SleepLockGuard},
     tools::errors::SysResult,
 };
 use alloc::{string::String, vec::Vec};
@@ -23,23 +23,29 @@ pub struct SyncAttrFile<F: ConcreteFile> {
     file: SleepLock<F>,
     kind: VfsFileKind,
     device: DeviceInfo,
+    ino: usize,
 }
 
 impl<F: ConcreteFile> SyncAttrFile<F> {
     pub fn new(file: F) -> Self {
         let kind = file.attr_kind();
         let device = file.attr_device();
+        let ino = file.attr_ino();
         Self {
             is_deleted: AtomicBool::new(false),
             file: SleepLock::new(file),
             kind,
             device,
+            ino,
         }
     }
 
     pub fn lock(&self) -> SleepLockFuture<F> {
         self.file.lock()
     }
+    pub fn try_lock(&self) -> Option<SleepLockGuard<F>> {
+        self.file.try_lock()
+    }
 
     pub fn mark_deleted(&self) {
         self.is_deleted.store(true, core::sync::atomic::Ordering::Relaxed);
@@ -57,12 +63,18 @@ impl<F: ConcreteFile> SyncAttrFile<F> {
     pub fn attr_device(&self) -> DeviceInfo {
         self.device.clone()
     }
+    pub fn attr_ino(&self) -> usize {
+        self.ino
+    }
     pub async fn attr_size(&self) -> SysResult<SizeInfo> {
         self.lock().await.attr_size().await
     }
     pub async fn attr_time(&self) -> SysResult<TimeInfo> {
         self.lock().await.attr_time().await
     }
+    pub async fn attr_nlink(&self) -> SysResult<usize> {
+        self.lock().await.attr_nlink().await
+    }
     pub async fn update_time(&self, info: TimeInfoChange) -> SysResult {
         self.lock().await.update_time(info).await
     }
@@ -70,6 +82,12 @@ impl<F: ConcreteFile> SyncAttrFile<F> {
     pub async fn delete(&self) -> SysResult {
         self.lock().await.delete().await
     }
+    pub async fn sync_metadata(&self) -> SysResult {
+        self.lock().await.sync_metadata().await
+    }
+    pub async fn sync_fs(&self) -> SysResult {
+        self.lock().await.sync_fs().await
+    }
 
     // 文件操作
     pub async fn read_page_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> SysResult<usize> {
@@ -97,9 +115,9 @@ impl<F: ConcreteFile> SyncAttrFile<F> {
         let other = file.lock().await;
         self.lock().await.rename(&other, new_name).await
     }
-    pub async fn detach<'a>(&'a self, file: &'a Self) -> SysResult {
+    pub async fn detach<'a>(&'a self, file: &'a Self, name: &'a str) -> SysResult {
         let other = file.lock().await;
-        self.lock().await.detach(&other).await
+        self.lock().await.detach(&other, name).await
     }
     pub async fn attach<'a>(&'a self, file: &'a Self, name: &'a str) -> SysResult {
         let other = file.lock().await;
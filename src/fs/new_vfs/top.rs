@@ -3,9 +3,15 @@ use crate::{
     consts,
     memory::address::PhysAddr4K,
     timer::get_time_us,
-    tools::errors::{dyn_future, ASysResult, SysResult},
+    tools::errors::{dyn_future, ASysResult, SysError, SysResult},
+};
+use alloc::{
+    collections::VecDeque,
+    string::{String, ToString},
+    sync::Arc,
+    vec,
+    vec::Vec,
 };
-use alloc::{string::String, sync::Arc, vec::Vec};
 use core::{
     any::Any,
     ops::{Deref, DerefMut},
@@ -13,6 +19,8 @@ use core::{
 
 pub enum VfsFSKind {
     Fat,
+    Ext2,
+    Ext4,
     Dev,
     Tmp,
     Proc,
@@ -53,6 +61,14 @@ pub const NORMAL_FILE_NAME_LENGTH: usize = 511;
 pub trait VfsFS {
     fn root(&self) -> VfsFileRef;
     fn attr(&self) -> VfsFSAttr;
+    /// 把文件系统中所有的脏数据写回磁盘 (syncfs). 内存中的文件系统什么都不用做
+    fn sync(&self) -> ASysResult {
+        dyn_future(async { Ok(()) })
+    }
+    /// 只把文件系统自己缓存的元数据 (分配表, 块缓存等) 写回磁盘, 不管页缓存. 后台写回用
+    fn sync_metadata(&self) -> ASysResult {
+        dyn_future(async { Ok(()) })
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -191,7 +207,27 @@ pub trait VfsFile: Send + Sync {
     fn attr_device(&self) -> DeviceInfo;
     fn attr_size(&self) -> ASysResult<SizeInfo>;
     fn attr_time(&self) -> ASysResult<TimeInfo>;
+    /// 硬链接数. 不支持硬链接的文件只有一个名字
+    fn attr_nlink(&self) -> ASysResult<usize> {
+        dyn_future(async { Ok(1) })
+    }
+    /// 权限位, 即 st_mode 的低 12 位. 我们并不检查权限, 不记录权限的文件一律是 0777
+    fn attr_mode(&self) -> u32 {
+        0o777
+    }
+    /// inode 号. 没有 inode 的文件用对象的地址, 在文件存在期间不会和别的文件重复
+    fn attr_ino(&self) -> usize {
+        self as *const Self as *const () as usize
+    }
     fn update_time(&self, info: TimeInfoChange) -> ASysResult;
+    /// 修改权限位. 不记录权限的文件直接忽略
+    fn set_mode(&self, _mode: u32) -> ASysResult {
+        dyn_future(async { Ok(()) })
+    }
+    /// 把文件的脏数据和元数据写回磁盘 (fsync). 不在磁盘上的文件什么都不用做
+    fn sync(&self) -> ASysResult {
+        dyn_future(async { Ok(()) })
+    }
 
     // 文件操作
     /// 读取文件内容
@@ -203,6 +239,28 @@ pub trait VfsFile: Send + Sync {
     fn get_page(&self, offset: usize, kind: MmapKind) -> ASysResult<PhysAddr4K>;
     /// 改变文件长度
     fn truncate(&self, length: usize) -> ASysResult;
+    /// 绕过页缓存, 直接从底层存储读取 [offset, offset + PAGE_SIZE) 范围内的内容.
+    /// 主要给 swap 用: 被换出的页不应该再占着页缓存. 不支持的文件返回 EINVAL
+    fn read_page_direct<'a>(&'a self, _offset: usize, _buf: &'a mut [u8]) -> ASysResult<usize> {
+        dyn_future(async { Err(SysError::EINVAL) })
+    }
+    /// 绕过页缓存, 直接写入底层存储, 参见 read_page_direct
+    fn write_page_direct<'a>(&'a self, _offset: usize, _buf: &'a [u8]) -> ASysResult<usize> {
+        dyn_future(async { Err(SysError::EINVAL) })
+    }
+    /// 只把 [offset, offset + len) 范围内的脏页写回, 不管元数据 (sync_file_range).
+    /// len 为 0 表示一直到文件尾
+    fn sync_range(&self, _offset: usize, _len: usize) -> ASysResult {
+        dyn_future(async { Ok(()) })
+    }
+    /// 获取文件的封印 (F_GET_SEALS). 不支持封印的文件返回 EINVAL
+    fn seals(&self) -> ASysResult<u32> {
+        dyn_future(async { Err(SysError::EINVAL) })
+    }
+    /// 给文件添加封印 (F_ADD_SEALS), 参见 seals
+    fn add_seals(&self, _seals: u32) -> ASysResult {
+        dyn_future(async { Err(SysError::EINVAL) })
+    }
 
     // 高级文件操作
     /// 要求文件准备好 [offset, offset + len) 范围内的内容以供读取或写入.
@@ -238,6 +296,11 @@ pub trait VfsFile: Send + Sync {
     fn as_any(&self) -> &dyn Any;
 }
 
+/// 符号链接目标的最大长度, 同 Linux 的 PATH_MAX
+pub const PATH_MAX: usize = 4096;
+/// 一次路径解析中最多跟随的符号链接数, 超过了返回 ELOOP. 同 Linux 的 MAXSYMLINKS
+pub const MAX_SYMLINK_FOLLOW: usize = 40;
+
 #[derive(Clone)]
 pub struct VfsFileRef(Arc<dyn VfsFile>);
 
@@ -259,6 +322,10 @@ impl VfsFileRef {
         Self(Arc::new(file))
     }
 
+    /// 是否是同一个文件对象
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
     pub async fn kind(&self) -> SysResult<VfsFileKind> {
         Ok(self.0.attr_kind())
     }
@@ -276,10 +343,80 @@ impl VfsFileRef {
         Ok(self.kind().await? == VfsFileKind::SymbolLink)
     }
 
+    /// 读出符号链接的目标
+    pub async fn read_link(&self) -> SysResult<String> {
+        if !self.is_symlink().await? {
+            return Err(SysError::EINVAL);
+        }
+        let size = self.size().await?;
+        if size > PATH_MAX {
+            return Err(SysError::ENAMETOOLONG);
+        }
+        let mut buf = vec![0; size];
+        let len = self.read_at(0, &mut buf).await?;
+        buf.truncate(len);
+        String::from_utf8(buf).map_err(|_| SysError::EINVAL)
+    }
+
+    /// 从当前目录出发查找 path, 跟随路径中的符号链接
     pub async fn resolve(&self, path: &Path) -> SysResult<Self> {
+        self.walk(path, true).await
+    }
+
+    /// 从当前目录出发查找 path. 路径中间的符号链接总是被跟随, 最后一级只有 follow_last 时才跟随.
+    /// 链接目标中的 .. 沿着走过的目录往回退, 以 / 开头的链接目标则从根目录重新开始.
+    /// 目录不知道自己的父目录, 所以从根目录出发时栈中就是链接所在目录的绝对路径;
+    /// 从其他目录出发时 .. 退到出发的目录之外就无从解析, 返回 ENOENT
+    pub async fn walk(&self, path: &Path, follow_last: bool) -> SysResult<Self> {
+        // 走过的目录, 栈底是出发的目录. 当前位置是目录时它就是栈顶
+        let mut dirs = vec![self.clone()];
+        let mut from_root = self.ptr_eq(&crate::fs::get_root_dir());
         let mut cur = self.clone();
-        for name in path.iter() {
-            cur = cur.lookup(name).await?;
+        let mut names: VecDeque<String> = path.iter().cloned().collect();
+        let mut follows = 0;
+        while let Some(name) = names.pop_front() {
+            if !cur.is_dir().await? {
+                return Err(SysError::ENOTDIR);
+            }
+            // 路径已经正规化过了, 只有链接目标中才会出现这些
+            match name.as_str() {
+                "" | "." => continue,
+                ".." => {
+                    if dirs.len() > 1 {
+                        dirs.pop();
+                    } else if !from_root {
+                        return Err(SysError::ENOENT);
+                    }
+                    cur = dirs.last().unwrap().clone();
+                    continue;
+                }
+                _ => {}
+            }
+
+            let next = cur.lookup(&name).await?;
+            if next.is_symlink().await? && (follow_last || !names.is_empty()) {
+                follows += 1;
+                if follows > MAX_SYMLINK_FOLLOW {
+                    return Err(SysError::ELOOP);
+                }
+                let target = next.read_link().await?;
+                if target.is_empty() {
+                    return Err(SysError::ENOENT);
+                }
+                if target.starts_with('/') {
+                    cur = crate::fs::get_root_dir();
+                    dirs = vec![cur.clone()];
+                    from_root = true;
+                }
+                for part in target.split('/').rev() {
+                    names.push_front(part.to_string());
+                }
+            } else {
+                if next.is_dir().await? {
+                    dirs.push(next.clone());
+                }
+                cur = next;
+            }
         }
         Ok(cur)
     }
@@ -435,6 +572,15 @@ macro_rules! impl_vfs_forward_file {
         fn truncate(&self, len: usize) -> $crate::tools::errors::ASysResult {
             self.$($e)+.truncate(len)
         }
+        fn sync_range(&self, offset: usize, len: usize) -> $crate::tools::errors::ASysResult {
+            self.$($e)+.sync_range(offset, len)
+        }
+        fn seals(&self) -> $crate::tools::errors::ASysResult<u32> {
+            self.$($e)+.seals()
+        }
+        fn add_seals(&self, seals: u32) -> $crate::tools::errors::ASysResult {
+            self.$($e)+.add_seals(seals)
+        }
         fn poll_ready(
             &self,
             offset: usize,
@@ -467,6 +613,15 @@ macro_rules! impl_vfs_forward_attr_getter {
         fn attr_time(&self) -> $crate::tools::errors::ASysResult<$crate::fs::new_vfs::top::TimeInfo> {
             self.$($e)+.attr_time()
         }
+        fn attr_nlink(&self) -> $crate::tools::errors::ASysResult<usize> {
+            self.$($e)+.attr_nlink()
+        }
+        fn attr_mode(&self) -> u32 {
+            self.$($e)+.attr_mode()
+        }
+        fn attr_ino(&self) -> usize {
+            self.$($e)+.attr_ino()
+        }
     };
 }
 
@@ -476,5 +631,11 @@ macro_rules! impl_vfs_forward_attr_setter {
         fn update_time(&self, info: $crate::fs::new_vfs::top::TimeInfoChange) -> $crate::tools::errors::ASysResult {
             self.$($e)+.update_time(info)
         }
+        fn sync(&self) -> $crate::tools::errors::ASysResult {
+            self.$($e)+.sync()
+        }
+        fn set_mode(&self, mode: u32) -> $crate::tools::errors::ASysResult {
+            self.$($e)+.set_mode(mode)
+        }
     };
 }
@@ -11,8 +11,16 @@ pub trait ConcreteFile: Send + Sync + Sized + 'static {
     fn attr_device(&self) -> DeviceInfo;
     fn attr_size(&self) -> ASysResult<SizeInfo>;
     fn attr_time(&self) -> ASysResult<TimeInfo>;
+    /// 硬链接数, 即有多少个目录项指向这个文件. 从目录中 detach 之后就不再算上那一个
+    fn attr_nlink(&self) -> ASysResult<usize>;
+    /// inode 号, 在文件的整个生命周期内不变
+    fn attr_ino(&self) -> usize;
     fn update_time(&self, info: TimeInfoChange) -> ASysResult;
     fn delete(&self) -> ASysResult;
+    /// 把文件自身的元数据 (目录项, inode 等) 写入文件系统的缓存
+    fn sync_metadata(&self) -> ASysResult;
+    /// 把文件系统缓存的元数据 (分配表, 块缓存等) 刷到磁盘
+    fn sync_fs(&self) -> ASysResult;
 
     // 文件操作
     fn read_page_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> ASysResult<usize>;
@@ -24,7 +32,7 @@ pub trait ConcreteFile: Send + Sync + Sized + 'static {
     fn list(&self) -> ASysResult<Vec<(String, Self)>>;
     fn create<'a>(&'a self, name: &'a str, kind: VfsFileKind) -> ASysResult<Self>;
     fn rename<'a>(&'a self, file: &'a Self, new_name: &'a str) -> ASysResult;
-    fn detach<'a>(&'a self, file: &'a Self) -> ASysResult;
+    fn detach<'a>(&'a self, file: &'a Self, name: &'a str) -> ASysResult;
     fn attach<'a>(&'a self, file: &'a Self, name: &'a str) -> ASysResult;
 }
 
@@ -0,0 +1,131 @@
+//! 页缓存的后台写回
+//!
+//! 仿照 Linux 的 flusher: 一个内核任务每隔 dirty_writeback_centisecs 醒来一次,
+//! 把变脏超过 dirty_expire_centisecs 的页写回, 再把文件系统自己缓存的元数据 (FAT 表, 块缓存等) 刷到磁盘,
+//! 这样意外断电最多丢掉这么一段时间内写的数据.
+//!
+//! 脏页超过 dirty_background_ratio 时提前唤醒它, 并且不管新旧全部写回;
+//! 超过 dirty_ratio 时, 写文件的进程要先把这个文件的脏页写回才能返回, 让写得太快的进程慢下来.
+//! 比例都相对于可以用作页缓存的内存, 即空闲页框加上已有的页缓存.
+
+use super::{mount::GlobalMountManager, page_lru};
+use crate::{
+    executor, here,
+    memory::frame,
+    sync::{SpinNoIrqLock, WaitQueue},
+    timer::{get_time_ms, with_timeout},
+};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+/// 脏页占可用内存的百分比超过它时, 写文件的进程自己写回
+pub static DIRTY_RATIO: AtomicUsize = AtomicUsize::new(20);
+/// 脏页占可用内存的百分比超过它时, 唤醒后台写回
+pub static DIRTY_BACKGROUND_RATIO: AtomicUsize = AtomicUsize::new(10);
+/// 脏了多久的页算是旧的, 单位为百分之一秒
+pub static DIRTY_EXPIRE_CENTISECS: AtomicUsize = AtomicUsize::new(3000);
+/// 后台写回的周期, 单位为百分之一秒. 为 0 时只在脏页太多时写回
+pub static DIRTY_WRITEBACK_CENTISECS: AtomicUsize = AtomicUsize::new(500);
+/// 上面两个时间的上限, 同 Linux 中它们的类型 unsigned int.
+/// 换算成毫秒再加上当前时间也不会溢出
+pub const MAX_CENTISECS: usize = u32::MAX as usize;
+
+/// 页缓存中的脏页数
+static DIRTY_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+struct Flusher {
+    kicked: bool,
+    waiters: WaitQueue,
+}
+
+static FLUSHER: SpinNoIrqLock<Flusher> = SpinNoIrqLock::new(Flusher {
+    kicked: false,
+    waiters: WaitQueue::new(),
+});
+
+/// 页从干净变脏时调用
+pub fn page_dirtied() {
+    DIRTY_PAGES.fetch_add(1, Ordering::Relaxed);
+}
+/// 脏页被写回或丢掉时调用
+pub fn page_cleaned() {
+    DIRTY_PAGES.fetch_sub(1, Ordering::Relaxed);
+}
+pub fn dirty_pages() -> usize {
+    DIRTY_PAGES.load(Ordering::Relaxed)
+}
+
+fn over_ratio(ratio: &AtomicUsize) -> bool {
+    let available = frame::free_frames() + page_lru::cached_pages();
+    dirty_pages() * 100 > available * ratio.load(Ordering::Relaxed)
+}
+
+/// 写完页缓存之后调用. 脏页超过后台阈值时唤醒后台写回;
+/// 返回 true 表示超过了 dirty_ratio, 调用者应该自己把脏页写回
+pub fn balance_dirty_pages() -> bool {
+    if over_ratio(&DIRTY_BACKGROUND_RATIO) {
+        kick();
+    }
+    over_ratio(&DIRTY_RATIO)
+}
+
+/// 唤醒后台写回
+pub fn kick() {
+    let mut flusher = FLUSHER.lock(here!());
+    flusher.kicked = true;
+    flusher.waiters.wake_all();
+}
+
+struct KickFuture;
+
+impl Future for KickFuture {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut flusher = FLUSHER.lock(here!());
+        if flusher.kicked {
+            flusher.kicked = false;
+            Poll::Ready(())
+        } else {
+            flusher.waiters.register(cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+/// 启动后台写回
+pub fn init() {
+    let (r, t) = executor::spawn(flusher());
+    r.schedule();
+    t.detach();
+}
+
+async fn flusher() {
+    loop {
+        let interval = DIRTY_WRITEBACK_CENTISECS.load(Ordering::Relaxed) * 10;
+        if interval == 0 {
+            KickFuture.await;
+        } else {
+            with_timeout(interval, KickFuture).await;
+        }
+
+        // 脏页太多时全部写回, 否则只写旧的
+        let before = if over_ratio(&DIRTY_BACKGROUND_RATIO) {
+            usize::MAX
+        } else {
+            let expire = DIRTY_EXPIRE_CENTISECS.load(Ordering::Relaxed) * 10;
+            get_time_ms().saturating_sub(expire)
+        };
+        if let Err(e) = page_lru::write_back_expired(before).await {
+            log::warn!("writeback: failed to write back pages: {:?}", e);
+        }
+        for (path, fs) in GlobalMountManager::list() {
+            if let Err(e) = fs.sync_metadata().await {
+                log::warn!("writeback: failed to sync {:?}: {:?}", path, e);
+            }
+        }
+    }
+}
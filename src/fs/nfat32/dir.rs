@@ -31,11 +31,13 @@ impl From<VfsFileKind> for Fat32DEntryAttr {
         match kind {
             VfsFileKind::RegularFile => Fat32DEntryAttr::ARCHIVE,
             VfsFileKind::Directory => Fat32DEntryAttr::DIRECTORY,
+            VfsFileKind::SymbolLink => Fat32DEntryAttr::ARCHIVE | Fat32DEntryAttr::SYSTEM,
             _ => panic!("Unsupported VfsFileKind"),
         }
     }
 }
 
+/// 只看属性分不出符号链接, 带 SYSTEM 属性的文件还要检查内容, 见 `FATFile::SYMLINK_COOKIE`
 impl From<Fat32DEntryAttr> for VfsFileKind {
     fn from(val: Fat32DEntryAttr) -> Self {
         if val.contains(Fat32DEntryAttr::DIRECTORY) {
@@ -64,6 +66,24 @@ impl FatDEntryData<'_> {
             (name_len + 12) / 13
         }
     }
+
+    /// 这一项的 8.3 目录项, 名字放不下时由 LFN 存放, 8.3 中的名字填空格
+    pub(super) fn to_std(&self) -> Standard8p3EntryRepr {
+        let mut std: Standard8p3EntryRepr = unsafe { MaybeUninit::zeroed().assume_init() };
+        if self.lfn_needed() == 0 {
+            for (j, c) in self.name.chars().enumerate() {
+                std.name[j] = c as u8;
+            }
+        } else {
+            std.name.fill(b' ');
+        }
+        std.ext.fill(b' ');
+        std.attr = self.attr.bits();
+        std.cluster_high = (self.begin_cluster >> 16) as u16;
+        std.cluster_low = (self.begin_cluster & 0xFFFF) as u16;
+        std.size = self.size;
+        std
+    }
 }
 
 // 命名约定:
@@ -425,11 +445,13 @@ impl GroupDEntryIter<'_> {
     }
 
     pub(super) fn change_size(&mut self, new_size: u32) {
+        self.window.mark_dirty();
         unsafe { self.get_std_entry().as_std_mut().size = new_size }
     }
     pub(super) fn delete_entry(&mut self) {
         // 要将所有的 ADE 的第一个字节设置为 0xE5, 并且标记全部 dirty
         self.is_deleted = true;
+        self.window.mark_dirty();
         for atom_entry in self.atom_iter() {
             unsafe { atom_entry.as_std_mut().name[0] = 0xE5 };
         }
@@ -453,6 +475,7 @@ impl GroupDEntryIter<'_> {
     ) -> SysResult<()> {
         // 从当前窗口切一块出来存放新的 GDE
         debug_assert!(self.can_create(dentry));
+        self.window.mark_dirty();
 
         // 写入名字
         let name = dentry.name;
@@ -484,25 +507,7 @@ impl GroupDEntryIter<'_> {
 
         // 写入 8.3
         let std = unsafe { self.window.get_in_buf(lfn_needed).as_std_mut() };
-        if lfn_needed == 0 {
-            for (j, c) in name.chars().enumerate() {
-                std.name[j] = c as u8;
-            }
-        } else {
-            std.name.fill(b' ');
-        }
-        std.ext.fill(b' ');
-        std.attr = dentry.attr.bits();
-        std._reserved = 0;
-        std.ctime_ts = 0;
-        std.ctime = 0;
-        std.cdate = 0;
-        std.adate = 0;
-        std.cluster_high = (dentry.begin_cluster >> 16) as u16;
-        std.mtime = 0;
-        std.mdate = 0;
-        std.cluster_low = (dentry.begin_cluster & 0xFFFF) as u16;
-        std.size = dentry.size;
+        *std = dentry.to_std();
 
         // 更新窗口
         self.window.move_left(lfn_needed + 1).await
@@ -550,6 +555,7 @@ impl<'it> GroupDEntryIter<'it> {
         // write an empty GDE
         self.window.move_right_one(self.chain).await?;
         unsafe { self.window.last().mark_end() };
+        self.window.mark_dirty();
 
         // sync
         self.sync_all().await?;
@@ -615,6 +621,15 @@ impl<'a> AtomDEntryWindow {
         }
         Ok(())
     }
+    /// 窗口中的目录项是通过裸指针改的, 块缓存不知道, 所以要在修改时手动标记.
+    /// 窗口里只有当前 GDE 所在的几个扇区, 全部标记也不会多写多少
+    fn mark_dirty(&self) {
+        for sc in self.sector_bufs.iter() {
+            if let Some(data) = &sc.data {
+                data.mark_dirty();
+            }
+        }
+    }
     fn buf_idx(&self, pos: AtomDEPos) -> usize {
         (pos.as_byte_offset() - self.begin_pos.as_byte_offset()) / BLOCK_SIZE
     }
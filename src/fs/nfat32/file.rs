@@ -19,7 +19,7 @@ use crate::{
     },
 };
 use alloc::vec::Vec;
-use core::cell::SyncUnsafeCell;
+use core::{cell::SyncUnsafeCell, mem::size_of};
 
 pub(super) struct DEntryPosInfo {
     /// 整个 GroupDE 的位置
@@ -43,14 +43,14 @@ impl StdEntryEditor {
         }
     }
 
-    pub fn new_free(kind: VfsFileKind) -> Self {
+    pub fn new_free(std: Standard8p3EntryRepr) -> Self {
         Self {
             pos: SyncUnsafeCell::new(DEntryPosInfo {
                 gde_pos: GroupDEPos::null(),
                 sector: 0,
                 offset: 0,
             }),
-            std: WithDirty::new(Standard8p3EntryRepr::new_empty(kind)),
+            std: WithDirty::new(std),
         }
     }
 
@@ -101,14 +101,45 @@ pub struct FATFile {
     pub(super) fs: &'static Fat32FS,
     pub(super) editor: StdEntryEditor,
     pub(super) chain: ClusterChain,
+    /// 符号链接要读过内容才能确定, 所以在构造时就定下来
+    pub(super) kind: VfsFileKind,
 }
 
 impl FATFile {
+    /// 同 Cygwin 的旧式符号链接: 带 SYSTEM 属性的文件, 内容是这个 cookie 后面跟着链接目标.
+    /// cookie 对上层隐藏, 上层读写的就是链接目标本身
+    const SYMLINK_COOKIE: &'static [u8] = b"!<symlink>";
+
     pub fn new_free(fs: &'static Fat32FS, begin_cluster: ClusterID, kind: VfsFileKind) -> Self {
         Self {
             fs,
-            editor: StdEntryEditor::new_free(kind),
+            editor: StdEntryEditor::new_free(Standard8p3EntryRepr::new_empty(kind)),
             chain: ClusterChain::new(fs, begin_cluster),
+            kind,
+        }
+    }
+
+    /// 确定磁盘上已有文件的类型. Windows 也会给普通文件加 SYSTEM 属性,
+    /// 所以同 Cygwin, 只有内容以 cookie 开头时才是符号链接
+    async fn probe_kind(
+        fs: &'static Fat32FS,
+        std: &Standard8p3EntryRepr,
+        begin_cluster: ClusterID,
+    ) -> SysResult<VfsFileKind> {
+        let attr = std.attr();
+        let kind = VfsFileKind::from(attr);
+        if kind != VfsFileKind::RegularFile
+            || !attr.contains(Fat32DEntryAttr::SYSTEM)
+            || (std.size as usize) < Self::SYMLINK_COOKIE.len()
+        {
+            return Ok(kind);
+        }
+        let mut buf = [0u8; BLOCK_SIZE];
+        fs.read_sector(fs.first_sector(begin_cluster), &mut buf).await?;
+        if buf.starts_with(Self::SYMLINK_COOKIE) {
+            Ok(VfsFileKind::SymbolLink)
+        } else {
+            Ok(VfsFileKind::RegularFile)
         }
     }
 
@@ -117,11 +148,11 @@ impl FATFile {
         // 递归的 async 函数必须 Box
         dyn_future(async {
             match self.attr_kind() {
-                VfsFileKind::RegularFile => self.delete_self(),
+                VfsFileKind::RegularFile | VfsFileKind::SymbolLink => self.delete_self(),
                 VfsFileKind::Directory => {
                     let mut it = self.gde_iter();
                     while it.mark_next().await?.is_some() {
-                        let file = self.into_file(&it);
+                        let file = self.into_file(&it).await?;
                         file.delete_recursive().await?;
                         it.leave_next().await?;
                     }
@@ -183,23 +214,64 @@ impl FATFile {
         StdEntryEditor::new_normal(self.into_de_pos(it), it.std_clone())
     }
 
-    fn into_file(&self, it: &GroupDEntryIter) -> Self {
+    async fn into_file(&self, it: &GroupDEntryIter) -> SysResult<Self> {
         let editor = self.into_de_editor(it);
         let begin_cluster = it.get_begin_cluster();
+        let kind = Self::probe_kind(self.fs, editor.std(), begin_cluster).await?;
         let chain = ClusterChain::new(self.fs, begin_cluster);
-        Self {
+        Ok(Self {
             fs: self.fs,
             editor,
             chain,
+            kind,
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.editor.std().size as usize
+    }
+
+    /// 上层看到的内容在磁盘上的起始偏移
+    fn data_offset(&self) -> usize {
+        if self.attr_kind() == VfsFileKind::SymbolLink {
+            Self::SYMLINK_COOKIE.len()
+        } else {
+            0
         }
     }
 
-    async fn sync_metadata(&self) {
-        self.editor.sync(self.fs).await.unwrap();
+    /// 符号链接的内容因为 cookie 不再按扇区对齐, 好在链接都很短, 逐个扇区拷贝即可
+    async fn read_link_at(&self, offset: usize, buf: &mut [u8]) -> SysResult<usize> {
+        let start = self.data_offset() + offset;
+        let end = self.size().min(start + buf.len());
+        let mut sector = [0u8; BLOCK_SIZE];
+        let mut pos = start;
+        while pos < end {
+            let (sid, sct_off) = self.chain.offset_sct(self.fs, pos);
+            let sct_off = sct_off as usize;
+            let len = (BLOCK_SIZE - sct_off).min(end - pos);
+            self.fs.read_sector(sid, &mut sector).await?;
+            buf[pos - start..][..len].copy_from_slice(&sector[sct_off..][..len]);
+            pos += len;
+        }
+        Ok(end.saturating_sub(start))
     }
 
-    fn size(&self) -> usize {
-        self.editor.std().size as usize
+    async fn write_link_at(&self, offset: usize, buf: &[u8]) -> SysResult<usize> {
+        let start = self.data_offset() + offset;
+        let end = self.size().min(start + buf.len());
+        let mut sector = [0u8; BLOCK_SIZE];
+        let mut pos = start;
+        while pos < end {
+            let (sid, sct_off) = self.chain.offset_sct(self.fs, pos);
+            let sct_off = sct_off as usize;
+            let len = (BLOCK_SIZE - sct_off).min(end - pos);
+            self.fs.read_sector(sid, &mut sector).await?;
+            sector[sct_off..][..len].copy_from_slice(&buf[pos - start..][..len]);
+            self.fs.write_sector(sid, &sector).await?;
+            pos += len;
+        }
+        Ok(end.saturating_sub(start))
     }
 
     const EMPTY_BLOCK: [u8; BLOCK_SIZE] = [0u8; BLOCK_SIZE];
@@ -247,12 +319,7 @@ impl FATFile {
 
 impl ConcreteFile for FATFile {
     fn attr_kind(&self) -> VfsFileKind {
-        let kind = self.editor.std().attr();
-        if kind.contains(Fat32DEntryAttr::DIRECTORY) {
-            VfsFileKind::Directory
-        } else {
-            VfsFileKind::RegularFile
-        }
+        self.kind
     }
     fn attr_device(&self) -> DeviceInfo {
         DeviceInfo {
@@ -263,7 +330,7 @@ impl ConcreteFile for FATFile {
     fn attr_size(&self) -> ASysResult<SizeInfo> {
         dyn_future(async move {
             Ok(SizeInfo {
-                bytes: self.size(),
+                bytes: self.size().saturating_sub(self.data_offset()),
                 blocks: self.chain.len() * (self.fs.cluster_size_sct as usize),
             })
         })
@@ -278,6 +345,23 @@ impl ConcreteFile for FATFile {
             })
         })
     }
+    fn attr_nlink(&self) -> ASysResult<usize> {
+        // FAT 没有硬链接, 目录项就是文件本身. 根目录没有目录项, 其他文件 detach 之后就没有名字了
+        dyn_future(async move {
+            let unlinked = self.editor.is_free() && self.chain.first() != self.fs.root_id_cls;
+            Ok(if unlinked { 0 } else { 1 })
+        })
+    }
+    fn attr_ino(&self) -> usize {
+        // FAT 没有 inode, 同 Linux vfat 的 i_pos, 用目录项在磁盘上的位置代替.
+        // 空文件的起始簇号都是 0, 不能用. 只有没有目录项的根目录用它的起始簇号
+        if self.editor.is_free() {
+            return self.chain.first() as usize;
+        }
+        let per_sector = BLOCK_SIZE / size_of::<Standard8p3EntryRepr>();
+        self.editor.sector() as usize * per_sector
+            + self.editor.offset() as usize / size_of::<Standard8p3EntryRepr>()
+    }
     fn update_time(&self, _info: TimeInfoChange) -> ASysResult {
         todo!()
     }
@@ -287,7 +371,11 @@ impl ConcreteFile for FATFile {
         // 如果小, 则调整 chain, 把多出来的块还给 fs
         // 如果大, 则向 fs 要新的块并更新 chain
         dyn_future(async move {
-            let new_size_cls = new_size >> (self.fs.log_cls_size_sct as usize + LOG2_BLOCK_SIZE);
+            self.fs.check_writable()?;
+            let new_size = new_size + self.data_offset();
+            // 向上取整, 并且至少保留一个 cluster, 目录项中的起始簇号一直指向它
+            let lcsb = self.fs.log_cls_size_sct as usize + LOG2_BLOCK_SIZE;
+            let new_size_cls = ((new_size + (1 << lcsb) - 1) >> lcsb).max(1);
             let old_size_cls = self.chain.len();
 
             // alloc/free cluster
@@ -309,10 +397,17 @@ impl ConcreteFile for FATFile {
 
     fn delete(&self) -> ASysResult {
         dyn_future(async move {
+            self.fs.check_writable()?;
             self.delete_self()?;
             Ok(())
         })
     }
+    fn sync_metadata(&self) -> ASysResult {
+        dyn_future(self.editor.sync(self.fs))
+    }
+    fn sync_fs(&self) -> ASysResult {
+        dyn_future(self.fs.sync())
+    }
 
     fn read_page_at<'a>(&'a self, offset: usize, buf: &'a mut [u8]) -> ASysResult<usize> {
         // 假设 VFS 上层都是按页读取的, 那么这意味着 offset 一定是 sector 对齐的,
@@ -321,6 +416,9 @@ impl ConcreteFile for FATFile {
         debug_assert!(buf.len() % BLOCK_SIZE == 0);
 
         dyn_future(async move {
+            if self.attr_kind() == VfsFileKind::SymbolLink {
+                return self.read_link_at(offset, buf).await;
+            }
             if offset >= self.size() {
                 return Ok(0);
             }
@@ -358,6 +456,10 @@ impl ConcreteFile for FATFile {
         debug_assert!(offset % BLOCK_SIZE == 0);
         debug_assert!(buf.len() % BLOCK_SIZE == 0);
         dyn_future(async move {
+            self.fs.check_writable()?;
+            if self.attr_kind() == VfsFileKind::SymbolLink {
+                return self.write_link_at(offset, buf).await;
+            }
             if offset >= self.size() {
                 return Ok(0);
             }
@@ -396,7 +498,7 @@ impl ConcreteFile for FATFile {
             let mut it = self.gde_iter();
             while it.mark_next().await?.is_some() {
                 if it.collect_name() == name {
-                    return Ok(self.into_file(&it));
+                    return self.into_file(&it).await;
                 }
                 it.leave_next().await?;
             }
@@ -412,7 +514,7 @@ impl ConcreteFile for FATFile {
             while it.mark_next().await?.is_some() {
                 log::debug!("list: {:?}", it.gde_pos());
                 let name = it.collect_name();
-                let file = self.into_file(&it);
+                let file = self.into_file(&it).await?;
                 res.push((name, file));
                 it.leave_next().await?;
             }
@@ -424,17 +526,27 @@ impl ConcreteFile for FATFile {
     fn create<'a>(&'a self, name: &'a str, kind: VfsFileKind) -> ASysResult<Self> {
         // 先向 fs 申请新创文件, 然后 attach 上去
         dyn_future(async move {
+            self.fs.check_writable()?;
             let begin_cluster = self.fs.with_fat(|f| f.alloc());
+            let mut size = 0;
+            if kind == VfsFileKind::SymbolLink {
+                // 新的链接目标为空, 只有 cookie
+                let mut buf = [0u8; BLOCK_SIZE];
+                buf[..Self::SYMLINK_COOKIE.len()].copy_from_slice(Self::SYMLINK_COOKIE);
+                self.fs.write_sector(self.fs.first_sector(begin_cluster), &buf).await?;
+                size = Self::SYMLINK_COOKIE.len() as u32;
+            }
             let data = FatDEntryData {
                 name,
                 attr: kind.into(),
                 begin_cluster,
-                size: 0,
+                size,
             };
             let file = FATFile {
                 fs: self.fs,
-                editor: StdEntryEditor::new_free(kind),
+                editor: StdEntryEditor::new_free(data.to_std()),
                 chain: ClusterChain::new(self.fs, begin_cluster),
+                kind,
             };
             self.attach_impl(&data, &file).await?;
             Ok(file)
@@ -444,6 +556,7 @@ impl ConcreteFile for FATFile {
     fn rename<'a>(&'a self, file: &'a Self, new_name: &'a str) -> ASysResult {
         // detach, 然后 attach
         dyn_future(async move {
+            self.fs.check_writable()?;
             let mut it = self.detach_impl(file).await?;
             let data = FatDEntryData {
                 attr: file.editor.std().attr(),
@@ -465,6 +578,11 @@ impl ConcreteFile for FATFile {
 
     fn attach<'a>(&'a self, file: &'a Self, name: &'a str) -> ASysResult {
         dyn_future(async move {
+            self.fs.check_writable()?;
+            // 还挂在某个目录中, 说明是要建立硬链接, FAT 不支持
+            if !file.editor.is_free() {
+                return Err(SysError::EPERM);
+            }
             let data = FatDEntryData {
                 attr: file.editor.std().attr(),
                 begin_cluster: file.chain.first(),
@@ -476,9 +594,10 @@ impl ConcreteFile for FATFile {
         })
     }
 
-    fn detach<'a>(&'a self, file: &'a Self) -> ASysResult {
+    fn detach<'a>(&'a self, file: &'a Self, _name: &'a str) -> ASysResult {
         // file 中包含 GDEPos, 所以可以直接定位到具体的 Sector, 使用 GDEIter 写入之即可
         dyn_future(async move {
+            self.fs.check_writable()?;
             let mut it = self.detach_impl(file).await?;
             it.leave_next().await?;
             Ok(())
@@ -489,6 +608,6 @@ impl ConcreteFile for FATFile {
 impl Drop for FATFile {
     fn drop(&mut self) {
         // TODO: 也许可以用 spawn 直接把它加入到调度器就完事了?
-        block_on(self.sync_metadata());
+        block_on(self.editor.sync(self.fs)).unwrap();
     }
 }
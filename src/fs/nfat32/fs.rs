@@ -3,14 +3,20 @@ use super::{super::disk::BLOCK_SIZE, BlockID, ClusterID, FATFile, SectorID};
 use crate::fs::nfat32::tools::cvt_err;
 
 use crate::{
-    consts::PAGE_SIZE, drivers::AsyncBlockDevice, fs::nfat32::parse, here, sync::SpinNoIrqLock,
-    tools::errors::SysResult,
+    consts::PAGE_SIZE,
+    drivers::AsyncBlockDevice,
+    fs::nfat32::parse,
+    here,
+    sync::SpinNoIrqLock,
+    tools::errors::{dyn_future, ASysResult, SysError, SysResult},
 };
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, collections::BTreeSet, sync::Arc, vec::Vec};
 
+use crate::fs::new_vfs::page_lru;
 use crate::fs::new_vfs::path_cache::PathCacheDir;
 use crate::fs::new_vfs::sync_attr_file::SyncAttrFile;
 use crate::fs::new_vfs::top::{VfsFS, VfsFSAttr, VfsFSKind, VfsFileRef, NORMAL_FILE_NAME_LENGTH};
+use crate::fs::new_vfs::DeviceIDCollection;
 
 use core::pin::Pin;
 use core::slice;
@@ -23,6 +29,7 @@ pub struct Fat32FS {
 
     // FS Info
     device_id: usize,
+    read_only: bool,
     pub(super) cluster_size_byte: u32,
     pub(super) cluster_size_sct: u32,
     /// log2(cluster_size_sct), 用于便利地计算 SID -> CID
@@ -47,7 +54,7 @@ fn int_log2(x: u8) -> u8 {
 
 // abbr: _sct: sector, _byte: byte, _clu: cluster, _cnt: element count (mostly item = dentry)
 impl Fat32FS {
-    pub async fn new(blk_dev: BlkDevRef) -> SysResult<Self> {
+    pub async fn new(blk_dev: BlkDevRef, read_only: bool) -> SysResult<Self> {
         let mut boot_record: [u8; BLOCK_SIZE] = [0; BLOCK_SIZE];
         blk_dev.read_block(0, &mut boot_record).await.map_err(cvt_err)?;
 
@@ -55,11 +62,22 @@ impl Fat32FS {
         let cluster_size_sct = parse!(u8, boot_record, 0x0D);
         let cluster_size_byte = (cluster_size_sct as u16) * sector_size_byte;
 
+        // 不是 FAT32 (或者损坏了) 时返回 EINVAL, 而不是 panic, 因为 mount 会用它来试探
+        if boot_record[510..512] != [0x55, 0xAA] {
+            log::warn!("FAT32: bad boot sector signature");
+            return Err(SysError::EINVAL);
+        }
         if sector_size_byte as usize != BLOCK_SIZE {
-            panic!("FAT32: byte per sector is not 512");
+            log::warn!("FAT32: byte per sector is not 512");
+            return Err(SysError::EINVAL);
+        }
+        if !cluster_size_sct.is_power_of_two() {
+            log::warn!("FAT32: invalid sectors per cluster: {}", cluster_size_sct);
+            return Err(SysError::EINVAL);
         }
         if cluster_size_byte as usize > PAGE_SIZE {
-            panic!("FAT32: cluster size is too large (> PAGE_SIZE)");
+            log::warn!("FAT32: cluster size is too large (> PAGE_SIZE)");
+            return Err(SysError::EINVAL);
         }
 
         // 用于便利地计算 SID -> CID
@@ -93,6 +111,19 @@ impl Fat32FS {
         log::debug!("FAT32 EBPB: root dir cluster id: {}", root_id_cls);
         log::debug!("FAT32 EBPB: volume id: {}", volume_id);
 
+        // FAT12/16 的根目录不在簇中, 每个扇区数为 0 说明不是 FAT32
+        if fat_cnt == 0
+            || fat_size_sct == 0
+            || root_dentry_cnt != 0
+            || root_id_cls < 2
+            || reserved_size_sct as u64 + fat_cnt as u64 * fat_size_sct as u64
+                >= volume_size_sct as u64
+            || volume_size_sct as u64 > blk_dev.num_blocks()
+        {
+            log::warn!("FAT32: invalid BPB");
+            return Err(SysError::EINVAL);
+        }
+
         // calculate fat table begin
         let first_fat_begin_sct = reserved_size_sct as SectorID;
 
@@ -106,7 +137,7 @@ impl Fat32FS {
 
         let main_fat_size_byte = (fat_size_sct as usize) * (sector_size_byte as usize);
         let mut main_fat =
-            unsafe { Box::<[u32]>::new_uninit_slice(main_fat_size_byte).assume_init() };
+            unsafe { Box::<[u32]>::new_uninit_slice(main_fat_size_byte / 4).assume_init() };
         for i in 0..fat_size_sct {
             let blk_offset = first_fat_begin_sct + i as BlockID;
             // 4 for 4*u8 == u32
@@ -124,6 +155,7 @@ impl Fat32FS {
         let fat_table_mgr = FATTableManager {
             fat_begins,
             fat: main_fat,
+            dirty: BTreeSet::new(),
         };
         // fat_table_mgr.debug_print_all_used_cluster();
 
@@ -145,7 +177,8 @@ impl Fat32FS {
             fat_table_mgr: SpinNoIrqLock::new(fat_table_mgr),
 
             // FS Info
-            device_id: 114514, // TODO: get device id
+            device_id: DeviceIDCollection::alloc(),
+            read_only,
             cluster_size_sct: cluster_size_sct as u32,
             log_cls_size_sct,
             cluster_size_byte: cluster_size_byte as u32,
@@ -163,6 +196,15 @@ impl Fat32FS {
         self.device_id
     }
 
+    /// 只读挂载时修改文件系统的操作都返回 EROFS
+    pub(super) fn check_writable(&self) -> SysResult {
+        if self.read_only {
+            Err(SysError::EROFS)
+        } else {
+            Ok(())
+        }
+    }
+
     pub(super) fn first_sector(&self, cluster_id: ClusterID) -> SectorID {
         // this formula can be cross verified with Self::next_sector,
         // and it's copied from https://wiki.osdev.org/FAT
@@ -217,17 +259,59 @@ impl Fat32FS {
     pub(super) fn block_dev(&self) -> &CachedBlkDev {
         &self.block_dev
     }
+
+    /// 把内存中的 FAT 表和块缓存写回磁盘.
+    /// 调用者应该先把文件的目录项写进块缓存 (StdEntryEditor::sync)
+    pub(super) async fn sync(&self) -> SysResult {
+        let (fat_begins, dirty) = self.with_fat(|f| (f.fat_begins.clone(), f.take_dirty()));
+        for (idx, sector) in dirty.iter() {
+            // 每一份 FAT 表都要写
+            for &begin in fat_begins.iter() {
+                if let Err(e) = self.block_dev.write_noc(begin + *idx as SectorID, sector).await {
+                    // 写失败了就重新标记, 下次再试
+                    self.with_fat(|f| f.dirty.extend(dirty.iter().map(|(idx, _)| *idx)));
+                    return Err(e);
+                }
+            }
+        }
+        self.block_dev.sync().await
+    }
 }
 
 pub(super) struct FATTableManager {
     fat_begins: Vec<SectorID>,
     fat: Box<[u32]>,
+    /// 修改过的 FAT 扇区, 是 FAT 表内的扇区号
+    dirty: BTreeSet<usize>,
 }
 
+/// 每个扇区中的 FAT 项数
+const FAT_ENTRY_PER_SECTOR: usize = BLOCK_SIZE / 4;
+
 impl FATTableManager {
     fn get_fat(&self, cid: ClusterID) -> u32 {
         self.fat[cid as usize]
     }
+    fn set_fat(&mut self, cid: ClusterID, value: u32) {
+        self.fat[cid as usize] = value;
+        self.dirty.insert(cid as usize / FAT_ENTRY_PER_SECTOR);
+    }
+
+    /// 取出所有修改过的扇区的内容, 并清除标记
+    fn take_dirty(&mut self) -> Vec<(usize, [u8; BLOCK_SIZE])> {
+        let dirty = core::mem::take(&mut self.dirty);
+        dirty
+            .into_iter()
+            .map(|idx| {
+                let mut sector = [0; BLOCK_SIZE];
+                let entries = &self.fat[idx * FAT_ENTRY_PER_SECTOR..][..FAT_ENTRY_PER_SECTOR];
+                for (dst, entry) in sector.chunks_exact_mut(4).zip(entries) {
+                    dst.copy_from_slice(&entry.to_le_bytes());
+                }
+                (idx, sector)
+            })
+            .collect()
+    }
 
     pub fn cluster_count(&self) -> usize {
         self.fat.len()
@@ -275,11 +359,11 @@ impl FATTableManager {
 
     pub fn alloc(&mut self) -> ClusterID {
         let cid = self.find_first_free();
-        self.fat[cid as usize] = 0x0FFFFFFF;
+        self.set_fat(cid, 0x0FFFFFFF);
         cid
     }
     pub fn free(&mut self, cid: ClusterID) {
-        self.fat[cid as usize] = 0;
+        self.set_fat(cid, 0);
     }
 
     pub fn alloc_next(&mut self, cid: ClusterID) -> ClusterID {
@@ -288,7 +372,7 @@ impl FATTableManager {
         next_cid
     }
     pub fn set_next(&mut self, cid: ClusterID, next_cid: ClusterID) {
-        self.fat[cid as usize] = next_cid;
+        self.set_fat(cid, next_cid);
     }
 
     pub fn next(&self, cid: ClusterID) -> Option<ClusterID> {
@@ -325,8 +409,8 @@ pub struct FatFSWrapper {
 }
 
 impl FatFSWrapper {
-    pub async fn new(blk_dev: BlkDevRef) -> SysResult<Self> {
-        Fat32FS::new(blk_dev).await.map(Box::pin).map(|fs| Self { fs })
+    pub async fn new(blk_dev: BlkDevRef, read_only: bool) -> SysResult<Self> {
+        Fat32FS::new(blk_dev, read_only).await.map(Box::pin).map(|fs| Self { fs })
     }
 
     pub fn get(&self) -> &'static Fat32FS {
@@ -362,4 +446,16 @@ impl VfsFS for FatFSWrapper {
             max_file_name_length: NORMAL_FILE_NAME_LENGTH,
         }
     }
+
+    fn sync(&self) -> ASysResult {
+        dyn_future(async move {
+            // 页缓存写回时会顺便把目录项写进块缓存, 最后再一起刷到磁盘
+            page_lru::write_back_device(self.get().device_id()).await?;
+            self.get().sync().await
+        })
+    }
+
+    fn sync_metadata(&self) -> ASysResult {
+        dyn_future(self.get().sync())
+    }
 }
@@ -10,6 +10,7 @@ pub use dir::FatDEntryData;
 pub use file::FATFile;
 pub use fs::BlkDevRef;
 pub use fs::FatFSWrapper;
+pub(crate) use tools::cvt_err;
 
 // https://wiki.osdev.org/FAT
 
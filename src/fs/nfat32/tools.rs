@@ -12,7 +12,7 @@ use core::{
     sync::atomic::{AtomicBool, AtomicUsize},
 };
 
-pub(super) fn cvt_err(dev_err: DevError) -> SysError {
+pub(crate) fn cvt_err(dev_err: DevError) -> SysError {
     match dev_err {
         DevError::AlreadyExists => SysError::EEXIST,
         DevError::Again => SysError::EAGAIN,
@@ -69,11 +69,40 @@ impl CachedBlkDev {
         Ok(())
     }
 
+    /// 把所有脏块写回磁盘
+    pub async fn sync(&self) -> SysResult {
+        let dirty: Vec<_> = {
+            let cache = self.cache.lock(here!());
+            let dirty = cache.iter().filter(|(_, entry)| entry.is_dirty());
+            dirty.map(|(&id, entry)| (id, entry.clone())).collect()
+        };
+        let blk_dev = self.blk_dev.lock().await;
+        for (id, entry) in dirty {
+            // 先清除标记再写, 写的过程中又被改了的话会重新变脏, 下次再写
+            entry.clear_dirty();
+            if let Err(e) = blk_dev.write_block(id, entry.as_slice()).await {
+                entry.mark_dirty();
+                return Err(cvt_err(e));
+            }
+        }
+        Ok(())
+    }
+
+    /// 绕过缓存直接写了某块之后, 缓存中的内容就过期了.
+    /// 这只会发生在目录被删除, 它的簇又分给了普通文件的时候, 所以直接丢掉就好
+    fn invalidate(&self, id: BlockID, n: usize) {
+        let mut cache = self.cache.lock(here!());
+        for id in id..id + n as BlockID {
+            cache.remove(&id);
+        }
+    }
+
     pub async fn read_noc(&self, id: BlockID, buf: &mut [u8]) -> SysResult {
         let blk_dev = self.blk_dev.lock().await;
         blk_dev.read_block(id, buf).await.map_err(cvt_err)
     }
     pub async fn write_noc(&self, id: BlockID, buf: &[u8]) -> SysResult {
+        self.invalidate(id, 1);
         let blk_dev = self.blk_dev.lock().await;
         blk_dev.write_block(id, buf).await.map_err(cvt_err)
     }
@@ -87,6 +116,7 @@ impl CachedBlkDev {
         Ok(())
     }
     pub async fn write_noc_multi(&self, id: BlockID, n: usize, buf: &[u8]) -> SysResult {
+        self.invalidate(id, n);
         let blk_dev = self.blk_dev.lock().await;
         for i in 0..n {
             let buf = &buf[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE];
@@ -132,6 +162,12 @@ impl BlockCacheEntry {
     pub fn is_dirty(&self) -> bool {
         self.dirty.load(core::sync::atomic::Ordering::Relaxed)
     }
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, core::sync::atomic::Ordering::Relaxed);
+    }
+    fn clear_dirty(&self) {
+        self.dirty.store(false, core::sync::atomic::Ordering::Relaxed);
+    }
     pub fn use_cnt(&self) -> usize {
         self.use_cnt.load(core::sync::atomic::Ordering::Relaxed)
     }
@@ -1,51 +1,173 @@
-use alloc::sync::Arc;
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
 
-use crate::{here, sync::SpinNoIrqLock};
+use crate::drivers::{
+    ADevResult, AsyncBlockDevice, BlockDevice, CharDevice, DevError, Device, DeviceType,
+};
+use crate::tools::errors::{dyn_future, SysError, SysResult};
+use log::{info, warn};
 
-use super::disk::Disk;
-use crate::drivers::DevResult;
-use log::info;
+use super::{gpt, nfat32::cvt_err};
 
+/// 分区表中的一项
 #[derive(Debug, Clone)]
+pub struct PartitionInfo {
+    /// 分区号, 从 1 开始
+    pub no: usize,
+    /// 起始块号
+    pub start: u64,
+    /// 块数
+    pub blocks: u64,
+    /// 分区的 GUID (PARTUUID), 小写. MBR 分区按 Linux 的习惯用 "磁盘签名-分区号"
+    pub uuid: Option<String>,
+    /// GPT 分区的名字 (PARTLABEL)
+    pub label: Option<String>,
+}
+
+/// 磁盘上的一个分区. 分区本身也是一个块设备, 块号加上分区的起始块号后交给所在的磁盘,
+/// 这样文件系统可以直接挂载在分区上
+#[derive(Debug)]
 pub struct Partition {
-    offset: u64,
-    size: u64,
-    pos: u64,
-    disk: Arc<SpinNoIrqLock<Disk>>,
+    name: String,
+    info: PartitionInfo,
+    disk: Arc<dyn AsyncBlockDevice>,
 }
 
 impl Partition {
-    pub fn new(offset: u64, size: u64, disk: Arc<SpinNoIrqLock<Disk>>) -> Self {
+    pub fn new(name: String, info: PartitionInfo, disk: Arc<dyn AsyncBlockDevice>) -> Self {
+        let size = info.blocks * disk.block_size() as u64;
         let humain_size = humansize::SizeFormatter::new(size, humansize::BINARY);
-        info!("Partition: offset: 0x{:x}, size: {}", offset, humain_size);
-        Self {
-            offset,
-            size,
-            pos: 0,
-            disk,
+        info!(
+            "Partition {}: start block: {:#x}, size: {}, uuid: {:?}, label: {:?}",
+            name, info.start, humain_size, info.uuid, info.label
+        );
+        Self { name, info, disk }
+    }
+    pub fn info(&self) -> &PartitionInfo {
+        &self.info
+    }
+}
+
+impl Device for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn mmio_base(&self) -> usize {
+        self.disk.mmio_base()
+    }
+    fn mmio_size(&self) -> usize {
+        self.disk.mmio_size()
+    }
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Block
+    }
+    fn interrupt_number(&self) -> Option<usize> {
+        // 中断由所在的磁盘处理
+        None
+    }
+    fn interrupt_handler(&self) {
+        panic!();
+    }
+    fn init(&self) {}
+
+    fn as_blk(self: Arc<Self>) -> Option<Arc<dyn BlockDevice>> {
+        None
+    }
+    fn as_char(self: Arc<Self>) -> Option<Arc<dyn CharDevice>> {
+        None
+    }
+    fn as_async_blk(self: Arc<Self>) -> Option<Arc<dyn AsyncBlockDevice>> {
+        Some(self)
+    }
+}
+
+impl AsyncBlockDevice for Partition {
+    fn num_blocks(&self) -> u64 {
+        self.info.blocks
+    }
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+    fn read_block(&self, block_id: u64, buf: &mut [u8]) -> ADevResult {
+        if block_id >= self.info.blocks {
+            return dyn_future(async { Err(DevError::InvalidParam) });
         }
+        self.disk.read_block(self.info.start + block_id, buf)
     }
-    pub fn size(&self) -> u64 {
-        self.size
+    fn write_block(&self, block_id: u64, buf: &[u8]) -> ADevResult {
+        if block_id >= self.info.blocks {
+            return dyn_future(async { Err(DevError::InvalidParam) });
+        }
+        self.disk.write_block(self.info.start + block_id, buf)
     }
-    pub fn read_one(&mut self, buf: &mut [u8]) -> DevResult<usize> {
-        let disk = self.disk.lock(here!());
-        disk.set_position(self.pos + self.offset);
-        let result = disk.read_one(buf);
-        self.pos = disk.position() - self.offset;
-        result
+    fn flush(&self) -> ADevResult {
+        self.disk.flush()
     }
-    pub fn write_one(&mut self, buf: &[u8]) -> DevResult<usize> {
-        let disk = self.disk.lock(here!());
-        disk.set_position(self.pos + self.offset);
-        let result = disk.write_one(buf);
-        self.pos = disk.position() - self.offset;
-        result
+}
+
+/// MBR 分区表的位置和每一项的大小
+const MBR_TABLE_OFFSET: usize = 0x1BE;
+const MBR_ENTRY_SIZE: usize = 16;
+/// 扩展分区只是逻辑分区的容器, 0xEE 则是 GPT 的保护分区
+const MBR_TYPE_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+/// MBR 中磁盘签名的位置
+const MBR_DISK_SIGNATURE_OFFSET: usize = 0x1B8;
+
+/// 读取磁盘的分区表. 有 GPT 保护分区时按 GPT 解析, GPT 无效或者没有保护分区时按 MBR 解析.
+/// 没有分区表时返回空
+pub async fn read_partitions(disk: &Arc<dyn AsyncBlockDevice>) -> SysResult<Vec<PartitionInfo>> {
+    let mut sector = vec![0; disk.block_size()];
+    disk.read_block(0, &mut sector).await.map_err(cvt_err)?;
+    let mbr = match mbr_nostd::MasterBootRecord::from_bytes(&sector) {
+        Ok(mbr) => mbr,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let raw_entry = |i: usize| &sector[MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE..];
+    // 和 Linux 一样, 引导标志不是 0 或 0x80 时认为这是不带分区表的文件系统 (比如 FAT) 的引导扇区
+    if (0..mbr.entries.len()).any(|i| !matches!(raw_entry(i)[0], 0x00 | 0x80)) {
+        return Ok(Vec::new());
     }
-    pub fn position(&self) -> u64 {
-        self.pos
+    if (0..mbr.entries.len()).any(|i| raw_entry(i)[4] == MBR_TYPE_GPT_PROTECTIVE) {
+        match gpt::read_gpt(disk).await {
+            Err(SysError::EINVAL) => warn!("{}: no valid GPT, falling back to MBR", disk.name()),
+            ret => return ret,
+        }
     }
-    pub fn set_position(&mut self, pos: u64) {
-        self.pos = pos;
+
+    let signature = u32::from_le_bytes(
+        sector[MBR_DISK_SIGNATURE_OFFSET..MBR_DISK_SIGNATURE_OFFSET + 4]
+            .try_into()
+            .unwrap(),
+    );
+    let mut ret = Vec::new();
+    for (i, entry) in mbr.entries.iter().enumerate() {
+        let kind = raw_entry(i)[4];
+        // 逻辑分区暂不支持
+        if matches!(entry.partition_type, mbr_nostd::PartitionType::Unused)
+            || MBR_TYPE_EXTENDED.contains(&kind)
+            || kind == MBR_TYPE_GPT_PROTECTIVE
+            || entry.sector_count == 0
+        {
+            continue;
+        }
+        let start = entry.logical_block_address as u64;
+        let blocks = entry.sector_count as u64;
+        if start + blocks > disk.num_blocks() {
+            warn!(
+                "{}: partition {} exceeds the end of disk, ignored",
+                disk.name(),
+                i + 1
+            );
+            continue;
+        }
+        ret.push(PartitionInfo {
+            no: i + 1,
+            start,
+            blocks,
+            uuid: Some(format!("{:08x}-{:02x}", signature, i + 1)),
+            label: None,
+        });
     }
+    Ok(ret)
 }
@@ -1,24 +1,29 @@
 use super::new_vfs::{
     mount::GlobalMountManager,
+    page_lru,
     top::{
         DeviceInfo, MmapKind, PollKind, SizeInfo, TimeInfo, TimeInfoChange, VfsFS, VfsFSAttr,
         VfsFSKind, VfsFile, VfsFileRef,
     },
-    DeviceIDCollection, VfsFileAttr, VfsFileKind,
+    writeback, DeviceIDCollection, VfsFileAttr, VfsFileKind,
 };
 use crate::{
+    consts::PAGE_SIZE,
     executor::hart_local::get_curr_lproc,
     impl_vfs_default_non_dir, impl_vfs_default_non_file,
-    memory::address::PhysAddr4K,
-    process::{lproc::LightProcess, lproc_mgr::GlobalLProcManager, pid::Pid},
-    tools::errors::{dyn_future, ASysResult, SysError},
+    memory::{address::PhysAddr4K, frame, heap, ksm, slab, swap},
+    process::{lproc::LightProcess, lproc_mgr::GlobalLProcManager, oom, pid::Pid},
+    tools::errors::{dyn_future, ASysResult, SysError, SysResult},
 };
 use alloc::{
     boxed::Box,
+    format,
     string::{String, ToString},
     sync::Arc,
+    vec,
     vec::Vec,
 };
+use core::sync::atomic::Ordering;
 
 pub mod interrupts;
 
@@ -112,6 +117,7 @@ impl ProcFSRootDir {
                     // TODO: real device path
                     let device_path = match kind {
                         VfsFSKind::Fat => "/dev/sda",
+                        VfsFSKind::Ext2 | VfsFSKind::Ext4 => "/dev/sda",
                         VfsFSKind::Dev => "devfs",
                         VfsFSKind::Tmp => "tmpfs",
                         VfsFSKind::Proc => "procfs",
@@ -124,6 +130,8 @@ impl ProcFSRootDir {
 
                     let kind_str = match kind {
                         VfsFSKind::Fat => "fat32",
+                        VfsFSKind::Ext2 => "ext2",
+                        VfsFSKind::Ext4 => "ext4",
                         VfsFSKind::Dev => "devfs",
                         VfsFSKind::Tmp => "tmpfs",
                         VfsFSKind::Proc => "procfs",
@@ -135,6 +143,76 @@ impl ProcFSRootDir {
                 }
                 content.as_bytes().into()
             },
+            w: None,
+        })
+    }
+
+    fn create_swaps(&self) -> VfsFileRef {
+        VfsFileRef::new(ProcFSStandaloneFile {
+            kind: VfsFileKind::RegularFile,
+            f: || swap::proc_swaps().as_bytes().into(),
+            w: None,
+        })
+    }
+
+    fn create_pagecache(&self) -> VfsFileRef {
+        VfsFileRef::new(ProcFSStandaloneFile {
+            kind: VfsFileKind::RegularFile,
+            f: || page_lru::proc_pagecache().as_bytes().into(),
+            w: None,
+        })
+    }
+
+    fn create_framecache(&self) -> VfsFileRef {
+        VfsFileRef::new(ProcFSStandaloneFile {
+            kind: VfsFileKind::RegularFile,
+            f: || frame::proc_framecache().as_bytes().into(),
+            w: None,
+        })
+    }
+
+    fn create_slabinfo(&self) -> VfsFileRef {
+        VfsFileRef::new(ProcFSStandaloneFile {
+            kind: VfsFileKind::RegularFile,
+            f: || slab::proc_slabinfo().as_bytes().into(),
+            w: None,
+        })
+    }
+
+    fn create_ksm(&self) -> VfsFileRef {
+        VfsFileRef::new(ProcFSStandaloneFile {
+            kind: VfsFileKind::RegularFile,
+            f: || ksm::proc_ksm().as_bytes().into(),
+            w: None,
+        })
+    }
+
+    fn create_meminfo(&self) -> VfsFileRef {
+        VfsFileRef::new(ProcFSStandaloneFile {
+            kind: VfsFileKind::RegularFile,
+            f: || {
+                // 单位为 kB
+                let kb = |pages: usize| pages * PAGE_SIZE / 1024;
+                let (active, inactive) = page_lru::lru_len();
+                let (swap_total, swap_used) = swap::swap_pages();
+                let items = [
+                    ("MemTotal", kb(frame::total_frames())),
+                    ("MemFree", kb(frame::free_frames())),
+                    ("Cached", kb(page_lru::cached_pages())),
+                    ("Dirty", kb(writeback::dirty_pages())),
+                    ("Active(file)", kb(active)),
+                    ("Inactive(file)", kb(inactive)),
+                    ("SwapTotal", kb(swap_total)),
+                    ("SwapFree", kb(swap_total - swap_used)),
+                    ("KernelHeap", heap::grown_bytes() / 1024),
+                ];
+                let mut content = String::with_capacity(256);
+                for (name, size) in items {
+                    content.push_str(&format!("{:<16}{:>8} kB\n", format!("{}:", name), size));
+                }
+                content.as_bytes().into()
+            },
+            w: None,
         })
     }
 
@@ -153,6 +231,7 @@ impl ProcFSRootDir {
                 log::warn!("proc: {content}");
                 content.as_bytes().into()
             },
+            w: None,
         })
     }
 }
@@ -189,6 +268,43 @@ impl VfsFile for ProcFSRootDir {
                 let file = self.create_interrupts();
                 ret.push(("interrupts".into(), file));
             }
+            {
+                // add swaps
+                let file = self.create_swaps();
+                ret.push(("swaps".into(), file));
+            }
+            {
+                // add pagecache
+                let file = self.create_pagecache();
+                ret.push(("pagecache".into(), file));
+            }
+            {
+                // add meminfo
+                let file = self.create_meminfo();
+                ret.push(("meminfo".into(), file));
+            }
+            {
+                // add framecache
+                let file = self.create_framecache();
+                ret.push(("framecache".into(), file));
+            }
+            {
+                // add slabinfo
+                let file = self.create_slabinfo();
+                ret.push(("slabinfo".into(), file));
+            }
+            {
+                // add ksm
+                let file = self.create_ksm();
+                ret.push(("ksm".into(), file));
+            }
+            {
+                // add sys
+                let file = VfsFileRef::new(ProcFSStaticDir {
+                    entries: sys_entries,
+                });
+                ret.push(("sys".into(), file));
+            }
 
             Ok(ret)
         })
@@ -200,6 +316,22 @@ impl VfsFile for ProcFSRootDir {
                 return Ok(self.create_mounts());
             } else if name == "interrupts" {
                 return Ok(self.create_interrupts());
+            } else if name == "swaps" {
+                return Ok(self.create_swaps());
+            } else if name == "pagecache" {
+                return Ok(self.create_pagecache());
+            } else if name == "meminfo" {
+                return Ok(self.create_meminfo());
+            } else if name == "framecache" {
+                return Ok(self.create_framecache());
+            } else if name == "slabinfo" {
+                return Ok(self.create_slabinfo());
+            } else if name == "ksm" {
+                return Ok(self.create_ksm());
+            } else if name == "sys" {
+                return Ok(VfsFileRef::new(ProcFSStaticDir {
+                    entries: sys_entries,
+                }));
             }
 
             let lproc = if name == "self" {
@@ -214,6 +346,80 @@ impl VfsFile for ProcFSRootDir {
     }
 }
 
+pub type ListEntriesFn = fn() -> Vec<(&'static str, VfsFileRef)>;
+
+/// 内容固定的目录, 如 /proc/sys
+pub struct ProcFSStaticDir {
+    entries: ListEntriesFn,
+}
+
+impl VfsFile for ProcFSStaticDir {
+    impl_vfs_default_non_file!(ProcFSStaticDir);
+    impl_proc_dir_default!();
+
+    fn list(&self) -> ASysResult<Vec<(String, VfsFileRef)>> {
+        dyn_future(async move {
+            let entries = (self.entries)().into_iter();
+            Ok(entries.map(|(name, file)| (name.to_string(), file)).collect())
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> ASysResult<VfsFileRef> {
+        dyn_future(async move {
+            let entry = (self.entries)().into_iter().find(|(n, _)| *n == name);
+            entry.map(|(_, file)| file).ok_or(SysError::ENOENT)
+        })
+    }
+}
+
+fn sys_entries() -> Vec<(&'static str, VfsFileRef)> {
+    let vm = VfsFileRef::new(ProcFSStaticDir {
+        entries: sys_vm_entries,
+    });
+    vec![("vm", vm)]
+}
+
+/// /proc/sys/vm 中的后台写回参数
+fn sys_vm_entries() -> Vec<(&'static str, VfsFileRef)> {
+    macro_rules! tunable {
+        ($var:path, $max:expr) => {
+            VfsFileRef::new(ProcFSStandaloneFile {
+                kind: VfsFileKind::RegularFile,
+                f: || format!("{}\n", $var.load(Ordering::Relaxed)).as_bytes().into(),
+                w: Some(|buf| {
+                    let val = core::str::from_utf8(buf)
+                        .ok()
+                        .and_then(|s| s.trim().parse::<usize>().ok())
+                        .filter(|&v| v <= $max)
+                        .ok_or(SysError::EINVAL)?;
+                    $var.store(val, Ordering::Relaxed);
+                    // 让后台写回马上按新的参数工作
+                    writeback::kick();
+                    Ok(())
+                }),
+            })
+        };
+    }
+    vec![
+        ("dirty_ratio", tunable!(writeback::DIRTY_RATIO, 100)),
+        (
+            "dirty_background_ratio",
+            tunable!(writeback::DIRTY_BACKGROUND_RATIO, 100),
+        ),
+        (
+            "dirty_expire_centisecs",
+            tunable!(writeback::DIRTY_EXPIRE_CENTISECS, writeback::MAX_CENTISECS),
+        ),
+        (
+            "dirty_writeback_centisecs",
+            tunable!(
+                writeback::DIRTY_WRITEBACK_CENTISECS,
+                writeback::MAX_CENTISECS
+            ),
+        ),
+    ]
+}
+
 pub struct ProcFSProcDir {
     lproc: Arc<LightProcess>,
 }
@@ -231,6 +437,37 @@ impl ProcFSProcDir {
                 let path = lproc.with_procfs_info(|info| info.exe_path.clone()).unwrap();
                 path.to_string().as_bytes().into()
             },
+            w: None,
+        }
+    }
+
+    fn create_oom_score(&self) -> ProcFSNormalFile {
+        ProcFSNormalFile {
+            kind: VfsFileKind::RegularFile,
+            lproc: self.lproc.clone(),
+            f: |lproc| format!("{}\n", oom::oom_score(lproc)).as_bytes().into(),
+            w: None,
+        }
+    }
+
+    fn create_oom_score_adj(&self) -> ProcFSNormalFile {
+        ProcFSNormalFile {
+            kind: VfsFileKind::RegularFile,
+            lproc: self.lproc.clone(),
+            f: |lproc| format!("{}\n", lproc.oom_score_adj()).as_bytes().into(),
+            w: Some(|lproc, buf| {
+                let adj = core::str::from_utf8(buf)
+                    .ok()
+                    .and_then(|s| s.trim().parse::<isize>().ok())
+                    .ok_or(SysError::EINVAL)?;
+                if !(oom::OOM_SCORE_ADJ_MIN..=oom::OOM_SCORE_ADJ_MAX).contains(&adj) {
+                    return Err(SysError::EINVAL);
+                }
+                // 整个线程组共用一个值
+                lproc.with_group(|g| g.iter().for_each(|t| t.set_oom_score_adj(adj)));
+                lproc.set_oom_score_adj(adj);
+                Ok(())
+            }),
         }
     }
 }
@@ -243,6 +480,14 @@ impl VfsFile for ProcFSProcDir {
         dyn_future(async {
             let mut ret = Vec::new();
             ret.push(("exe".to_string(), VfsFileRef::new(self.create_exe())));
+            ret.push((
+                "oom_score".to_string(),
+                VfsFileRef::new(self.create_oom_score()),
+            ));
+            ret.push((
+                "oom_score_adj".to_string(),
+                VfsFileRef::new(self.create_oom_score_adj()),
+            ));
             Ok(ret)
         })
     }
@@ -251,6 +496,8 @@ impl VfsFile for ProcFSProcDir {
         dyn_future(async move {
             let file = match name {
                 "exe" => self.create_exe(),
+                "oom_score" => self.create_oom_score(),
+                "oom_score_adj" => self.create_oom_score_adj(),
                 _ => return Err(SysError::ENOENT),
             };
             Ok(VfsFileRef::new(file))
@@ -259,11 +506,14 @@ impl VfsFile for ProcFSProcDir {
 }
 
 pub type GetStringInfoFn = fn(&Arc<LightProcess>) -> Box<[u8]>;
+pub type SetStringInfoFn = fn(&Arc<LightProcess>, &[u8]) -> SysResult;
 
 pub struct ProcFSNormalFile {
     kind: VfsFileKind,
     lproc: Arc<LightProcess>,
     f: GetStringInfoFn,
+    /// 为 None 时文件只读
+    w: Option<SetStringInfoFn>,
 }
 
 impl VfsFile for ProcFSNormalFile {
@@ -309,11 +559,22 @@ impl VfsFile for ProcFSNormalFile {
         })
     }
 
-    fn write_at<'a>(&'a self, _offset: usize, _buf: &'a [u8]) -> ASysResult<usize> {
-        dyn_future(async { Err(SysError::EPERM) })
+    fn write_at<'a>(&'a self, _offset: usize, buf: &'a [u8]) -> ASysResult<usize> {
+        dyn_future(async move {
+            match self.w {
+                Some(w) => w(&self.lproc, buf).map(|()| buf.len()),
+                None => Err(SysError::EPERM),
+            }
+        })
     }
     fn truncate(&self, _length: usize) -> ASysResult {
-        dyn_future(async { Err(SysError::EPERM) })
+        // 可写的文件允许以 O_TRUNC 打开, 截断本身没有意义
+        dyn_future(async move {
+            match self.w {
+                Some(_) => Ok(()),
+                None => Err(SysError::EPERM),
+            }
+        })
     }
 
     fn get_page(&self, _offset: usize, _kind: MmapKind) -> ASysResult<PhysAddr4K> {
@@ -334,10 +595,13 @@ impl VfsFile for ProcFSNormalFile {
 }
 
 pub type GetStandardaloneStringInfoFn = fn() -> Box<[u8]>;
+pub type SetStandardaloneStringInfoFn = fn(&[u8]) -> SysResult;
 
 pub struct ProcFSStandaloneFile {
     kind: VfsFileKind,
     f: GetStandardaloneStringInfoFn,
+    /// 为 None 时文件只读
+    w: Option<SetStandardaloneStringInfoFn>,
 }
 
 impl VfsFile for ProcFSStandaloneFile {
@@ -383,11 +647,21 @@ impl VfsFile for ProcFSStandaloneFile {
         })
     }
 
-    fn write_at<'a>(&'a self, _offset: usize, _buf: &'a [u8]) -> ASysResult<usize> {
-        dyn_future(async { Err(SysError::EPERM) })
+    fn write_at<'a>(&'a self, _offset: usize, buf: &'a [u8]) -> ASysResult<usize> {
+        dyn_future(async move {
+            match self.w {
+                Some(w) => w(buf).map(|()| buf.len()),
+                None => Err(SysError::EPERM),
+            }
+        })
     }
     fn truncate(&self, _length: usize) -> ASysResult {
-        dyn_future(async { Err(SysError::EPERM) })
+        dyn_future(async move {
+            match self.w {
+                Some(_) => Ok(()),
+                None => Err(SysError::EPERM),
+            }
+        })
     }
 
     fn get_page(&self, _offset: usize, _kind: MmapKind) -> ASysResult<PhysAddr4K> {
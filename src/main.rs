@@ -32,6 +32,7 @@ use lazy_static::lazy_static;
 
 mod arch;
 mod boot;
+mod cmdline;
 mod consts;
 mod drivers;
 mod fs;
@@ -129,6 +130,7 @@ pub extern "C" fn boot_rust_main(boot_hart_id: usize, boot_pc: usize) -> ! {
 
     // Initialize interrupt controller
     trap::trap::init();
+    memory::tlb::init();
 
     // Initialize timer
     timer::init();
@@ -147,6 +149,8 @@ pub extern "C" fn boot_rust_main(boot_hart_id: usize, boot_pc: usize) -> ! {
     );
 
     init_frame_ref_cnt();
+    memory::slab::init();
+    heap::init_growable();
 
     // Next stage device initialization
     device_tree::device_init();
@@ -196,7 +200,8 @@ pub extern "C" fn boot_rust_main(boot_hart_id: usize, boot_pc: usize) -> ! {
     pagetable::pagetable::unmap_boot_seg();
     info!("Boot memory unmapped");
 
-    fs::init_filesystems(manager.disks()[0].clone());
+    fs::init_filesystems(manager.disks().first().cloned());
+    memory::ksm::init();
 
     unsafe { riscv::register::sstatus::set_sie() };
 
@@ -2,9 +2,17 @@
 //!
 //! Max physical frame amount is currently hard coded
 //!
+//! Every hart keeps a small cache of free frames in front of the global
+//! bitmap allocator, which is refilled and drained in batches, so most
+//! allocations and deallocations don't touch the global lock.
 //!
-//!
-use crate::consts::platform::max_physical_memory;
+use alloc::{format, string::String};
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::consts::platform::{initrd_end, initrd_start, max_physical_memory};
+use crate::executor::hart_local::{get_hart_id, HART_MAX};
+use crate::fs::new_vfs::page_lru;
 use crate::{here, when_debug};
 
 use bitmap_allocator::BitAlloc;
@@ -23,37 +31,174 @@ pub type FrameAllocator = bitmap_allocator::BitAlloc16M;
 pub static FRAME_ALLOCATOR: SpinNoIrqLock<FrameAllocator> =
     SpinNoIrqLock::new(FrameAllocator::DEFAULT);
 
+static TOTAL_FRAMES: AtomicUsize = AtomicUsize::new(0);
+static FREE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// Start evicting the page cache when free frames drop below this
+const LOW_WATERMARK: usize = 1024;
+/// Frames to evict every time
+const RECLAIM_BATCH: usize = 32;
+/// Evicting pages may allocate frames itself, don't recurse
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+/// Free frames a hart keeps for itself
+const CACHE_SIZE: usize = 64;
+/// Frames moved between a hart cache and the global allocator at a time
+const CACHE_BATCH: usize = 32;
+
+/// Per-hart magazine of free frame ids.
+///
+/// Frames in the caches still count as free in `FREE_FRAMES`.
+struct FrameCache {
+    frames: [usize; CACHE_SIZE],
+    len: usize,
+    /// Allocations served from the cache
+    hits: usize,
+    /// Allocations that had to refill the cache from the global allocator
+    misses: usize,
+    /// Times the cache was full and drained to the global allocator
+    drains: usize,
+}
+
+impl FrameCache {
+    const fn new() -> Self {
+        Self {
+            frames: [0; CACHE_SIZE],
+            len: 0,
+            hits: 0,
+            misses: 0,
+            drains: 0,
+        }
+    }
+
+    fn alloc(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            self.misses += 1;
+            let mut global = FRAME_ALLOCATOR.lock(here!());
+            while self.len < CACHE_BATCH {
+                match global.alloc() {
+                    Some(id) => {
+                        self.frames[self.len] = id;
+                        self.len += 1;
+                    }
+                    None => break,
+                }
+            }
+        } else {
+            self.hits += 1;
+        }
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(self.frames[self.len])
+    }
+
+    fn dealloc(&mut self, id: usize) {
+        if self.len == CACHE_SIZE {
+            self.drains += 1;
+            self.drain(CACHE_BATCH);
+        }
+        self.frames[self.len] = id;
+        self.len += 1;
+    }
+
+    /// Give back at most `n` frames to the global allocator
+    fn drain(&mut self, n: usize) {
+        let mut global = FRAME_ALLOCATOR.lock(here!());
+        let n = n.min(self.len);
+        for &id in &self.frames[self.len - n..self.len] {
+            global.dealloc(id);
+        }
+        self.len -= n;
+    }
+}
+
+const EMPTY_CACHE: SpinNoIrqLock<FrameCache> = SpinNoIrqLock::new(FrameCache::new());
+/// Lock order: a hart cache, then `FRAME_ALLOCATOR`
+static FRAME_CACHES: [SpinNoIrqLock<FrameCache>; HART_MAX] = [EMPTY_CACHE; HART_MAX];
+
+fn curr_cache() -> &'static SpinNoIrqLock<FrameCache> {
+    let hart_id = get_hart_id();
+    debug_assert!(hart_id < HART_MAX);
+    &FRAME_CACHES[hart_id]
+}
+
+/// Return the frames cached by all harts to the global allocator,
+/// when it can't serve a request by itself
+fn drain_all_caches() {
+    for cache in FRAME_CACHES.iter() {
+        cache.lock(here!()).drain(CACHE_SIZE);
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct GlobalFrameAlloc;
 
 impl GlobalFrameAlloc {
     fn alloc(&self) -> Option<PhysAddr4K> {
+        let mut id = curr_cache().lock(here!()).alloc();
+        if id.is_none() {
+            // the global allocator is empty, but other harts may still cache some frames
+            drain_all_caches();
+            id = curr_cache().lock(here!()).alloc();
+        }
         // get the real address of the alloc frame
-        let ret = FRAME_ALLOCATOR
-            .lock(here!())
-            .alloc()
-            .map(|id| id * PAGE_SIZE + phymem_start())
-            .map(PhysAddr4K::from);
+        let ret = id.map(|id| id * PAGE_SIZE + phymem_start()).map(PhysAddr4K::from);
+        if ret.is_some() {
+            FREE_FRAMES.fetch_sub(1, Ordering::Relaxed);
+        }
         trace!("Allocate frame: {:x?}", ret);
         ret
     }
     fn alloc_contiguous(&self, size: usize, align_log2: usize) -> Option<PhysAddr4K> {
         // get the real address of the alloc frame
-        let ret = FRAME_ALLOCATOR
-            .lock(here!())
-            .alloc_contiguous(size, align_log2)
-            .map(|id| id * PAGE_SIZE + phymem_start())
-            .map(PhysAddr4K::from);
+        let mut id = FRAME_ALLOCATOR.lock(here!()).alloc_contiguous(size, align_log2);
+        if id.is_none() {
+            // cached frames may fill the holes
+            drain_all_caches();
+            id = FRAME_ALLOCATOR.lock(here!()).alloc_contiguous(size, align_log2);
+        }
+        let ret = id.map(|id| id * PAGE_SIZE + phymem_start()).map(PhysAddr4K::from);
+        if ret.is_some() {
+            FREE_FRAMES.fetch_sub(size, Ordering::Relaxed);
+        }
         trace!("Allocate frame: {:x?}", ret);
         ret
     }
     fn dealloc(&self, target: PhysAddr4K) {
         trace!("Deallocate frame: {:x}", target);
         let target: usize = target.bits();
-        FRAME_ALLOCATOR.lock(here!()).dealloc((target - phymem_start()) / PAGE_SIZE);
+        curr_cache().lock(here!()).dealloc((target - phymem_start()) / PAGE_SIZE);
+        FREE_FRAMES.fetch_add(1, Ordering::Relaxed);
     }
 }
 
+/// Content of /proc/framecache
+pub fn proc_framecache() -> String {
+    let mut content = format!(
+        "{:<6}{:>8}{:>12}{:>12}{:>10}{:>8}\n",
+        "hart", "cached", "hits", "misses", "drains", "hit%"
+    );
+    for (hart_id, cache) in FRAME_CACHES.iter().enumerate() {
+        let cache = cache.lock(here!());
+        let total = cache.hits + cache.misses;
+        if total == 0 {
+            continue;
+        }
+        content.push_str(&format!(
+            "{:<6}{:>8}{:>12}{:>12}{:>10}{:>8}\n",
+            hart_id,
+            cache.len,
+            cache.hits,
+            cache.misses,
+            cache.drains,
+            cache.hits * 100 / total
+        ));
+    }
+    content
+}
+
 pub fn init() {
     // Insert frames into allocator
     FRAME_ALLOCATOR.lock(here!()).insert(0..(max_physical_memory() / PAGE_SIZE));
@@ -62,12 +207,98 @@ pub fn init() {
     let kernel_end = kernel_virt_text_to_phys(kernel_end);
     let kernel_end = (kernel_end - phymem_start()) / PAGE_SIZE;
     FRAME_ALLOCATOR.lock(here!()).remove(0..kernel_end);
+    // Keep the initrd until it is unpacked
+    let initrd = initrd_frames();
+    FRAME_ALLOCATOR.lock(here!()).remove(initrd.clone());
+
+    let total = max_physical_memory() / PAGE_SIZE - kernel_end - initrd.len();
+    TOTAL_FRAMES.store(total, Ordering::Relaxed);
+    FREE_FRAMES.store(total, Ordering::Relaxed);
+}
+
+/// Frames occupied by the initrd and not by the kernel image
+fn initrd_frames() -> Range<usize> {
+    let (start, end) = (initrd_start(), initrd_end());
+    if start >= end || start < phymem_start() {
+        return 0..0;
+    }
+    let kernel_end = kernel_virt_text_to_phys(memlayout::kernel_end as usize);
+    let begin = (start - phymem_start()) / PAGE_SIZE;
+    let begin = begin.max((kernel_end - phymem_start()) / PAGE_SIZE);
+    let end = (end - phymem_start() + PAGE_SIZE - 1) / PAGE_SIZE;
+    let end = end.min(max_physical_memory() / PAGE_SIZE);
+    begin..end.max(begin)
+}
+
+/// Give the frames of the initrd to the allocator, after it is unpacked
+pub fn free_initrd() {
+    let initrd = initrd_frames();
+    if initrd.is_empty() {
+        return;
+    }
+    for id in initrd.clone() {
+        dealloc_frame(PhysAddr4K::from(id * PAGE_SIZE + phymem_start()));
+    }
+    TOTAL_FRAMES.fetch_add(initrd.len(), Ordering::Relaxed);
+    info!(
+        "Freed initrd memory: {} KiB",
+        initrd.len() * PAGE_SIZE / 1024
+    );
+}
+
+/// Frames available to the allocator after the kernel image
+pub fn total_frames() -> usize {
+    TOTAL_FRAMES.load(Ordering::Relaxed)
+}
+
+pub fn free_frames() -> usize {
+    FREE_FRAMES.load(Ordering::Relaxed)
+}
+
+/// The frame shared by all not-yet-written anonymous pages, 0 if not allocated yet
+static ZERO_FRAME: AtomicUsize = AtomicUsize::new(0);
+
+/// A read-only frame filled with zero, allocated on first use.
+///
+/// The allocator holds one reference to it forever, so it is always shared
+/// and writing to it always goes through CoW.
+pub fn zero_frame() -> Option<PhysAddr4K> {
+    let zero = ZERO_FRAME.load(Ordering::Acquire);
+    if zero != 0 {
+        return Some(PhysAddr4K::from(zero));
+    }
+    let frame = alloc_frame()?;
+    unsafe { frame.as_mut_page_slice().fill(0) };
+    match ZERO_FRAME.compare_exchange(0, frame.bits(), Ordering::AcqRel, Ordering::Acquire) {
+        Ok(_) => Some(frame),
+        Err(zero) => {
+            // someone else won the race
+            frame.page_num().decrease_and_must_dealloc();
+            Some(PhysAddr4K::from(zero))
+        }
+    }
+}
+
+/// Evict clean or written back pages from the page cache
+fn reclaim_page_cache(want: usize) -> usize {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+    let freed = page_lru::reclaim(want);
+    RECLAIMING.store(false, Ordering::Release);
+    freed
 }
 
 /// Allocate a frame
 /// returns the physical address of the frame, usually 0x80xxxxxx
 pub fn alloc_frame() -> Option<PhysAddr4K> {
-    let paddr = GlobalFrameAlloc.alloc();
+    if free_frames() < LOW_WATERMARK {
+        reclaim_page_cache(RECLAIM_BATCH);
+    }
+    let mut paddr = GlobalFrameAlloc.alloc();
+    if paddr.is_none() && reclaim_page_cache(RECLAIM_BATCH) != 0 {
+        paddr = GlobalFrameAlloc.alloc();
+    }
     when_debug!({
         use crate::executor::hart_local::within_sum;
         if let Some(paddr) = paddr {
@@ -95,6 +326,15 @@ pub fn dealloc_frame(target: PhysAddr4K) {
 pub fn alloc_frame_contiguous(size: usize, align_log2: usize) -> Option<PhysAddr4K> {
     GlobalFrameAlloc.alloc_contiguous(size, align_log2)
 }
+/// Like `alloc_frame_contiguous`, but evicts the page cache when out of frames.
+/// The caller must not hold any lock the eviction may need, e.g. heap locks
+pub fn alloc_frame_contiguous_reclaim(size: usize, align_log2: usize) -> Option<PhysAddr4K> {
+    let mut paddr = GlobalFrameAlloc.alloc_contiguous(size, align_log2);
+    if paddr.is_none() && reclaim_page_cache(RECLAIM_BATCH.max(size)) != 0 {
+        paddr = GlobalFrameAlloc.alloc_contiguous(size, align_log2);
+    }
+    paddr
+}
 pub fn dealloc_frames(target: usize, pages: usize) {
     for i in 0..pages {
         GlobalFrameAlloc.dealloc(PhysAddr4K::from(target + i * PAGE_SIZE));
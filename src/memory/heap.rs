@@ -1,83 +1,231 @@
-use core::cmp::max;
+//! Kernel heap
+//!
+//! Small objects go to the slab caches. Everything else starts in a static
+//! buddy heap, which is all we have before the physical memory is mapped.
+//! After that the heap grows on demand by superblocks of frames taken from
+//! the frame allocator and accessed through the physical memory mapping,
+//! and a superblock goes back to the frame allocator once it's fully free.
+//! Allocations bigger than a quarter superblock get frames of their own.
 
-use buddy_system_allocator::{Heap, LockedHeapWithRescue};
-use log::warn;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem::size_of,
+    ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use buddy_system_allocator::{Heap, LockedHeap};
 
 use crate::{
-    boot,
-    consts::{self, address_space::K_SEG_HEAP_BEG},
+    consts::{address_space::K_SEG_PHY_MEM_BEG, platform::phymem_start, PAGE_SIZE},
+    here,
+    sync::SpinNoIrqLock,
 };
 
 use super::{
-    frame::alloc_frame_contiguous,
-    pagetable::{self, pte::PTEFlags},
+    frame::{alloc_frame_contiguous_reclaim, dealloc_frames},
+    slab,
 };
 
 /// 2 MiB kernel init heap
-/// Auto expand when needed
 const KERNEL_HEAP_SIZE: usize = 2 * 1024 * 1024;
 
+/// The heap grows by 2^SUPERBLOCK_ORDER pages at a time
+const SUPERBLOCK_ORDER: usize = 4;
+const SUPERBLOCK_SIZE: usize = PAGE_SIZE << SUPERBLOCK_ORDER;
+/// Allocations bigger than this get frames of their own
+const LARGE_ALLOC: usize = SUPERBLOCK_SIZE / 4;
+
+/// Small objects go to the slab caches, the rest to the buddy heaps
+struct KernelAllocator;
+
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeapWithRescue<32> =
-    LockedHeapWithRescue::<32>::new(heap_allocate_rescue);
+static KERNEL_ALLOCATOR: KernelAllocator = KernelAllocator;
+
+static INIT_HEAP: LockedHeap<32> = LockedHeap::<32>::new();
 
 static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 
-static mut KERNEL_HEAP_TOP: usize = K_SEG_HEAP_BEG;
+/// Set once the physical memory is mapped
+static GROWABLE: AtomicBool = AtomicBool::new(false);
+
+/// Bytes of frames held by superblocks and large allocations
+static GROWN_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// At the start of every superblock, the rest of which is managed by `heap`
+#[repr(C)]
+struct Superblock {
+    heap: Heap<32>,
+    prev: usize,
+    next: usize,
+}
+
+/// Doubly linked through the superblock headers
+struct Superblocks {
+    head: usize,
+    count: usize,
+}
+
+static SUPERBLOCKS: SpinNoIrqLock<Superblocks> =
+    SpinNoIrqLock::new(Superblocks { head: 0, count: 0 });
 
 pub fn init() {
     unsafe {
-        HEAP_ALLOCATOR.lock().init(HEAP_SPACE.as_ptr() as usize, KERNEL_HEAP_SIZE);
+        INIT_HEAP.lock().init(HEAP_SPACE.as_ptr() as usize, KERNEL_HEAP_SIZE);
     }
 }
 
+/// Must be called after the physical memory is mapped
+pub fn init_growable() {
+    GROWABLE.store(true, Ordering::Release);
+}
+
+/// Bytes the heap has taken from the frame allocator so far, for /proc/meminfo
+pub fn grown_bytes() -> usize {
+    GROWN_BYTES.load(Ordering::Relaxed)
+}
+
 #[alloc_error_handler]
-pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
+pub fn handle_alloc_error(layout: Layout) -> ! {
     panic!("Heap allocation error, layout = {:x?}", layout);
 }
 
-fn heap_allocate_rescue(heap: &mut Heap<32>, layout: &core::alloc::Layout) {
-    warn!("Heap expanding, layout = {:x?}", layout);
-    let mut root_pagetable = pagetable::pagetable::PageTable::new_with_paddr_no_heap_alloc(
-        boot::boot_pagetable_paddr().into(),
-    );
-
-    let allocate_size = layout.size().next_power_of_two() * 2; // Ensure that can fulfill the request
-    let allocate_size = max(allocate_size, consts::PAGE_SIZE);
-
-    let page_cnt = (allocate_size + consts::PAGE_SIZE - 1) / consts::PAGE_SIZE;
-    let paddr = alloc_frame_contiguous(
-        page_cnt,
-        layout.align().checked_ilog2().expect("alignment is not power of 2") as _,
-    )
-    .expect("Heap expansion failed, cannot allocate frame from physical frame allocator");
-    let aligned_heap_top = (unsafe { KERNEL_HEAP_TOP } + layout.align() - 1)
-        & !(layout.align() - 1)
-        & !consts::PAGE_MASK;
-
-    warn!(
-        "Allocation success, paddr = 0x{:x}, aligned_heap_top = 0x{:x}",
-        paddr, aligned_heap_top
-    );
-
-    warn!("Before heap: {:x?}", heap);
-    root_pagetable.map_region(
-        aligned_heap_top.into(),
-        paddr,
-        allocate_size,
-        PTEFlags::rw(),
-    );
-    unsafe {
-        // Add the newly allocated frame to the heap
-        heap.add_to_heap(aligned_heap_top, aligned_heap_top + allocate_size);
-        KERNEL_HEAP_TOP = aligned_heap_top + allocate_size;
+fn is_init_heap(ptr: *mut u8) -> bool {
+    let begin = unsafe { HEAP_SPACE.as_ptr() } as usize;
+    (begin..begin + KERNEL_HEAP_SIZE).contains(&(ptr as usize))
+}
+
+// not kernel_phys_to_virt and friends, which may log
+fn frames_to_virt(paddr: usize) -> usize {
+    paddr - phymem_start() + K_SEG_PHY_MEM_BEG
+}
+
+fn virt_to_frames(vaddr: usize) -> usize {
+    vaddr - K_SEG_PHY_MEM_BEG + phymem_start()
+}
+
+fn large_pages(layout: Layout) -> usize {
+    (layout.size() + PAGE_SIZE - 1) / PAGE_SIZE
+}
+
+fn alloc_large(layout: Layout) -> *mut u8 {
+    let pages = large_pages(layout);
+    let align_log2 = (layout.align() / PAGE_SIZE).max(1).ilog2() as usize;
+    match alloc_frame_contiguous_reclaim(pages, align_log2) {
+        Some(paddr) => {
+            GROWN_BYTES.fetch_add(pages * PAGE_SIZE, Ordering::Relaxed);
+            frames_to_virt(paddr.bits()) as *mut u8
+        }
+        None => null_mut(),
+    }
+}
+
+fn dealloc_large(ptr: *mut u8, layout: Layout) {
+    let pages = large_pages(layout);
+    dealloc_frames(virt_to_frames(ptr as usize), pages);
+    GROWN_BYTES.fetch_sub(pages * PAGE_SIZE, Ordering::Relaxed);
+}
+
+impl Superblocks {
+    fn alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let mut sb = self.head;
+        while sb != 0 {
+            let superblock = unsafe { &mut *(sb as *mut Superblock) };
+            if let Ok(ptr) = superblock.heap.alloc(layout) {
+                return Some(ptr);
+            }
+            sb = superblock.next;
+        }
+        None
+    }
+
+    /// Set up a superblock in fresh frames at `sb` and link it
+    unsafe fn add(&mut self, sb: usize) {
+        core::ptr::write(
+            sb as *mut Superblock,
+            Superblock {
+                heap: Heap::<32>::new(),
+                prev: 0,
+                next: self.head,
+            },
+        );
+        let begin = (sb + size_of::<Superblock>() + 63) & !63;
+        (*(sb as *mut Superblock)).heap.init(begin, sb + SUPERBLOCK_SIZE - begin);
+        if self.head != 0 {
+            (*(self.head as *mut Superblock)).prev = sb;
+        }
+        self.head = sb;
+        self.count += 1;
+    }
+
+    /// Free `ptr`, and return its superblock to the frame allocator if it is
+    /// now fully free and not the only one left
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let sb = ptr as usize & !(SUPERBLOCK_SIZE - 1);
+        let superblock = &mut *(sb as *mut Superblock);
+        superblock.heap.dealloc(NonNull::new_unchecked(ptr), layout);
+        if superblock.heap.stats_alloc_actual() != 0 || self.count == 1 {
+            return;
+        }
+
+        if superblock.prev != 0 {
+            (*(superblock.prev as *mut Superblock)).next = superblock.next;
+        } else {
+            self.head = superblock.next;
+        }
+        if superblock.next != 0 {
+            (*(superblock.next as *mut Superblock)).prev = superblock.prev;
+        }
+        self.count -= 1;
+        dealloc_frames(virt_to_frames(sb), 1 << SUPERBLOCK_ORDER);
+        GROWN_BYTES.fetch_sub(SUPERBLOCK_SIZE, Ordering::Relaxed);
+    }
+}
+
+fn alloc_growable(layout: Layout) -> *mut u8 {
+    if layout.size() > LARGE_ALLOC {
+        return alloc_large(layout);
+    }
+    if let Ok(ptr) = INIT_HEAP.lock().alloc(layout) {
+        return ptr.as_ptr();
+    }
+    if let Some(ptr) = SUPERBLOCKS.lock(here!()).alloc(layout) {
+        return ptr.as_ptr();
+    }
+
+    // Don't hold the lock while asking for frames,
+    // evicting the page cache frees heap objects
+    let paddr = match alloc_frame_contiguous_reclaim(1 << SUPERBLOCK_ORDER, SUPERBLOCK_ORDER) {
+        Some(paddr) => paddr,
+        None => return null_mut(),
+    };
+    GROWN_BYTES.fetch_add(SUPERBLOCK_SIZE, Ordering::Relaxed);
+    let mut superblocks = SUPERBLOCKS.lock(here!());
+    unsafe { superblocks.add(frames_to_virt(paddr.bits())) };
+    superblocks.alloc(layout).map_or(null_mut(), |ptr| ptr.as_ptr())
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(cache) = slab::cache_for(layout) {
+            return cache.alloc();
+        }
+        if GROWABLE.load(Ordering::Acquire) {
+            return alloc_growable(layout);
+        }
+        INIT_HEAP.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Things allocated before the heap grew may live on after it
+        if is_init_heap(ptr) {
+            INIT_HEAP.dealloc(ptr, layout)
+        } else if let Some(cache) = slab::cache_for(layout) {
+            cache.dealloc(ptr)
+        } else if layout.size() > LARGE_ALLOC {
+            dealloc_large(ptr, layout)
+        } else {
+            SUPERBLOCKS.lock(here!()).dealloc(ptr, layout)
+        }
     }
-    core::mem::forget(root_pagetable);
-    warn!(
-        "Heap expansion success, aligned_heap_top = {:#x}, paddr = {:#x}, allocate_size = 0x{:x}",
-        unsafe { KERNEL_HEAP_TOP },
-        paddr,
-        allocate_size
-    );
-    warn!("Current heap: {:x?}", heap);
 }
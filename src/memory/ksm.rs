@@ -0,0 +1,225 @@
+//! Kernel Samepage Merging
+//!
+//! A background task scans the pages of anonymous and private file mappings marked
+//! with `madvise(MADV_MERGEABLE)`, and merges pages with identical content into one
+//! frame. The merged frame is mapped read-only with the SHARED (CoW) flag everywhere,
+//! so the first write to it takes the usual CoW fault and gets a private copy again.
+//!
+//! Like Linux, there are two trees:
+//! - The stable tree holds the KSM frames, keyed by content hash. Their content never
+//!   changes since they are never mapped writable. The tree owns one reference of every
+//!   frame, a frame nobody maps any more is freed at the end of a full scan.
+//! - The unstable tree remembers which frame had which hash during the current full scan.
+//!   When another frame with the same hash shows up, that frame is write protected and
+//!   moved to the stable tree; the first one merges with it when it is scanned next time.
+//!   Entries of the unstable tree are never dereferenced, and it is emptied on every full scan.
+//!
+//! All-zero pages are merged with the shared zero frame instead.
+
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use log::debug;
+
+use crate::{
+    executor, here,
+    memory::{
+        address::{PhysAddr4K, PhysPageNum, VirtAddr, VirtPageNum},
+        frame::zero_frame,
+        pagetable::{
+            pagetable::PageTable,
+            pte::{PTEFlags, PageTableEntry},
+        },
+        tlb,
+    },
+    process::lproc_mgr::GlobalLProcManager,
+    sync::SpinNoIrqLock,
+    timer::wake_after,
+    tools::exam_hash,
+};
+
+/// Pages scanned every time ksmd wakes up
+const PAGES_TO_SCAN: usize = 100;
+/// How long ksmd sleeps between two batches
+const SLEEP_MILLISECS: usize = 20;
+
+struct Ksm {
+    /// Content hash -> KSM frames with that hash
+    stable: BTreeMap<usize, Vec<PhysAddr4K>>,
+    /// Content hash -> a frame seen with that hash during this full scan
+    unstable: BTreeMap<usize, PhysPageNum>,
+    /// Where the next batch starts: pid and virtual address in it
+    cursor: (usize, usize),
+    full_scans: usize,
+    /// Pages merged with the zero frame, never decreased
+    zero_pages: usize,
+}
+
+static KSM: SpinNoIrqLock<Ksm> = SpinNoIrqLock::new(Ksm {
+    stable: BTreeMap::new(),
+    unstable: BTreeMap::new(),
+    cursor: (0, 0),
+    full_scans: 0,
+    zero_pages: 0,
+});
+
+/// Start ksmd
+pub fn init() {
+    let (r, t) = executor::spawn(ksmd());
+    r.schedule();
+    t.detach();
+}
+
+async fn ksmd() {
+    loop {
+        wake_after(SLEEP_MILLISECS).await;
+        scan_batch(PAGES_TO_SCAN);
+    }
+}
+
+/// Scan at most `budget` pages, continuing from where the last batch stopped.
+/// Address spaces locked by someone else are skipped for this full scan.
+fn scan_batch(mut budget: usize) {
+    let (cursor_pid, start) = KSM.lock(here!()).cursor;
+    let mut start = VirtAddr::from(start);
+    for (pid, lproc) in GlobalLProcManager::all() {
+        let pid = usize::from(pid);
+        if pid < cursor_pid {
+            continue;
+        }
+        if pid > cursor_pid {
+            start = VirtAddr::from(0);
+        }
+        let next = lproc.try_with_mut_memory(|m| m.ksm_scan(start, &mut budget)).flatten();
+        if let Some(next) = next {
+            KSM.lock(here!()).cursor = (pid, next.bits());
+            return;
+        }
+    }
+    end_full_scan();
+}
+
+fn end_full_scan() {
+    let mut ksm = KSM.lock(here!());
+    ksm.cursor = (0, 0);
+    ksm.unstable.clear();
+    ksm.full_scans += 1;
+    // Only the stable tree itself holds these, and nobody else can take a new reference
+    ksm.stable.retain(|_, frames| {
+        frames.retain(|frame| {
+            if frame.page_num().is_unique() {
+                frame.page_num().decrease_and_must_dealloc();
+                false
+            } else {
+                true
+            }
+        });
+        !frames.is_empty()
+    });
+}
+
+/// Try to merge the page mapped at `vpn`, `flags` are the PTE flags of its area.
+/// The caller holds the address space lock.
+pub fn scan_page(page_table: &mut PageTable, vpn: VirtPageNum, flags: PTEFlags) {
+    let pte = match page_table.get_pte_mut_from_vpn(vpn) {
+        Some(pte) if pte.is_valid() => pte,
+        _ => return,
+    };
+    let frame = pte.paddr();
+    // Already shared with someone: a KSM frame, the zero frame or a CoW page after fork
+    if frame.page_num().is_shared() {
+        return;
+    }
+
+    let mut ksm = KSM.lock(here!());
+    let hash = exam_hash(unsafe { frame.as_page_slice() });
+    let candidate = ksm.stable.contains_key(&hash)
+        || matches!(ksm.unstable.get(&hash), Some(&ppn) if ppn != frame.page_num())
+        || unsafe { frame.as_page_slice() }.iter().all(|&b| b == 0);
+    if !candidate {
+        ksm.unstable.insert(hash, frame.page_num());
+        return;
+    }
+
+    // Write protect the page first, so that its content can't change any more.
+    // If it doesn't get merged, the next write just takes a CoW fault on a unique frame
+    let mut ro_flags = flags;
+    ro_flags.remove(PTEFlags::W);
+    ro_flags.insert(PTEFlags::SHARED);
+    *pte = PageTableEntry::new(frame, ro_flags);
+    tlb::shootdown_page(page_table.root_paddr(), vpn.addr().into());
+
+    let data = unsafe { frame.as_page_slice() };
+    let hash = exam_hash(data);
+    let is_zero = data.iter().all(|&b| b == 0);
+    let merge_with = if is_zero {
+        zero_frame()
+    } else {
+        ksm.stable.get(&hash).and_then(|frames| {
+            frames
+                .iter()
+                .copied()
+                .find(|ksm_frame| unsafe { ksm_frame.as_page_slice() } == data)
+        })
+    };
+
+    match merge_with {
+        Some(ksm_frame) => {
+            ksm_frame.page_num().increase();
+            let pte = page_table.get_pte_mut_from_vpn(vpn).unwrap();
+            *pte = PageTableEntry::new(ksm_frame, ro_flags);
+            tlb::shootdown_page(page_table.root_paddr(), vpn.addr().into());
+            frame.page_num().decrease_and_must_dealloc();
+            if is_zero {
+                ksm.zero_pages += 1;
+            }
+            debug!("ksm: merged {:x?} into {:x?}", vpn, ksm_frame);
+        }
+        None => match ksm.unstable.get(&hash) {
+            Some(&ppn) if ppn != frame.page_num() => {
+                // Promote it to a KSM frame, the tree holds a reference
+                frame.page_num().increase();
+                ksm.unstable.remove(&hash);
+                ksm.stable.entry(hash).or_default().push(frame);
+            }
+            _ => {
+                ksm.unstable.insert(hash, frame.page_num());
+            }
+        },
+    }
+}
+
+/// Whether `frame` is a KSM frame, used by MADV_UNMERGEABLE to break them
+pub fn is_ksm_frame(frame: PhysAddr4K) -> bool {
+    let hash = exam_hash(unsafe { frame.as_page_slice() });
+    KSM.lock(here!())
+        .stable
+        .get(&hash)
+        .map_or(false, |frames| frames.contains(&frame))
+}
+
+/// Content of /proc/ksm, named after the files in /sys/kernel/mm/ksm
+pub fn proc_ksm() -> String {
+    let ksm = KSM.lock(here!());
+    let (mut pages_shared, mut pages_sharing) = (0, 0);
+    for frame in ksm.stable.values().flatten() {
+        // The stable tree holds one reference
+        let mappings = frame.page_num().get_ref_cnt() as usize - 1;
+        if mappings > 0 {
+            pages_shared += 1;
+            pages_sharing += mappings - 1;
+        }
+    }
+    let items = [
+        ("pages_to_scan", PAGES_TO_SCAN),
+        ("sleep_millisecs", SLEEP_MILLISECS),
+        ("pages_shared", pages_shared),
+        ("pages_sharing", pages_sharing),
+        ("pages_unshared", ksm.unstable.len()),
+        ("full_scans", ksm.full_scans),
+        ("ksm_zero_pages", ksm.zero_pages),
+    ];
+    let mut content = String::with_capacity(256);
+    for (name, value) in items {
+        content.push_str(&format!("{:<16}{:>8}\n", name, value));
+    }
+    content
+}
@@ -2,7 +2,11 @@ pub mod address;
 pub mod frame;
 pub mod frame_ref_cnt;
 pub mod heap;
+pub mod ksm;
 pub mod pagetable;
+pub mod slab;
+pub mod swap;
+pub mod tlb;
 
 mod user_ptr;
 
@@ -18,7 +18,9 @@ use crate::{
     memory::{
         address::{PhysAddr, PhysAddr4K, VirtAddr, VirtAddr4K},
         frame,
+        swap::{self, SwapEntry},
     },
+    tools::errors::{SysError, SysResult},
     when_debug,
 };
 
@@ -85,19 +87,23 @@ pub struct PageTable {
 
 impl PageTable {
     pub fn new() -> Self {
+        Self::try_new().expect("failed to allocate page")
+    }
+
+    pub fn try_new() -> SysResult<Self> {
         // Allocate 1 page for the root page table
-        let root_paddr = Self::alloc_table();
+        let root_paddr = Self::alloc_table()?;
 
-        PageTable {
+        Ok(PageTable {
             root_paddr,
             intrm_tables: vec![root_paddr],
             no_alloc: false,
-        }
+        })
     }
 
     pub fn new_with_kernel_seg() -> Self {
         // Allocate 1 page for the root page table
-        let root_paddr = Self::alloc_table();
+        let root_paddr = Self::alloc_table().expect("failed to allocate page");
         let boot_root_paddr = PhysAddr::from(boot::boot_pagetable_paddr()).assert_4k();
 
         // Copy kernel segment
@@ -140,28 +146,68 @@ impl PageTable {
         self.root_paddr
     }
 
+    /// Count user pages that are resident and swapped out
+    pub fn count_user_pages(&self) -> (usize, usize) {
+        let mut resident = 0;
+        let mut swapped = 0;
+        let p3 = self.table_of(self.root_paddr);
+        // Lower half is user space
+        for p3e in p3[..ENTRY_COUNT / 2].iter() {
+            if !p3e.is_valid() || p3e.is_leaf() {
+                continue;
+            }
+            for p2e in self.table_of(p3e.paddr()).iter() {
+                if !p2e.is_valid() || p2e.is_leaf() {
+                    continue;
+                }
+                for p1e in self.table_of(p2e.paddr()).iter() {
+                    if (p1e.is_valid() && p1e.is_user()) || p1e.is_prot_none() {
+                        resident += 1;
+                    } else if p1e.is_swap() {
+                        swapped += 1;
+                    }
+                }
+            }
+        }
+        (resident, swapped)
+    }
+
+    /// Frames used by the table itself
+    pub fn table_pages(&self) -> usize {
+        self.intrm_tables.len()
+    }
+
     /// map_page maps a physical page to a virtual address
     /// PTE::V is guaranteed to be set, so no need to set PTE::V
-    pub fn map_page(&mut self, vaddr: VirtAddr4K, paddr: PhysAddr4K, flags: PTEFlags) {
+    /// Fails with ENOMEM if an intermediate table can not be allocated
+    pub fn map_page(&mut self, vaddr: VirtAddr4K, paddr: PhysAddr4K, flags: PTEFlags) -> SysResult {
         debug_assert!(paddr.is_valid());
         let new_pte = pte::PageTableEntry::new(paddr, PTEFlags::V | flags);
         // Get entry by vaddr
-        let entry = self.get_entry_mut_or_create(vaddr.into());
+        let entry = self.get_entry_mut_or_create(vaddr.into())?;
         debug_assert!(!entry.is_valid(), "Remapping a valid page table entry");
         *entry = new_pte;
+        Ok(())
     }
     /// remap_page allows remapping valid page
-    pub fn remap_page(&mut self, vaddr: VirtAddr4K, paddr: PhysAddr4K, flags: PTEFlags) {
+    /// Never fails if the leaf entry already exists (valid or swapped out)
+    pub fn remap_page(
+        &mut self,
+        vaddr: VirtAddr4K,
+        paddr: PhysAddr4K,
+        flags: PTEFlags,
+    ) -> SysResult {
         debug_assert!(paddr.is_valid());
         let new_pte = pte::PageTableEntry::new(paddr, PTEFlags::V | flags);
         // Get entry by vaddr
-        let entry = self.get_entry_mut_or_create(vaddr.into());
+        let entry = self.get_entry_mut_or_create(vaddr.into())?;
         *entry = new_pte;
+        Ok(())
     }
     pub fn unmap_page(&mut self, vaddr: VirtAddr4K) -> PhysAddr4K {
         let entry = self.get_entry_mut(vaddr.into());
         let paddr = entry.paddr();
-        debug_assert!(entry.is_valid(), "Unmapping a invalid page table entry");
+        debug_assert!(entry.is_present(), "Unmapping a invalid page table entry");
         entry.clear();
         paddr
     }
@@ -188,7 +234,8 @@ impl PageTable {
         let mut paddr = paddr;
         let mut size = size;
         while size > 0 {
-            self.map_page(vaddr, paddr, flags);
+            // Only used for kernel mappings during boot
+            self.map_page(vaddr, paddr, flags).expect("failed to allocate page");
             vaddr.offset_to_next_page();
             paddr.offset_to_next_page();
             size -= consts::PAGE_SIZE;
@@ -218,13 +265,34 @@ impl PageTable {
         self.get_entry_mut_opt(vpn.addr().into())
     }
 
+    /// Whether the page at vpn is present in memory
+    pub fn is_mapped(&self, vpn: VirtPageNum) -> bool {
+        self.get_entry_mut_opt(vpn.addr().into()).is_some()
+    }
+    /// Get the swap entry if the page at vpn is swapped out
+    pub fn get_swap_entry(&self, vpn: VirtPageNum) -> Option<SwapEntry> {
+        self.get_leaf_mut_opt(vpn.addr().into())
+            .filter(|pte| pte.is_swap())
+            .map(|pte| pte.swap_entry())
+    }
+    /// Clear the PTE of a swapped out page and return its swap entry
+    pub fn take_swap_entry(&mut self, vpn: VirtPageNum) -> Option<SwapEntry> {
+        let pte = self.get_leaf_mut_opt(vpn.addr().into()).filter(|pte| pte.is_swap())?;
+        let entry = pte.swap_entry();
+        pte.clear();
+        Some(entry)
+    }
+
     pub fn get_paddr_from_vaddr(&self, vaddr: VirtAddr) -> PhysAddr {
         self.get_entry_mut(vaddr).paddr().into() + vaddr.page_offset()
     }
 
-    pub fn copy_table_and_mark_self_cow(&mut self, do_with_frame: impl Fn(PhysAddr4K)) -> Self {
+    pub fn copy_table_and_mark_self_cow(
+        &mut self,
+        do_with_frame: impl Fn(PhysAddr4K),
+    ) -> SysResult<Self> {
         let old = self;
-        let mut new = Self::new();
+        let mut new = Self::try_new()?;
 
         // Copy kernel space
         unsafe {
@@ -234,6 +302,27 @@ impl PageTable {
         }
         new.unmap_user_space();
 
+        // Allocate all intermediate tables first, so that running out of frames
+        // leaves the old table and the frame reference counts untouched
+        let op1_iter = old.table_of_mut(old.root_paddr).iter_mut();
+        let np1_iter = new.table_of_mut(new.root_paddr).iter_mut();
+        for (op1, np1) in Iterator::zip(op1_iter, np1_iter) {
+            if op1.is_leaf() {
+                continue;
+            }
+            let op2t = old.next_table_mut_opt(op1);
+            if op2t.is_none() {
+                continue;
+            }
+            let op2_iter = op2t.unwrap().iter();
+            let np2_iter = new.next_table_mut_or_create(np1)?.iter_mut();
+            for (op2, np2) in Iterator::zip(op2_iter, np2_iter) {
+                if op2.is_valid() && !op2.is_leaf() {
+                    new.next_table_mut_or_create(np2)?;
+                }
+            }
+        }
+
         let op1_iter = old.table_of_mut(old.root_paddr).iter_mut();
         let np1_iter = new.table_of_mut(new.root_paddr).iter_mut();
 
@@ -248,7 +337,7 @@ impl PageTable {
                 continue;
             }
             let op2_iter = op2t.unwrap().iter_mut();
-            let np2_iter = new.next_table_mut_or_create(np1).iter_mut();
+            let np2_iter = new.next_table_mut_or_create(np1)?.iter_mut();
 
             for (idx2, (op2, np2)) in Iterator::zip(op2_iter, np2_iter).enumerate() {
                 if op2.is_leaf() {
@@ -261,7 +350,7 @@ impl PageTable {
                     continue;
                 }
                 let op3_iter = op3t.unwrap().iter_mut();
-                let np3_iter = new.next_table_mut_or_create(np2).iter_mut();
+                let np3_iter = new.next_table_mut_or_create(np2)?.iter_mut();
 
                 for (idx3, (op3, np3)) in Iterator::zip(op3_iter, np3_iter).enumerate() {
                     let vaddr = (idx1 << 18 | idx2 << 9 | idx3) << 12;
@@ -279,12 +368,23 @@ impl PageTable {
                             }
                         }
                         *np3 = *op3;
+                    } else if op3.is_prot_none() {
+                        // Keeps its frame like a valid user page, W is already clear
+                        do_with_frame(op3.paddr());
+                        if !in_share_seg {
+                            op3.set_shared();
+                        }
+                        *np3 = *op3;
+                    } else if op3.is_swap() {
+                        // Both sides refer to the same swap slot, each will read it back on fault
+                        swap::dup_slot(op3.swap_entry());
+                        *np3 = *op3;
                     }
                 }
             }
         }
 
-        new
+        Ok(new)
     }
 }
 
@@ -292,13 +392,13 @@ impl PageTable {
 impl PageTable {
     // Allocates a page for a table
     // the allocated page will be zeroed to ensure every PTE is not valid
-    fn alloc_table() -> PhysAddr4K {
-        let paddr = frame::alloc_frame().expect("failed to allocate page");
+    fn alloc_table() -> SysResult<PhysAddr4K> {
+        let paddr = frame::alloc_frame().ok_or(SysError::ENOMEM)?;
         // Fill with zeros
         unsafe {
             paddr.as_mut_page_slice().fill(0);
         }
-        paddr
+        Ok(paddr)
     }
     fn table_of<'a>(&self, paddr: PhysAddr4K) -> &'a [PageTableEntry] {
         // use kernel_vaddr here to work after kernel remapped
@@ -337,17 +437,17 @@ impl PageTable {
     fn next_table_mut_or_create<'a>(
         &mut self,
         pte: &mut PageTableEntry,
-    ) -> &'a mut [PageTableEntry] {
+    ) -> SysResult<&'a mut [PageTableEntry]> {
         if !pte.is_valid() {
-            let paddr = Self::alloc_table();
+            let paddr = Self::alloc_table()?;
             // Sometimes we don't want to allocate on heap
             if !self.no_alloc {
                 self.intrm_tables.push(paddr);
             }
             *pte = PageTableEntry::new(paddr, PTEFlags::V);
-            self.table_of_mut(paddr)
+            Ok(self.table_of_mut(paddr))
         } else {
-            self.next_table_mut(pte)
+            Ok(self.next_table_mut(pte))
         }
     }
 
@@ -358,13 +458,24 @@ impl PageTable {
         let p2e = &mut p2[p2_index(vaddr)];
         let p1 = self.next_table_mut_opt(p2e)?;
         let p1e = &mut p1[p1_index(vaddr)];
-        if p1e.is_valid() {
+        // PROT_NONE pages still own their frames
+        if p1e.is_present() {
             Some(p1e)
         } else {
             None
         }
     }
 
+    // Like get_entry_mut_opt, but also returns non-valid leaf entries
+    fn get_leaf_mut_opt(&self, vaddr: VirtAddr) -> Option<&mut PageTableEntry> {
+        let p3 = self.table_of_mut(self.root_paddr);
+        let p3e = &mut p3[p3_index(vaddr)];
+        let p2 = self.next_table_mut_opt(p3e)?;
+        let p2e = &mut p2[p2_index(vaddr)];
+        let p1 = self.next_table_mut_opt(p2e)?;
+        Some(&mut p1[p1_index(vaddr)])
+    }
+
     fn get_entry_mut(&self, vaddr: VirtAddr) -> &mut PageTableEntry {
         let p3 = self.table_of_mut(self.root_paddr);
         let p3e = &mut p3[p3_index(vaddr)];
@@ -375,14 +486,14 @@ impl PageTable {
         &mut p1[p1_index(vaddr)]
     }
 
-    fn get_entry_mut_or_create(&mut self, vaddr: VirtAddr) -> &mut PageTableEntry {
+    fn get_entry_mut_or_create(&mut self, vaddr: VirtAddr) -> SysResult<&mut PageTableEntry> {
         let p3 = self.table_of_mut(self.root_paddr);
         let p3e = &mut p3[p3_index(vaddr)];
-        let p2 = self.next_table_mut_or_create(p3e);
+        let p2 = self.next_table_mut_or_create(p3e)?;
         let p2e = &mut p2[p2_index(vaddr)];
-        let p1 = self.next_table_mut_or_create(p2e);
+        let p1 = self.next_table_mut_or_create(p2e)?;
 
-        &mut p1[p1_index(vaddr)]
+        Ok(&mut p1[p1_index(vaddr)])
     }
 }
 
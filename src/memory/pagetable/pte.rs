@@ -11,6 +11,8 @@ use core::fmt;
 use crate::consts;
 use crate::memory::address::{PhysAddr4K, PhysPageNum};
 use crate::memory::frame;
+use crate::memory::swap::SwapEntry;
+use crate::tools::errors::{SysError, SysResult};
 
 // Define the PTEFlags bitflags structure
 bitflags! {
@@ -26,7 +28,7 @@ bitflags! {
         const A = 1 << 6; // access, set to 1 after r/w/x
         const D = 1 << 7; // dirty, set to 1 after write
         const SHARED = 1 << 8; // copy-on-write
-        const RSW2 = 1 << 9; // software reserved 2
+        const SWAP = 1 << 9; // swapped out, only meaningful when V is clear
     }
 }
 
@@ -70,6 +72,44 @@ impl PageTableEntry {
     // Define an empty PageTableEntry
     pub const EMPTY: Self = Self { bits: 0 };
 
+    /// Create a non-valid PageTableEntry recording where the page is swapped out to.
+    /// The swap entry takes the place of the PPN
+    pub fn new_swap(entry: SwapEntry) -> Self {
+        PageTableEntry {
+            bits: ((entry.bits() << consts::PTE_FLAGS_BITS) & consts::PTE_PPN_MASK_SV39)
+                | PTEFlags::SWAP.bits() as usize,
+        }
+    }
+
+    /// Create a non-valid PageTableEntry for a page whose area has no permission (PROT_NONE).
+    /// V|U with R=W=X=0 would be taken as a pointer to the next level table by the hardware,
+    /// so V is cleared instead. The frame and the other flags are kept, U marks the entry
+    pub fn new_prot_none(paddr: PhysAddr4K, perm: PTEFlags) -> Self {
+        let perm = perm - (PTEFlags::V | PTEFlags::R | PTEFlags::W | PTEFlags::X);
+        Self::new(paddr, perm | PTEFlags::U)
+    }
+
+    // Check if the PageTableEntry keeps a frame of a PROT_NONE page
+    pub fn is_prot_none(&self) -> bool {
+        !self.is_valid() && self.flags().contains(PTEFlags::U)
+    }
+
+    // Check if the PageTableEntry holds a frame, either valid or PROT_NONE
+    pub fn is_present(&self) -> bool {
+        self.is_valid() || self.is_prot_none()
+    }
+
+    // Check if the PageTableEntry records a swapped out page
+    pub fn is_swap(&self) -> bool {
+        !self.is_valid() && self.flags().contains(PTEFlags::SWAP)
+    }
+
+    // Get the swap entry from a swapped out PageTableEntry
+    pub fn swap_entry(&self) -> SwapEntry {
+        debug_assert!(self.is_swap());
+        SwapEntry::from_bits((self.bits & consts::PTE_PPN_MASK_SV39) >> consts::PTE_FLAGS_BITS)
+    }
+
     // Clear the PageTableEntry
     pub fn clear(&mut self) {
         *self = Self::EMPTY;
@@ -174,6 +214,21 @@ impl PageTableEntry {
         self.bits &= !(PTEFlags::U.bits() as usize);
     }
 
+    // Check if the PageTableEntry has been accessed since the A bit was cleared
+    pub fn accessed(&self) -> bool {
+        self.flags().contains(PTEFlags::A)
+    }
+
+    // Set the accessed flag, for hardware that faults instead of setting it
+    pub fn set_accessed(&mut self) {
+        self.bits |= PTEFlags::A.bits() as usize;
+    }
+
+    // Clear the accessed flag for the PageTableEntry
+    pub fn clear_accessed(&mut self) {
+        self.bits &= !(PTEFlags::A.bits() as usize);
+    }
+
     // Set the shared flag for the PageTableEntry
     pub fn set_shared(&mut self) {
         self.bits |= PTEFlags::SHARED.bits() as usize;
@@ -203,18 +258,20 @@ impl PageTableEntry {
     }
 
     /// Allocate a non-leaf PageTableEntry with the given permissions
-    pub fn alloc_non_leaf(&mut self, perm: PTEFlags) {
+    pub fn alloc_non_leaf(&mut self, perm: PTEFlags) -> SysResult {
         debug_assert!(!self.is_valid(), "try alloc to a valid pte");
         debug_assert!(!perm.intersects(PTEFlags::U | PTEFlags::R | PTEFlags::W));
-        let pa = frame::alloc_frame().unwrap(); // TODO: add error checking
+        let pa = frame::alloc_frame().ok_or(SysError::ENOMEM)?;
         *self = Self::new(pa, perm | PTEFlags::V);
+        Ok(())
     }
 
     /// Allocate a physical page for the PageTableEntry with the given permissions
-    pub fn alloc(&mut self, perm: PTEFlags) {
+    pub fn alloc(&mut self, perm: PTEFlags) -> SysResult {
         debug_assert!(!self.is_valid(), "try alloc to a valid pte");
-        let pa = frame::alloc_frame().unwrap(); // TODO: add error checking
+        let pa = frame::alloc_frame().ok_or(SysError::ENOMEM)?;
         *self = Self::new(pa, perm | PTEFlags::D | PTEFlags::A | PTEFlags::V);
+        Ok(())
     }
 
     // Map a frame to the PageTableEntry with the given permissions and physical address
@@ -0,0 +1,374 @@
+//! Slab allocator for small kernel objects
+//!
+//! Small allocations are served from object caches instead of the buddy heap.
+//! A slab is a naturally aligned block of frames taken from the frame allocator
+//! and accessed through the physical memory mapping, with a header at its start
+//! followed by the objects.
+//!
+//! Every cache has a per-hart magazine of free objects, refilled from and
+//! flushed to the shared slab lists in batches, so the common path only takes
+//! an uncontended hart-local lock.
+//!
+//! The hottest objects have dedicated caches matched by exact layout, anything
+//! else up to 2 KiB goes to the power-of-two `kmalloc-*` caches. Allocations
+//! made before [`init`] and larger ones go to the buddy heap.
+//!
+//! Nothing in here may allocate from the heap, since it runs inside the global allocator.
+
+use core::{
+    alloc::Layout,
+    mem::{align_of, size_of},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use alloc::{format, string::String};
+
+use crate::{
+    consts::{address_space::K_SEG_PHY_MEM_BEG, platform::phymem_start, PAGE_SIZE},
+    executor::hart_local::{get_hart_id, HART_MAX},
+    fs::new_vfs::page_lru::PageMeta,
+    here,
+    process::lproc::{FileDescriptor, LightProcess},
+    sync::SpinNoIrqLock,
+};
+
+use super::frame::{alloc_frame_contiguous_reclaim, dealloc_frames};
+
+/// Objects a hart keeps for each cache
+const MAG_SIZE: usize = 32;
+/// Objects moved between a magazine and the slab lists at a time
+const MAG_BATCH: usize = 16;
+/// Use bigger slabs until at least this many objects fit in one
+const MIN_OBJS_PER_SLAB: usize = 8;
+const MAX_SLAB_ORDER: usize = 3;
+
+/// Set once the physical memory mapping is ready
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// At the start of every slab
+#[repr(C)]
+struct SlabHeader {
+    /// Free objects in this slab, linked through their first word, 0 terminated
+    free: usize,
+    /// Objects handed out, including those sitting in magazines
+    inuse: usize,
+    prev: usize,
+    next: usize,
+}
+
+struct Magazine {
+    objs: [usize; MAG_SIZE],
+    len: usize,
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            objs: [0; MAG_SIZE],
+            len: 0,
+        }
+    }
+}
+
+struct SlabLists {
+    /// Slabs with free objects, doubly linked through their headers
+    partial: usize,
+    nr_slabs: usize,
+    /// Free objects in all slabs, not counting the magazines
+    nr_free: usize,
+}
+
+impl SlabLists {
+    unsafe fn push_partial(&mut self, slab: usize) {
+        let hdr = &mut *(slab as *mut SlabHeader);
+        hdr.prev = 0;
+        hdr.next = self.partial;
+        if self.partial != 0 {
+            (*(self.partial as *mut SlabHeader)).prev = slab;
+        }
+        self.partial = slab;
+    }
+
+    unsafe fn unlink_partial(&mut self, slab: usize) {
+        let hdr = &mut *(slab as *mut SlabHeader);
+        if hdr.prev != 0 {
+            (*(hdr.prev as *mut SlabHeader)).next = hdr.next;
+        } else {
+            self.partial = hdr.next;
+        }
+        if hdr.next != 0 {
+            (*(hdr.next as *mut SlabHeader)).prev = hdr.prev;
+        }
+    }
+}
+
+const EMPTY_MAGAZINE: SpinNoIrqLock<Magazine> = SpinNoIrqLock::new(Magazine::new());
+
+/// Lock order: a magazine, then the slab lists, then the frame allocator
+pub struct KmemCache {
+    name: &'static str,
+    /// Object size, a multiple of `align`
+    size: usize,
+    align: usize,
+    /// A slab is 2^order pages
+    order: usize,
+    /// Offset of the first object in a slab
+    offset: usize,
+    objs_per_slab: usize,
+    mags: [SpinNoIrqLock<Magazine>; HART_MAX],
+    lists: SpinNoIrqLock<SlabLists>,
+    /// Allocations served from the magazine
+    hits: AtomicUsize,
+    /// Allocations that had to refill the magazine
+    misses: AtomicUsize,
+}
+
+impl KmemCache {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        let align = if align < size_of::<usize>() {
+            size_of::<usize>()
+        } else {
+            align
+        };
+        let size = (size + align - 1) & !(align - 1);
+        let offset = (size_of::<SlabHeader>() + align - 1) & !(align - 1);
+        let mut order = 0;
+        while order < MAX_SLAB_ORDER && ((PAGE_SIZE << order) - offset) / size < MIN_OBJS_PER_SLAB {
+            order += 1;
+        }
+        Self {
+            name,
+            size,
+            align,
+            order,
+            offset,
+            objs_per_slab: ((PAGE_SIZE << order) - offset) / size,
+            mags: [EMPTY_MAGAZINE; HART_MAX],
+            lists: SpinNoIrqLock::new(SlabLists {
+                partial: 0,
+                nr_slabs: 0,
+                nr_free: 0,
+            }),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    fn slab_bytes(&self) -> usize {
+        PAGE_SIZE << self.order
+    }
+
+    fn curr_mag(&self) -> &SpinNoIrqLock<Magazine> {
+        let hart_id = get_hart_id();
+        debug_assert!(hart_id < HART_MAX);
+        &self.mags[hart_id]
+    }
+
+    /// Null when out of memory
+    pub fn alloc(&self) -> *mut u8 {
+        self.alloc_obj().map_or(core::ptr::null_mut(), |obj| obj as *mut u8)
+    }
+
+    pub fn dealloc(&self, ptr: *mut u8) {
+        self.dealloc_obj(ptr as usize)
+    }
+
+    fn alloc_obj(&self) -> Option<usize> {
+        let mut mag = self.curr_mag().lock(here!());
+        if mag.len > 0 {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            self.refill(&mut mag);
+            if mag.len == 0 {
+                // don't hold any lock while asking for frames
+                drop(mag);
+                self.grow()?;
+                mag = self.curr_mag().lock(here!());
+                self.refill(&mut mag);
+                if mag.len == 0 {
+                    return None;
+                }
+            }
+        }
+        mag.len -= 1;
+        Some(mag.objs[mag.len])
+    }
+
+    fn dealloc_obj(&self, obj: usize) {
+        let mut mag = self.curr_mag().lock(here!());
+        if mag.len == MAG_SIZE {
+            self.flush(&mut mag, MAG_BATCH);
+        }
+        let len = mag.len;
+        mag.objs[len] = obj;
+        mag.len += 1;
+    }
+
+    /// Move up to `MAG_BATCH` objects from the partial slabs into the magazine
+    fn refill(&self, mag: &mut Magazine) {
+        let mut lists = self.lists.lock(here!());
+        while mag.len < MAG_BATCH && lists.partial != 0 {
+            let slab = lists.partial;
+            unsafe {
+                let hdr = &mut *(slab as *mut SlabHeader);
+                let obj = hdr.free;
+                hdr.free = *(obj as *const usize);
+                hdr.inuse += 1;
+                if hdr.free == 0 {
+                    lists.unlink_partial(slab);
+                }
+                mag.objs[mag.len] = obj;
+            }
+            mag.len += 1;
+            lists.nr_free -= 1;
+        }
+    }
+
+    /// Give the last `n` objects of the magazine back to their slabs,
+    /// freeing slabs that become empty as long as another slab worth of objects stays free
+    fn flush(&self, mag: &mut Magazine, n: usize) {
+        let mut lists = self.lists.lock(here!());
+        let n = n.min(mag.len);
+        for &obj in &mag.objs[mag.len - n..mag.len] {
+            let slab = obj & !(self.slab_bytes() - 1);
+            unsafe {
+                let hdr = &mut *(slab as *mut SlabHeader);
+                let was_full = hdr.free == 0;
+                *(obj as *mut usize) = hdr.free;
+                hdr.free = obj;
+                hdr.inuse -= 1;
+                lists.nr_free += 1;
+                if was_full {
+                    lists.push_partial(slab);
+                }
+                if hdr.inuse == 0 && lists.nr_free >= 2 * self.objs_per_slab {
+                    lists.unlink_partial(slab);
+                    lists.nr_slabs -= 1;
+                    lists.nr_free -= self.objs_per_slab;
+                    dealloc_frames(slab - K_SEG_PHY_MEM_BEG + phymem_start(), 1 << self.order);
+                }
+            }
+        }
+        mag.len -= n;
+    }
+
+    /// Add a new slab to the partial list
+    fn grow(&self) -> Option<()> {
+        let paddr = alloc_frame_contiguous_reclaim(1 << self.order, self.order)?;
+        // not kernel_phys_to_virt, which may log
+        let slab = paddr.bits() - phymem_start() + K_SEG_PHY_MEM_BEG;
+        unsafe {
+            let hdr = &mut *(slab as *mut SlabHeader);
+            hdr.inuse = 0;
+            hdr.free = 0;
+            // link objects from the last one, so that the first one is handed out first
+            for i in (0..self.objs_per_slab).rev() {
+                let obj = slab + self.offset + i * self.size;
+                *(obj as *mut usize) = hdr.free;
+                hdr.free = obj;
+            }
+            let mut lists = self.lists.lock(here!());
+            lists.push_partial(slab);
+            lists.nr_slabs += 1;
+            lists.nr_free += self.objs_per_slab;
+        }
+        Some(())
+    }
+
+    fn fits(&self, layout: Layout) -> bool {
+        layout.size() <= self.size && layout.align() <= self.align
+    }
+}
+
+/// Layout of the allocation behind an `Arc<T>`: two counters followed by the value
+const fn arc_inner_align<T>() -> usize {
+    if align_of::<T>() > align_of::<usize>() {
+        align_of::<T>()
+    } else {
+        align_of::<usize>()
+    }
+}
+
+const fn arc_inner_size<T>() -> usize {
+    let align = arc_inner_align::<T>();
+    let counters = (2 * size_of::<usize>() + align_of::<T>() - 1) & !(align_of::<T>() - 1);
+    (counters + size_of::<T>() + align - 1) & !(align - 1)
+}
+
+macro_rules! arc_cache {
+    ($name: expr, $ty: ty) => {
+        KmemCache::new($name, arc_inner_size::<$ty>(), arc_inner_align::<$ty>())
+    };
+}
+
+static LPROC_CACHE: KmemCache = arc_cache!("lightprocess", LightProcess);
+static FD_CACHE: KmemCache = arc_cache!("file_descriptor", FileDescriptor);
+static PAGE_META_CACHE: KmemCache = arc_cache!("page_meta", PageMeta);
+
+/// Dedicated caches, used for allocations of exactly their layout
+static TYPED_CACHES: [&KmemCache; 3] = [&LPROC_CACHE, &FD_CACHE, &PAGE_META_CACHE];
+
+static KMALLOC_CACHES: [KmemCache; 8] = [
+    KmemCache::new("kmalloc-16", 16, 16),
+    KmemCache::new("kmalloc-32", 32, 32),
+    KmemCache::new("kmalloc-64", 64, 64),
+    KmemCache::new("kmalloc-128", 128, 128),
+    KmemCache::new("kmalloc-256", 256, 256),
+    KmemCache::new("kmalloc-512", 512, 512),
+    KmemCache::new("kmalloc-1k", 1024, 1024),
+    KmemCache::new("kmalloc-2k", 2048, 2048),
+];
+
+/// Must be called after the physical memory is mapped
+pub fn init() {
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// The cache serving `layout`, None if it should go to the heap
+pub fn cache_for(layout: Layout) -> Option<&'static KmemCache> {
+    if !ENABLED.load(Ordering::Acquire) {
+        return None;
+    }
+    if let Some(cache) = TYPED_CACHES
+        .iter()
+        .find(|c| c.size == layout.size() && c.align == layout.align())
+    {
+        return Some(cache);
+    }
+    KMALLOC_CACHES.iter().find(|c| c.fits(layout))
+}
+
+/// Content of /proc/slabinfo
+pub fn proc_slabinfo() -> String {
+    let mut content = String::from("slabinfo - version: 2.1\n");
+    content.push_str(&format!(
+        "# {:<18}{:>8}{:>8}{:>8}{:>8}{:>8} : tunables <limit> <batchcount> : slabdata <num_slabs> : stats <hits> <misses>\n",
+        "name", "<active_objs>", "<num_objs>", "<objsize>", "<objperslab>", "<pagesperslab>"
+    ));
+    let caches = TYPED_CACHES.iter().copied().chain(KMALLOC_CACHES.iter());
+    for cache in caches {
+        // collect the numbers first, formatting may allocate from this very cache
+        let cached: usize = cache.mags.iter().map(|m| m.lock(here!()).len).sum();
+        let (nr_slabs, nr_free) = {
+            let lists = cache.lists.lock(here!());
+            (lists.nr_slabs, lists.nr_free)
+        };
+        let num_objs = nr_slabs * cache.objs_per_slab;
+        content.push_str(&format!(
+            "{:<20}{:>8}{:>8}{:>8}{:>8}{:>8} : tunables {:>4} {:>4} : slabdata {:>6} : stats {} {}\n",
+            cache.name,
+            num_objs.saturating_sub(nr_free + cached),
+            num_objs,
+            cache.size,
+            cache.objs_per_slab,
+            1 << cache.order,
+            MAG_SIZE,
+            MAG_BATCH,
+            nr_slabs,
+            cache.hits.load(Ordering::Relaxed),
+            cache.misses.load(Ordering::Relaxed),
+        ));
+    }
+    content
+}
@@ -0,0 +1,327 @@
+//! Swap space
+//!
+//! When physical frames run out, anonymous user pages can be evicted to a swap area,
+//! which is a block device or a regular file prepared by `mkswap`.
+//!
+//! A swapped out page is recorded in its leaf PTE: V is cleared, the SWAP bit is set
+//! and the PPN field holds a [`SwapEntry`] (swap area index + page slot in the area).
+//! The page is read back into a new frame on the next page fault.
+//!
+//! Every slot has a reference count, because `fork` duplicates swap entries
+//! the same way it shares frames. Each address space reads the slot back
+//! into its own frame and drops one reference.
+//!
+//! Swap I/O bypasses the page cache (see `VfsFile::read_page_direct`),
+//! otherwise evicting a page would just move it into another frame.
+
+use core::sync::atomic::{AtomicIsize, Ordering};
+
+use alloc::{format, string::String, vec, vec::Vec};
+use log::{debug, info, warn};
+
+use crate::{
+    consts::PAGE_SIZE,
+    executor::block_on,
+    fs::new_vfs::{top::VfsFileRef, VfsFileKind},
+    here,
+    memory::address::PhysAddr4K,
+    process::lproc_mgr::GlobalLProcManager,
+    sync::SpinNoIrqLock,
+    tools::errors::{SysError, SysResult},
+};
+
+/// Same as Linux
+pub const MAX_SWAPFILES: usize = 32;
+const SWAP_AREA_BITS: usize = 5;
+
+/// Where a swapped out page lives: swap area index and page slot inside the area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapEntry(usize);
+
+impl SwapEntry {
+    pub fn new(area: usize, slot: usize) -> Self {
+        debug_assert!(area < MAX_SWAPFILES);
+        Self(slot << SWAP_AREA_BITS | area)
+    }
+    pub const fn from_bits(bits: usize) -> Self {
+        Self(bits)
+    }
+    pub const fn bits(self) -> usize {
+        self.0
+    }
+    pub const fn area(self) -> usize {
+        self.0 & (MAX_SWAPFILES - 1)
+    }
+    pub const fn slot(self) -> usize {
+        self.0 >> SWAP_AREA_BITS
+    }
+}
+
+// mkswap header, see `union swap_header` in include/linux/swap.h
+// The first page of a swap area is the header, which ends with the magic
+const SWAP_MAGIC: &[u8] = b"SWAPSPACE2";
+const SWAP_HEADER_VERSION: u32 = 1;
+const SWAP_INFO_OFFSET: usize = 1024;
+const SWAP_INFO_VERSION: usize = SWAP_INFO_OFFSET;
+const SWAP_INFO_LAST_PAGE: usize = SWAP_INFO_OFFSET + 4;
+const SWAP_INFO_NR_BADPAGES: usize = SWAP_INFO_OFFSET + 8;
+// version, last_page, nr_badpages, uuid[16], volume_name[16], padding[117]
+const SWAP_INFO_BADPAGES: usize = SWAP_INFO_OFFSET + 12 + 16 + 16 + 117 * 4;
+const SWAP_MAX_BADPAGES: usize = (PAGE_SIZE - SWAP_MAGIC.len() - SWAP_INFO_BADPAGES) / 4;
+
+/// Slot reference count of the header and bad pages
+const SLOT_UNUSABLE: u32 = u32::MAX;
+
+struct SwapArea {
+    file: VfsFileRef,
+    /// Path passed to swapon, used by swapoff and /proc/swaps
+    path: String,
+    prio: isize,
+    /// Reference count of every page slot
+    slot_ref: Vec<u32>,
+    /// Usable slots
+    pages: usize,
+    /// Slots in use
+    inuse: usize,
+    /// Where to start looking for a free slot
+    cursor: usize,
+    /// swapoff in progress, no more slots will be handed out
+    closing: bool,
+}
+
+impl SwapArea {
+    fn alloc_slot(&mut self) -> Option<usize> {
+        if self.closing || self.inuse == self.pages {
+            return None;
+        }
+        let total = self.slot_ref.len();
+        for i in 0..total {
+            let slot = (self.cursor + i) % total;
+            if self.slot_ref[slot] == 0 {
+                self.slot_ref[slot] = 1;
+                self.inuse += 1;
+                self.cursor = slot + 1;
+                return Some(slot);
+            }
+        }
+        None
+    }
+}
+
+const NO_AREA: Option<SwapArea> = None;
+static SWAP_AREAS: SpinNoIrqLock<[Option<SwapArea>; MAX_SWAPFILES]> =
+    SpinNoIrqLock::new([NO_AREA; MAX_SWAPFILES]);
+
+/// Areas without explicit priority get decreasing negative ones, like Linux
+static LEAST_PRIORITY: AtomicIsize = AtomicIsize::new(0);
+
+fn read_u32(page: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(page[offset..offset + 4].try_into().unwrap())
+}
+
+/// Enable swapping to `file`, which must have been formatted by mkswap
+pub async fn swapon(file: VfsFileRef, path: String, prio: Option<isize>) -> SysResult {
+    if SWAP_AREAS.lock(here!()).iter().flatten().any(|a| a.path == path) {
+        return Err(SysError::EBUSY);
+    }
+
+    let mut header = vec![0u8; PAGE_SIZE];
+    if file.read_page_direct(0, &mut header).await? != PAGE_SIZE {
+        return Err(SysError::EINVAL);
+    }
+    if &header[PAGE_SIZE - SWAP_MAGIC.len()..] != SWAP_MAGIC {
+        warn!("swapon: {} has no swap signature", path);
+        return Err(SysError::EINVAL);
+    }
+    if read_u32(&header, SWAP_INFO_VERSION) != SWAP_HEADER_VERSION {
+        return Err(SysError::EINVAL);
+    }
+
+    let last_page = read_u32(&header, SWAP_INFO_LAST_PAGE) as usize;
+    let file_pages = file.size().await? / PAGE_SIZE;
+    let total = (last_page + 1).min(file_pages);
+    if total <= 1 {
+        return Err(SysError::EINVAL);
+    }
+
+    let mut slot_ref = vec![0u32; total];
+    slot_ref[0] = SLOT_UNUSABLE;
+    let nr_badpages = read_u32(&header, SWAP_INFO_NR_BADPAGES) as usize;
+    if nr_badpages > SWAP_MAX_BADPAGES {
+        return Err(SysError::EINVAL);
+    }
+    for i in 0..nr_badpages {
+        let bad = read_u32(&header, SWAP_INFO_BADPAGES + i * 4) as usize;
+        if bad < total {
+            slot_ref[bad] = SLOT_UNUSABLE;
+        }
+    }
+    let pages = slot_ref.iter().filter(|&&r| r == 0).count();
+
+    let prio = prio.unwrap_or_else(|| LEAST_PRIORITY.fetch_sub(1, Ordering::Relaxed) - 1);
+    info!("swapon: {}, {} pages, priority {}", path, pages, prio);
+
+    let mut areas = SWAP_AREAS.lock(here!());
+    let free = areas.iter_mut().find(|a| a.is_none()).ok_or(SysError::EPERM)?;
+    *free = Some(SwapArea {
+        file,
+        path,
+        prio,
+        slot_ref,
+        pages,
+        inuse: 0,
+        cursor: 1,
+        closing: false,
+    });
+    Ok(())
+}
+
+/// Read every page in the swap area at `path` back, then disable it
+pub async fn swapoff(path: &str) -> SysResult {
+    let idx = {
+        let mut areas = SWAP_AREAS.lock(here!());
+        let (idx, area) = areas
+            .iter_mut()
+            .enumerate()
+            .find_map(|(i, a)| a.as_mut().filter(|a| a.path == path).map(|a| (i, a)))
+            .ok_or(SysError::EINVAL)?;
+        area.closing = true;
+        idx
+    };
+
+    for (_, lproc) in GlobalLProcManager::all() {
+        let result = lproc.with_mut_memory(|m| m.swap_in_area(idx));
+        if let Err(e) = result {
+            if let Some(area) = SWAP_AREAS.lock(here!())[idx].as_mut() {
+                area.closing = false;
+            }
+            return Err(e);
+        }
+    }
+
+    let mut areas = SWAP_AREAS.lock(here!());
+    let area = areas[idx].as_ref().unwrap();
+    if area.inuse != 0 {
+        // Pages owned by address spaces that are not reachable from any process
+        warn!("swapoff: {} still has {} slots in use", path, area.inuse);
+        areas[idx].as_mut().unwrap().closing = false;
+        return Err(SysError::EBUSY);
+    }
+    info!("swapoff: {}", path);
+    areas[idx] = None;
+    Ok(())
+}
+
+/// Take a free slot from the swap area with the highest priority
+pub fn alloc_slot() -> Option<SwapEntry> {
+    let mut areas = SWAP_AREAS.lock(here!());
+    let mut order: Vec<usize> = (0..MAX_SWAPFILES).filter(|&i| areas[i].is_some()).collect();
+    order.sort_by_key(|&i| -areas[i].as_ref().unwrap().prio);
+    for idx in order {
+        if let Some(slot) = areas[idx].as_mut().unwrap().alloc_slot() {
+            return Some(SwapEntry::new(idx, slot));
+        }
+    }
+    None
+}
+
+/// One more PTE refers to the slot
+pub fn dup_slot(entry: SwapEntry) {
+    let mut areas = SWAP_AREAS.lock(here!());
+    let area = areas[entry.area()].as_mut().expect("swap area gone");
+    debug_assert!(area.slot_ref[entry.slot()] != 0);
+    area.slot_ref[entry.slot()] += 1;
+}
+
+/// A PTE stops referring to the slot, the slot is free when nobody refers to it
+pub fn free_slot(entry: SwapEntry) {
+    let mut areas = SWAP_AREAS.lock(here!());
+    let area = areas[entry.area()].as_mut().expect("swap area gone");
+    let cnt = &mut area.slot_ref[entry.slot()];
+    debug_assert!(*cnt != 0 && *cnt != SLOT_UNUSABLE);
+    *cnt -= 1;
+    if *cnt == 0 {
+        area.inuse -= 1;
+    }
+}
+
+fn area_file(entry: SwapEntry) -> VfsFileRef {
+    SWAP_AREAS.lock(here!())[entry.area()]
+        .as_ref()
+        .expect("swap area gone")
+        .file
+        .clone()
+}
+
+/// Write the frame to the slot
+pub fn write_page(entry: SwapEntry, frame: PhysAddr4K) -> SysResult {
+    let file = area_file(entry);
+    let buf = unsafe { frame.as_page_slice() };
+    // TODO-PERF: block on swap I/O
+    match block_on(file.write_page_direct(entry.slot() * PAGE_SIZE, buf))? {
+        PAGE_SIZE => Ok(()),
+        _ => Err(SysError::EIO),
+    }
+}
+
+/// Read the slot into the frame
+pub fn read_page(entry: SwapEntry, frame: PhysAddr4K) -> SysResult {
+    let file = area_file(entry);
+    let buf = unsafe { frame.as_mut_page_slice() };
+    // TODO-PERF: block on swap I/O
+    match block_on(file.read_page_direct(entry.slot() * PAGE_SIZE, buf))? {
+        PAGE_SIZE => Ok(()),
+        _ => Err(SysError::EIO),
+    }
+}
+
+/// Try to free `want` frames by swapping out anonymous pages of all processes.
+/// Address spaces locked by someone else (including the caller's) are skipped.
+/// Returns the number of frames freed.
+pub fn reclaim(want: usize) -> usize {
+    let has_free_slot = SWAP_AREAS
+        .lock(here!())
+        .iter()
+        .flatten()
+        .any(|a| !a.closing && a.inuse < a.pages);
+    if !has_free_slot {
+        return 0;
+    }
+
+    let mut freed = 0;
+    for (_, lproc) in GlobalLProcManager::all() {
+        if freed >= want {
+            break;
+        }
+        freed += lproc.try_with_mut_memory(|m| m.swap_out(want - freed)).unwrap_or(0);
+    }
+    debug!("swap reclaim: want {}, freed {}", want, freed);
+    freed
+}
+
+/// (usable, in use) slots of all swap areas
+pub fn swap_pages() -> (usize, usize) {
+    SWAP_AREAS.lock(here!()).iter().flatten().fold((0, 0), |(total, inuse), a| {
+        (total + a.pages, inuse + a.inuse)
+    })
+}
+
+/// Content of /proc/swaps
+pub fn proc_swaps() -> String {
+    let mut content = String::from("Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority\n");
+    for area in SWAP_AREAS.lock(here!()).iter().flatten() {
+        let kind = match area.file.attr_kind() {
+            VfsFileKind::BlockDevice => "partition",
+            _ => "file",
+        };
+        content.push_str(&format!(
+            "{:<40}{}\t{}\t\t{}\t\t{}\n",
+            area.path,
+            kind,
+            area.pages * PAGE_SIZE / 1024,
+            area.inuse * PAGE_SIZE / 1024,
+            area.prio
+        ));
+    }
+    content
+}
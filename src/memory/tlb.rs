@@ -0,0 +1,178 @@
+//! Cross-hart TLB shootdown
+//!
+//! Threads sharing one `UserSpace` may run on different harts at the same time,
+//! so changing a page table and flushing only the local TLB is not enough:
+//! other harts may still hold stale translations to frames we are about to free.
+//!
+//! Every hart records the page table it currently runs on in its `HartLocalInfo`.
+//! The initiator of a shootdown flushes its own TLB, posts a request, sends an IPI
+//! (SBI `send_ipi`) to every other hart that has the same page table active,
+//! and spins until all of them acknowledged. Only after that it is safe to
+//! release the frames which were mapped in the flushed range.
+//!
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use log::trace;
+use riscv::register::{sie, sip};
+
+use crate::{
+    arch,
+    consts::PAGE_SIZE,
+    executor::hart_local::{self, HART_MAX},
+    memory::address::{PhysAddr4K, VirtAddr, VirtAddrRange},
+};
+
+/// Flushing page by page is slower than flushing everything for large ranges
+const FLUSH_ALL_THRESHOLD: usize = 64 * PAGE_SIZE;
+
+/// Marker size for "flush the whole address space"
+const FLUSH_ALL: usize = usize::MAX;
+
+struct ShootdownRequest {
+    pgt: AtomicUsize,
+    start: AtomicUsize,
+    size: AtomicUsize,
+    // Harts that have not acknowledged yet
+    pending_ack: AtomicUsize,
+}
+
+static REQUEST: ShootdownRequest = ShootdownRequest {
+    pgt: AtomicUsize::new(0),
+    start: AtomicUsize::new(0),
+    size: AtomicUsize::new(0),
+    pending_ack: AtomicUsize::new(0),
+};
+
+/// Only one request can be in flight at a time
+static REQUEST_BUSY: AtomicBool = AtomicBool::new(false);
+
+const NOT_PENDING: AtomicBool = AtomicBool::new(false);
+/// Set by the initiator for every target hart, cleared by the target when it flushed
+static HART_PENDING: [AtomicBool; HART_MAX] = [NOT_PENDING; HART_MAX];
+
+/// Enable supervisor software interrupt on current hart,
+/// which is used to receive shootdown IPIs
+pub fn init() {
+    unsafe { sie::set_ssoft() };
+}
+
+/// Flush a single page of the address space `pgt` on all harts
+pub fn shootdown_page(pgt: PhysAddr4K, vaddr: VirtAddr) {
+    shootdown(pgt, vaddr.round_down().bits(), PAGE_SIZE);
+}
+
+/// Flush a range of the address space `pgt` on all harts
+pub fn shootdown_range(pgt: PhysAddr4K, range: VirtAddrRange) {
+    let start = range.start.round_down().bits();
+    let end = range.end.round_up().bits();
+    if end <= start {
+        return;
+    }
+    shootdown(pgt, start, end - start);
+}
+
+/// Flush the whole address space `pgt` on all harts
+pub fn shootdown_all(pgt: PhysAddr4K) {
+    shootdown(pgt, 0, FLUSH_ALL);
+}
+
+fn flush_local(start: usize, size: usize) {
+    if size == FLUSH_ALL || size > FLUSH_ALL_THRESHOLD {
+        arch::flush_tlb_all();
+    } else {
+        for vaddr in (start..start + size).step_by(PAGE_SIZE) {
+            arch::flush_tlb(vaddr);
+        }
+    }
+}
+
+fn shootdown(pgt: PhysAddr4K, start: usize, size: usize) {
+    let pgt = pgt.bits();
+    let hart_id = arch::get_hart_id();
+
+    // Switching page table always flushes the whole TLB,
+    // so only the harts running on `pgt` now can hold stale entries of it
+    if hart_local::hart_active_pgt(hart_id) == pgt {
+        flush_local(start, size);
+    }
+
+    let mut target_mask = 0usize;
+    for target in (0..HART_MAX).filter(|&h| h != hart_id) {
+        if hart_local::hart_active_pgt(target) == pgt {
+            target_mask |= 1 << target;
+        }
+    }
+    if target_mask == 0 {
+        return;
+    }
+
+    // Keep serving requests for ourself while waiting,
+    // otherwise two harts shooting down each other would dead lock
+    while REQUEST_BUSY
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        handle_pending_shootdown();
+        core::hint::spin_loop();
+    }
+
+    trace!(
+        "TLB shootdown from hart {}: pgt {:#x}, [{:#x}, +{:#x}), targets {:#b}",
+        hart_id,
+        pgt,
+        start,
+        size,
+        target_mask
+    );
+
+    REQUEST.pgt.store(pgt, Ordering::Relaxed);
+    REQUEST.start.store(start, Ordering::Relaxed);
+    REQUEST.size.store(size, Ordering::Relaxed);
+    REQUEST.pending_ack.store(target_mask.count_ones() as usize, Ordering::Relaxed);
+    for target in 0..HART_MAX {
+        if target_mask & (1 << target) != 0 {
+            HART_PENDING[target].store(true, Ordering::Release);
+        }
+    }
+
+    let ret = sbi_rt::send_ipi(target_mask, 0);
+    debug_assert!(ret.is_ok(), "send_ipi failed: {:?}", ret);
+
+    // Frames must not be released before every target stops using them
+    while REQUEST.pending_ack.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+
+    REQUEST_BUSY.store(false, Ordering::Release);
+}
+
+/// Serve the shootdown request for current hart, if any.
+///
+/// Called from the supervisor software interrupt handler,
+/// and also polled by spinning locks, since a hart spinning with
+/// interrupt disabled can not receive the IPI.
+pub fn handle_pending_shootdown() {
+    let hart_id = arch::get_hart_id();
+    if !HART_PENDING[hart_id].load(Ordering::Acquire) {
+        return;
+    }
+
+    let pgt = REQUEST.pgt.load(Ordering::Relaxed);
+    // If we have switched away from `pgt`, the switch has flushed it already
+    if hart_local::hart_active_pgt(hart_id) == pgt {
+        flush_local(
+            REQUEST.start.load(Ordering::Relaxed),
+            REQUEST.size.load(Ordering::Relaxed),
+        );
+    }
+
+    HART_PENDING[hart_id].store(false, Ordering::Relaxed);
+    REQUEST.pending_ack.fetch_sub(1, Ordering::Release);
+}
+
+/// Supervisor software interrupt handler
+pub fn ipi_handler() {
+    unsafe { sip::clear_ssoft() };
+    handle_pending_shootdown();
+}
@@ -0,0 +1,46 @@
+    .section .text
+    .global __copy_user
+
+# 在内核与用户空间之间拷贝内存
+# 访问用户内存时发生的异常会在 __ex_table 中找到修复入口, 跳转到 3 处返回
+#
+# a0: dst, a1: src, a2: len
+# 返回没有拷贝的字节数, 0 表示全部拷贝完成
+__copy_user:
+    # 源和目的都 8 字节对齐时, 先按 8 字节拷贝
+    or   t1, a0, a1
+    andi t1, t1, 7
+    bnez t1, 4f
+    li   t1, 8
+5:
+    bltu a2, t1, 4f
+1:
+    ld   t0, 0(a1)
+2:
+    sd   t0, 0(a0)
+    addi a0, a0, 8
+    addi a1, a1, 8
+    addi a2, a2, -8
+    j    5b
+    # 剩下的按字节拷贝
+4:
+    beqz a2, 3f
+6:
+    lb   t0, 0(a1)
+7:
+    sb   t0, 0(a0)
+    addi a0, a0, 1
+    addi a1, a1, 1
+    addi a2, a2, -1
+    j    4b
+3:
+    mv   a0, a2
+    ret
+
+    .pushsection __ex_table, "a"
+    .balign 8
+    .dword 1b, 3b
+    .dword 2b, 3b
+    .dword 6b, 3b
+    .dword 7b, 3b
+    .popsection
@@ -5,16 +5,24 @@
 
 #![allow(dead_code)]
 use crate::{
+    consts::{address_space::U_SEG_END, PAGE_SIZE},
+    executor::hart_local::within_sum,
     memory::address::VirtAddr,
-    process::{lproc::LightProcess, user_space::user_area::PageFaultAccessType},
+    process::{
+        lproc::LightProcess,
+        oom,
+        user_space::user_area::{PageFaultAccessType, PageFaultErr},
+    },
     tools::errors::{SysError, SysResult},
     trap::trap::{set_kernel_trap, set_kernel_user_rw_trap, will_read_fail, will_write_fail},
 };
 use alloc::{string::String, sync::Arc, vec::Vec};
 use core::{
+    arch::global_asm,
     fmt::{Display, Formatter},
     intrinsics::size_of,
     marker::PhantomData,
+    mem::MaybeUninit,
     ops::ControlFlow,
 };
 
@@ -127,32 +135,22 @@ impl<T: Clone + Copy + 'static, P: Read> UserPtr<T, P> {
     #[must_use]
     pub fn read(self, lproc: &Arc<LightProcess>) -> SysResult<T> {
         debug_assert!(self.not_null());
-        lproc.just_ensure_user_area(
-            VirtAddr::from(self.as_usize()),
-            size_of::<T>(),
-            PageFaultAccessType::RO,
-        )?;
-        let res = unsafe { core::ptr::read(self.ptr) };
-        Ok(res)
+        let mut res = MaybeUninit::<T>::uninit();
+        let buf =
+            unsafe { core::slice::from_raw_parts_mut(res.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        lproc.copy_from_user(buf, self.as_usize())?;
+        Ok(unsafe { res.assume_init() })
     }
 
     #[must_use]
     pub fn read_array(self, n: usize, lproc: &Arc<LightProcess>) -> SysResult<Vec<T>> {
         debug_assert!(n == 0 || self.not_null());
-        lproc.just_ensure_user_area(
-            VirtAddr::from(self.as_usize()),
-            size_of::<T>() * n,
-            PageFaultAccessType::RO,
-        )?;
-
-        let mut res = Vec::with_capacity(n);
-        unsafe {
-            let ptr = self.ptr;
-            for i in 0..n {
-                res.push(ptr.add(i).read());
-            }
-        }
-
+        let mut res = Vec::<T>::with_capacity(n);
+        let buf = unsafe {
+            core::slice::from_raw_parts_mut(res.as_mut_ptr() as *mut u8, size_of::<T>() * n)
+        };
+        lproc.copy_from_user(buf, self.as_usize())?;
+        unsafe { res.set_len(n) };
         Ok(res)
     }
 }
@@ -162,31 +160,21 @@ impl<P: Read> UserPtr<u8, P> {
     pub fn read_cstr(self, lproc: &Arc<LightProcess>) -> SysResult<String> {
         debug_assert!(self.not_null());
         let mut str = String::with_capacity(32);
-        let mut has_ended = false;
-
-        lproc.ensure_user_area(
-            VirtAddr::from(self.as_usize()),
-            usize::MAX,
-            PageFaultAccessType::RO,
-            |beg, len| unsafe {
-                let mut ptr = beg.as_mut_ptr();
-                for _ in 0..len {
-                    let c = ptr.read();
-                    if c == 0 {
-                        has_ended = true;
-                        return ControlFlow::Break(None);
-                    }
-                    str.push(c as char);
-                    ptr = ptr.offset(1);
+        let mut buf = [0u8; 256];
+
+        // 分块拷贝, 每块不跨页, 以免越过字符串结尾访问到没有映射的页
+        let mut curr_vaddr = self.as_usize();
+        loop {
+            let page_left = PAGE_SIZE - curr_vaddr % PAGE_SIZE;
+            let chunk = &mut buf[..page_left.min(256)];
+            lproc.copy_from_user(chunk, curr_vaddr)?;
+            for &c in chunk.iter() {
+                if c == 0 {
+                    return Ok(str);
                 }
-                ControlFlow::Continue(())
-            },
-        )?;
-
-        if has_ended {
-            Ok(str)
-        } else {
-            Err(SysError::EINVAL)
+                str.push(c as char);
+            }
+            curr_vaddr += chunk.len();
         }
     }
 }
@@ -226,31 +214,18 @@ impl<T: Clone + Copy + 'static, P: Write> UserPtr<T, P> {
     #[must_use]
     pub fn write(self, lproc: &Arc<LightProcess>, val: T) -> SysResult<()> {
         debug_assert!(self.not_null());
-        lproc.just_ensure_user_area(
-            VirtAddr::from(self.as_usize()),
-            size_of::<T>(),
-            PageFaultAccessType::RW,
-        )?;
-        unsafe { core::ptr::write(self.ptr, val) };
-        Ok(())
+        let buf =
+            unsafe { core::slice::from_raw_parts(&val as *const T as *const u8, size_of::<T>()) };
+        lproc.copy_to_user(self.as_usize(), buf)
     }
 
     #[must_use]
     pub fn write_array(self, lproc: &Arc<LightProcess>, val: &[T]) -> SysResult<()> {
         debug_assert!(self.not_null());
-        lproc.just_ensure_user_area(
-            VirtAddr::from(self.as_usize()),
-            size_of::<T>() * val.len(),
-            PageFaultAccessType::RW,
-        )?;
-        unsafe {
-            let mut ptr = self.ptr;
-            for &v in val {
-                ptr.write(v);
-                ptr = ptr.offset(1);
-            }
-        }
-        Ok(())
+        let buf = unsafe {
+            core::slice::from_raw_parts(val.as_ptr() as *const u8, size_of::<T>() * val.len())
+        };
+        lproc.copy_to_user(self.as_usize(), buf)
     }
 }
 
@@ -260,57 +235,16 @@ impl<P: Write> UserPtr<u8, P> {
     pub unsafe fn write_as_bytes<U>(self, lproc: &Arc<LightProcess>, val: &U) -> SysResult<()> {
         debug_assert!(self.not_null());
 
-        let len = size_of::<U>();
-        lproc.just_ensure_user_area(
-            VirtAddr::from(self.as_usize()),
-            len,
-            PageFaultAccessType::RW,
-        )?;
-
-        unsafe {
-            let view = core::slice::from_raw_parts(val as *const U as *const u8, len);
-            let mut ptr = self.ptr as *mut u8;
-            for &c in view {
-                ptr.write(c);
-                ptr = ptr.offset(1);
-            }
-        }
-        Ok(())
+        let view =
+            unsafe { core::slice::from_raw_parts(val as *const U as *const u8, size_of::<U>()) };
+        lproc.copy_to_user(self.as_usize(), view)
     }
 
     #[must_use]
     pub fn write_cstr(self, lproc: &Arc<LightProcess>, val: &str) -> SysResult<()> {
         debug_assert!(self.not_null());
-
-        let mut str = val.as_bytes();
-        let mut has_filled_zero = false;
-
-        lproc.ensure_user_area(
-            VirtAddr::from(self.as_usize()),
-            val.len() + 1,
-            PageFaultAccessType::RW,
-            |beg, len| unsafe {
-                let mut ptr = beg.as_mut_ptr();
-                let writable_len = len.min(str.len());
-                for _ in 0..writable_len {
-                    let c = str[0];
-                    str = &str[1..];
-                    ptr.write(c);
-                    ptr = ptr.offset(1);
-                }
-                if str.is_empty() && writable_len < len {
-                    ptr.write(0);
-                    has_filled_zero = true;
-                }
-                ControlFlow::Continue(())
-            },
-        )?;
-
-        if has_filled_zero {
-            Ok(())
-        } else {
-            Err(SysError::EINVAL)
-        }
+        lproc.copy_to_user(self.as_usize(), val.as_bytes())?;
+        lproc.copy_to_user(self.as_usize() + val.len(), &[0])
     }
 }
 
@@ -326,7 +260,86 @@ impl<T: Clone + Copy + 'static, P: Policy> Display for UserPtr<T, P> {
     }
 }
 
+global_asm!(include_str!("user_copy.asm"));
+
+extern "C" {
+    /// 返回没有拷贝的字节数
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+}
+
+fn page_fault_errno(e: PageFaultErr) -> SysError {
+    match e {
+        PageFaultErr::KernelOOM => {
+            oom::out_of_memory();
+            SysError::ENOMEM
+        }
+        _ => SysError::EFAULT,
+    }
+}
+
 impl LightProcess {
+    /// 从用户地址 src 拷贝 dst.len() 字节.
+    /// 调用者不能持有该进程的地址空间锁
+    pub fn copy_from_user(&self, dst: &mut [u8], src: usize) -> SysResult<()> {
+        let len = dst.len();
+        self.copy_user(
+            dst.as_mut_ptr(),
+            src as *const u8,
+            len,
+            src,
+            PageFaultAccessType::RO,
+        )
+    }
+
+    /// 将 src 拷贝到用户地址 dst.
+    /// 调用者不能持有该进程的地址空间锁
+    pub fn copy_to_user(&self, dst: usize, src: &[u8]) -> SysResult<()> {
+        self.copy_user(
+            dst as *mut u8,
+            src.as_ptr(),
+            src.len(),
+            dst,
+            PageFaultAccessType::RW,
+        )
+    }
+
+    /// 直接拷贝, 访问用户内存缺页时 (lazy alloc, CoW, 换出, 或者被别的线程 munmap 了)
+    /// 由 __ex_table 修复, 拷贝中止. 此时按普通的缺页处理, 成功就从断点继续, 否则返回 EFAULT
+    fn copy_user(
+        &self,
+        dst: *mut u8,
+        src: *const u8,
+        len: usize,
+        user_begin: usize,
+        access: PageFaultAccessType,
+    ) -> SysResult<()> {
+        // __copy_user 在 SUM 下运行, 不检查的话用户可以借系统调用读写内核
+        match user_begin.checked_add(len) {
+            Some(user_end) if user_end <= U_SEG_END => {}
+            _ => return Err(SysError::EFAULT),
+        }
+        let mut done = 0;
+        let mut last_fault = None;
+        while done < len {
+            let left =
+                within_sum(|| unsafe { __copy_user(dst.add(done), src.add(done), len - done) });
+            done = len - left;
+            if left == 0 {
+                break;
+            }
+
+            let fault_vaddr = VirtAddr::from(user_begin + done);
+            // 缺页处理成功了却还在同一处出错, 不要死循环
+            if last_fault == Some(fault_vaddr) {
+                return Err(SysError::EFAULT);
+            }
+            last_fault = Some(fault_vaddr);
+            self.with_mut_memory(|m| m.handle_pagefault(fault_vaddr, access))
+                .map_err(page_fault_errno)?;
+        }
+        Ok(())
+    }
+
     #[inline(always)]
     fn just_ensure_user_area(
         &self,
@@ -363,7 +376,7 @@ impl LightProcess {
         while readable_len < len {
             if test_fn(curr_vaddr.bits()) {
                 self.with_mut_memory(|m| m.handle_pagefault(curr_vaddr, access))
-                    .map_err(|_| SysError::EFAULT)?;
+                    .map_err(page_fault_errno)?;
             }
 
             let next_page_beg = curr_vaddr.round_down().next_page().into();
@@ -70,11 +70,12 @@ pub struct AuxVector {
 }
 
 impl AuxVector {
-    pub fn from_elf_analyzer(elf: &ElfAnalyzer, begin_addr: VirtAddr) -> Self {
+    /// begin_addr 是装载后的第一个段的地址, load_base 是 PIE 程序的装载基址
+    pub fn from_elf_analyzer(elf: &ElfAnalyzer, begin_addr: VirtAddr, load_base: usize) -> Self {
         let pgm_header_addr = (begin_addr + elf.pt2.ph_offset as usize).bits();
         let pgm_header_cnt = elf.pt2.ph_count as usize;
         let pgm_header_entry_size = elf.pt2.ph_entry_size as usize;
-        let entry_point = elf.pt2.entry_point as usize;
+        let entry_point = load_base + elf.pt2.entry_point as usize;
 
         let mut auxv = Vec::new();
         macro_rules! push_elm {
@@ -115,6 +116,14 @@ impl AuxVector {
 
         AuxVector { vec: auxv }
     }
+
+    pub fn len(&self) -> usize {
+        self.vec.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vec.is_empty()
+    }
 }
 
 // make AuxVector iterable
@@ -10,6 +10,7 @@ use crate::tools::sync_ptr::SyncMutPtr;
 pub use aux_vector::AuxElement;
 pub use aux_vector::AuxVector;
 use core::panic;
+use xmas_elf::header;
 
 impl LightProcess {
     /// Return: entry_point, auxv
@@ -22,14 +23,21 @@ impl LightProcess {
         let elf = parse(&elf_file).await?;
         let mut elf_begin = VirtAddr::from(usize::MAX);
 
+        // 位置无关的程序 (ET_DYN) 装载到 pie_base, 开启 ASLR 时是随机的
+        let load_base = if elf.pt2.type_.as_type() == header::Type::SharedObject {
+            self.with_memory(|m| m.areas().layout().pie_base.bits())
+        } else {
+            0
+        };
+
         for i in 0..elf.ph_count() {
             let ph = elf.program_header(i).await?;
             if ph.type_()? != PhType::Load {
                 continue;
             }
 
-            let mem_begin = VirtAddr::from(ph.virtual_addr as usize);
-            let mem_end = VirtAddr::from((ph.virtual_addr + ph.mem_size) as usize);
+            let mem_begin = VirtAddr::from(load_base + ph.virtual_addr as usize);
+            let mem_end = VirtAddr::from(load_base + (ph.virtual_addr + ph.mem_size) as usize);
 
             let aligned_mem_begin = mem_begin.floor().into();
             let aligned_mem_end = mem_end.ceil().into();
@@ -65,8 +73,8 @@ impl LightProcess {
                     m.areas_mut()
                         .insert_mmap_anonymous_at(aligned_mem_begin, aligned_mem_size, area_perm)
                         .unwrap();
-                    m.force_map_area(aligned_mem_begin);
-                });
+                    m.force_map_area(aligned_mem_begin)
+                })?;
 
                 // fill file contents or zeros
                 let _auto_sum = AutoSUM::new();
@@ -116,8 +124,8 @@ impl LightProcess {
             panic!("Elf has no loadable segment!");
         }
 
-        let auxv = AuxVector::from_elf_analyzer(&elf, elf_begin);
-        let entry_point = VirtAddr::from(elf.pt2.entry_point as usize);
+        let auxv = AuxVector::from_elf_analyzer(&elf, elf_begin, load_base);
+        let entry_point = VirtAddr::from(load_base + elf.pt2.entry_point as usize);
 
         Ok((entry_point, auxv))
     }
@@ -0,0 +1,127 @@
+//! System V IPC 中的消息队列和信号量
+//!
+//! 共享内存见 user_space/shm_mgr.rs, 三者都用 [`IpcIds`] 管理 key 和 id.
+//! id 是全局的, 不属于某个进程, fork 出来的子进程可以直接使用父进程拿到的 id.
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::future::Future;
+
+use crate::{
+    executor::{
+        hart_local::get_curr_lproc,
+        util_futures::{get_waker, join_future},
+    },
+    process::lproc::EventKind,
+    tools::{
+        errors::{SysError, SysResult},
+        Either,
+    },
+};
+
+pub mod msg;
+pub mod sem;
+
+pub type IpcKey = usize;
+pub type IpcId = usize;
+
+/* Mode bits for `msgget', `semget', and `shmget'.  */
+/// Create key if key does not exist.
+pub const IPC_CREAT: usize = 0o1000;
+/// Fail if key exists.
+pub const IPC_EXCL: usize = 0o2000;
+/// Return error on wait.
+pub const IPC_NOWAIT: usize = 0o4000;
+
+/* Special key values.  */
+/// Private key.
+pub const IPC_PRIVATE: IpcKey = 0;
+
+/// xxxget 的 flags 中表示权限的部分
+pub const IPC_MODE_MASK: usize = 0o777;
+
+/// 一类 IPC 对象的 key -> id 和 id -> 对象的映射
+pub struct IpcIds<T> {
+    next_id: IpcId,
+    keys: BTreeMap<IpcKey, IpcId>,
+    objs: BTreeMap<IpcId, Arc<T>>,
+}
+
+impl<T> IpcIds<T> {
+    pub const fn new() -> Self {
+        Self {
+            next_id: 0,
+            keys: BTreeMap::new(),
+            objs: BTreeMap::new(),
+        }
+    }
+
+    /// xxxget 的公共部分: 按 key 找到已有的对象, 或者在 IPC_CREAT 时用 create 新建一个.
+    /// create 的参数是新对象的 id. 返回 (id, 是否是新建的)
+    pub fn get_or_create(
+        &mut self,
+        key: IpcKey,
+        flags: usize,
+        create: impl FnOnce(IpcId) -> SysResult<T>,
+    ) -> SysResult<(IpcId, bool)> {
+        if key != IPC_PRIVATE {
+            if let Some(&id) = self.keys.get(&key) {
+                if flags & IPC_CREAT != 0 && flags & IPC_EXCL != 0 {
+                    return Err(SysError::EEXIST);
+                }
+                return Ok((id, false));
+            }
+            if flags & IPC_CREAT == 0 {
+                return Err(SysError::ENOENT);
+            }
+        }
+
+        // id 不复用, 避免删除之后旧的 id 指向新的对象
+        let id = self.next_id;
+        let obj = create(id)?;
+        self.next_id += 1;
+        if key != IPC_PRIVATE {
+            self.keys.insert(key, id);
+        }
+        self.objs.insert(id, Arc::new(obj));
+        Ok((id, true))
+    }
+
+    pub fn get(&self, id: IpcId) -> Option<Arc<T>> {
+        self.objs.get(&id).cloned()
+    }
+
+    /// 删除 key 的映射, 之后同一个 key 会创建新的对象, 但旧对象仍能通过 id 找到
+    pub fn remove_key(&mut self, id: IpcId) {
+        self.keys.retain(|_, v| *v != id);
+    }
+
+    pub fn remove(&mut self, id: IpcId) -> Option<Arc<T>> {
+        self.remove_key(id);
+        self.objs.remove(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.objs.len()
+    }
+
+    /// 最大的 id, 用于 IPC_INFO 的返回值
+    pub fn max_id(&self) -> usize {
+        self.objs.keys().next_back().copied().unwrap_or(0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&IpcId, &Arc<T>)> {
+        self.objs.iter()
+    }
+}
+
+/// 等待 f 完成, 期间收到信号则返回 EINTR
+pub async fn await_or_signal<T>(f: impl Future<Output = SysResult<T>>) -> SysResult<T> {
+    let lproc = get_curr_lproc().unwrap();
+    let waker = get_waker().await;
+    let interrupt_future = lproc.wait_for_event(EventKind::Signal, &waker);
+
+    match join_future(f, interrupt_future).await {
+        Either::Left(r) => r,
+        Either::Right(_) => Err(SysError::EINTR),
+    }
+}
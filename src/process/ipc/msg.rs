@@ -0,0 +1,269 @@
+//! System V 消息队列
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{future::poll_fn, task::Poll};
+
+use super::{IpcId, IpcIds, IpcKey, IPC_MODE_MASK};
+use crate::{
+    sync::{SpinNoIrqLock, WaitQueue},
+    timer::get_time_sec,
+    tools::errors::{SysError, SysResult},
+};
+
+/// 单条消息的最大长度
+pub const MSGMAX: usize = 8192;
+/// 队列默认的最大字节数
+pub const MSGMNB: usize = 16384;
+/// 最多的队列数
+pub const MSGMNI: usize = 32000;
+
+/// 消息比缓冲区长时截断, 而不是返回 E2BIG
+pub const MSG_NOERROR: usize = 0o10000;
+/// 取第一条类型不等于 msgtyp 的消息
+pub const MSG_EXCEPT: usize = 0o20000;
+/// 按下标复制消息而不取走, 不支持
+pub const MSG_COPY: usize = 0o40000;
+
+static MSG_QUEUES: SpinNoIrqLock<IpcIds<MsgQueue>> = SpinNoIrqLock::new(IpcIds::new());
+
+pub struct Message {
+    pub mtype: isize,
+    pub data: Vec<u8>,
+}
+
+/// msqid_ds 中需要的信息
+pub struct MsgQueueStat {
+    pub key: IpcKey,
+    pub mode: u32,
+    pub stime: usize,
+    pub rtime: usize,
+    pub ctime: usize,
+    pub cbytes: usize,
+    pub qnum: usize,
+    pub qbytes: usize,
+    pub lspid: usize,
+    pub lrpid: usize,
+}
+
+/// 所有队列的统计信息, 用于 MSG_INFO
+pub struct MsgInfo {
+    pub queues: usize,
+    pub messages: usize,
+    pub bytes: usize,
+    pub max_id: usize,
+}
+
+struct MsgQueueInner {
+    key: IpcKey,
+    mode: u32,
+    messages: VecDeque<Message>,
+    /// 队列中消息的总字节数
+    cbytes: usize,
+    qbytes: usize,
+    stime: usize,
+    rtime: usize,
+    ctime: usize,
+    lspid: usize,
+    lrpid: usize,
+    removed: bool,
+    /// 等待队列有空间的发送者
+    senders: WaitQueue,
+    /// 等待消息的接收者
+    receivers: WaitQueue,
+}
+
+impl MsgQueueInner {
+    /// 按 msgrcv 的规则找到要取的消息
+    fn find(&self, msgtyp: isize, except: bool) -> Option<usize> {
+        if msgtyp == 0 {
+            return if self.messages.is_empty() {
+                None
+            } else {
+                Some(0)
+            };
+        }
+        if msgtyp > 0 {
+            return self.messages.iter().position(|m| (m.mtype == msgtyp) != except);
+        }
+        // 类型不超过 |msgtyp| 的消息中类型最小的第一条
+        let bound = msgtyp.checked_neg().unwrap_or(isize::MAX);
+        let mut found: Option<(usize, isize)> = None;
+        for (i, m) in self.messages.iter().enumerate() {
+            if m.mtype <= bound && found.map_or(true, |(_, t)| m.mtype < t) {
+                found = Some((i, m.mtype));
+            }
+        }
+        found.map(|(i, _)| i)
+    }
+}
+
+pub struct MsgQueue {
+    inner: SpinNoIrqLock<MsgQueueInner>,
+}
+
+impl MsgQueue {
+    fn new(key: IpcKey, mode: u32) -> Self {
+        Self {
+            inner: SpinNoIrqLock::new(MsgQueueInner {
+                key,
+                mode,
+                messages: VecDeque::new(),
+                cbytes: 0,
+                qbytes: MSGMNB,
+                stime: 0,
+                rtime: 0,
+                ctime: get_time_sec(),
+                lspid: 0,
+                lrpid: 0,
+                removed: false,
+                senders: WaitQueue::new(),
+                receivers: WaitQueue::new(),
+            }),
+        }
+    }
+
+    pub fn stat(&self) -> MsgQueueStat {
+        let inner = self.inner.lock(here!());
+        MsgQueueStat {
+            key: inner.key,
+            mode: inner.mode,
+            stime: inner.stime,
+            rtime: inner.rtime,
+            ctime: inner.ctime,
+            cbytes: inner.cbytes,
+            qnum: inner.messages.len(),
+            qbytes: inner.qbytes,
+            lspid: inner.lspid,
+            lrpid: inner.lrpid,
+        }
+    }
+
+    /// IPC_SET. 我们没有 CAP_SYS_RESOURCE, 同 Linux 中的普通进程, qbytes 不能超过 MSGMNB,
+    /// 否则一个队列就能占掉任意多的内核内存
+    pub fn set(&self, mode: u32, qbytes: usize) -> SysResult {
+        if qbytes > MSGMNB {
+            return Err(SysError::EPERM);
+        }
+        let mut inner = self.inner.lock(here!());
+        inner.mode = mode & IPC_MODE_MASK as u32;
+        inner.qbytes = qbytes;
+        inner.ctime = get_time_sec();
+        // 队列可能变大了
+        inner.senders.wake_all();
+        Ok(())
+    }
+
+    /// 发送一条消息, 队列满时阻塞, 除非 nowait
+    pub async fn send(&self, msg: Message, nowait: bool, pid: usize) -> SysResult {
+        let mut msg = Some(msg);
+        poll_fn(|cx| {
+            let mut inner = self.inner.lock(here!());
+            if inner.removed {
+                return Poll::Ready(Err(SysError::EIDRM));
+            }
+            let len = msg.as_ref().unwrap().data.len();
+            // 同 Linux, 消息数也不能超过 qbytes, 否则 0 字节的消息能无限地发
+            if inner.cbytes + len <= inner.qbytes && inner.messages.len() + 1 <= inner.qbytes {
+                inner.cbytes += len;
+                inner.messages.push_back(msg.take().unwrap());
+                inner.stime = get_time_sec();
+                inner.lspid = pid;
+                inner.receivers.wake_all();
+                return Poll::Ready(Ok(()));
+            }
+            if nowait {
+                return Poll::Ready(Err(SysError::EAGAIN));
+            }
+            inner.senders.register(cx.waker());
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// 按 msgtyp 取一条消息, 没有时阻塞, 除非 IPC_NOWAIT.
+    /// 消息比 maxsize 长时返回 E2BIG, 除非 MSG_NOERROR, 此时截断
+    pub async fn receive(
+        &self,
+        msgtyp: isize,
+        maxsize: usize,
+        flags: usize,
+        pid: usize,
+    ) -> SysResult<Message> {
+        let nowait = flags & super::IPC_NOWAIT != 0;
+        let except = flags & MSG_EXCEPT != 0;
+        poll_fn(|cx| {
+            let mut inner = self.inner.lock(here!());
+            if inner.removed {
+                return Poll::Ready(Err(SysError::EIDRM));
+            }
+            if let Some(idx) = inner.find(msgtyp, except) {
+                if inner.messages[idx].data.len() > maxsize && flags & MSG_NOERROR == 0 {
+                    return Poll::Ready(Err(SysError::E2BIG));
+                }
+                let mut msg = inner.messages.remove(idx).unwrap();
+                inner.cbytes -= msg.data.len();
+                inner.rtime = get_time_sec();
+                inner.lrpid = pid;
+                inner.senders.wake_all();
+                msg.data.truncate(maxsize);
+                return Poll::Ready(Ok(msg));
+            }
+            if nowait {
+                return Poll::Ready(Err(SysError::ENOMSG));
+            }
+            inner.receivers.register(cx.waker());
+            Poll::Pending
+        })
+        .await
+    }
+
+    fn mark_removed(&self) {
+        let mut inner = self.inner.lock(here!());
+        inner.removed = true;
+        inner.messages.clear();
+        inner.cbytes = 0;
+        inner.senders.wake_all();
+        inner.receivers.wake_all();
+    }
+}
+
+pub fn msgget(key: IpcKey, flags: usize) -> SysResult<IpcId> {
+    let mut queues = MSG_QUEUES.lock(here!());
+    let full = queues.len() >= MSGMNI;
+    let mode = (flags & IPC_MODE_MASK) as u32;
+    queues
+        .get_or_create(key, flags, |_| {
+            if full {
+                return Err(SysError::ENOSPC);
+            }
+            Ok(MsgQueue::new(key, mode))
+        })
+        .map(|(id, _)| id)
+}
+
+pub fn get(id: IpcId) -> SysResult<Arc<MsgQueue>> {
+    MSG_QUEUES.lock(here!()).get(id).ok_or(SysError::EINVAL)
+}
+
+/// IPC_RMID: 立即删除, 正在等待的进程返回 EIDRM
+pub fn remove(id: IpcId) -> SysResult {
+    let queue = MSG_QUEUES.lock(here!()).remove(id).ok_or(SysError::EINVAL)?;
+    queue.mark_removed();
+    Ok(())
+}
+
+pub fn info() -> MsgInfo {
+    let queues = MSG_QUEUES.lock(here!());
+    let mut info = MsgInfo {
+        queues: queues.len(),
+        messages: 0,
+        bytes: 0,
+        max_id: queues.max_id(),
+    };
+    for (_, queue) in queues.iter() {
+        let inner = queue.inner.lock(here!());
+        info.messages += inner.messages.len();
+        info.bytes += inner.cbytes;
+    }
+    info
+}
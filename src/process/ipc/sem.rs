@@ -0,0 +1,375 @@
+//! System V 信号量
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use super::{IpcId, IpcIds, IpcKey, IPC_MODE_MASK, IPC_NOWAIT};
+use crate::{
+    sync::{SpinNoIrqLock, WaitQueue},
+    timer::get_time_sec,
+    tools::errors::{SysError, SysResult},
+};
+
+/// 一个集合中最多的信号量数
+pub const SEMMSL: usize = 32000;
+/// 最多的集合数
+pub const SEMMNI: usize = 32000;
+/// 一次 semop 最多的操作数
+pub const SEMOPM: usize = 500;
+/// 信号量的最大值
+pub const SEMVMX: i32 = 32767;
+
+/// 进程退出时撤销这次操作
+pub const SEM_UNDO: i16 = 0x1000;
+
+static SEM_SETS: SpinNoIrqLock<IpcIds<SemSet>> = SpinNoIrqLock::new(IpcIds::new());
+
+/// 用户传入的 struct sembuf
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SemBuf {
+    pub sem_num: u16,
+    pub sem_op: i16,
+    pub sem_flg: i16,
+}
+
+#[derive(Clone, Copy)]
+struct Sem {
+    val: i32,
+    /// 最后一次操作它的进程
+    pid: usize,
+    /// 等待它增大的进程数
+    ncnt: usize,
+    /// 等待它变为 0 的进程数
+    zcnt: usize,
+}
+
+/// semid_ds 中需要的信息
+pub struct SemSetStat {
+    pub key: IpcKey,
+    pub mode: u32,
+    pub otime: usize,
+    pub ctime: usize,
+    pub nsems: usize,
+}
+
+/// 所有集合的统计信息, 用于 SEM_INFO
+pub struct SemInfo {
+    pub sets: usize,
+    pub sems: usize,
+    pub max_id: usize,
+}
+
+struct SemSetInner {
+    key: IpcKey,
+    mode: u32,
+    sems: Vec<Sem>,
+    otime: usize,
+    ctime: usize,
+    removed: bool,
+    /// 任何一个信号量变化时都唤醒所有等待者, 让它们重新尝试
+    waiters: WaitQueue,
+}
+
+impl SemSetInner {
+    /// 原子地执行一组操作: 要么全部完成, 要么一个都不做.
+    /// 返回 Some((sem_num, 是否在等待变为 0)) 表示需要阻塞
+    fn try_apply(&mut self, sops: &[SemBuf], pid: usize) -> SysResult<Option<(usize, bool)>> {
+        let mut vals: Vec<i32> = self.sems.iter().map(|s| s.val).collect();
+        for sop in sops {
+            let num = sop.sem_num as usize;
+            let op = sop.sem_op as i32;
+            let val = vals.get_mut(num).ok_or(SysError::EFBIG)?;
+            let blocked = if op > 0 {
+                if *val + op > SEMVMX {
+                    return Err(SysError::ERANGE);
+                }
+                *val += op;
+                None
+            } else if op == 0 {
+                (*val != 0).then_some((num, true))
+            } else if *val + op < 0 {
+                Some((num, false))
+            } else {
+                *val += op;
+                None
+            };
+            if blocked.is_some() {
+                if sop.sem_flg as usize & IPC_NOWAIT != 0 {
+                    return Err(SysError::EAGAIN);
+                }
+                return Ok(blocked);
+            }
+        }
+
+        let mut changed = false;
+        for sop in sops {
+            let sem = &mut self.sems[sop.sem_num as usize];
+            changed |= sem.val != vals[sop.sem_num as usize];
+            sem.val = vals[sop.sem_num as usize];
+            sem.pid = pid;
+        }
+        self.otime = get_time_sec();
+        if changed {
+            self.waiters.wake_all();
+        }
+        Ok(None)
+    }
+}
+
+pub struct SemSet {
+    inner: SpinNoIrqLock<SemSetInner>,
+}
+
+impl SemSet {
+    fn new(key: IpcKey, mode: u32, nsems: usize) -> Self {
+        let sem = Sem {
+            val: 0,
+            pid: 0,
+            ncnt: 0,
+            zcnt: 0,
+        };
+        Self {
+            inner: SpinNoIrqLock::new(SemSetInner {
+                key,
+                mode,
+                sems: alloc::vec![sem; nsems],
+                otime: 0,
+                ctime: get_time_sec(),
+                removed: false,
+                waiters: WaitQueue::new(),
+            }),
+        }
+    }
+
+    pub fn nsems(&self) -> usize {
+        self.inner.lock(here!()).sems.len()
+    }
+
+    pub fn stat(&self) -> SemSetStat {
+        let inner = self.inner.lock(here!());
+        SemSetStat {
+            key: inner.key,
+            mode: inner.mode,
+            otime: inner.otime,
+            ctime: inner.ctime,
+            nsems: inner.sems.len(),
+        }
+    }
+
+    pub fn set_mode(&self, mode: u32) {
+        let mut inner = self.inner.lock(here!());
+        inner.mode = mode & IPC_MODE_MASK as u32;
+        inner.ctime = get_time_sec();
+    }
+
+    fn with_sem<T>(&self, num: usize, f: impl FnOnce(&Sem) -> T) -> SysResult<T> {
+        let inner = self.inner.lock(here!());
+        inner.sems.get(num).map(f).ok_or(SysError::EINVAL)
+    }
+
+    pub fn getval(&self, num: usize) -> SysResult<i32> {
+        self.with_sem(num, |s| s.val)
+    }
+    pub fn getpid(&self, num: usize) -> SysResult<usize> {
+        self.with_sem(num, |s| s.pid)
+    }
+    pub fn getncnt(&self, num: usize) -> SysResult<usize> {
+        self.with_sem(num, |s| s.ncnt)
+    }
+    pub fn getzcnt(&self, num: usize) -> SysResult<usize> {
+        self.with_sem(num, |s| s.zcnt)
+    }
+    pub fn getall(&self) -> Vec<u16> {
+        self.inner.lock(here!()).sems.iter().map(|s| s.val as u16).collect()
+    }
+
+    pub fn setval(&self, num: usize, val: i32, pid: usize) -> SysResult {
+        if !(0..=SEMVMX).contains(&val) {
+            return Err(SysError::ERANGE);
+        }
+        let mut inner = self.inner.lock(here!());
+        let sem = inner.sems.get_mut(num).ok_or(SysError::EINVAL)?;
+        sem.val = val;
+        sem.pid = pid;
+        inner.ctime = get_time_sec();
+        inner.waiters.wake_all();
+        Ok(())
+    }
+
+    pub fn setall(&self, vals: &[u16], pid: usize) -> SysResult {
+        if vals.iter().any(|&v| v as i32 > SEMVMX) {
+            return Err(SysError::ERANGE);
+        }
+        let mut inner = self.inner.lock(here!());
+        for (sem, &val) in inner.sems.iter_mut().zip(vals) {
+            sem.val = val as i32;
+            sem.pid = pid;
+        }
+        inner.ctime = get_time_sec();
+        inner.waiters.wake_all();
+        Ok(())
+    }
+
+    /// 执行一组操作, 不能立即完成时阻塞, 直到可以完成或者集合被删除
+    pub fn semop<'a>(&'a self, sops: &'a [SemBuf], pid: usize) -> SemopFuture<'a> {
+        SemopFuture {
+            set: self,
+            sops,
+            pid,
+            blocked: None,
+        }
+    }
+
+    fn mark_removed(&self) {
+        let mut inner = self.inner.lock(here!());
+        inner.removed = true;
+        inner.waiters.wake_all();
+    }
+}
+
+pub struct SemopFuture<'a> {
+    set: &'a SemSet,
+    sops: &'a [SemBuf],
+    pid: usize,
+    /// 正在等待的信号量, 计入它的 ncnt 或 zcnt
+    blocked: Option<(usize, bool)>,
+}
+
+impl SemopFuture<'_> {
+    fn unblock(&mut self, inner: &mut SemSetInner) {
+        if let Some((num, zero)) = self.blocked.take() {
+            let sem = &mut inner.sems[num];
+            if zero {
+                sem.zcnt -= 1;
+            } else {
+                sem.ncnt -= 1;
+            }
+        }
+    }
+}
+
+impl Future for SemopFuture<'_> {
+    type Output = SysResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let set = this.set;
+        let mut inner = set.inner.lock(here!());
+        this.unblock(&mut inner);
+        if inner.removed {
+            return Poll::Ready(Err(SysError::EIDRM));
+        }
+        match inner.try_apply(this.sops, this.pid) {
+            Err(e) => Poll::Ready(Err(e)),
+            Ok(None) => Poll::Ready(Ok(())),
+            Ok(Some((num, zero))) => {
+                let sem = &mut inner.sems[num];
+                if zero {
+                    sem.zcnt += 1;
+                } else {
+                    sem.ncnt += 1;
+                }
+                this.blocked = Some((num, zero));
+                inner.waiters.register(cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for SemopFuture<'_> {
+    fn drop(&mut self) {
+        // 超时或者被信号打断
+        if self.blocked.is_some() {
+            let set = self.set;
+            let mut inner = set.inner.lock(here!());
+            self.unblock(&mut inner);
+        }
+    }
+}
+
+/// 一个进程因 SEM_UNDO 积累的调整值, 进程退出时加回到信号量上.
+/// 每个线程各有一份, 在线程退出时撤销, 不支持 CLONE_SYSVSEM
+#[derive(Default)]
+pub struct SemUndo {
+    adj: BTreeMap<(IpcId, usize), i32>,
+}
+
+impl SemUndo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录一组成功完成的操作中带 SEM_UNDO 的部分
+    pub fn record(&mut self, id: IpcId, sops: &[SemBuf]) {
+        for sop in sops.iter().filter(|sop| sop.sem_flg & SEM_UNDO != 0) {
+            let key = (id, sop.sem_num as usize);
+            let adj = self.adj.entry(key).or_insert(0);
+            *adj -= sop.sem_op as i32;
+            if *adj == 0 {
+                self.adj.remove(&key);
+            }
+        }
+    }
+
+    /// 撤销所有记录的操作, 已经被删除的集合直接跳过
+    pub fn apply(self, pid: usize) {
+        for ((id, num), adj) in self.adj {
+            let set = match SEM_SETS.lock(here!()).get(id) {
+                Some(set) => set,
+                None => continue,
+            };
+            let mut inner = set.inner.lock(here!());
+            if let Some(sem) = inner.sems.get_mut(num) {
+                // 同 Linux, 结果超出范围时截断而不是报错
+                sem.val = (sem.val + adj).clamp(0, SEMVMX);
+                sem.pid = pid;
+                inner.waiters.wake_all();
+            }
+        }
+    }
+}
+
+pub fn semget(key: IpcKey, nsems: usize, flags: usize) -> SysResult<IpcId> {
+    let mut sets = SEM_SETS.lock(here!());
+    let full = sets.len() >= SEMMNI;
+    let mode = (flags & IPC_MODE_MASK) as u32;
+    let (id, created) = sets.get_or_create(key, flags, |_| {
+        if nsems == 0 || nsems > SEMMSL {
+            return Err(SysError::EINVAL);
+        }
+        if full {
+            return Err(SysError::ENOSPC);
+        }
+        Ok(SemSet::new(key, mode, nsems))
+    })?;
+    if !created && nsems > sets.get(id).unwrap().nsems() {
+        return Err(SysError::EINVAL);
+    }
+    Ok(id)
+}
+
+pub fn get(id: IpcId) -> SysResult<Arc<SemSet>> {
+    SEM_SETS.lock(here!()).get(id).ok_or(SysError::EINVAL)
+}
+
+/// IPC_RMID: 立即删除, 正在等待的进程返回 EIDRM
+pub fn remove(id: IpcId) -> SysResult {
+    let set = SEM_SETS.lock(here!()).remove(id).ok_or(SysError::EINVAL)?;
+    set.mark_removed();
+    Ok(())
+}
+
+pub fn info() -> SemInfo {
+    let sets = SEM_SETS.lock(here!());
+    SemInfo {
+        sets: sets.len(),
+        sems: sets.iter().map(|(_, set)| set.nsems()).sum(),
+        max_id: sets.max_id(),
+    }
+}
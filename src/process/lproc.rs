@@ -1,13 +1,11 @@
 use super::{
+    ipc::sem::SemUndo,
     lproc_mgr::GlobalLProcManager,
     pid::{alloc_pid, Pid, PidHandler},
-    user_space::{
-        shm_mgr::{Shm, ShmId},
-        UserSpace,
-    },
+    user_space::UserSpace,
 };
 use crate::{
-    arch::{self, switch_page_table},
+    arch::switch_page_table,
     consts::PAGE_SIZE,
     executor::hart_local::within_sum,
     fs::{
@@ -15,7 +13,9 @@ use crate::{
         new_vfs::{path::Path, top::VfsFileRef},
     },
     memory::address::VirtAddr,
-    process::user_space::{init_stack, THREAD_STACK_SIZE},
+    process::user_space::{
+        aslr, init_stack, init_stack_size, user_area::UserAreaPerm, THREAD_STACK_SIZE,
+    },
     signal,
     sync::SpinNoIrqLock,
     syscall,
@@ -33,7 +33,7 @@ use alloc::{
 };
 use core::{
     cell::SyncUnsafeCell,
-    sync::atomic::{AtomicI32, AtomicUsize, Ordering},
+    sync::atomic::{AtomicI32, AtomicIsize, AtomicUsize, Ordering},
     task::Waker,
 };
 use futures::Future;
@@ -82,6 +82,8 @@ pub struct Signal {
     // User process signal handler
     // expected the same life cycle as above signal_processing, share the same lock
     pub signal_handler: BTreeMap<usize, VirtAddr>,
+    // 待处理信号的附加信息, 如 SIGSEGV 出错的地址
+    pub signal_info: BTreeMap<usize, signal::SigInfo>,
     // Store the previous context when processing signal
     pub before_signal_context: SyncUnsafeCell<Box<UKContext, Global>>,
 }
@@ -92,6 +94,7 @@ impl Clone for Signal {
             signal_pending: self.signal_pending.clone(),
             signal_processing: self.signal_processing.clone(),
             signal_handler: self.signal_handler.clone(),
+            signal_info: self.signal_info.clone(),
             before_signal_context: SyncUnsafeCell::new(unsafe { UKContext::new_uninit() }),
         }
     }
@@ -103,6 +106,7 @@ impl Signal {
             signal_pending: signal::SignalSet::empty(),
             signal_processing: signal::SignalSet::empty(),
             signal_handler: BTreeMap::new(),
+            signal_info: BTreeMap::new(),
             before_signal_context: SyncUnsafeCell::new(unsafe { UKContext::new_uninit() }),
         }
     }
@@ -123,7 +127,12 @@ pub struct LightProcess {
     status: SpinNoIrqLock<SyncUnsafeCell<ProcessStatus>>,
     timer: SpinNoIrqLock<TimeStat>,
     exit_code: AtomicI32,
-    shm_table: SpinNoIrqLock<ShmTable>,
+    // SEM_UNDO 积累的调整值, 退出时撤销
+    sem_undo: SpinNoIrqLock<SemUndo>,
+    // 见 /proc/[pid]/oom_score_adj, 范围 [-1000, 1000]
+    oom_score_adj: AtomicIsize,
+    // 见 personality(2), 目前只用到 ADDR_NO_RANDOMIZE
+    personality: AtomicUsize,
 
     // Per thread information
     private_info: SpinNoIrqLock<PrivateInfo>,
@@ -161,6 +170,10 @@ macro_rules! with_ {
             pub fn [<with_mut_ $name>]<T>(&self, f: impl FnOnce(&mut $ty) -> T) -> T {
                 f(&mut self.$name.lock(here!()))
             }
+            /// 若锁已被占用 (可能就是被自己占用), 直接返回 None
+            pub fn [<try_with_mut_ $name>]<T>(&self, f: impl FnOnce(&mut $ty) -> T) -> Option<T> {
+                self.$name.try_lock().map(|mut guard| f(&mut guard))
+            }
         }
     };
 }
@@ -222,8 +235,24 @@ impl LightProcess {
         self.signal.lock(here!()).signal_pending.set(signal_set, true);
         self.with_mut_event_bus(|bus| bus.notify(EventKind::Signal));
     }
+    /// 发送带附加信息的信号
+    pub fn send_signal_info(self: &Arc<Self>, info: signal::SigInfo) {
+        let signum = info.si_signo as usize;
+        self.signal.lock(here!()).signal_info.insert(signum, info);
+        self.send_signal(signum);
+    }
+    /// 取出信号的附加信息, 没有的话就当作是 kill 发来的
+    pub fn take_signal_info(&self, signum: usize) -> signal::SigInfo {
+        self.signal
+            .lock(here!())
+            .signal_info
+            .remove(&signum)
+            .unwrap_or_else(|| signal::SigInfo::new(signum, signal::SI_USER))
+    }
     pub fn clear_signal(self: &Arc<Self>, signal: signal::SignalSet) {
-        self.signal.lock(here!()).signal_pending.set(signal, false);
+        let mut s = self.signal.lock(here!());
+        s.signal_pending.set(signal, false);
+        s.signal_info.retain(|signum, _| !signal.contain_sig(*signum));
     }
 
     pub fn context(&self) -> &mut UKContext {
@@ -238,6 +267,22 @@ impl LightProcess {
         self.exit_code.store(code, Ordering::SeqCst);
     }
 
+    pub fn oom_score_adj(&self) -> isize {
+        self.oom_score_adj.load(Ordering::Relaxed)
+    }
+
+    pub fn set_oom_score_adj(&self, adj: isize) {
+        self.oom_score_adj.store(adj, Ordering::Relaxed);
+    }
+
+    pub fn personality(&self) -> usize {
+        self.personality.load(Ordering::Relaxed)
+    }
+
+    pub fn set_personality(&self, persona: usize) {
+        self.personality.store(persona, Ordering::Relaxed);
+    }
+
     pub fn children(&self) -> Vec<Arc<LightProcess>> {
         self.children.lock(here!()).clone()
     }
@@ -285,6 +330,16 @@ impl LightProcess {
         );
         // release all fd
         self.with_mut_fdtable(|f| f.release_all());
+
+        // 撤销带 SEM_UNDO 的信号量操作
+        let undo = self.with_mut_sem_undo(core::mem::take);
+        undo.apply(self.id().into());
+
+        // 没有别的线程共享地址空间时, 现在就释放用户内存, 而不是等到父进程 wait
+        // 这样 OOM killer 杀掉的进程能马上把内存还回来
+        if Arc::strong_count(&self.memory) == 1 {
+            self.with_mut_memory(|m| m.release_all());
+        }
     }
 
     with_!(group, ThreadGroup);
@@ -293,7 +348,7 @@ impl LightProcess {
     with_!(fdtable, FdTable);
     with_!(private_info, PrivateInfo);
     with_!(procfs_info, ProcFSInfo);
-    with_!(shm_table, ShmTable);
+    with_!(sem_undo, SemUndo);
     with_!(signal, Signal);
     with_!(timer_map, BTreeMap<usize, bool>);
     with_!(event_bus, EventBus);
@@ -316,7 +371,9 @@ impl LightProcess {
             status: SpinNoIrqLock::new(SyncUnsafeCell::new(ProcessStatus::UNINIT)),
             timer: SpinNoIrqLock::new(TimeStat::new()),
             exit_code: AtomicI32::new(0),
-            shm_table: SpinNoIrqLock::new(ShmTable::new_empty()),
+            sem_undo: SpinNoIrqLock::new(SemUndo::new()),
+            oom_score_adj: AtomicIsize::new(0),
+            personality: AtomicUsize::new(0),
             group: new_shared(ThreadGroup::new_empty()),
             memory: new_shared(UserSpace::new()),
             fsinfo: new_shared(FsInfo::new()),
@@ -333,14 +390,23 @@ impl LightProcess {
         new
     }
 
-    // Create new userspace
+    /// Create new userspace.
+    /// 旧的地址空间一开始就被丢弃了, 返回错误时进程已经无法继续运行
     pub async fn do_exec(
         self: &Arc<Self>,
         elf_file: VfsFileRef,
         args: Vec<String>,
         envp: Vec<String>,
-    ) {
-        let new_userspace = UserSpace::new();
+    ) -> SysResult {
+        // RLIMIT_STACK 和 RLIMIT_MEMLOCK 在 exec 后保留, 内存锁则不保留
+        let (stack_limit, memlock_limit) =
+            self.with_memory(|m| (m.areas().stack_limit(), m.areas().memlock_limit()));
+        let mut new_userspace = UserSpace::new();
+        new_userspace.areas_mut().set_stack_limit(stack_limit);
+        new_userspace.areas_mut().set_memlock_limit(memlock_limit);
+        if aslr::randomize() && self.personality() & aslr::ADDR_NO_RANDOMIZE == 0 {
+            new_userspace.areas_mut().set_layout(aslr::Layout::random());
+        }
 
         let page_table_paddr = new_userspace.page_table.root_paddr();
         debug!(
@@ -355,16 +421,20 @@ impl LightProcess {
         log::debug!("do_exec: new userspace switched");
 
         // 把 elf 的 segment 映射到用户空间
-        let (entry_point, auxv) = self.parse_and_map_elf_file_async(elf_file).await.unwrap();
+        let (entry_point, auxv) = self.parse_and_map_elf_file_async(elf_file).await?;
         debug!("Parse ELF file done.");
 
         // 分配栈
         let stack_begin = self.with_mut_memory(|m| {
-            let stack_begin = m.areas_mut().alloc_stack(THREAD_STACK_SIZE);
-            // Force map since kernel need to init it
-            m.force_map_area(stack_begin);
-            stack_begin
-        });
+            // 内核要往栈顶写参数, 只映射这一部分, 其余的等缺页时再分配
+            let init_size = init_stack_size(&args, &envp, &auxv);
+            let stack_begin = m.areas_mut().alloc_main_stack(init_size);
+            m.force_map_range(
+                stack_begin - init_size..stack_begin + 1,
+                UserAreaPerm::WRITE,
+            )
+            .map(|()| stack_begin)
+        })?;
 
         debug!("Stack alloc done.");
         // 将参数，auxv 和环境变量放到栈上
@@ -375,21 +445,21 @@ impl LightProcess {
         let sepc = entry_point.bits();
         self.context().init_user(sp, sepc, sstatus::read(), argc, argv, envp);
 
-        // 分配堆
-        // TODO: 改成彻底的 lazy alloc
+        // 分配堆, 堆是匿名映射, 缺页时才分配
         self.with_mut_memory(|m| m.areas_mut().insert_heap(PAGE_SIZE));
         debug!("Heap alloc done.");
 
         // 设置状态为 READY
         self.set_status(ProcessStatus::READY);
         debug!("User init done.");
+        Ok(())
     }
 
     pub fn do_clone(
         self: Arc<Self>,
         flags: syscall::CloneFlags,
         user_stack_begin: Option<VirtAddr>,
-    ) -> Arc<Self> {
+    ) -> SysResult<Arc<Self>> {
         use syscall::CloneFlags;
 
         let id = alloc_pid();
@@ -401,6 +471,8 @@ impl LightProcess {
         let status = SpinNoIrqLock::new(SyncUnsafeCell::new(self.status()));
         let timer = SpinNoIrqLock::new(TimeStat::new());
         let exit_code = AtomicI32::new(self.exit_code());
+        let oom_score_adj = AtomicIsize::new(self.oom_score_adj());
+        let personality = AtomicUsize::new(self.personality());
 
         let parent;
         let children;
@@ -424,10 +496,8 @@ impl LightProcess {
         } else {
             // 这里应该可以优化
             // Noop, 这里不能优化，如果延迟cow，其他线程如果对vm做了修改，不能保证符合clone的语意
-            memory = new_shared(self.with_mut_memory(|m| m.clone_cow()));
-            // Flush both old and new process
-            arch::flush_tlb_all();
-            // TODO: avoid flushing global entries like kernel mappings
+            // clone_cow will shoot down the TLB of old process on all harts
+            memory = new_shared(self.with_mut_memory(|m| m.clone_cow())?);
         }
         let old_memory = self.memory.lock(here!());
 
@@ -445,10 +515,11 @@ impl LightProcess {
             new_sp = sp;
         } else if flags.contains(CloneFlags::VM) {
             new_stack_top = new_memory.areas_mut().alloc_stack(THREAD_STACK_SIZE);
-            new_memory.force_map_area(new_stack_top);
 
             let stack_length = old_stack_top - old_sp;
             new_sp = new_stack_top - stack_length;
+            // 只映射要拷贝的部分
+            new_memory.force_map_range(new_sp..new_stack_top + 1, UserAreaPerm::WRITE)?;
             // Copy old stack to new stack
             // [old_sp, old_stack_top] => [new_sp, new_stack_top]
             within_sum(|| {
@@ -511,7 +582,9 @@ impl LightProcess {
             status,
             timer,
             exit_code,
-            shm_table: SpinNoIrqLock::new(ShmTable::new_empty()),
+            sem_undo: SpinNoIrqLock::new(SemUndo::new()),
+            oom_score_adj,
+            personality,
             group,
             memory,
             fsinfo,
@@ -533,7 +606,7 @@ impl LightProcess {
             self.add_child(new.clone());
         }
 
-        new
+        Ok(new)
     }
 
     pub async fn wait_for_event(self: &Arc<Self>, listen_for: EventKind, waker: &Waker) {
@@ -738,44 +811,6 @@ impl Drop for FdTable {
     }
 }
 
-pub struct ShmTable {
-    pool: UsizePool,
-    table: BTreeMap<ShmId, Arc<Shm>>,
-}
-
-impl ShmTable {
-    pub fn new_empty() -> Self {
-        Self {
-            pool: UsizePool::new(0),
-            table: BTreeMap::new(),
-        }
-    }
-
-    pub fn alloc(&mut self, shm: Arc<Shm>) -> ShmId {
-        let id = self.pool.get();
-        self.table.insert(id, shm);
-        id
-    }
-
-    pub fn get(&self, id: ShmId) -> Option<Arc<Shm>> {
-        self.table.get(&id).cloned()
-    }
-
-    pub fn remove(&mut self, id: ShmId) -> Option<Arc<Shm>> {
-        self.table.remove(&id).map(|shm| {
-            self.pool.release(id);
-            shm
-        })
-    }
-
-    pub fn release_all(&mut self) {
-        self.table.retain(|no, _| {
-            self.pool.release(*no);
-            false
-        })
-    }
-}
-
 pub struct ThreadGroup {
     members: BTreeMap<Pid, Arc<LightProcess>>,
     leader: Option<Weak<LightProcess>>,
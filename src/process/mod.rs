@@ -1,5 +1,6 @@
 use self::{lproc::LightProcess, userloop::OutermostFuture};
 use crate::{
+    cmdline,
     executor::{self, block_on},
     fs::{
         self,
@@ -9,12 +10,15 @@ use crate::{
 use alloc::{
     string::{String, ToString},
     sync::Arc,
+    vec,
     vec::Vec,
 };
 
 pub mod elf;
+pub mod ipc;
 pub mod lproc;
 pub mod lproc_mgr;
+pub mod oom;
 pub mod pid;
 pub mod user_space;
 pub mod userloop;
@@ -22,22 +26,42 @@ pub mod userloop;
 pub fn spawn_proc_from_file(path: Path, file: VfsFileRef) {
     let lproc = LightProcess::new();
 
-    block_on(lproc.do_exec(file, Vec::new(), Vec::new()));
+    block_on(lproc.do_exec(file, Vec::new(), Vec::new())).expect("failed to exec");
     lproc.with_mut_procfs_info(|info| info.exe_path = Some(path));
     spawn_proc(lproc);
 }
 
 pub fn spawn_init() {
-    // Currently, we use busybox sh as the init process.
-
+    // init= in the kernel command line overrides the default init process,
+    // which is busybox sh.
     let root_dir = fs::get_root_dir();
-    let busybox = block_on(root_dir.lookup("busybox")).expect("Read busybox failed");
-
-    let args = ["busybox", "sh"]
-        .to_vec()
-        .into_iter()
-        .map(|s: &str| s.to_string())
-        .collect::<Vec<_>>();
+    let cmdline = cmdline::get();
+    let init = cmdline.init.as_ref().and_then(|init| {
+        let path = Path::from_string(init.clone()).ok()?;
+        match block_on(root_dir.resolve(&path)) {
+            Ok(file) => Some((init, path, file)),
+            Err(e) => {
+                log::error!("Run init {} failed: {:?}, fall back to busybox sh", init, e);
+                None
+            }
+        }
+    });
+    let (exe_path, exe, args) = match init {
+        Some((init, path, file)) => {
+            let mut args = vec![init.clone()];
+            args.extend(cmdline.init_args.iter().cloned());
+            (path, file, args)
+        }
+        None => {
+            let busybox = block_on(root_dir.lookup("busybox")).expect("Read busybox failed");
+            let args = ["busybox", "sh"]
+                .to_vec()
+                .into_iter()
+                .map(|s: &str| s.to_string())
+                .collect::<Vec<_>>();
+            (Path::from("/busybox"), busybox, args)
+        }
+    };
 
     // Some necessary environment variables.
     let mut envp = Vec::new();
@@ -60,8 +84,8 @@ pub fn spawn_init() {
     envp.push(String::from("PATH=/"));
 
     let lproc = LightProcess::new();
-    block_on(lproc.do_exec(busybox, args, envp));
-    lproc.with_mut_procfs_info(|info| info.exe_path = Some(Path::from("/busybox")));
+    block_on(lproc.do_exec(exe, args, envp)).expect("failed to exec init");
+    lproc.with_mut_procfs_info(|info| info.exe_path = Some(exe_path));
     spawn_proc(lproc);
 }
 
@@ -0,0 +1,102 @@
+//! OOM killer
+//!
+//! 物理页用完, 并且回收页缓存和换出都无济于事时, 挑一个进程杀掉.
+//! 和 Linux 一样, 进程的 badness 为它占用的页数 (常驻页 + 换出页 + 页表页),
+//! 再加上 oom_score_adj * 总页数 / 1000. oom_score_adj 为 -1000 的进程永远不会被选中.
+
+use super::{
+    lproc::{LightProcess, ProcessStatus},
+    lproc_mgr::GlobalLProcManager,
+};
+use crate::{
+    consts::PAGE_SIZE,
+    memory::{frame, swap},
+    signal::SignalSet,
+};
+use alloc::{sync::Arc, vec::Vec};
+use log::warn;
+
+pub const OOM_SCORE_ADJ_MIN: isize = -1000;
+pub const OOM_SCORE_ADJ_MAX: isize = 1000;
+
+/// 物理页和 swap 槽位的总数
+fn total_pages() -> usize {
+    (frame::total_frames() + swap::swap_pages().0).max(1)
+}
+
+fn is_alive(lproc: &LightProcess) -> bool {
+    !matches!(
+        lproc.status(),
+        ProcessStatus::ZOMBIE | ProcessStatus::STOPPED
+    )
+}
+
+/// 已经被杀, 但还没有退出
+fn is_dying(lproc: &LightProcess) -> bool {
+    is_alive(lproc) && lproc.signal_pending().contains(SignalSet::SIGKILL)
+}
+
+/// 进程的 badness, 不可杀时返回 None
+fn badness(lproc: &LightProcess, total: usize) -> Option<isize> {
+    let adj = lproc.oom_score_adj();
+    if adj == OOM_SCORE_ADJ_MIN {
+        return None;
+    }
+    let usage = lproc.with_memory(|m| m.mem_usage());
+    let points = usage.total() as isize + adj * total as isize / 1000;
+    Some(points.max(1))
+}
+
+/// /proc/[pid]/oom_score, 取值 [0, 2000]
+pub fn oom_score(lproc: &LightProcess) -> usize {
+    let total = total_pages();
+    match badness(lproc, total) {
+        Some(points) => points as usize * 1000 / total,
+        None => 0,
+    }
+}
+
+/// 杀掉 badness 最高的进程, 返回是否有进程正在因此退出.
+/// 调用者不能持有任何进程的地址空间锁
+pub fn out_of_memory() -> bool {
+    let procs = GlobalLProcManager::all();
+    // 上一个被杀的进程还没退出, 等它把内存还回来就好, 不要再杀一个
+    if procs.iter().any(|(_, lproc)| is_dying(lproc)) {
+        return true;
+    }
+
+    let total = total_pages();
+    let victim = procs
+        .iter()
+        .filter(|(pid, lproc)| *pid != 1 && is_alive(lproc))
+        .filter_map(|(_, lproc)| badness(lproc, total).map(|points| (points, lproc)))
+        .max_by_key(|(points, _)| *points);
+    let (points, victim) = match victim {
+        Some(victim) => victim,
+        None => {
+            warn!("Out of memory and no killable process");
+            return false;
+        }
+    };
+
+    let usage = victim.with_memory(|m| m.mem_usage());
+    warn!(
+        "Out of memory: killed process {:?}, rss: {} kB, swap: {} kB, pgtables: {} kB, oom_score_adj: {}, badness: {}",
+        victim.id(),
+        usage.resident * PAGE_SIZE / 1024,
+        usage.swapped * PAGE_SIZE / 1024,
+        usage.page_tables * PAGE_SIZE / 1024,
+        victim.oom_score_adj(),
+        points
+    );
+
+    // 同一线程组共享地址空间, 要一起杀掉
+    let mut threads: Vec<Arc<LightProcess>> = victim.with_group(|g| g.iter().cloned().collect());
+    if threads.is_empty() {
+        threads.push(victim.clone());
+    }
+    for thread in threads {
+        thread.send_signal(SignalSet::SIGKILL.get_signum());
+    }
+    true
+}
@@ -0,0 +1,74 @@
+//! 地址空间布局随机化 (ASLR)
+//!
+//! exec 时为新的地址空间随机选取栈顶, mmap 基址, 堆基址以及 PIE 程序的装载基址,
+//! 都是在原来固定的位置上偏移若干页. 可以用启动参数 norandmaps 全局关闭,
+//! 也可以用 personality(ADDR_NO_RANDOMIZE) 对单个进程关闭.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    consts::{
+        address_space::{U_SEG_FILE_BEG, U_SEG_HEAP_BEG, U_SEG_LINK_ADDR, U_SEG_STACK_END},
+        PAGE_SIZE,
+    },
+    memory::address::VirtAddr,
+    tools::random,
+};
+
+/// personality 中关闭 ASLR 的位
+pub const ADDR_NO_RANDOMIZE: usize = 0x0040000;
+
+/// 各个区域的随机偏移量的位数, 以页为单位
+const STACK_RND_BITS: u32 = 18; // 1 GiB
+const MMAP_RND_BITS: u32 = 18; // 1 GiB
+const HEAP_RND_BITS: u32 = 13; // 32 MiB
+const PIE_RND_BITS: u32 = 16; // 256 MiB
+
+static RANDOMIZE: AtomicBool = AtomicBool::new(true);
+
+/// 全局开关, 启动参数 norandmaps 时关闭
+pub fn set_randomize(enable: bool) {
+    RANDOMIZE.store(enable, Ordering::Relaxed);
+}
+
+pub fn randomize() -> bool {
+    RANDOMIZE.load(Ordering::Relaxed)
+}
+
+/// 一个地址空间中各个区域的基址
+#[derive(Clone, Copy, Debug)]
+pub struct Layout {
+    /// 主线程栈的顶端
+    pub stack_top: VirtAddr,
+    /// mmap 从这里开始找空闲区域
+    pub mmap_base: VirtAddr,
+    /// 堆的起始地址
+    pub heap_base: VirtAddr,
+    /// 位置无关 (ET_DYN) 程序的装载基址
+    pub pie_base: VirtAddr,
+}
+
+impl Layout {
+    pub const fn fixed() -> Self {
+        Self {
+            stack_top: VirtAddr::from(U_SEG_STACK_END),
+            mmap_base: VirtAddr::from(U_SEG_FILE_BEG),
+            heap_base: VirtAddr::from(U_SEG_HEAP_BEG),
+            pie_base: VirtAddr::from(U_SEG_LINK_ADDR),
+        }
+    }
+
+    pub fn random() -> Self {
+        let fixed = Self::fixed();
+        Self {
+            stack_top: fixed.stack_top - random_pages(STACK_RND_BITS),
+            mmap_base: fixed.mmap_base + random_pages(MMAP_RND_BITS),
+            heap_base: fixed.heap_base + random_pages(HEAP_RND_BITS),
+            pie_base: fixed.pie_base + random_pages(PIE_RND_BITS),
+        }
+    }
+}
+
+fn random_pages(bits: u32) -> usize {
+    random::below(1 << bits) * PAGE_SIZE
+}
@@ -1,3 +1,4 @@
+pub mod aslr;
 pub mod range_map;
 pub mod shm_mgr;
 pub mod user_area;
@@ -5,20 +6,23 @@ pub mod user_area;
 use alloc::{string::String, sync::Arc, vec::Vec};
 
 use crate::{
-    arch::flush_tlb,
     memory::{
         address::{iter_vpn, VirtAddr, VirtAddrRange},
-        pagetable::pagetable::PageTable,
+        pagetable::{
+            pagetable::PageTable,
+            pte::{PTEFlags, PageTableEntry},
+        },
+        tlb,
     },
     process::{elf::AuxElement, user_space::user_area::PageFaultAccessType},
-    tools::errors::SysResult,
+    tools::errors::{SysError, SysResult},
 };
 
 use super::{elf::AuxVector, pid::Pid};
 
 use self::{
     shm_mgr::{Shm, ShmId},
-    user_area::{PageFaultErr, UserAreaManager, UserAreaPerm},
+    user_area::{MemLock, PageFaultErr, UserAreaManager, UserAreaPerm},
 };
 use log::{debug, trace};
 
@@ -33,6 +37,17 @@ pub struct UserSpace {
     areas: UserAreaManager,
 }
 
+/// init_stack 最多会用掉的栈空间, 内核只需预先映射这么多
+pub fn init_stack_size(args: &[String], envp: &[String], auxv: &AuxVector) -> usize {
+    let strings: usize = args.iter().chain(envp.iter()).map(|s| s.len() + 1).sum();
+    // 平台标识符与随机数, 以及两次 16 字节对齐
+    let platform = 16 + 16 + 2 * 16;
+    let auxv = (auxv.len() + 1) * core::mem::size_of::<AuxElement>();
+    // argv, envp 指针 (各带一个 NULL) 与 argc
+    let ptrs = (args.len() + envp.len() + 3) * core::mem::size_of::<usize>();
+    strings + platform + auxv + ptrs
+}
+
 pub fn init_stack(
     sp_init: VirtAddr,
     args: Vec<String>,
@@ -183,15 +198,20 @@ impl UserSpace {
         shm: Arc<Shm>,
         perm: UserAreaPerm,
     ) -> SysResult<VirtAddr> {
+        let attach = shm.attach(pid);
         let (range, _) = match vaddr {
-            Some(vaddr) => self.areas.insert_shm_at(vaddr, perm, id, shm.clone()),
-            None => self.areas.insert_shm(perm, id, shm.clone()),
+            Some(vaddr) => self.areas.insert_shm_at(vaddr, perm, id, attach),
+            None => self.areas.insert_shm(perm, id, attach),
         }?;
 
         let mut vaddr4k = range.start.assert_4k();
-        for frame in shm.attach(pid) {
+        for frame in shm.frames() {
+            if let Err(e) = self.page_table.map_page(vaddr4k, *frame, perm.into()) {
+                // 撤销已经映射的部分
+                self.areas.unmap_range(&mut self.page_table, range);
+                return Err(e);
+            }
             frame.page_num().increase();
-            self.page_table.map_page(vaddr4k, *frame, perm.into());
             vaddr4k = vaddr4k.next_page();
         }
 
@@ -200,11 +220,14 @@ impl UserSpace {
 
     pub fn detach_shm(&mut self, vaddr: VirtAddr) -> SysResult {
         let range = self.areas.remove_shm(vaddr)?;
-        iter_vpn(range, |vpn| {
-            let paddr = self.page_table.unmap_page(vpn.addr());
-            paddr.page_num().decrease_and_try_dealloc();
-            flush_tlb(vaddr.bits());
+        let mut frames = Vec::new();
+        iter_vpn(range.clone(), |vpn| {
+            frames.push(self.page_table.unmap_page(vpn.addr()));
         });
+        tlb::shootdown_range(self.page_table.root_paddr(), range);
+        for paddr in frames {
+            paddr.page_num().decrease_and_try_dealloc();
+        }
         Ok(())
     }
 
@@ -222,13 +245,37 @@ impl UserSpace {
         self.areas.page_fault(&mut self.page_table, vaddr.page_num_down(), access_type)
     }
 
-    pub fn force_map_range(&mut self, range: VirtAddrRange, perm: UserAreaPerm) {
-        self.areas.force_map_range(&mut self.page_table, range, perm);
+    /// 换出至多 want 页, 返回实际释放的物理页数
+    pub fn swap_out(&mut self, want: usize) -> usize {
+        self.areas.swap_out(&mut self.page_table, want)
+    }
+
+    /// KSM 扫描一批页, 见 [`UserAreaManager::ksm_scan`]
+    pub fn ksm_scan(&mut self, start: VirtAddr, budget: &mut usize) -> Option<VirtAddr> {
+        self.areas.ksm_scan(&mut self.page_table, start, budget)
     }
 
-    pub fn force_map_buf(&mut self, buf: &[u8], perm: UserAreaPerm) {
+    /// madvise(MADV_MERGEABLE / MADV_UNMERGEABLE)
+    pub fn set_mergeable(&mut self, range: VirtAddrRange, mergeable: bool) -> SysResult {
+        self.areas.set_mergeable(range.clone(), mergeable)?;
+        if !mergeable {
+            self.areas.ksm_unmerge(&mut self.page_table, range)?;
+        }
+        Ok(())
+    }
+
+    /// 将换出到第 area_idx 个 swap 区的页全部读回来
+    pub fn swap_in_area(&mut self, area_idx: usize) -> SysResult {
+        self.areas.swap_in_area(&mut self.page_table, area_idx)
+    }
+
+    pub fn force_map_range(&mut self, range: VirtAddrRange, perm: UserAreaPerm) -> SysResult {
+        self.areas.force_map_range(&mut self.page_table, range, perm)
+    }
+
+    pub fn force_map_buf(&mut self, buf: &[u8], perm: UserAreaPerm) -> SysResult {
         if buf.is_empty() {
-            return;
+            return Ok(());
         }
         let begin = VirtAddr::from(buf.as_ptr() as usize);
         let end = begin + buf.len();
@@ -236,26 +283,126 @@ impl UserSpace {
     }
 
     /// 将 vaddr 所在的区域的所有页强制分配
-    pub fn force_map_area(&mut self, vaddr: VirtAddr) {
+    pub fn force_map_area(&mut self, vaddr: VirtAddr) -> SysResult {
         let (range, area) = self.areas.get(vaddr).unwrap();
-        self.force_map_range(range, area.perm());
+        self.force_map_range(range, area.perm())
     }
 
-    pub fn clone_cow(&mut self) -> Self {
-        Self {
+    /// 分配 range 中被锁定 (不是 MLOCK_ONFAULT) 的段的所有页, 换出的页也会被读回来
+    pub fn populate_locked(&mut self, range: VirtAddrRange) -> SysResult {
+        let todo: Vec<(VirtAddrRange, UserAreaPerm)> = self
+            .areas
+            .iter()
+            .filter(|(r, area)| {
+                area.mlock() == MemLock::Locked && r.start < range.end && range.start < r.end
+            })
+            .map(|(r, area)| (r.start.max(range.start)..r.end.min(range.end), area.perm()))
+            .collect();
+
+        for (r, perm) in todo {
+            // PROT_NONE 的段无法访问, 不用分配
+            if perm.is_empty() {
+                continue;
+            }
+            let mut result = Ok(());
+            iter_vpn(r, |vpn| {
+                if result.is_ok() {
+                    result = self.areas.page_fault(&mut self.page_table, vpn, perm.into());
+                }
+            });
+            result.map_err(|e| {
+                debug!("populate locked pages failed: {:?}", e);
+                SysError::ENOMEM
+            })?;
+        }
+        Ok(())
+    }
+
+    /// mincore: range 中的每一页是否在内存中, 被换出或者还没分配的页都不算
+    pub fn mincore(&self, range: VirtAddrRange) -> SysResult<Vec<u8>> {
+        self.areas.check_mapped(range.clone())?;
+        let mut vec = Vec::new();
+        iter_vpn(range, |vpn| {
+            vec.push(self.page_table.is_mapped(vpn) as u8);
+        });
+        Ok(vec)
+    }
+
+    pub fn clone_cow(&mut self) -> SysResult<Self> {
+        let mut new = Self {
             page_table: self
                 .page_table
-                .copy_table_and_mark_self_cow(|frame_paddr| frame_paddr.page_num().increase()),
+                .copy_table_and_mark_self_cow(|frame_paddr| frame_paddr.page_num().increase())?,
             areas: self.areas.clone(),
+        };
+        // Our pages are read-only now, but threads on other harts may still cache writable entries
+        tlb::shootdown_all(self.page_table.root_paddr());
+        // 子进程不继承内存锁
+        new.areas.munlock_all();
+        Ok(new)
+    }
+
+    /// 内存占用, 用于 OOM killer
+    pub fn mem_usage(&self) -> MemUsage {
+        let (resident, swapped) = self.page_table.count_user_pages();
+        MemUsage {
+            resident,
+            swapped,
+            page_tables: self.page_table.table_pages(),
         }
     }
 
+    /// 释放所有用户页, 页表本身留到析构时再释放, 因为它可能还在被使用
+    pub fn release_all(&mut self) {
+        self.areas.release_all(&mut self.page_table);
+    }
+
     pub fn unmap_range(&mut self, range: VirtAddrRange) {
         self.areas.unmap_range(&mut self.page_table, range);
     }
 
-    pub fn remap_range(&mut self, range: VirtAddrRange, new_perm: UserAreaPerm) {
-        self.areas.remap_range(&mut self.page_table, range, new_perm);
+    pub fn remap_range(&mut self, range: VirtAddrRange, new_perm: UserAreaPerm) -> SysResult {
+        self.areas.remap_range(&mut self.page_table, range.clone(), new_perm)?;
+        self.protect_range(range, new_perm);
+        Ok(())
+    }
+
+    /// 将范围内已经映射的页的权限修改为 new_perm, 并在所有核上刷新 TLB
+    pub fn protect_range(&mut self, range: VirtAddrRange, new_perm: UserAreaPerm) {
+        let mut changed = false;
+        iter_vpn(range.clone(), |vpn| {
+            if let Some(pte) = self.page_table.get_pte_mut_from_vpn(vpn) {
+                let mut flags = PTEFlags::from(new_perm);
+                if pte.shared() {
+                    // CoW 页仍需保持只读, 等写时再复制
+                    flags.remove(PTEFlags::W);
+                    flags.insert(PTEFlags::SHARED);
+                }
+                *pte = if new_perm.is_empty() {
+                    // PROT_NONE 的页不能是有效的叶子, 清掉 V 但保留物理页
+                    PageTableEntry::new_prot_none(pte.paddr(), flags)
+                } else {
+                    PageTableEntry::new(pte.paddr(), flags)
+                };
+                changed = true;
+            }
+        });
+        if changed {
+            tlb::shootdown_range(self.page_table.root_paddr(), range);
+        }
+    }
+}
+
+/// 单位均为页
+pub struct MemUsage {
+    pub resident: usize,
+    pub swapped: usize,
+    pub page_tables: usize,
+}
+
+impl MemUsage {
+    pub fn total(&self) -> usize {
+        self.resident + self.swapped + self.page_tables
     }
 }
 
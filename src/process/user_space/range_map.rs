@@ -303,6 +303,15 @@ impl<U: Ord + Copy, V> RangeMap<U, V> {
         Ok(())
     }
 
+    /// 向前 (虚拟地址减小方向) 扩展一个从 start 开始的段, 这个段必须存在. 扩展成功返回 Ok, 否则 Err
+    pub fn extend_front(&mut self, start: U, new_start: U) -> Result<(), ()> {
+        self.range_is_free(new_start..start)?;
+
+        let node = self.0.remove(&start).unwrap();
+        self.0.try_insert(new_start, node).ok().unwrap();
+        Ok(())
+    }
+
     /// 向后 (虚拟地址增大方向) 减少一个从 start 开始的段, 这个段必须存在.
     ///
     /// 减少成功 (长度为 0 时删除该段) 返回被删除的段的范围, 越界或超出长度返回 Err
@@ -1,95 +1,252 @@
 use crate::{
     consts::PAGE_SIZE,
+    executor::hart_local::get_curr_lproc,
     memory::{address::PhysAddr4K, frame::alloc_frame},
-    process::pid::Pid,
+    process::{
+        ipc::{IpcId, IpcIds, IpcKey, IPC_MODE_MASK},
+        pid::Pid,
+    },
     sync::SpinNoIrqLock,
+    timer::get_time_sec,
     tools::errors::{SysError, SysResult},
 };
-use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
-use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::{sync::Arc, vec::Vec};
+
+/// 段的最小大小
+pub const SHMMIN: usize = 1;
+/// 段的最大大小
+pub const SHMMAX: usize = 1 << 30;
+/// 最多的段数
+pub const SHMMNI: usize = 4096;
+/// 所有段的总页数上限
+pub const SHMALL: usize = SHMMAX / PAGE_SIZE;
+
+/// 已被 IPC_RMID 标记删除, 在 shm_perm.mode 中报告
+pub const SHM_DEST: u32 = 0o1000;
 
 static GLOBAL_SHM_MGR: ShmManager = ShmManager::new();
 pub fn global_shm_mgr() -> &'static ShmManager {
     &GLOBAL_SHM_MGR
 }
 
-pub type ShmKey = usize;
-pub type ShmId = usize;
+pub type ShmKey = IpcKey;
+pub type ShmId = IpcId;
+
+/// 所有段的统计信息, 用于 SHM_INFO
+pub struct ShmInfo {
+    pub segments: usize,
+    pub pages: usize,
+    pub max_id: usize,
+}
 
 pub struct ShmManager {
-    shms: SpinNoIrqLock<BTreeMap<ShmKey, Arc<Shm>>>,
+    shms: SpinNoIrqLock<IpcIds<Shm>>,
 }
 
 impl ShmManager {
     pub const fn new() -> Self {
         Self {
-            shms: SpinNoIrqLock::new(BTreeMap::new()),
+            shms: SpinNoIrqLock::new(IpcIds::new()),
         }
     }
 
-    pub fn get(&self, key: ShmKey) -> Option<Arc<Shm>> {
-        self.shms.lock(here!()).get(&key).map(Arc::clone)
+    pub fn get(&self, id: ShmId) -> Option<Arc<Shm>> {
+        self.shms.lock(here!()).get(id)
     }
-    pub fn remove(&self, key: ShmKey) -> Option<Arc<Shm>> {
-        self.shms.lock(here!()).remove(&key)
+
+    /// shmget: 按 key 找到已有的段, 或者创建一个新的
+    pub fn get_or_create(
+        &self,
+        key: ShmKey,
+        size: usize,
+        flags: usize,
+        creator: Pid,
+    ) -> SysResult<ShmId> {
+        let mut shms = self.shms.lock(here!());
+        let full = shms.len() >= SHMMNI;
+        let (id, created) = shms.get_or_create(key, flags, |id| {
+            if !(SHMMIN..=SHMMAX).contains(&size) {
+                return Err(SysError::EINVAL);
+            }
+            if full {
+                return Err(SysError::ENOSPC);
+            }
+            Shm::alloc(id, key, size, (flags & IPC_MODE_MASK) as u32, creator)
+        })?;
+        if !created && size > shms.get(id).unwrap().size() {
+            return Err(SysError::EINVAL);
+        }
+        Ok(id)
     }
-    pub fn create(&self, key: Option<ShmKey>, size: usize, creator: Pid) -> SysResult<Arc<Shm>> {
-        let shm = Shm::alloc(size, creator)?;
-        if let Some(key) = key {
-            self.shms.lock(here!()).insert(key, shm.clone());
+
+    /// IPC_RMID: 之后 key 可以用来创建新的段.
+    /// 段本身在最后一次 detach 之后才销毁, 在此之前仍然可以通过 id 访问
+    pub fn remove(&self, id: ShmId) -> SysResult {
+        let mut shms = self.shms.lock(here!());
+        let shm = shms.get(id).ok_or(SysError::EINVAL)?;
+        let mut stat = shm.stat.lock(here!());
+        stat.removed = true;
+        stat.ctime = get_time_sec();
+        if stat.nattch == 0 {
+            drop(stat);
+            shms.remove(id);
+        } else {
+            shms.remove_key(id);
         }
-        Ok(shm)
+        Ok(())
     }
+
+    fn detach(&self, shm: &Shm) {
+        let mut shms = self.shms.lock(here!());
+        let mut stat = shm.stat.lock(here!());
+        stat.nattch -= 1;
+        stat.dtime = get_time_sec();
+        if let Some(lproc) = get_curr_lproc() {
+            stat.lpid = lproc.id();
+        }
+        if stat.removed && stat.nattch == 0 {
+            drop(stat);
+            shms.remove(shm.id);
+        }
+    }
+
+    pub fn info(&self) -> ShmInfo {
+        let shms = self.shms.lock(here!());
+        ShmInfo {
+            segments: shms.len(),
+            pages: shms.iter().map(|(_, shm)| shm.frames.len()).sum(),
+            max_id: shms.max_id(),
+        }
+    }
+}
+
+/// shmid_ds 中会变化的部分
+#[derive(Clone)]
+pub struct ShmStat {
+    pub mode: u32,
+    pub atime: usize,
+    pub dtime: usize,
+    pub ctime: usize,
+    pub lpid: Pid,
+    pub nattch: usize,
+    pub removed: bool,
 }
 
 pub struct Shm {
-    attach_cnt: AtomicUsize,
-    last_operater: SpinNoIrqLock<Pid>,
+    id: ShmId,
+    key: ShmKey,
     size: usize,
     creater: Pid,
     frames: Vec<PhysAddr4K>,
+    stat: SpinNoIrqLock<ShmStat>,
 }
 
 impl Shm {
-    pub fn alloc(size: usize, creater: Pid) -> SysResult<Arc<Self>> {
-        debug_assert!(size % PAGE_SIZE == 0);
+    fn alloc(id: ShmId, key: ShmKey, size: usize, mode: u32, creater: Pid) -> SysResult<Self> {
+        let pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
         let mut frames = Vec::new();
-        for _ in 0..(size / PAGE_SIZE) {
-            frames.push(alloc_frame().ok_or(SysError::ENOMEM)?);
+        for _ in 0..pages {
+            match alloc_frame() {
+                Some(frame) => {
+                    unsafe { frame.as_mut_page_slice().fill(0) };
+                    frames.push(frame);
+                }
+                None => {
+                    for frame in frames {
+                        frame.page_num().decrease_and_must_dealloc();
+                    }
+                    return Err(SysError::ENOMEM);
+                }
+            }
         }
-        Ok(Arc::new(Self {
-            attach_cnt: AtomicUsize::new(0),
-            last_operater: SpinNoIrqLock::new(creater),
+        Ok(Self {
+            id,
+            key,
             size,
             creater,
             frames,
-        }))
+            stat: SpinNoIrqLock::new(ShmStat {
+                mode,
+                atime: 0,
+                dtime: 0,
+                ctime: get_time_sec(),
+                lpid: creater,
+                nattch: 0,
+                removed: false,
+            }),
+        })
     }
 
+    pub fn id(&self) -> ShmId {
+        self.id
+    }
+    pub fn key(&self) -> ShmKey {
+        self.key
+    }
+    /// shmget 时要求的大小
     pub fn size(&self) -> usize {
         self.size
     }
+    /// 实际映射的大小, 按页对齐
+    pub fn mapped_size(&self) -> usize {
+        self.frames.len() * PAGE_SIZE
+    }
     pub fn creater(&self) -> Pid {
         self.creater
     }
-    pub fn last_operater(&self) -> Pid {
-        *(self.last_operater.lock(here!()))
+    pub fn stat(&self) -> ShmStat {
+        self.stat.lock(here!()).clone()
     }
-    pub fn attach_cnt(self: &Arc<Self>) -> usize {
-        self.attach_cnt.load(Ordering::Relaxed)
+
+    /// IPC_SET, 我们没有用户, 只能改权限位
+    pub fn set_mode(&self, mode: u32) {
+        let mut stat = self.stat.lock(here!());
+        stat.mode = mode & IPC_MODE_MASK as u32;
+        stat.ctime = get_time_sec();
     }
 
-    pub fn attach(&self, pid: Pid) -> impl Iterator<Item = &PhysAddr4K> {
-        self.attach_cnt.fetch_add(1, Ordering::Relaxed);
-        *(self.last_operater.lock(here!())) = pid;
+    pub fn frames(&self) -> impl Iterator<Item = &PhysAddr4K> {
         self.frames.iter()
     }
+
+    pub fn attach(self: &Arc<Self>, pid: Pid) -> ShmAttach {
+        let mut stat = self.stat.lock(here!());
+        stat.nattch += 1;
+        stat.atime = get_time_sec();
+        stat.lpid = pid;
+        ShmAttach(self.clone())
+    }
 }
 
 impl Drop for Shm {
     fn drop(&mut self) {
         for frame in self.frames.iter() {
-            frame.page_num().decrease_and_must_dealloc();
+            frame.page_num().decrease_and_try_dealloc();
         }
     }
 }
+
+/// 一次 shmat 得到的映射, 和映射它的段一起 fork 时复制, munmap, shmdt 或退出时释放.
+/// 用来维护 shm_nattch, 并在被标记删除的段最后一次 detach 时销毁它
+pub struct ShmAttach(Arc<Shm>);
+
+impl ShmAttach {
+    pub fn shm(&self) -> &Arc<Shm> {
+        &self.0
+    }
+}
+
+impl Clone for ShmAttach {
+    fn clone(&self) -> Self {
+        let mut stat = self.0.stat.lock(here!());
+        stat.nattch += 1;
+        stat.atime = get_time_sec();
+        ShmAttach(self.0.clone())
+    }
+}
+
+impl Drop for ShmAttach {
+    fn drop(&mut self) {
+        global_shm_mgr().detach(&self.0);
+    }
+}
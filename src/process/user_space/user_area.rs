@@ -1,30 +1,40 @@
 use bitflags::bitflags;
 
-use super::range_map::RangeMap;
-use crate::memory::address::{iter_vpn, VirtAddr, VirtAddr4K, VirtAddrRange};
+use super::{aslr::Layout, range_map::RangeMap};
+use crate::memory::address::{iter_vpn, round_range_vpn, VirtAddr, VirtAddr4K, VirtAddrRange};
 
 use crate::memory::{
     address::VirtPageNum,
-    frame::alloc_frame,
-    pagetable::{pagetable::PageTable, pte::PTEFlags},
+    frame::{self, alloc_frame},
+    ksm,
+    pagetable::{
+        pagetable::PageTable,
+        pte::{PTEFlags, PageTableEntry},
+    },
+    swap::{self, SwapEntry},
+    tlb,
 };
 
 use core::fmt::Debug;
 use riscv::register::scause;
 
 use crate::consts::address_space::{
-    U_SEG_FILE_BEG, U_SEG_FILE_END, U_SEG_HEAP_BEG, U_SEG_SHARE_BEG, U_SEG_SHARE_END,
-    U_SEG_STACK_BEG, U_SEG_STACK_END,
+    U_SEG_END, U_SEG_FILE_BEG, U_SEG_FILE_END, U_SEG_SHARE_BEG, U_SEG_SHARE_END, U_SEG_STACK_BEG,
+    U_SEG_STACK_END,
 };
 
-use crate::arch::{flush_tlb, get_curr_page_table_addr};
+use crate::arch::{self, get_curr_page_table_addr};
+use crate::consts::PAGE_SIZE;
 
-use super::shm_mgr::{Shm, ShmId};
+use super::shm_mgr::{ShmAttach, ShmId};
 use crate::executor::block_on;
 
-use crate::fs::new_vfs::top::{MmapKind, VfsFileRef};
+use crate::fs::{
+    memfs::shmem::ShmemWritableMap,
+    new_vfs::top::{MmapKind, VfsFileRef},
+};
 use crate::tools::errors::{SysError, SysResult};
-use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::ops::Range;
 use log::debug;
 
@@ -149,14 +159,16 @@ enum UserAreaType {
         file: VfsFileRef,
         offset: usize,
     },
-    // TODO: 共享映射区域
-    // MmapShared {
-    //     file: VfsFileRef,
-    //     offset: usize,
-    // },
+    /// 共享映射区域, 直接映射文件自己的页
+    MmapShared {
+        file: VfsFileRef,
+        offset: usize,
+        /// 段可写时持有, 防止在还能写的时候给文件加上 WRITE 封印
+        writable: Option<ShmemWritableMap>,
+    },
     Shm {
         id: ShmId,
-        shm: Arc<Shm>,
+        shm: ShmAttach,
     },
 }
 
@@ -167,6 +179,9 @@ impl Debug for UserAreaType {
             UserAreaType::MmapPrivate { file: _, offset } => {
                 write!(f, "MmapPrivate {{ offset: {offset} }}")
             }
+            UserAreaType::MmapShared { offset, .. } => {
+                write!(f, "MmapShared {{ offset: {offset} }}")
+            }
             UserAreaType::Shm { id, shm: _ } => write!(f, "Shm {{ id: {id} }}"),
         }
     }
@@ -175,31 +190,63 @@ impl Debug for UserAreaType {
 #[derive(Debug, Clone, Copy)]
 pub enum PageFaultErr {
     NoSegment,
+    /// 访问了栈下方的 guard gap, 或者栈已经超过了 RLIMIT_STACK
+    StackGuard,
     PermUnmatch,
     KernelOOM,
+    SwapIOErr,
 }
 
 unsafe impl Send for PageFaultErr {}
 unsafe impl Sync for PageFaultErr {}
 
+/// 段的 mlock 状态, 同 Linux 的 VM_LOCKED 和 VM_LOCKONFAULT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemLock {
+    None,
+    /// 锁定, 并立即分配段中所有的页
+    Locked,
+    /// 锁定, 但页仍在第一次访问时才分配 (MLOCK_ONFAULT)
+    OnFault,
+}
+
 #[derive(Clone, Debug)]
 pub struct UserArea {
     kind: UserAreaType,
     perm: UserAreaPerm,
+    /// 栈: 访问段下方不远处时自动向下扩展
+    growsdown: bool,
+    /// 被锁定的段中的页不会被换出
+    mlock: MemLock,
+    /// MADV_MERGEABLE: 其中的页会被 KSM 扫描和合并
+    mergeable: bool,
 }
 
 impl UserArea {
-    pub fn new_with_same_kind(old: UserArea, perm: UserAreaPerm) -> Self {
+    pub fn new_with_same_kind(old: UserArea, perm: UserAreaPerm) -> SysResult<Self> {
+        let mut area = old;
+        area.set_perm(perm)?;
+        Ok(area)
+    }
+
+    pub fn new_anonymous(perm: UserAreaPerm) -> Self {
         Self {
-            kind: old.kind,
+            kind: UserAreaType::MmapAnonymous,
             perm,
+            growsdown: false,
+            mlock: MemLock::None,
+            mergeable: false,
         }
     }
 
-    pub fn new_anonymous(perm: UserAreaPerm) -> Self {
+    /// 向下增长的匿名映射, 用于主线程的栈和 MAP_GROWSDOWN
+    pub fn new_growsdown(perm: UserAreaPerm) -> Self {
         Self {
             kind: UserAreaType::MmapAnonymous,
             perm,
+            growsdown: true,
+            mlock: MemLock::None,
+            mergeable: false,
         }
     }
 
@@ -207,22 +254,77 @@ impl UserArea {
         Self {
             kind: UserAreaType::MmapPrivate { file, offset },
             perm,
+            growsdown: false,
+            mlock: MemLock::None,
+            mergeable: false,
         }
     }
 
-    pub fn new_shm(perm: UserAreaPerm, id: ShmId, shm: Arc<Shm>) -> Self {
+    /// 可写的共享映射不能映射有 WRITE 或 FUTURE_WRITE 封印的文件, 返回 EPERM
+    pub fn new_shared(perm: UserAreaPerm, file: VfsFileRef, offset: usize) -> SysResult<Self> {
+        let writable = match perm.contains(UserAreaPerm::WRITE) {
+            true => Some(ShmemWritableMap::new(file.clone())?),
+            false => None,
+        };
+        Ok(Self::new_shared_with(perm, file, offset, writable))
+    }
+
+    fn new_shared_with(
+        perm: UserAreaPerm,
+        file: VfsFileRef,
+        offset: usize,
+        writable: Option<ShmemWritableMap>,
+    ) -> Self {
+        Self {
+            kind: UserAreaType::MmapShared {
+                file,
+                offset,
+                writable,
+            },
+            perm,
+            growsdown: false,
+            mlock: MemLock::None,
+            mergeable: false,
+        }
+    }
+
+    pub fn new_shm(perm: UserAreaPerm, id: ShmId, shm: ShmAttach) -> Self {
         Self {
             kind: UserAreaType::Shm { id, shm },
             perm,
+            growsdown: false,
+            mlock: MemLock::None,
+            mergeable: false,
         }
     }
 
+    pub fn growsdown(&self) -> bool {
+        self.growsdown
+    }
+
+    pub fn mlock(&self) -> MemLock {
+        self.mlock
+    }
+
+    pub fn locked(&self) -> bool {
+        self.mlock != MemLock::None
+    }
+
     pub fn perm(&self) -> UserAreaPerm {
         self.perm
     }
 
-    pub fn set_perm(&mut self, perm: UserAreaPerm) {
+    /// 同 new_shared, 把共享映射改为可写时会检查文件的封印
+    pub fn set_perm(&mut self, perm: UserAreaPerm) -> SysResult {
+        if let UserAreaType::MmapShared { file, writable, .. } = &mut self.kind {
+            if !perm.contains(UserAreaPerm::WRITE) {
+                *writable = None;
+            } else if writable.is_none() {
+                *writable = Some(ShmemWritableMap::new(file.clone())?);
+            }
+        }
         self.perm = perm;
+        Ok(())
     }
 
     pub fn page_fault(
@@ -244,6 +346,11 @@ impl UserArea {
             return Err(PageFaultErr::PermUnmatch);
         }
 
+        // 被换出的页, 读回来就好
+        if let Some(entry) = page_table.get_swap_entry(access_vpn) {
+            return self.swap_in(page_table, access_vpn, entry);
+        }
+
         // anyway we need a new frame
         let mut frame = 0.into();
         debug_assert!(frame == 0); // depress the warning of unused value
@@ -254,7 +361,24 @@ impl UserArea {
             // PTE valid is ensured
 
             log::debug!("pte flags: {:?}", pte.flags());
+            if pte.is_prot_none() {
+                // 段的权限已经改回来了, 按段的权限重新映射原来的物理页
+                let mut flags: PTEFlags = self.perm().into();
+                if pte.shared() {
+                    flags.remove(PTEFlags::W);
+                    flags.insert(PTEFlags::SHARED);
+                }
+                *pte = PageTableEntry::new(pte.paddr(), flags);
+                // 原来的 PTE 无效, 不需要刷 TLB
+                return Ok(());
+            }
             if pte.flags().match_area_perm(self.perm()) {
+                if !pte.accessed() {
+                    // 换出扫描清除了 A, 而硬件不会自己置位 A
+                    pte.set_accessed();
+                    arch::flush_tlb(access_vpn.addr().bits());
+                    return Ok(());
+                }
                 // 一次假的缺页异常
                 // 当我们不确定某个范围是否被映射了, 我们可以强行调一次 page_fault
                 // 如果它已经被映射了, 那么就会进入这个分支
@@ -264,6 +388,10 @@ impl UserArea {
 
             // must be CoW
             let pte_flags = pte.flags();
+            if !access_type.contains(PageFaultAccessType::WRITE) {
+                // 只是读一个 CoW 页 (包括零页), 等到写的时候再复制
+                return Ok(());
+            }
             debug_assert!(pte_flags.contains(PTEFlags::SHARED));
             debug_assert!(!pte_flags.contains(PTEFlags::W));
             debug_assert!(self.perm().contains(UserAreaPerm::WRITE));
@@ -272,6 +400,10 @@ impl UserArea {
             let old_frame = pte.ppn();
 
             if old_frame.is_shared() {
+                // allocate before dropping the old reference,
+                // so that the fault can be retried after OOM
+                frame = alloc_frame().ok_or(PageFaultErr::KernelOOM)?;
+
                 // must not be the last one
                 old_frame.decrease();
                 debug_assert!(!old_frame.is_free());
@@ -279,7 +411,6 @@ impl UserArea {
                 // copy the data
                 // assert we are in process's page table now
                 debug_assert!(page_table.root_paddr().bits() == get_curr_page_table_addr());
-                frame = alloc_frame().ok_or(PageFaultErr::KernelOOM)?;
                 unsafe {
                     frame.as_mut_page_slice().copy_from_slice(old_frame.addr().as_page_slice());
                 }
@@ -290,8 +421,11 @@ impl UserArea {
         } else {
             // a lazy alloc or lazy load (demand paging)
             match &self.kind {
-                // If lazy alloc, do nothing (or maybe memset it to zero?)
                 UserAreaType::MmapAnonymous => {
+                    if !access_type.contains(PageFaultAccessType::WRITE) {
+                        // 第一次读, 先映射共享的零页, 写的时候再走 CoW 分配私有页
+                        return self.map_zero_frame(page_table, access_vpn);
+                    }
                     frame = alloc_frame().ok_or(PageFaultErr::KernelOOM)?;
                     // https://man7.org/linux/man-pages/man2/mmap.2.html
                     // MAP_ANONYMOUS
@@ -304,10 +438,23 @@ impl UserArea {
                     let access_vaddr = access_vpn.addr();
                     let real_offset = offset + (access_vaddr.into() - range_begin);
                     // TODO-PERF: block on read_at
-                    frame = block_on(file.get_page(real_offset, MmapKind::Private))
-                        .expect("read file failed");
+                    frame = match block_on(file.get_page(real_offset, MmapKind::Private)) {
+                        Ok(frame) => frame,
+                        Err(SysError::ENOMEM) => return Err(PageFaultErr::KernelOOM),
+                        Err(e) => panic!("read file failed: {:?}", e),
+                    };
                     // Read length may be less than PAGE_SIZE, due to file mmap
                 }
+                // 共享映射不需要 CoW, 直接以段的权限映射文件的页
+                UserAreaType::MmapShared { file, offset, .. } => {
+                    let access_vaddr = access_vpn.addr();
+                    let real_offset = offset + (access_vaddr.into() - range_begin);
+                    frame = match block_on(file.get_page(real_offset, MmapKind::Shared)) {
+                        Ok(frame) => frame,
+                        Err(SysError::ENOMEM) => return Err(PageFaultErr::KernelOOM),
+                        Err(e) => panic!("get shared page failed: {:?}", e),
+                    };
+                }
                 UserAreaType::Shm { id: _, shm: _ } => {
                     panic!("shm should be mapped immediately, will never page fault")
                 }
@@ -325,15 +472,61 @@ impl UserArea {
             );
         }
         // remap the frame
-        page_table.remap_page(access_vpn.addr(), frame, self.perm().into());
-        flush_tlb(access_vpn.addr().bits());
+        // other harts sharing this address space may still cache the old (CoW) mapping
+        if page_table.remap_page(access_vpn.addr(), frame, self.perm().into()).is_err() {
+            // 只有 lazy alloc/load 时才可能需要分配页表, 此时 frame 是新分配的
+            // 或是共享映射的文件页, 文件自己还持有一份引用
+            if matches!(self.kind, UserAreaType::MmapShared { .. }) {
+                frame.page_num().decrease();
+            } else {
+                frame.page_num().decrease_and_must_dealloc();
+            }
+            return Err(PageFaultErr::KernelOOM);
+        }
+        tlb::shootdown_page(page_table.root_paddr(), access_vpn.addr().into());
+        Ok(())
+    }
+
+    /// 将零页以只读 CoW 的方式映射到 vpn 处
+    fn map_zero_frame(
+        &self,
+        page_table: &mut PageTable,
+        vpn: VirtPageNum,
+    ) -> Result<(), PageFaultErr> {
+        let zero = frame::zero_frame().ok_or(PageFaultErr::KernelOOM)?;
+        let mut flags: PTEFlags = self.perm().into();
+        flags.remove(PTEFlags::W);
+        flags.insert(PTEFlags::SHARED);
+        if page_table.remap_page(vpn.addr(), zero, flags).is_err() {
+            return Err(PageFaultErr::KernelOOM);
+        }
+        zero.page_num().increase();
+        // 原来的 PTE 无效, 不需要刷 TLB
+        Ok(())
+    }
+
+    fn swap_in(
+        &self,
+        page_table: &mut PageTable,
+        vpn: VirtPageNum,
+        entry: SwapEntry,
+    ) -> Result<(), PageFaultErr> {
+        let frame = alloc_frame().ok_or(PageFaultErr::KernelOOM)?;
+        if swap::read_page(entry, frame).is_err() {
+            frame.page_num().decrease_and_must_dealloc();
+            return Err(PageFaultErr::SwapIOErr);
+        }
+        swap::free_slot(entry);
+        // 原来的 PTE 无效, 不需要刷 TLB
+        // swap PTE 所在的页表已经存在, 不会失败
+        page_table.remap_page(vpn.addr(), frame, self.perm().into()).unwrap();
         Ok(())
     }
 
     fn split_and_make_left(&mut self, split_at: VirtAddr, range: VirtAddrRange) -> Self {
         use UserAreaType::*;
         // return left-hand-side area
-        match &mut self.kind {
+        let mut left = match &mut self.kind {
             MmapAnonymous => UserArea::new_anonymous(self.perm),
             MmapPrivate { file, offset } => {
                 let old_offset = *offset;
@@ -341,21 +534,48 @@ impl UserArea {
                 *offset += split_at - range.start;
                 UserArea::new_private(self.perm, file.clone(), old_offset)
             }
+            MmapShared {
+                file,
+                offset,
+                writable,
+            } => {
+                let old_offset = *offset;
+                *offset += split_at - range.start;
+                UserArea::new_shared_with(self.perm, file.clone(), old_offset, writable.clone())
+            }
             Shm { id: _, shm: _ } => panic!("shm should never be split"),
-        }
+        };
+        left.growsdown = self.growsdown;
+        left.mlock = self.mlock;
+        left.mergeable = self.mergeable;
+        left
     }
 
     fn split_and_make_right(&mut self, split_at: VirtAddr, range: VirtAddrRange) -> Self {
         use UserAreaType::*;
         // change self to become the new left-hand-side area: nothing need to do
         // return right-hand-side area
-        match &self.kind {
+        let mut right = match &self.kind {
             MmapAnonymous => UserArea::new_anonymous(self.perm),
             MmapPrivate { file, offset } => {
                 UserArea::new_private(self.perm, file.clone(), *offset + (split_at - range.start))
             }
+            MmapShared {
+                file,
+                offset,
+                writable,
+            } => UserArea::new_shared_with(
+                self.perm,
+                file.clone(),
+                *offset + (split_at - range.start),
+                writable.clone(),
+            ),
             Shm { id: _, shm: _ } => panic!("shm should never be split"),
-        }
+        };
+        right.growsdown = self.growsdown;
+        right.mlock = self.mlock;
+        right.mergeable = self.mergeable;
+        right
     }
 
     /// debug only
@@ -363,6 +583,7 @@ impl UserArea {
         match self.kind {
             UserAreaType::MmapAnonymous => "anonymous",
             UserAreaType::MmapPrivate { .. } => "private",
+            UserAreaType::MmapShared { .. } => "shared",
             UserAreaType::Shm { .. } => "shm",
         }
     }
@@ -373,23 +594,222 @@ impl UserArea {
 #[derive(Clone)]
 pub struct UserAreaManager {
     map: RangeMap<VirtAddr, UserArea>,
+    /// RLIMIT_STACK, 向下增长的段最多能长到多大
+    stack_limit: usize,
+    /// 栈, 堆, mmap 等的基址, 开启 ASLR 时是随机的
+    layout: Layout,
+    /// RLIMIT_MEMLOCK, 被锁定的段的总大小上限
+    memlock_limit: usize,
+    /// mlockall(MCL_FUTURE) 之后新映射的段的锁定状态
+    mlock_future: MemLock,
 }
 
 impl UserAreaManager {
-    const HEAP_BEG: VirtAddr = VirtAddr::from(U_SEG_HEAP_BEG);
     const STACK_RANGE: VirtAddrRange =
         VirtAddr::from(U_SEG_STACK_BEG)..VirtAddr::from(U_SEG_STACK_END);
     const MMAP_RANGE: VirtAddrRange =
         VirtAddr::from(U_SEG_FILE_BEG)..VirtAddr::from(U_SEG_FILE_END);
     const SHARE_RANGE: VirtAddrRange =
         VirtAddr::from(U_SEG_SHARE_BEG)..VirtAddr::from(U_SEG_SHARE_END);
+    /// 缺页时物理页不够, 一次换出多少页
+    const RECLAIM_BATCH: usize = 32;
+    /// 栈向下增长时, 和下面的段之间至少要隔开这么远, 同 Linux 的 stack_guard_gap
+    pub const STACK_GUARD_GAP: usize = 256 * PAGE_SIZE;
+    /// RLIMIT_STACK 的默认值
+    pub const DEFAULT_STACK_LIMIT: usize = 8 * 1024 * 1024;
+    /// 主线程的栈一开始的大小, 之后按需增长
+    const MAIN_STACK_INIT_SIZE: usize = 128 * 1024;
+    /// RLIMIT_MEMLOCK 的默认值, 同 Linux
+    pub const DEFAULT_MEMLOCK_LIMIT: usize = 8 * 1024 * 1024;
 
     pub fn new() -> Self {
         Self {
             map: RangeMap::new(),
+            stack_limit: Self::DEFAULT_STACK_LIMIT,
+            layout: Layout::fixed(),
+            memlock_limit: Self::DEFAULT_MEMLOCK_LIMIT,
+            mlock_future: MemLock::None,
+        }
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// 只能在还没有任何段时调用
+    pub fn set_layout(&mut self, layout: Layout) {
+        debug_assert!(self.map.iter().next().is_none());
+        self.layout = layout;
+    }
+
+    pub fn stack_limit(&self) -> usize {
+        self.stack_limit
+    }
+
+    pub fn set_stack_limit(&mut self, limit: usize) {
+        self.stack_limit = limit;
+    }
+
+    pub fn memlock_limit(&self) -> usize {
+        self.memlock_limit
+    }
+
+    pub fn set_memlock_limit(&mut self, limit: usize) {
+        self.memlock_limit = limit;
+    }
+
+    /// 被锁定的段的总大小
+    pub fn locked_size(&self) -> usize {
+        self.map
+            .iter()
+            .filter(|(_, area)| area.locked())
+            .map(|(range, _)| range.end - range.start)
+            .sum()
+    }
+
+    /// range 必须完全被段覆盖, 否则返回 ENOMEM
+    pub fn check_mapped(&self, range: VirtAddrRange) -> SysResult {
+        let mut curr = range.start;
+        while curr < range.end {
+            let (r, _) = self.map.get(curr).ok_or(SysError::ENOMEM)?;
+            curr = r.end;
+        }
+        Ok(())
+    }
+
+    /// 在 p 处把所在的段切成两半, 用于只修改段的一部分的属性.
+    /// shm 不能切分, 会被整个修改
+    fn split_area_at(&mut self, p: VirtAddr) {
+        let need_split = match self.map.get(p) {
+            Some((r, area)) => r.start != p && !matches!(area.kind, UserAreaType::Shm { .. }),
+            None => false,
+        };
+        if need_split {
+            self.map.split_at(p, UserArea::split_and_make_right);
         }
     }
 
+    /// mlock / munlock: 修改 range 中所有段的锁定状态, 必要时切分段.
+    /// 锁定后超过 RLIMIT_MEMLOCK 时返回 ENOMEM
+    pub fn set_mlock(&mut self, range: VirtAddrRange, mlock: MemLock) -> SysResult {
+        if range.start >= range.end {
+            return Ok(());
+        }
+        self.check_mapped(range.clone())?;
+        self.split_area_at(range.start);
+        self.split_area_at(range.end);
+        let start = self.map.get(range.start).unwrap().0.start;
+
+        if mlock != MemLock::None {
+            let adding: usize = self
+                .map
+                .range(start..range.end)
+                .filter(|(_, area)| !area.locked())
+                .map(|(r, _)| r.end - r.start)
+                .sum();
+            if self.locked_size() + adding > self.memlock_limit {
+                return Err(SysError::ENOMEM);
+            }
+        }
+        self.map.range_mut(start..range.end).for_each(|(_, area)| area.mlock = mlock);
+        Ok(())
+    }
+
+    /// mlockall(MCL_CURRENT): 锁定现有的所有段
+    pub fn mlock_all(&mut self, mlock: MemLock) -> SysResult {
+        if mlock != MemLock::None {
+            let total: usize = self.map.iter().map(|(r, _)| r.end - r.start).sum();
+            if total > self.memlock_limit {
+                return Err(SysError::ENOMEM);
+            }
+        }
+        self.map.iter_mut().for_each(|(_, area)| area.mlock = mlock);
+        Ok(())
+    }
+
+    /// mlockall(MCL_FUTURE): 之后新映射的段都以 mlock 锁定
+    pub fn set_mlock_future(&mut self, mlock: MemLock) {
+        self.mlock_future = mlock;
+    }
+
+    /// munlockall, 也用于 fork: 子进程不继承内存锁
+    pub fn munlock_all(&mut self) {
+        self.mlock_all(MemLock::None).unwrap();
+        self.mlock_future = MemLock::None;
+    }
+
+    /// MADV_MERGEABLE / MADV_UNMERGEABLE: 修改 range 中所有段是否参与 KSM, 必要时切分段.
+    /// 同 Linux, 共享映射和 shm 被忽略
+    pub fn set_mergeable(&mut self, range: VirtAddrRange, mergeable: bool) -> SysResult {
+        if range.start >= range.end {
+            return Ok(());
+        }
+        self.check_mapped(range.clone())?;
+        self.split_area_at(range.start);
+        self.split_area_at(range.end);
+        let start = self.map.get(range.start).unwrap().0.start;
+        self.map
+            .range_mut(start..range.end)
+            .filter(|(_, area)| {
+                matches!(
+                    area.kind,
+                    UserAreaType::MmapAnonymous | UserAreaType::MmapPrivate { .. }
+                )
+            })
+            .for_each(|(_, area)| area.mergeable = mergeable);
+        Ok(())
+    }
+
+    /// KSM: 从 start 开始扫描可合并的段中已经映射的页, 至多 budget 页.
+    /// 返回下次开始扫描的地址, 整个地址空间都扫描完了则返回 None
+    pub fn ksm_scan(
+        &self,
+        page_table: &mut PageTable,
+        start: VirtAddr,
+        budget: &mut usize,
+    ) -> Option<VirtAddr> {
+        for (range, area) in self.map.iter() {
+            if range.end <= start || !area.mergeable {
+                continue;
+            }
+            let flags: PTEFlags = area.perm().into();
+            let range = round_range_vpn(range.start.max(start)..range.end);
+            let mut vpn = range.start;
+            while vpn < range.end {
+                if *budget == 0 {
+                    return Some(vpn.addr().into());
+                }
+                ksm::scan_page(page_table, vpn, flags);
+                *budget -= 1;
+                vpn += 1;
+            }
+        }
+        None
+    }
+
+    /// MADV_UNMERGEABLE: 让 range 中已被 KSM 合并的页重新变成私有的.
+    /// 只读的段不会被写入, 合并的页留着也无妨
+    pub fn ksm_unmerge(&mut self, page_table: &mut PageTable, range: VirtAddrRange) -> SysResult {
+        let range = round_range_vpn(range);
+        let mut vpn = range.start;
+        while vpn < range.end {
+            let writable = self
+                .get_area(vpn.addr().into())
+                .map_or(false, |area| area.perm().contains(UserAreaPerm::WRITE));
+            let merged = match page_table.get_pte_copied_from_vpn(vpn) {
+                Some(pte) => writable && ksm::is_ksm_frame(pte.paddr()),
+                None => false,
+            };
+            if merged {
+                // 和写入时一样走 CoW
+                self.page_fault(page_table, vpn, PageFaultAccessType::RW)
+                    .map_err(|_| SysError::ENOMEM)?;
+            }
+            vpn += 1;
+        }
+        Ok(())
+    }
+
     pub fn get_area(&self, vaddr: VirtAddr) -> Option<&UserArea> {
         self.get(vaddr).map(|(_, a)| a)
     }
@@ -432,10 +852,58 @@ impl UserAreaManager {
         sp_init
     }
 
+    /// 主线程的栈, 顶端在 layout.stack_top, 缺页时向下增长, 最多长到 RLIMIT_STACK.
+    /// 返回值同 alloc_stack
+    pub fn alloc_main_stack(&mut self, init_size: usize) -> VirtAddr {
+        let size = init_size.max(Self::MAIN_STACK_INIT_SIZE.min(self.stack_limit));
+        let end = self.layout.stack_top;
+        let range = VirtAddr::from((end - size).floor())..end;
+
+        let sp_init = VirtAddr::from((end.bits() - 1) & !0xf);
+        debug!("alloc main stack: {:x?}, sp_init: {:x?}", range, sp_init);
+
+        let area = UserArea::new_growsdown(UserAreaPerm::READ | UserAreaPerm::WRITE);
+        self.map.try_insert(range, area).unwrap();
+
+        sp_init
+    }
+
+    /// vaddr 不属于任何段时调用.
+    /// 如果 vaddr 上方的第一个段是向下增长的 (栈), 就把它扩展到 vaddr 所在的页
+    fn expand_stack(&mut self, vaddr: VirtAddr) -> Result<(), PageFaultErr> {
+        let (range, area) = self
+            .map
+            .range(vaddr..VirtAddr::from(U_SEG_END))
+            .next()
+            .ok_or(PageFaultErr::NoSegment)?;
+        if !area.growsdown() {
+            return Err(PageFaultErr::NoSegment);
+        }
+
+        let new_start = VirtAddr::from(vaddr.floor());
+        if range.end - new_start > self.stack_limit {
+            debug!(
+                "stack {:x?} exceeds limit when growing to {:x?}",
+                range, vaddr
+            );
+            return Err(PageFaultErr::StackGuard);
+        }
+        // 和下面的段之间要留出 guard gap
+        let gap_start = VirtAddr::from(new_start.bits().saturating_sub(Self::STACK_GUARD_GAP));
+        if self.map.range_is_free(gap_start..new_start).is_err() {
+            debug!("stack {:x?} hits the guard gap at {:x?}", range, vaddr);
+            return Err(PageFaultErr::StackGuard);
+        }
+
+        debug!("expand stack {:x?} to {:x?}", range, new_start);
+        self.map.extend_front(range.start, new_start).unwrap();
+        Ok(())
+    }
+
     pub fn insert_heap(&mut self, init_size: usize) {
         let range = VirtAddrRange {
-            start: Self::HEAP_BEG,
-            end: Self::HEAP_BEG + init_size,
+            start: self.layout.heap_base,
+            end: self.layout.heap_base + init_size,
         };
         let area = UserArea::new_anonymous(UserAreaPerm::READ | UserAreaPerm::WRITE);
         self.map.try_insert(range, area).unwrap();
@@ -443,13 +911,14 @@ impl UserAreaManager {
 
     pub fn get_heap_break(&self) -> VirtAddr {
         let (Range { start: _, end }, _) =
-            self.map.get(Self::HEAP_BEG).expect("get heap break without heap");
+            self.map.get(self.layout.heap_base).expect("get heap break without heap");
         end
     }
 
     pub fn reset_heap_break(&mut self, new_brk: VirtAddr) -> SysResult<()> {
         // TODO-PERF: 缓存 heap 的位置，减少一次查询
-        let (Range { start, end }, _) = self.map.get(Self::HEAP_BEG).expect("brk without heap");
+        let (Range { start, end }, _) =
+            self.map.get(self.layout.heap_base).expect("brk without heap");
 
         if end < new_brk {
             // when larger, create a new area [heap_end, new_brk), then merge it with current heap
@@ -466,8 +935,9 @@ impl UserAreaManager {
 
     /// for mmap private / mmap anonymous
     fn find_free_mmap_area(&self, size: usize) -> SysResult<(VirtAddr, usize)> {
+        let range = self.layout.mmap_base..Self::MMAP_RANGE.end;
         self.map
-            .find_free_range(Self::MMAP_RANGE, size, |va, n| (va + n).ceil().into())
+            .find_free_range(range, size, |va, n| (va + n).ceil().into())
             .map(|r| (r.start, r.end - r.start))
             .ok_or(SysError::ENOMEM)
     }
@@ -489,6 +959,18 @@ impl UserAreaManager {
         self.insert_mmap_anonymous_at(begin, size, perm)
     }
 
+    /// MAP_GROWSDOWN, 在下方预留出 guard gap 的空间
+    pub fn insert_mmap_growsdown(
+        &mut self,
+        size: usize,
+        perm: UserAreaPerm,
+    ) -> SysResult<(VirtAddrRange, &UserArea)> {
+        let (begin, total) = self.find_free_mmap_area(size + Self::STACK_GUARD_GAP)?;
+        let begin = begin + Self::STACK_GUARD_GAP;
+        let size = total - Self::STACK_GUARD_GAP;
+        self.insert_at(begin, size, UserArea::new_growsdown(perm))
+    }
+
     pub fn insert_mmap_private(
         &mut self,
         size: usize,
@@ -500,13 +982,25 @@ impl UserAreaManager {
         self.insert_mmap_private_at(begin, size, perm, file, offset)
     }
 
+    /// 共享映射放在共享段中, fork 时不会被标记为 CoW
+    pub fn insert_mmap_shared(
+        &mut self,
+        size: usize,
+        perm: UserAreaPerm,
+        file: VfsFileRef,
+        offset: usize,
+    ) -> SysResult<(VirtAddrRange, &UserArea)> {
+        let (begin, size) = self.find_free_share_area(size)?;
+        self.insert_at(begin, size, UserArea::new_shared(perm, file, offset)?)
+    }
+
     pub fn insert_shm(
         &mut self,
         perm: UserAreaPerm,
         id: ShmId,
-        shm: Arc<Shm>,
+        shm: ShmAttach,
     ) -> SysResult<(VirtAddrRange, &UserArea)> {
-        let (begin, _) = self.find_free_share_area(shm.size())?;
+        let (begin, _) = self.find_free_share_area(shm.shm().mapped_size())?;
         self.insert_shm_at(begin, perm, id, shm)
     }
 
@@ -514,13 +1008,20 @@ impl UserAreaManager {
         &mut self,
         begin: VirtAddr,
         size: usize,
-        area: UserArea,
+        mut area: UserArea,
     ) -> SysResult<(VirtAddrRange, &UserArea)> {
         let range = VirtAddrRange {
             start: begin,
             end: begin + size,
         };
 
+        if self.mlock_future != MemLock::None {
+            if self.locked_size() + size > self.memlock_limit {
+                return Err(SysError::EAGAIN);
+            }
+            area.mlock = self.mlock_future;
+        }
+
         log::debug!(
             "try insert_at: {:?}, perm: {:?}, type: {}",
             range,
@@ -559,13 +1060,14 @@ impl UserAreaManager {
         begin_vaddr: VirtAddr,
         perm: UserAreaPerm,
         id: ShmId,
-        shm: Arc<Shm>,
+        shm: ShmAttach,
     ) -> SysResult<(VirtAddrRange, &UserArea)> {
         assert!(
             Self::SHARE_RANGE.contains(&begin_vaddr),
             "shm must be in share range"
         );
-        self.insert_at(begin_vaddr, shm.size(), UserArea::new_shm(perm, id, shm))
+        let size = shm.shm().mapped_size();
+        self.insert_at(begin_vaddr, size, UserArea::new_shm(perm, id, shm))
     }
 
     pub fn page_fault(
@@ -574,25 +1076,145 @@ impl UserAreaManager {
         access_vpn: VirtPageNum,
         access_type: PageFaultAccessType,
     ) -> Result<(), PageFaultErr> {
-        let (range, area) =
-            self.map.get_mut(access_vpn.addr().into()).ok_or(PageFaultErr::NoSegment)?;
+        let vaddr: VirtAddr = access_vpn.addr().into();
+        if self.map.get(vaddr).is_none() {
+            self.expand_stack(vaddr)?;
+        }
+        let (range, area) = self.map.get(vaddr).ok_or(PageFaultErr::NoSegment)?;
+        match area.page_fault(page_table, range.start, access_vpn, access_type) {
+            Err(PageFaultErr::KernelOOM) => {}
+            result => return result,
+        }
+
+        // 物理页不够了, 先换出自己的页, 不够再换出别的进程的页, 然后重试
+        // 自己的地址空间此时被锁着, swap::reclaim 会跳过它
+        if self.swap_out(page_table, Self::RECLAIM_BATCH) == 0
+            && swap::reclaim(Self::RECLAIM_BATCH) == 0
+        {
+            return Err(PageFaultErr::KernelOOM);
+        }
+        let (range, area) = self.map.get(access_vpn.addr().into()).unwrap();
         area.page_fault(page_table, range.start, access_vpn, access_type)
     }
 
+    /// 将匿名映射区域中的页换出, 至多 want 页, 返回实际换出 (释放) 的页数
+    ///
+    /// 简化的时钟算法: 第一遍遇到最近访问过 (A 位被置上) 的页时, 只清除 A 位并跳过,
+    /// 第二遍时仍未被访问的页才会被换出.
+    /// 与别人共享的页 (CoW 或 shm) 和被 mlock 的页不会被换出.
+    pub fn swap_out(&self, page_table: &mut PageTable, want: usize) -> usize {
+        let mut freed = 0;
+        for _ in 0..2 {
+            for (range, area) in self.map.iter() {
+                if !matches!(area.kind, UserAreaType::MmapAnonymous) || area.locked() {
+                    continue;
+                }
+                let range = round_range_vpn(range);
+                let mut vpn = range.start;
+                while vpn < range.end {
+                    if freed >= want {
+                        return freed;
+                    }
+                    match Self::swap_out_page(page_table, vpn) {
+                        Ok(true) => freed += 1,
+                        Ok(false) => {}
+                        // 没有 swap 槽位或 I/O 失败, 放弃
+                        Err(_) => return freed,
+                    }
+                    vpn += 1;
+                }
+            }
+        }
+        freed
+    }
+
+    /// 尝试换出一页, 返回是否换出了
+    fn swap_out_page(page_table: &mut PageTable, vpn: VirtPageNum) -> SysResult<bool> {
+        let pte = match page_table.get_pte_mut_from_vpn(vpn) {
+            Some(pte) => pte,
+            None => return Ok(false),
+        };
+        let frame = pte.ppn();
+        // PROT_NONE 的页很少被访问, 但换出失败时没法按原样恢复, 留在内存中
+        if pte.shared() || frame.is_shared() || pte.is_prot_none() {
+            return Ok(false);
+        }
+        if pte.accessed() {
+            // TLB 中可能还缓存着 A 为 1 的表项, 不刷新的话之后的访问不会再置位 A.
+            // 硬件不置位 A 时, 访问会产生缺页异常, 由 page_fault 重新置位
+            pte.clear_accessed();
+            tlb::shootdown_page(page_table.root_paddr(), vpn.addr().into());
+            return Ok(false);
+        }
+
+        let entry = swap::alloc_slot().ok_or(SysError::ENOMEM)?;
+        let old_pte = *pte;
+        // 先让所有核都看不到这一页, 再写出, 避免写出之后又被修改
+        *pte = PageTableEntry::new_swap(entry);
+        tlb::shootdown_page(page_table.root_paddr(), vpn.addr().into());
+
+        if let Err(e) = swap::write_page(entry, frame.addr()) {
+            log::warn!("swap out {:x?} failed: {:?}", vpn, e);
+            swap::free_slot(entry);
+            page_table.remap_page(vpn.addr(), old_pte.paddr(), old_pte.flags()).unwrap();
+            return Err(e);
+        }
+        frame.decrease_and_must_dealloc();
+        Ok(true)
+    }
+
+    /// 将换出到第 area_idx 个 swap 区的页全部读回来, 用于 swapoff
+    pub fn swap_in_area(&self, page_table: &mut PageTable, area_idx: usize) -> SysResult {
+        for (range, area) in self.map.iter() {
+            let range = round_range_vpn(range);
+            let mut vpn = range.start;
+            while vpn < range.end {
+                match page_table.get_swap_entry(vpn) {
+                    Some(entry) if entry.area() == area_idx => {
+                        area.swap_in(page_table, vpn, entry).map_err(|e| match e {
+                            PageFaultErr::KernelOOM => SysError::ENOMEM,
+                            _ => SysError::EIO,
+                        })?;
+                    }
+                    _ => {}
+                }
+                vpn += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// 分配 range 中的所有页. 页帧不够时返回 ENOMEM, 由调用者决定是否交给 OOM killer
     pub fn force_map_range(
         &mut self,
         page_table: &mut PageTable,
         range: VirtAddrRange,
         perm: UserAreaPerm,
-    ) {
+    ) -> SysResult {
         debug!("force map range: {:?}, perm: {:?}", range, perm);
+        let mut result = Ok(());
         iter_vpn(range, |vpn| {
-            self.page_fault(page_table, vpn, perm.into()).unwrap();
+            if result.is_ok() {
+                result = self.page_fault(page_table, vpn, perm.into());
+            }
         });
+        result.map_err(|e| match e {
+            PageFaultErr::KernelOOM => SysError::ENOMEM,
+            PageFaultErr::SwapIOErr => SysError::EIO,
+            _ => SysError::EFAULT,
+        })
+    }
+
+    pub fn release_all(&mut self, page_table: &mut PageTable) {
+        self.map.clear(|_area, range| Self::release_range(page_table, range));
     }
 
+    /// shmdt: vaddr 必须是某个 shm 段的起始地址
     pub fn remove_shm(&mut self, vaddr: VirtAddr) -> SysResult<VirtAddrRange> {
-        let (range, _) = self.map.get(vaddr).ok_or(SysError::EINVAL)?;
+        let (range, area) = self.map.get(vaddr).ok_or(SysError::EINVAL)?;
+        if range.start != vaddr || !matches!(area.kind, UserAreaType::Shm { .. }) {
+            return Err(SysError::EINVAL);
+        }
         self.map.force_remove_one(range.clone());
         Ok(range)
     }
@@ -612,9 +1234,9 @@ impl UserAreaManager {
         _page_table: &mut PageTable,
         range: VirtAddrRange,
         new_perm: UserAreaPerm,
-    ) {
+    ) -> SysResult {
         let old_area = self.get_area(range.start).expect("range not mapped");
-        let new_area = UserArea::new_with_same_kind(old_area.clone(), new_perm);
+        let new_area = UserArea::new_with_same_kind(old_area.clone(), new_perm)?;
         self.map.remove(
             range.clone(),
             UserArea::split_and_make_left,
@@ -622,6 +1244,7 @@ impl UserAreaManager {
             |_, _| {},
         );
         self.map.try_insert(range, new_area).expect("failed to remap range");
+        Ok(())
     }
 
     /// 释放一个虚拟地址范围内的所有页
@@ -630,19 +1253,31 @@ impl UserAreaManager {
     pub fn release_range(page_table: &mut PageTable, range: VirtAddrRange) {
         debug!("release range: {:?}", range);
         // 释放被删除的段
-        iter_vpn(range, |vpn| {
+        let mut frames = Vec::new();
+        iter_vpn(range.clone(), |vpn| {
             log::trace!("release vpn: {:x?}", vpn);
             let pte = page_table.get_pte_copied_from_vpn(vpn);
             if pte.is_none() {
+                // 被换出的页只需要释放 swap 槽位
+                if let Some(entry) = page_table.take_swap_entry(vpn) {
+                    swap::free_slot(entry);
+                }
                 return;
             }
             let pte = pte.unwrap();
             // Remove the page from the page table.
             page_table.unmap_page(vpn.addr());
+            frames.push(pte.ppn());
+        });
+        if frames.is_empty() {
+            return;
+        }
+        // 必须等所有核都刷新了 TLB 之后, 才能释放物理页
+        tlb::shootdown_range(page_table.root_paddr(), range);
+        for frame in frames {
             // Decrement the reference count of the page and try to deallocate it.
-            pte.ppn().decrease_and_try_dealloc();
-            flush_tlb(vpn.addr().bits());
-        })
+            frame.decrease_and_try_dealloc();
+        }
     }
 
     /// only for debug
@@ -684,7 +1319,6 @@ impl UserAreaManager {
 
     /// only for debug
     pub fn print_page(&self, page_table: &PageTable, vaddr: VirtAddr4K) {
-        use crate::consts::PAGE_SIZE;
         use crate::executor::hart_local::AutoSUM;
         use alloc::format;
 
@@ -10,17 +10,21 @@ use crate::{
         hart_local::{set_curr_lproc, AutoSIE},
         util_futures::yield_now,
     },
-    memory::address::VirtAddr,
-    process::user_space::user_area::PageFaultAccessType,
-    signal::{SignalSet, SIG_DFL, SIG_IGN},
+    memory::{address::VirtAddr, tlb, UserWritePtr},
+    process::user_space::user_area::{PageFaultAccessType, PageFaultErr},
+    signal::{SigInfo, SignalSet, SEGV_ACCERR, SEGV_MAPERR, SIG_DFL, SIG_IGN},
     syscall::Syscall,
     timer,
     trap::trap::run_user,
 };
 
-use super::lproc::{LightProcess, ProcessStatus};
+use super::{
+    lproc::{LightProcess, ProcessStatus},
+    oom,
+};
 use core::{
     future::Future,
+    mem::size_of,
     pin::Pin,
     task::{Context, Poll},
 };
@@ -34,6 +38,12 @@ pub async fn userloop(lproc: Arc<LightProcess>) {
         let context = lproc.context();
         let timer = lproc.timer();
 
+        // SIGKILL 不能被捕获或忽略
+        if lproc.signal_pending().contains(SignalSet::SIGKILL) {
+            info!("Process {:?} killed by SIGKILL", lproc.id());
+            break;
+        }
+
         match lproc.status() {
             ProcessStatus::UNINIT => panic!("Uninitialized process should not enter userloop"),
             ProcessStatus::READY => {
@@ -70,6 +80,7 @@ pub async fn userloop(lproc: Arc<LightProcess>) {
                         if handler == SIG_DFL {
                             match signum {
                                 SignalSet::SIGKILL
+                                | SignalSet::SIGSEGV
                                 | SignalSet::SIGALRM
                                 | SignalSet::SIGHUP
                                 | SignalSet::SIGINT
@@ -88,15 +99,31 @@ pub async fn userloop(lproc: Arc<LightProcess>) {
                         lproc.with_mut_signal(|s| {
                             *s.before_signal_context.get_mut().as_mut() = context.clone();
                         });
-                        // Set epc to signal handler
                         // Signal handler run on the same stack
+                        // siginfo 放在栈上, 作为 handler 的第二个参数
+                        let info = lproc.take_signal_info(signum_1 as usize + 1);
+                        let info_ptr = (context.get_user_sp() - size_of::<SigInfo>()) & !0xf;
+                        if UserWritePtr::<SigInfo>::from(info_ptr).write(&lproc, info).is_err() {
+                            // 栈已经用不了了 (比如栈溢出), 只能杀掉
+                            warn!(
+                                "Process {:?} killed: failed to push siginfo at 0x{:x}",
+                                lproc.id(),
+                                info_ptr
+                            );
+                            break;
+                        }
+                        // Set epc to signal handler
                         log::warn!("enter signal handler: {:x?}", handler);
                         context.user_sepc = handler;
+                        context.set_user_sp(info_ptr);
                         context.set_user_a0(signum_1 as usize + 1);
+                        context.set_user_a1(info_ptr);
+                        context.set_user_a2(0);
                         // Set processing signal
                         lproc.with_mut_signal(|s| {
                             s.signal_processing.insert(signum);
                         });
+                        lproc.clear_signal(signum);
                     }
                 }
 
@@ -145,15 +172,36 @@ pub async fn userloop(lproc: Arc<LightProcess>) {
                     let result = lproc.with_mut_memory(|m| {
                         m.handle_pagefault(VirtAddr::from(stval), access_type)
                     });
+                    if let Err(PageFaultErr::KernelOOM) = result {
+                        // 让 OOM killer 杀掉一个进程 (可能就是自己), 等它释放内存后重试
+                        if oom::out_of_memory() {
+                            yield_now().await;
+                            continue;
+                        }
+                    }
                     if let Err(e) = result {
-                        warn!(
-                            "Pagefault failed: {:?} ({:?}), process {:?} killed, STVAL: 0x{stval:x}",
-                            e,
-                            access_type,
-                            lproc.id(),
-                        );
-                        lproc.with_memory(|m| m.areas().print_all());
-                        is_exit = true;
+                        if can_catch_sigsegv(&lproc) {
+                            // 交给用户的 SIGSEGV handler 处理
+                            debug!(
+                                "Pagefault failed: {:?} ({:?}), send SIGSEGV, STVAL: 0x{stval:x}",
+                                e, access_type
+                            );
+                            let code = match e {
+                                PageFaultErr::PermUnmatch => SEGV_ACCERR,
+                                _ => SEGV_MAPERR,
+                            };
+                            let signum = SignalSet::SIGSEGV.get_signum();
+                            lproc.send_signal_info(SigInfo::new_fault(signum, code, stval));
+                        } else {
+                            warn!(
+                                "Pagefault failed: {:?} ({:?}), process {:?} killed, STVAL: 0x{stval:x}",
+                                e,
+                                access_type,
+                                lproc.id(),
+                            );
+                            lproc.with_memory(|m| m.areas().print_all());
+                            is_exit = true;
+                        }
                     }
                 }
                 Exception::InstructionFault | Exception::IllegalInstruction => {
@@ -183,6 +231,7 @@ pub async fn userloop(lproc: Arc<LightProcess>) {
                 Interrupt::SupervisorExternal => {
                     drivers::get_device_manager_mut().interrupt_handler()
                 }
+                Interrupt::SupervisorSoft => tlb::ipi_handler(),
                 _ => todo!(),
             },
         }
@@ -202,6 +251,17 @@ pub async fn userloop(lproc: Arc<LightProcess>) {
     lproc.do_exit();
 }
 
+/// 缺页失败时能否把 SIGSEGV 交给用户处理, 否则直接杀掉进程.
+/// 正在处理别的信号时, 新的信号要等 sigreturn 后才会被处理, 那样只会不停地缺页
+fn can_catch_sigsegv(lproc: &LightProcess) -> bool {
+    if !lproc.signal_processing().is_empty() {
+        return false;
+    }
+    let handler = lproc
+        .with_signal(|s| s.signal_handler.get(&SignalSet::SIGSEGV.get_signum()).map(|h| h.bits()));
+    matches!(handler, Some(h) if h != SIG_DFL && h != SIG_IGN)
+}
+
 pub struct OutermostFuture<F: Future + Send + 'static> {
     lproc: Arc<LightProcess>,
     future: F,
@@ -61,3 +61,44 @@ impl SignalSet {
 
 pub const SIG_DFL: usize = 0;
 pub const SIG_IGN: usize = 1;
+
+// si_code
+/// 由 kill 等系统调用发送
+pub const SI_USER: i32 = 0;
+/// 访问了没有映射的地址
+pub const SEGV_MAPERR: i32 = 1;
+/// 没有权限访问该地址
+pub const SEGV_ACCERR: i32 = 2;
+
+/// siginfo_t, 只填了用得上的字段
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SigInfo {
+    pub si_signo: i32,
+    pub si_errno: i32,
+    pub si_code: i32,
+    _pad: i32,
+    /// SIGSEGV 和 SIGBUS 时是出错的地址
+    pub si_addr: usize,
+    _rest: [u64; 13],
+}
+
+impl SigInfo {
+    pub fn new(signum: usize, code: i32) -> Self {
+        Self {
+            si_signo: signum as i32,
+            si_errno: 0,
+            si_code: code,
+            _pad: 0,
+            si_addr: 0,
+            _rest: [0; 13],
+        }
+    }
+
+    pub fn new_fault(signum: usize, code: i32, addr: usize) -> Self {
+        Self {
+            si_addr: addr,
+            ..Self::new(signum, code)
+        }
+    }
+}
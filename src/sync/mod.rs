@@ -1,8 +1,10 @@
 mod mutex;
 mod sleep;
+mod wait_queue;
 
 pub type SpinNoIrqLock<T> = mutex::Mutex<T, mutex::SpinNoIrq>;
 pub type SpinNoIrqLockGuard<'a, T> = mutex::MutexGuard<'a, T, mutex::SpinNoIrq>;
 pub type SleepLock<T> = sleep::SleepLock<T>;
 pub type SleepLockFuture<'a, T> = sleep::SleepLockFuture<'a, T>;
 pub type SleepLockGuard<'a, T> = sleep::SleepLockGuard<'a, T>;
+pub type WaitQueue = wait_queue::WaitQueue;
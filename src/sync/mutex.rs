@@ -107,6 +107,26 @@ impl<T: ?Sized, S: MutexSupport> Mutex<T, S> {
         }
     }
 
+    /// Try to lock the spinlock without spinning,
+    /// returns None if it is held by someone else.
+    pub fn try_lock(&self) -> Option<MutexGuard<T, S>> {
+        let support_guard = S::before_lock();
+
+        // Ensure support is initialized
+        self.ensure_support();
+
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            != Ok(false)
+        {
+            return None;
+        }
+        unsafe { self.hart_id.get().write(arch::get_hart_id()) };
+        Some(MutexGuard {
+            mutex: self,
+            support_guard,
+        })
+    }
+
     pub fn ensure_support(&self) {
         let initialization = self.support_init.load(Ordering::Relaxed);
         if initialization == 2 {
@@ -210,7 +230,9 @@ impl MutexSupport for SpinNoIrq {
         SpinNoIrq
     }
     fn cpu_relax(&self) {
-        // No relax, continue spinning
+        // Interrupt is disabled while spinning, so TLB shootdown IPI can not reach us,
+        // serve it here to avoid dead lock with the initiator
+        crate::memory::tlb::handle_pending_shootdown();
         core::hint::spin_loop();
     }
     fn before_lock() -> Self::GuardData {
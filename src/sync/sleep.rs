@@ -46,6 +46,17 @@ impl<T: Sized> SleepLock<T> {
     pub fn lock(&self) -> SleepLockFuture<'_, T> {
         SleepLockFuture { mutex: self }
     }
+
+    /// 不等待, 锁被持有时直接返回 None
+    pub fn try_lock(&self) -> Option<SleepLockGuard<'_, T>> {
+        let mut inner = self.inner.lock(here!());
+        if inner.holding {
+            None
+        } else {
+            inner.holding = true;
+            Some(SleepLockGuard { mutex: self })
+        }
+    }
 }
 
 pub struct SleepLockFuture<'a, T: ?Sized + 'a> {
@@ -0,0 +1,30 @@
+use alloc::vec::Vec;
+use core::task::Waker;
+
+/// 等待某个条件成立的任务.
+///
+/// 本身不带锁, 应该和它等待的条件放在同一把锁里:
+/// 检查条件和登记 waker 在同一个临界区中完成, 就不会丢失唤醒.
+/// 被唤醒的任务需要重新检查条件, 所以多唤醒几次也没关系.
+pub struct WaitQueue {
+    wakers: Vec<Waker>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self { wakers: Vec::new() }
+    }
+
+    /// 登记一个等待者, 同一个任务重复登记只算一次
+    pub fn register(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|w| w.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+    }
+
+    pub fn wake_all(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
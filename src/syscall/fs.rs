@@ -9,11 +9,11 @@ use crate::{
     fs::{
         self,
         disk::BLOCK_SIZE,
-        memfs::zero::ZeroDev,
+        memfs::shmem::ShmemFile,
         new_vfs::{
             mount::GlobalMountManager,
             path::Path,
-            top::{TimeChange, TimeInfoChange, VfsFileRef},
+            top::{TimeChange, TimeInfoChange, VfsFileRef, PATH_MAX},
             VfsFileKind,
         },
     },
@@ -66,9 +66,20 @@ pub struct Kstat {
     pub st_ctime_nsec: isize,
 }
 
+pub const AT_SYMLINK_NOFOLLOW: usize = 1 << 8;
 pub const AT_REMOVEDIR: usize = 1 << 9;
+pub const AT_SYMLINK_FOLLOW: usize = 1 << 10;
+pub const AT_EMPTY_PATH: usize = 1 << 12;
 pub const AT_FDCWD: usize = -100isize as usize;
 
+/// mount flags
+pub const MS_RDONLY: u32 = 1;
+
+/// sync_file_range flags
+pub const SYNC_FILE_RANGE_WAIT_BEFORE: u32 = 1;
+pub const SYNC_FILE_RANGE_WRITE: u32 = 2;
+pub const SYNC_FILE_RANGE_WAIT_AFTER: u32 = 4;
+
 // fnctl flags
 bitflags::bitflags! {
     #[derive(Default)]
@@ -87,20 +98,23 @@ impl Kstat {
         let device = file.attr_device();
         let size = file.attr_size().await?;
         let time = file.attr_time().await?;
+        let nlink = file.attr_nlink().await?;
         debug!(
-            "file info: {:?}, {:?}, {:?}, {:?}",
-            kind, device, size, time
+            "file info: {:?}, {:?}, {:?}, {:?}, nlink: {}",
+            kind, device, size, time, nlink
         );
 
         Ok(Kstat {
             st_dev: file.attr_device().device_id as u64,
-            st_ino: 1,
-            st_mode: u32::from(kind) | 0o777, // 0777 permission, we don't care about permission
-            // don't support hard link, just return 1
-            st_nlink: 1,
+            st_ino: file.attr_ino() as u64,
+            st_mode: u32::from(kind) | file.attr_mode(),
+            st_nlink: nlink as u32,
             st_uid: 0,
             st_gid: 0,
-            st_rdev: 0,
+            st_rdev: match kind {
+                VfsFileKind::BlockDevice | VfsFileKind::CharDevice => device.self_device_id as u64,
+                _ => 0,
+            },
             _pad0: 0,
             st_size: size.bytes as i64,
             st_blksize: BLOCK_SIZE as i32,
@@ -147,8 +161,8 @@ impl<'a> Syscall<'a> {
             dir_fd, path_name, kstat
         );
 
-        let (dir, file_name) = self.at_helper(dir_fd, path_name, flags).await?;
-        let file = self.lookup_helper(dir, &file_name).await?;
+        let follow = flags & AT_SYMLINK_NOFOLLOW == 0;
+        let file = self.resolve_helper(dir_fd, path_name, follow).await?;
         kstat.write(&self.lproc, Kstat::from_vfs_file(&file).await?)?;
 
         Ok(0)
@@ -175,6 +189,88 @@ impl<'a> Syscall<'a> {
         Ok(0)
     }
 
+    pub async fn sys_symlinkat(&self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (target, new_dir_fd, link_path) = (
+            UserReadPtr::<u8>::from(args[0]),
+            args[1],
+            UserReadPtr::<u8>::from(args[2]),
+        );
+        let target = target.read_cstr(&self.lproc)?;
+        let link_path = link_path.read_cstr(&self.lproc)?;
+
+        info!(
+            "Syscall: symlinkat, target: {:?}, new_dir_fd: {}, link_path: {:?}",
+            target, new_dir_fd, link_path
+        );
+
+        if target.is_empty() {
+            return Err(SysError::ENOENT);
+        }
+        if target.len() > PATH_MAX {
+            return Err(SysError::ENAMETOOLONG);
+        }
+
+        // 链接目标不需要存在, 原样保存
+        let (dir, file_name) = self.at_helper(new_dir_fd, link_path, 0).await?;
+        if !dir.is_dir().await? {
+            return Err(SysError::ENOTDIR);
+        }
+        if file_name.is_empty() || dir.lookup(&file_name).await.is_ok() {
+            return Err(SysError::EEXIST);
+        }
+        let link = dir.create(&file_name, VfsFileKind::SymbolLink).await?;
+        link.write_at(0, target.as_bytes()).await?;
+        Ok(0)
+    }
+
+    pub async fn sys_linkat(&self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (old_dir_fd, old_path, new_dir_fd, new_path, flags) = (
+            args[0],
+            UserReadPtr::<u8>::from(args[1]),
+            args[2],
+            UserReadPtr::<u8>::from(args[3]),
+            args[4],
+        );
+        let old_path = old_path.read_cstr(&self.lproc)?;
+        let new_path = new_path.read_cstr(&self.lproc)?;
+
+        info!(
+            "Syscall: linkat, old_dir_fd: {}, old_path: {:?}, new_dir_fd: {}, new_path: {:?}, flags: {:#x}",
+            old_dir_fd, old_path, new_dir_fd, new_path, flags
+        );
+
+        if flags & !(AT_SYMLINK_FOLLOW | AT_EMPTY_PATH) != 0 {
+            return Err(SysError::EINVAL);
+        }
+        if old_path.is_empty() && flags & AT_EMPTY_PATH == 0 {
+            return Err(SysError::ENOENT);
+        }
+
+        // 同 Linux, 默认给符号链接本身建立硬链接
+        let follow = flags & AT_SYMLINK_FOLLOW != 0;
+        let old_file = self.resolve_helper(old_dir_fd, old_path, follow).await?;
+        if old_file.is_dir().await? {
+            return Err(SysError::EPERM);
+        }
+
+        let (new_dir, new_file_name) = self.at_helper(new_dir_fd, new_path, 0).await?;
+        if !new_dir.is_dir().await? {
+            return Err(SysError::ENOTDIR);
+        }
+        if new_file_name.is_empty() || new_dir.lookup(&new_file_name).await.is_ok() {
+            return Err(SysError::EEXIST);
+        }
+        // 硬链接不能跨文件系统, 否则 attach 会把它当成挂载. 每个挂载的文件系统实例的设备号都不同
+        if old_file.attr_device().device_id != new_dir.attr_device().device_id {
+            return Err(SysError::EXDEV);
+        }
+
+        new_dir.attach(&new_file_name, old_file).await?;
+        Ok(0)
+    }
+
     pub async fn sys_renameat2(&self) -> SyscallResult {
         let args = self.cx.syscall_args();
         let (old_dir_fd, old_path, new_dir_fd, new_path) = (
@@ -230,7 +326,7 @@ impl<'a> Syscall<'a> {
 
     pub async fn sys_utimensat(&self) -> SyscallResult {
         let args = self.cx.syscall_args();
-        let (dir_fd, path, times_ptr, _flags) = (
+        let (dir_fd, path, times_ptr, flags) = (
             args[0],
             UserReadPtr::<u8>::from(args[1]),
             UserReadPtr::<timer::TimeSpec>::from(args[2]),
@@ -244,11 +340,8 @@ impl<'a> Syscall<'a> {
                 dir_fd, path, times_ptr
             );
 
-            let (dir, file_name) = self.at_helper(dir_fd, path, 0).await?;
-            if !dir.is_dir().await? {
-                return Err(SysError::ENOTDIR);
-            }
-            self.lookup_helper(dir.clone(), &file_name).await?
+            let follow = flags & AT_SYMLINK_NOFOLLOW == 0;
+            self.resolve_helper(dir_fd, path, follow).await?
         } else {
             info!("Syscall: ftimens, fd: {}, times_ptr: {}", dir_fd, times_ptr);
             self.lproc.with_fdtable(|f| f.get(dir_fd)).ok_or(SysError::EBADF)?.file.clone()
@@ -383,7 +476,7 @@ impl<'a> Syscall<'a> {
             }
 
             let dirent_front = DirentFront {
-                d_ino: 1,
+                d_ino: vfs_entry.attr_ino() as u64,
                 d_off: this_entry_len as u64,
                 d_reclen: this_entry_len as u16,
                 d_type: DirentFront::as_dtype(vfs_entry.kind().await?),
@@ -445,10 +538,9 @@ impl<'a> Syscall<'a> {
             dir_fd, path_name, mode, flags
         );
 
-        let (dir, file_name) = self.at_helper(dir_fd, path_name, flags).await?;
-
         // only to ensure file exists
-        let _file = dir.lookup(&file_name).await?;
+        let follow = flags & AT_SYMLINK_NOFOLLOW == 0;
+        let _file = self.resolve_helper(dir_fd, path_name, follow).await?;
         Ok(0)
     }
 
@@ -500,7 +592,9 @@ impl<'a> Syscall<'a> {
         use fs::new_vfs::top::VfsFSKind;
         let f_type = match attr.kind {
             // from https://man7.org/linux/man-pages/man2/statfs.2.html
-            VfsFSKind::Fat => 0x4d44, // MSDOS_SUPER_MAGIC
+            VfsFSKind::Fat => 0x4d44,  // MSDOS_SUPER_MAGIC
+            VfsFSKind::Ext2 => 0xef53, // EXT2_SUPER_MAGIC
+            VfsFSKind::Ext4 => 0xef53, // EXT4_SUPER_MAGIC
             VfsFSKind::Dev => 0x1373,
             VfsFSKind::Tmp => 0x01021994,
             VfsFSKind::Proc => 0x9fa0,
@@ -525,17 +619,9 @@ impl<'a> Syscall<'a> {
         Ok(0)
     }
 
-    /// Path resolve helper for __at syscall
-    /// return (dir, filename)
-    pub(super) async fn at_helper(
-        &self,
-        dir_fd: usize,
-        path_name: String,
-        _flags: usize,
-    ) -> SysResult<(VfsFileRef, String)> {
-        let dir;
-        let file_name;
-
+    /// *at 系统调用中路径解析的起点, 返回出发的目录和相对它的路径.
+    /// 相对 cwd 的路径也从根目录出发, 这样链接目标中的 .. 才能退到 cwd 之外
+    fn start_helper(&self, dir_fd: usize, path_name: String) -> SysResult<(VfsFileRef, Path)> {
         let path = Path::from_string(path_name)?;
         log::trace!(
             "path: {:?} (is_absolute: {}, is_current: {})",
@@ -545,40 +631,46 @@ impl<'a> Syscall<'a> {
         );
 
         if path.is_absolute() {
-            if path.is_root() {
-                // 处理在根目录下解析 "/" 的情况
-                dir = fs::get_root_dir();
-                file_name = String::from("");
-            } else {
-                let dir_path = path.remove_tail();
-                dir = fs::get_root_dir().resolve(&dir_path).await?;
-                file_name = path.last().clone();
-            }
+            Ok((fs::get_root_dir(), path))
+        } else if dir_fd == AT_FDCWD {
+            let cwd = self.lproc.with_fsinfo(|f| f.cwd.clone());
+            Ok((fs::get_root_dir(), cwd.append(&path)))
         } else {
-            let fd_dir = if dir_fd == AT_FDCWD {
-                let cwd = self.lproc.with_fsinfo(|f| f.cwd.clone());
-                if cwd.is_root() {
-                    fs::get_root_dir()
-                } else {
-                    fs::get_root_dir().resolve(&cwd).await?
-                }
-            } else {
-                self.lproc.with_fdtable(|f| f.get(dir_fd)).ok_or(SysError::EBADF)?.file.clone()
-            };
-
-            if path.is_current() {
-                dir = fd_dir;
-                file_name = String::from("");
-            } else {
-                let rel_dir_path = path.remove_tail();
-                dir = fd_dir.resolve(&rel_dir_path).await?;
-                file_name = path.last().clone();
-            }
+            let fd_dir =
+                self.lproc.with_fdtable(|f| f.get(dir_fd)).ok_or(SysError::EBADF)?.file.clone();
+            Ok((fd_dir, path))
         }
+    }
 
+    /// Path resolve helper for __at syscall
+    /// return (dir, filename), 中间的符号链接会被跟随, 最后一级不会
+    pub(super) async fn at_helper(
+        &self,
+        dir_fd: usize,
+        path_name: String,
+        _flags: usize,
+    ) -> SysResult<(VfsFileRef, String)> {
+        let (start, path) = self.start_helper(dir_fd, path_name)?;
+        if path.is_empty() {
+            // 处理 "/" 和 "." 的情况
+            return Ok((start, String::from("")));
+        }
+        let (dir_path, file_name) = path.split_dir_file();
+        let dir = start.resolve(&dir_path).await?;
         Ok((dir, file_name))
     }
 
+    /// 解析 *at 系统调用中的路径, follow 决定最后一级是符号链接时是否跟随
+    pub(super) async fn resolve_helper(
+        &self,
+        dir_fd: usize,
+        path_name: String,
+        follow: bool,
+    ) -> SysResult<VfsFileRef> {
+        let (start, path) = self.start_helper(dir_fd, path_name)?;
+        start.walk(&path, follow).await
+    }
+
     /// if file_name is "", return dir; otherwise, return dir/file_name
     pub(super) async fn lookup_helper(
         &self,
@@ -592,8 +684,10 @@ impl<'a> Syscall<'a> {
         }
     }
 
-    pub fn sys_fcntl(&mut self) -> SyscallResult {
+    pub async fn sys_fcntl(&mut self) -> SyscallResult {
         const F_DUPFD_CLOEXEC: usize = 1030;
+        const F_ADD_SEALS: usize = 1033;
+        const F_GET_SEALS: usize = 1034;
 
         let args = self.cx.syscall_args();
         let (fd, cmd, arg) = (args[0], args[1], args[2]);
@@ -611,6 +705,15 @@ impl<'a> Syscall<'a> {
                 });
                 new_fd
             }
+            F_ADD_SEALS => {
+                let fd = self.lproc.with_mut_fdtable(|f| f.get(fd)).ok_or(SysError::EBADF)?;
+                fd.file.add_seals(arg as u32).await?;
+                Ok(0)
+            }
+            F_GET_SEALS => {
+                let fd = self.lproc.with_mut_fdtable(|f| f.get(fd)).ok_or(SysError::EBADF)?;
+                fd.file.seals().await.map(|seals| seals as usize)
+            }
             _ => {
                 log::warn!("fcntl cmd: {} not implemented, returning 0 as default", cmd);
                 Ok(0)
@@ -618,9 +721,36 @@ impl<'a> Syscall<'a> {
         }
     }
 
+    pub fn sys_memfd_create(&mut self) -> SyscallResult {
+        const MFD_CLOEXEC: usize = 1 << 0;
+        const MFD_ALLOW_SEALING: usize = 1 << 1;
+        /// 不算结尾的 0, 同 Linux 的 MFD_NAME_MAX_LEN
+        const MFD_NAME_MAX_LEN: usize = 249;
+
+        let args = self.cx.syscall_args();
+        let (name, flags) = (UserReadPtr::<u8>::from(args[0]), args[1]);
+        let name = name.read_cstr(&self.lproc)?;
+        info!(
+            "Syscall: memfd_create (name: {:?}, flags: {:#x})",
+            name, flags
+        );
+
+        // 不支持 MFD_HUGETLB
+        if flags & !(MFD_CLOEXEC | MFD_ALLOW_SEALING) != 0 {
+            return Err(SysError::EINVAL);
+        }
+        if name.len() > MFD_NAME_MAX_LEN {
+            return Err(SysError::EINVAL);
+        }
+
+        // 名字只用于调试, 文件不出现在任何目录中
+        let file = VfsFileRef::new(ShmemFile::new_memfd(flags & MFD_ALLOW_SEALING != 0));
+        self.lproc.with_mut_fdtable(|f| f.alloc(file))
+    }
+
     pub async fn sys_mount(&mut self) -> SyscallResult {
         let args = self.cx.syscall_args();
-        let (device, mount_point, _fs_type, _flags, _data) = (
+        let (device, mount_point, fs_type, flags, _data) = (
             UserReadPtr::<u8>::from(args[0]),
             UserReadPtr::<u8>::from(args[1]),
             UserReadPtr::<u8>::from(args[2]),
@@ -630,14 +760,17 @@ impl<'a> Syscall<'a> {
 
         let device = device.read_cstr(&self.lproc)?;
         let mount_point = mount_point.read_cstr(&self.lproc)?;
+        let fs_type = match fs_type.not_null() {
+            true => fs_type.read_cstr(&self.lproc)?,
+            false => String::new(),
+        };
 
         info!(
-            "Syscall: mount (device: {:?}, mount_point: {:?})",
-            device, mount_point
+            "Syscall: mount (device: {:?}, mount_point: {:?}, fs_type: {:?}, flags: {:#x})",
+            device, mount_point, fs_type, flags
         );
 
-        let _device_path = Path::from_string(device)?;
-        // TODO: real mount the device
+        let device_path = Path::from_string(device)?;
 
         // TODO: deal with relative path?
         let cwd = self.lproc.with_mut_fsinfo(|f| f.cwd.clone());
@@ -656,7 +789,12 @@ impl<'a> Syscall<'a> {
             warn!("mount: to pass the test, we create it");
             dir.create(&name, VfsFileKind::Directory).await?;
         }
-        dir.attach(&name, VfsFileRef::new(ZeroDev)).await?;
+
+        // 文件系统类型为空时自动识别, 不认识的类型返回 ENODEV
+        let blk_dev = fs::find_block_device(&device_path).await?;
+        let mounted_fs = fs::open_fs(blk_dev, &fs_type, flags & MS_RDONLY != 0).await?;
+        let mounted = GlobalMountManager::register_as_file(&mount_point.to_string(), mounted_fs);
+        dir.attach(&name, mounted).await?;
 
         Ok(0)
     }
@@ -676,4 +814,69 @@ impl<'a> Syscall<'a> {
         fs::get_root_dir().resolve(&dir_path).await?.detach(&file_name).await?;
         Ok(0)
     }
+
+    pub async fn sys_sync(&self) -> SyscallResult {
+        info!("Syscall: sync");
+        // sync 不返回错误, 某个文件系统写失败了也继续写别的
+        for (path, fs) in GlobalMountManager::list() {
+            if let Err(e) = fs.sync().await {
+                warn!("sync: failed to sync {:?}: {:?}", path, e);
+            }
+        }
+        Ok(0)
+    }
+
+    /// fsync 和 fdatasync 共用. 文件的大小和时间都在同一个目录项或 inode 里,
+    /// 分开写省不了什么, 所以 fdatasync 也会写回全部元数据
+    pub async fn sys_fsync(&self) -> SyscallResult {
+        let fd = self.cx.syscall_args()[0];
+        info!("Syscall: fsync, fd: {}", fd);
+        let file = self.lproc.with_mut_fdtable(|f| f.get(fd)).ok_or(SysError::EBADF)?;
+        file.file.sync().await?;
+        Ok(0)
+    }
+
+    pub async fn sys_syncfs(&self) -> SyscallResult {
+        let fd = self.cx.syscall_args()[0];
+        info!("Syscall: syncfs, fd: {}", fd);
+        let file = self.lproc.with_mut_fdtable(|f| f.get(fd)).ok_or(SysError::EBADF)?;
+        let device_id = file.file.attr_device().device_id;
+        // 管道之类不在任何挂载的文件系统上的文件, 没有什么要写的
+        let fs = GlobalMountManager::list()
+            .into_iter()
+            .find(|(_, fs)| fs.attr().fs_id == device_id);
+        if let Some((_, fs)) = fs {
+            fs.sync().await?;
+        }
+        Ok(0)
+    }
+
+    pub async fn sys_sync_file_range(&self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (fd, offset, nbytes, flags) =
+            (args[0], args[1] as isize, args[2] as isize, args[3] as u32);
+        info!(
+            "Syscall: sync_file_range, fd: {}, offset: {}, nbytes: {}, flags: {:#x}",
+            fd, offset, nbytes, flags
+        );
+
+        let valid =
+            SYNC_FILE_RANGE_WAIT_BEFORE | SYNC_FILE_RANGE_WRITE | SYNC_FILE_RANGE_WAIT_AFTER;
+        if flags & !valid != 0 || offset < 0 || nbytes < 0 {
+            return Err(SysError::EINVAL);
+        }
+        let file = self.lproc.with_mut_fdtable(|f| f.get(fd)).ok_or(SysError::EBADF)?;
+        match file.file.attr_kind() {
+            VfsFileKind::RegularFile
+            | VfsFileKind::Directory
+            | VfsFileKind::BlockDevice
+            | VfsFileKind::SymbolLink => {}
+            _ => return Err(SysError::ESPIPE),
+        }
+        // 写回都是同步完成的, 不用再等, 所以只看 WRITE
+        if flags & SYNC_FILE_RANGE_WRITE != 0 {
+            file.file.sync_range(offset as usize, nbytes as usize).await?;
+        }
+        Ok(0)
+    }
 }
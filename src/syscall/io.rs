@@ -4,8 +4,7 @@ use crate::{
     consts::MAX_OPEN_FILES,
     executor::util_futures::AnyFuture,
     fs::{
-        self,
-        new_vfs::{path::Path, top::PollKind, VfsFileKind},
+        new_vfs::{top::PollKind, VfsFileKind},
         npipe::Pipe,
     },
     memory::{address::VirtAddr, UserInOutPtr, UserReadPtr, UserWritePtr},
@@ -15,7 +14,7 @@ use crate::{
 };
 
 use super::{Syscall, SyscallResult};
-use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use alloc::{collections::BTreeMap, vec::Vec};
 
 impl Syscall<'_> {
     pub async fn sys_write(&mut self) -> SyscallResult {
@@ -70,7 +69,6 @@ impl Syscall<'_> {
     }
 
     pub async fn sys_openat(&mut self) -> SyscallResult {
-        // TODO: refactor using `at_helper`
         let args = self.cx.syscall_args();
         let (dir_fd, path, raw_flags, _user_mode) = (
             args[0],
@@ -92,53 +90,29 @@ impl Syscall<'_> {
             Ok(())
         })?;
 
-        let (dir, file_name) = self.at_helper(dir_fd, path.clone(), 0).await?;
-        let file = match self.lookup_helper(dir.clone(), &file_name).await {
+        let follow = !flags.contains(OpenFlags::NOFOLLOW);
+        let file = match self.resolve_helper(dir_fd, path.clone(), follow).await {
             Ok(file) => file,
             Err(SysError::ENOENT) => {
                 // Check if CREATE flag is set
                 if !flags.contains(OpenFlags::CREATE) {
                     return Err(SysError::ENOENT);
                 }
-                // Create file
+                // Create file, 上一级目录不存在时仍然返回 ENOENT
                 log::debug!("openat: creating new file");
+                let (dir, file_name) = self.at_helper(dir_fd, path, 0).await?;
                 dir.create(&file_name, VfsFileKind::RegularFile).await?
             }
             Err(e) => {
                 return Err(e);
             }
         };
+        // 同 Linux, O_NOFOLLOW 时最后一级是符号链接则失败
+        if !follow && file.is_symlink().await? {
+            return Err(SysError::ELOOP);
+        }
 
-        let final_file = if !flags.contains(OpenFlags::NOFOLLOW) {
-            let mut file = file;
-            let mut curr_path = Path::from_string(path)?;
-            loop {
-                // 递归跟随符号链接
-                if file.kind().await? == VfsFileKind::SymbolLink {
-                    let mut buf = Box::new([0u8; 512]);
-                    let read_len = file.read_at(0, &mut *buf).await?;
-                    if read_len == 512 {
-                        panic!("openat: path in symbol file too long");
-                    }
-                    let next_path_str = core::str::from_utf8(&buf[..read_len])
-                        .expect("invalid path in symbol file");
-                    let next_path = Path::from_str(next_path_str)?;
-
-                    curr_path = if next_path.is_absolute() {
-                        next_path
-                    } else {
-                        curr_path.append(&next_path)
-                    };
-                    file = fs::get_root_dir().resolve(&curr_path).await?;
-                } else {
-                    break file.clone();
-                };
-            }
-        } else {
-            file
-        };
-
-        self.lproc.with_mut_fdtable(|table| table.alloc(final_file))
+        self.lproc.with_mut_fdtable(|table| table.alloc(file))
     }
 
     pub async fn sys_readlinkat(&self) -> SyscallResult {
@@ -152,14 +126,12 @@ impl Syscall<'_> {
             dir_fd, path, buf, buf_len
         );
 
-        let (dir, file_name) = self.at_helper(dir_fd, path, 0).await?;
-
-        let file = dir.lookup(&file_name).await?;
+        let file = self.resolve_helper(dir_fd, path, false).await?;
         if file.kind().await? != VfsFileKind::SymbolLink {
             Err(SysError::EINVAL)
         } else {
             let buf = unsafe { VirtAddr::from(buf).as_mut_slice(buf_len) };
-            self.lproc.with_mut_memory(|m| m.force_map_buf(buf, UserAreaPerm::WRITE));
+            self.lproc.with_mut_memory(|m| m.force_map_buf(buf, UserAreaPerm::WRITE))?;
             file.read_at(0, buf).await
         }
     }
@@ -0,0 +1,593 @@
+//! System V IPC syscall: 共享内存, 消息队列和信号量
+//!
+
+use log::info;
+
+use alloc::vec::Vec;
+
+use crate::{
+    consts::{
+        address_space::{U_SEG_SHARE_BEG, U_SEG_SHARE_END},
+        PAGE_MASK,
+    },
+    memory::{address::VirtAddr, UserReadPtr, UserWritePtr},
+    process::{
+        ipc::{
+            await_or_signal,
+            msg::{self, Message, MSGMAX, MSGMNB, MSGMNI, MSG_COPY},
+            sem::{self, SemBuf, SEMMNI, SEMMSL, SEMOPM, SEMVMX},
+            IpcId, IpcKey, IPC_NOWAIT, IPC_PRIVATE,
+        },
+        user_space::{
+            shm_mgr::{global_shm_mgr, Shm, SHMALL, SHMMAX, SHMMIN, SHMMNI, SHM_DEST},
+            user_area::UserAreaPerm,
+        },
+    },
+    timer::{with_timeout, TimeSpec},
+    tools::errors::SysError,
+};
+
+use super::{Syscall, SyscallResult};
+
+/* Control commands for `msgctl', `semctl', and `shmctl'.  */
+/// Remove identifier.
+const IPC_RMID: usize = 0;
+/// Set `ipc_perm' options.
+const IPC_SET: usize = 1;
+/// Get `ipc_perm' options.
+const IPC_STAT: usize = 2;
+/// Get kernel structure.
+const IPC_INFO: usize = 3;
+/// musl 在 cmd 上带的新版结构体标志, 我们只支持 64 位的结构体
+const IPC_64: usize = 0x100;
+
+const SHM_RDONLY: usize = 0o10000;
+const SHM_RND: usize = 0o20000;
+const SHM_EXEC: usize = 0o100000;
+
+const SHM_LOCK: usize = 11;
+const SHM_UNLOCK: usize = 12;
+const SHM_STAT: usize = 13;
+const SHM_INFO: usize = 14;
+const SHM_STAT_ANY: usize = 15;
+
+const MSG_STAT: usize = 11;
+const MSG_INFO: usize = 12;
+const MSG_STAT_ANY: usize = 13;
+
+const GETPID: usize = 11;
+const GETVAL: usize = 12;
+const GETALL: usize = 13;
+const GETNCNT: usize = 14;
+const GETZCNT: usize = 15;
+const SETVAL: usize = 16;
+const SETALL: usize = 17;
+const SEM_STAT: usize = 18;
+const SEM_INFO: usize = 19;
+const SEM_STAT_ANY: usize = 20;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct IPCPerm {
+    pub __key: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub cuid: u32,
+    pub cgid: u32,
+    pub mode: u32,
+    pub __seq: u16,
+    pub __pad2: u16,
+    pub __glibc_reserved1: u64,
+    pub __glibc_reserved2: u64,
+}
+
+impl IPCPerm {
+    /// 我们没有用户, 所有对象都属于 root
+    fn new(key: IpcKey, mode: u32) -> Self {
+        Self {
+            __key: key as _,
+            uid: 0,
+            gid: 0,
+            cuid: 0,
+            cgid: 0,
+            mode,
+            __seq: 0,
+            __pad2: 0,
+            __glibc_reserved1: 0,
+            __glibc_reserved2: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ShmIdDs {
+    pub shm_perm: IPCPerm,
+    pub shm_segsz: u64,
+    pub shm_atime: u64,
+    pub shm_dtime: u64,
+    pub shm_ctime: u64,
+    pub shm_cpid: u32,
+    pub shm_lpid: u32,
+    pub shm_nattch: u64,
+    pub __glibc_reserved4: u64,
+    pub __glibc_reserved5: u64,
+}
+
+/// IPC_INFO 返回的 struct shminfo
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ShmInfoDs {
+    pub shmmax: u64,
+    pub shmmin: u64,
+    pub shmmni: u64,
+    pub shmseg: u64,
+    pub shmall: u64,
+    pub __unused: [u64; 4],
+}
+
+/// SHM_INFO 返回的 struct shm_info
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ShmUsageDs {
+    pub used_ids: i32,
+    pub shm_tot: u64,
+    pub shm_rss: u64,
+    pub shm_swp: u64,
+    pub swap_attempts: u64,
+    pub swap_successes: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MsqIdDs {
+    pub msg_perm: IPCPerm,
+    pub msg_stime: u64,
+    pub msg_rtime: u64,
+    pub msg_ctime: u64,
+    pub msg_cbytes: u64,
+    pub msg_qnum: u64,
+    pub msg_qbytes: u64,
+    pub msg_lspid: u32,
+    pub msg_lrpid: u32,
+    pub __unused4: u64,
+    pub __unused5: u64,
+}
+
+/// IPC_INFO 和 MSG_INFO 返回的 struct msginfo
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MsgInfoDs {
+    pub msgpool: i32,
+    pub msgmap: i32,
+    pub msgmax: i32,
+    pub msgmnb: i32,
+    pub msgmni: i32,
+    pub msgssz: i32,
+    pub msgtql: i32,
+    pub msgseg: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SemIdDs {
+    pub sem_perm: IPCPerm,
+    pub sem_otime: u64,
+    pub sem_ctime: u64,
+    pub sem_nsems: u64,
+    pub __unused3: u64,
+    pub __unused4: u64,
+}
+
+/// IPC_INFO 和 SEM_INFO 返回的 struct seminfo
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SemInfoDs {
+    pub semmap: i32,
+    pub semmni: i32,
+    pub semmns: i32,
+    pub semmnu: i32,
+    pub semmsl: i32,
+    pub semopm: i32,
+    pub semume: i32,
+    pub semusz: i32,
+    pub semvmx: i32,
+    pub semaem: i32,
+}
+
+fn shm_id_ds(shm: &Shm) -> ShmIdDs {
+    let stat = shm.stat();
+    // 同 Linux, 被标记删除的段的 key 报告为 IPC_PRIVATE
+    let (key, mode) = if stat.removed {
+        (IPC_PRIVATE, stat.mode | SHM_DEST)
+    } else {
+        (shm.key(), stat.mode)
+    };
+    ShmIdDs {
+        shm_perm: IPCPerm::new(key, mode),
+        shm_segsz: shm.size() as _,
+        shm_atime: stat.atime as _,
+        shm_dtime: stat.dtime as _,
+        shm_ctime: stat.ctime as _,
+        shm_cpid: Into::<usize>::into(shm.creater()) as _,
+        shm_lpid: Into::<usize>::into(stat.lpid) as _,
+        shm_nattch: stat.nattch as _,
+        __glibc_reserved4: 0,
+        __glibc_reserved5: 0,
+    }
+}
+
+impl<'a> Syscall<'a> {
+    pub fn sys_shmget(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (key, size, shmflg) = (args[0], args[1], args[2]);
+        info!(
+            "Syscall shmget: shmget key={} size={} shmflg={}",
+            key, size, shmflg
+        );
+
+        global_shm_mgr().get_or_create(key, size, shmflg, self.lproc.id())
+    }
+
+    pub fn sys_shmat(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (shmid, shmaddr, shmflg) = (args[0], args[1], args[2]);
+        info!(
+            "Syscall shmat: shmat shmid={} shmaddr={} shmflg={}",
+            shmid, shmaddr, shmflg
+        );
+
+        let shm = global_shm_mgr().get(shmid).ok_or(SysError::EINVAL)?;
+        let vaddr = if shmaddr == 0 {
+            None
+        } else {
+            let shmaddr = if shmflg & SHM_RND != 0 {
+                shmaddr & !PAGE_MASK
+            } else if shmaddr & PAGE_MASK != 0 {
+                return Err(SysError::EINVAL);
+            } else {
+                shmaddr
+            };
+            // shm 只能放在共享段中, fork 时才不会被标记为 CoW
+            if !(U_SEG_SHARE_BEG..U_SEG_SHARE_END).contains(&shmaddr) {
+                return Err(SysError::EINVAL);
+            }
+            Some(VirtAddr::from(shmaddr))
+        };
+        let pid = self.lproc.id();
+        let mut perm = if shmflg & SHM_RDONLY != 0 {
+            UserAreaPerm::READ
+        } else {
+            UserAreaPerm::READ | UserAreaPerm::WRITE
+        };
+        if shmflg & SHM_EXEC != 0 {
+            perm |= UserAreaPerm::EXECUTE;
+        }
+
+        let vaddr = self.lproc.with_mut_memory(|m| m.attach_shm(vaddr, pid, shmid, shm, perm))?;
+
+        Ok(vaddr.bits())
+    }
+
+    pub fn sys_shmdt(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let shmaddr = VirtAddr::from(args[0]);
+        info!("Syscall shmdt: shmdt shmaddr={:?}", shmaddr);
+        self.lproc.with_mut_memory(|m| m.detach_shm(shmaddr)).map(|()| 0)
+    }
+
+    pub fn sys_shmctl(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (shmid, cmd, buf) = (args[0], args[1] & !IPC_64, args[2]);
+        info!(
+            "Syscall shmctl: shmctl shmid={} cmd={} buf={:#x}",
+            shmid, cmd, buf
+        );
+
+        let mgr = global_shm_mgr();
+        match cmd {
+            IPC_INFO => {
+                let info = ShmInfoDs {
+                    shmmax: SHMMAX as _,
+                    shmmin: SHMMIN as _,
+                    shmmni: SHMMNI as _,
+                    shmseg: SHMMNI as _,
+                    shmall: SHMALL as _,
+                    __unused: [0; 4],
+                };
+                UserWritePtr::<ShmInfoDs>::from(buf).write(&self.lproc, info)?;
+                Ok(mgr.info().max_id)
+            }
+            SHM_INFO => {
+                let info = mgr.info();
+                let usage = ShmUsageDs {
+                    used_ids: info.segments as _,
+                    shm_tot: info.pages as _,
+                    shm_rss: info.pages as _,
+                    shm_swp: 0,
+                    swap_attempts: 0,
+                    swap_successes: 0,
+                };
+                UserWritePtr::<ShmUsageDs>::from(buf).write(&self.lproc, usage)?;
+                Ok(info.max_id)
+            }
+            _ => {
+                let shm = mgr.get(shmid).ok_or(SysError::EINVAL)?;
+                match cmd {
+                    IPC_STAT => {
+                        UserWritePtr::from(buf).write(&self.lproc, shm_id_ds(&shm))?;
+                        Ok(0)
+                    }
+                    // 我们没有 IPC 命名空间, id 就是下标
+                    SHM_STAT | SHM_STAT_ANY => {
+                        UserWritePtr::from(buf).write(&self.lproc, shm_id_ds(&shm))?;
+                        Ok(shmid)
+                    }
+                    IPC_SET => {
+                        let ds = UserReadPtr::<ShmIdDs>::from(buf).read(&self.lproc)?;
+                        shm.set_mode(ds.shm_perm.mode);
+                        Ok(0)
+                    }
+                    IPC_RMID => mgr.remove(shmid).map(|()| 0),
+                    // shm 的页本来就不会被换出
+                    SHM_LOCK | SHM_UNLOCK => Ok(0),
+                    _ => Err(SysError::EINVAL),
+                }
+            }
+        }
+    }
+
+    pub fn sys_msgget(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (key, msgflg) = (args[0], args[1]);
+        info!("Syscall msgget: key={} msgflg={:#o}", key, msgflg);
+
+        msg::msgget(key, msgflg)
+    }
+
+    pub async fn sys_msgsnd(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (msqid, msgp, msgsz, msgflg) = (args[0], args[1], args[2], args[3]);
+        info!(
+            "Syscall msgsnd: msqid={} msgp={:#x} msgsz={} msgflg={:#o}",
+            msqid, msgp, msgsz, msgflg
+        );
+
+        if msgsz > MSGMAX {
+            return Err(SysError::EINVAL);
+        }
+        let queue = msg::get(msqid)?;
+        // struct msgbuf { long mtype; char mtext[]; }
+        let mtype = UserReadPtr::<isize>::from(msgp).read(&self.lproc)?;
+        if mtype < 1 {
+            return Err(SysError::EINVAL);
+        }
+        let data = UserReadPtr::<u8>::from(msgp + 8).read_array(msgsz, &self.lproc)?;
+
+        let msg = Message { mtype, data };
+        let pid = self.lproc.id().into();
+        await_or_signal(queue.send(msg, msgflg & IPC_NOWAIT != 0, pid)).await?;
+        Ok(0)
+    }
+
+    pub async fn sys_msgrcv(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (msqid, msgp, msgsz, msgtyp, msgflg) =
+            (args[0], args[1], args[2], args[3] as isize, args[4]);
+        info!(
+            "Syscall msgrcv: msqid={} msgp={:#x} msgsz={} msgtyp={} msgflg={:#o}",
+            msqid, msgp, msgsz, msgtyp, msgflg
+        );
+
+        if (msgsz as isize) < 0 {
+            return Err(SysError::EINVAL);
+        }
+        // TODO: MSG_COPY 需要 CONFIG_CHECKPOINT_RESTORE, 不支持
+        if msgflg & MSG_COPY != 0 {
+            return Err(SysError::ENOSYS);
+        }
+        let queue = msg::get(msqid)?;
+        let pid = self.lproc.id().into();
+        let msg = await_or_signal(queue.receive(msgtyp, msgsz, msgflg, pid)).await?;
+
+        UserWritePtr::<isize>::from(msgp).write(&self.lproc, msg.mtype)?;
+        UserWritePtr::<u8>::from(msgp + 8).write_array(&self.lproc, &msg.data)?;
+        Ok(msg.data.len())
+    }
+
+    pub fn sys_msgctl(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (msqid, cmd, buf) = (args[0], args[1] & !IPC_64, args[2]);
+        info!("Syscall msgctl: msqid={} cmd={} buf={:#x}", msqid, cmd, buf);
+
+        match cmd {
+            IPC_INFO | MSG_INFO => {
+                let info = msg::info();
+                let mut ds = MsgInfoDs {
+                    msgpool: (MSGMNI * MSGMNB / 1024) as _,
+                    msgmap: MSGMNB as _,
+                    msgmax: MSGMAX as _,
+                    msgmnb: MSGMNB as _,
+                    msgmni: MSGMNI as _,
+                    msgssz: 16,
+                    msgtql: MSGMNB as _,
+                    msgseg: 0xffff,
+                };
+                // 同 Linux, MSG_INFO 在这几个字段中报告当前的使用情况
+                if cmd == MSG_INFO {
+                    ds.msgpool = info.queues as _;
+                    ds.msgmap = info.messages as _;
+                    ds.msgtql = info.bytes as _;
+                }
+                UserWritePtr::<MsgInfoDs>::from(buf).write(&self.lproc, ds)?;
+                Ok(info.max_id)
+            }
+            _ => {
+                let queue = msg::get(msqid)?;
+                match cmd {
+                    IPC_STAT | MSG_STAT | MSG_STAT_ANY => {
+                        let stat = queue.stat();
+                        let ds = MsqIdDs {
+                            msg_perm: IPCPerm::new(stat.key, stat.mode),
+                            msg_stime: stat.stime as _,
+                            msg_rtime: stat.rtime as _,
+                            msg_ctime: stat.ctime as _,
+                            msg_cbytes: stat.cbytes as _,
+                            msg_qnum: stat.qnum as _,
+                            msg_qbytes: stat.qbytes as _,
+                            msg_lspid: stat.lspid as _,
+                            msg_lrpid: stat.lrpid as _,
+                            __unused4: 0,
+                            __unused5: 0,
+                        };
+                        UserWritePtr::from(buf).write(&self.lproc, ds)?;
+                        Ok(if cmd == IPC_STAT { 0 } else { msqid })
+                    }
+                    IPC_SET => {
+                        let ds = UserReadPtr::<MsqIdDs>::from(buf).read(&self.lproc)?;
+                        queue.set(ds.msg_perm.mode, ds.msg_qbytes as usize)?;
+                        Ok(0)
+                    }
+                    IPC_RMID => msg::remove(msqid).map(|()| 0),
+                    _ => Err(SysError::EINVAL),
+                }
+            }
+        }
+    }
+
+    pub fn sys_semget(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (key, nsems, semflg) = (args[0], args[1], args[2]);
+        info!(
+            "Syscall semget: key={} nsems={} semflg={:#o}",
+            key, nsems, semflg
+        );
+
+        sem::semget(key, nsems, semflg)
+    }
+
+    pub async fn sys_semop(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (semid, sops, nsops) = (args[0], args[1], args[2]);
+        self.do_semtimedop(semid, sops, nsops, None).await
+    }
+
+    pub async fn sys_semtimedop(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (semid, sops, nsops) = (args[0], args[1], args[2]);
+        let timeout = UserReadPtr::<TimeSpec>::from(args[3]);
+        let timeout = if timeout.is_null() {
+            None
+        } else {
+            Some(timeout.read(&self.lproc)?.time_in_ms())
+        };
+        self.do_semtimedop(semid, sops, nsops, timeout).await
+    }
+
+    async fn do_semtimedop(
+        &mut self,
+        semid: IpcId,
+        sops: usize,
+        nsops: usize,
+        timeout: Option<usize>,
+    ) -> SyscallResult {
+        info!(
+            "Syscall semtimedop: semid={} sops={:#x} nsops={} timeout={:?}",
+            semid, sops, nsops, timeout
+        );
+
+        if nsops == 0 {
+            return Err(SysError::EINVAL);
+        }
+        if nsops > SEMOPM {
+            return Err(SysError::E2BIG);
+        }
+        let sops: Vec<SemBuf> = UserReadPtr::<SemBuf>::from(sops).read_array(nsops, &self.lproc)?;
+        let set = sem::get(semid)?;
+        let pid = self.lproc.id().into();
+
+        let semop = await_or_signal(set.semop(&sops, pid));
+        match timeout {
+            Some(ms) => with_timeout(ms, semop).await.ok_or(SysError::EAGAIN)??,
+            None => semop.await?,
+        }
+
+        self.lproc.with_mut_sem_undo(|u| u.record(semid, &sops));
+        Ok(0)
+    }
+
+    pub fn sys_semctl(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (semid, semnum, cmd, arg) = (args[0], args[1], args[2] & !IPC_64, args[3]);
+        info!(
+            "Syscall semctl: semid={} semnum={} cmd={} arg={:#x}",
+            semid, semnum, cmd, arg
+        );
+
+        match cmd {
+            IPC_INFO | SEM_INFO => {
+                let info = sem::info();
+                let mut ds = SemInfoDs {
+                    semmap: SEMMNI as _,
+                    semmni: SEMMNI as _,
+                    semmns: (SEMMNI * SEMMSL) as _,
+                    semmnu: SEMMNI as _,
+                    semmsl: SEMMSL as _,
+                    semopm: SEMOPM as _,
+                    semume: SEMOPM as _,
+                    semusz: 0,
+                    semvmx: SEMVMX,
+                    semaem: SEMVMX,
+                };
+                // 同 Linux, SEM_INFO 在这两个字段中报告当前的使用情况
+                if cmd == SEM_INFO {
+                    ds.semusz = info.sets as _;
+                    ds.semaem = info.sems as _;
+                }
+                UserWritePtr::<SemInfoDs>::from(arg).write(&self.lproc, ds)?;
+                return Ok(info.max_id);
+            }
+            IPC_RMID => return sem::remove(semid).map(|()| 0),
+            _ => {}
+        }
+
+        let set = sem::get(semid)?;
+        let pid = self.lproc.id().into();
+        match cmd {
+            IPC_STAT | SEM_STAT | SEM_STAT_ANY => {
+                let stat = set.stat();
+                let ds = SemIdDs {
+                    sem_perm: IPCPerm::new(stat.key, stat.mode),
+                    sem_otime: stat.otime as _,
+                    sem_ctime: stat.ctime as _,
+                    sem_nsems: stat.nsems as _,
+                    __unused3: 0,
+                    __unused4: 0,
+                };
+                UserWritePtr::from(arg).write(&self.lproc, ds)?;
+                Ok(if cmd == IPC_STAT { 0 } else { semid })
+            }
+            IPC_SET => {
+                let ds = UserReadPtr::<SemIdDs>::from(arg).read(&self.lproc)?;
+                set.set_mode(ds.sem_perm.mode);
+                Ok(0)
+            }
+            GETVAL => set.getval(semnum).map(|v| v as usize),
+            GETPID => set.getpid(semnum),
+            GETNCNT => set.getncnt(semnum),
+            GETZCNT => set.getzcnt(semnum),
+            GETALL => {
+                let vals = set.getall();
+                UserWritePtr::<u16>::from(arg).write_array(&self.lproc, &vals)?;
+                Ok(0)
+            }
+            // union semun 按值传递, 这里取其中的 int val
+            SETVAL => set.setval(semnum, arg as i32, pid).map(|()| 0),
+            SETALL => {
+                let vals = UserReadPtr::<u16>::from(arg).read_array(set.nsems(), &self.lproc)?;
+                set.setall(&vals, pid).map(|()| 0)
+            }
+            _ => Err(SysError::EINVAL),
+        }
+    }
+}
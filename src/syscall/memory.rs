@@ -4,18 +4,24 @@
 use bitflags::bitflags;
 use log::info;
 
+use alloc::string::String;
+
 use crate::{
-    consts::PAGE_MASK,
-    memory::{address::VirtAddr, pagetable::pte::PTEFlags, UserWritePtr},
-    process::user_space::{
-        shm_mgr::{global_shm_mgr, ShmId},
-        user_area::UserAreaPerm,
+    consts::{address_space::U_SEG_END, PAGE_MASK},
+    fs::{
+        memfs::shmem::ShmemFile,
+        new_vfs::{path::Path, top::VfsFileRef, DeviceIDCollection, VfsFileKind},
+    },
+    memory::{
+        address::{VirtAddr, VirtAddrRange},
+        pagetable::pte::PTEFlags,
+        swap, UserReadPtr, UserWritePtr,
     },
-    syscall::memory::ipc::{ShmIdDs, IPC_RMID, IPC_SET, IPC_STAT},
-    tools::errors::{LinuxError, SysError},
+    process::user_space::user_area::{MemLock, UserAreaPerm},
+    tools::errors::{LinuxError, SysError, SysResult},
 };
 
-use super::{Syscall, SyscallResult};
+use super::{fs::AT_FDCWD, Syscall, SyscallResult};
 
 bitflags! {
     /// 指定 mmap 的选项
@@ -77,11 +83,40 @@ bitflags! {
         const MAP_FIXED = 1 << 4;
         /// 不映射到实际文件
         const MAP_ANONYMOUS = 1 << 5;
+        /// 用作栈, 访问下方时自动向下增长
+        const MAP_GROWSDOWN = 1 << 8;
         /// 映射时不保留空间，即可能在实际使用 mmap 出来的内存时内存溢出
         const MAP_NORESERVE = 1 << 14;
+        /// 用作线程栈, 目前没有特殊处理
+        const MAP_STACK = 1 << 17;
     }
 }
 
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub struct MlockAllFlags: u32 {
+        /// 锁定现有的所有映射
+        const MCL_CURRENT = 1 << 0;
+        /// 锁定之后新建的映射
+        const MCL_FUTURE = 1 << 1;
+        /// 与以上两者一起使用, 页在第一次访问时才分配
+        const MCL_ONFAULT = 1 << 2;
+    }
+}
+
+/// mlock2 的 flags, 页在第一次访问时才分配
+const MLOCK_ONFAULT: usize = 1;
+
+/// madvise 的 advice, 其余的都当作只是建议而忽略
+const MADV_MERGEABLE: usize = 12;
+const MADV_UNMERGEABLE: usize = 13;
+
+/// mlock 系列的范围: 起始地址向下取整到页, 结束地址向上取整到页
+fn mlock_range(start: usize, len: usize) -> VirtAddrRange {
+    let end = VirtAddr::from(start.saturating_add(len));
+    VirtAddr::from(VirtAddr::from(start).floor())..VirtAddr::from(end.ceil())
+}
+
 impl<'a> Syscall<'a> {
     pub fn sys_brk(&mut self) -> SyscallResult {
         let args = self.cx.syscall_args();
@@ -133,26 +168,40 @@ impl<'a> Syscall<'a> {
             // 根据 linux 规范需要 fd 设为 -1 且 offset 设为 0
             if fd == -1 && offset == 0 {
                 return self.lproc.with_mut_memory(|m| {
-                    m.areas_mut()
-                        .insert_mmap_anonymous(len, prot.into())
-                        .map(|(r, _)| r.start.bits())
+                    let range = if flags.contains(MMAPFlags::MAP_SHARED) {
+                        // 共享的匿名映射, 同 Linux, 背后是一个不属于任何目录的内存文件
+                        let file = VfsFileRef::new(ShmemFile::new(DeviceIDCollection::TMP_FS_ID));
+                        m.areas_mut().insert_mmap_shared(len, prot.into(), file, 0)
+                    } else if flags.contains(MMAPFlags::MAP_GROWSDOWN) {
+                        m.areas_mut().insert_mmap_growsdown(len, prot.into())
+                    } else {
+                        m.areas_mut().insert_mmap_anonymous(len, prot.into())
+                    }
+                    .map(|(r, _)| r)?;
+                    // mlockall(MCL_FUTURE) 之后的映射要立即分配, 同 Linux, 失败了也不报错
+                    let _ = m.populate_locked(range.clone());
+                    Ok(range.start.bits())
                 });
             }
         } else {
             // File
             if fd >= 0 {
                 let fd = fd as usize;
-                return self.lproc.with_mut_fdtable(|f| {
-                    if let Some(fd) = f.get(fd) {
-                        // Currently, we don't support shared mappings.
-                        self.lproc.with_mut_memory(|m| {
-                            m.areas_mut()
-                                .insert_mmap_private(len, prot.into(), fd.file.clone(), offset)
-                                .map(|(r, _)| r.start.bits())
-                        })
+                let file =
+                    self.lproc.with_mut_fdtable(|f| f.get(fd)).ok_or(SysError::EBADF)?.file.clone();
+                // 目前只有内存文件 (tmpfs, memfd) 支持共享映射, 其他文件仍按私有映射处理
+                let shared =
+                    flags.contains(MMAPFlags::MAP_SHARED) && file.as_any().is::<ShmemFile>();
+                // 可写的共享映射不能映射有 WRITE 封印的文件, 由 insert_mmap_shared 检查
+                return self.lproc.with_mut_memory(|m| {
+                    let range = if shared {
+                        m.areas_mut().insert_mmap_shared(len, prot.into(), file, offset)
                     } else {
-                        Err(SysError::EBADF)
+                        m.areas_mut().insert_mmap_private(len, prot.into(), file, offset)
                     }
+                    .map(|(r, _)| r)?;
+                    let _ = m.populate_locked(range.clone());
+                    Ok(range.start.bits())
                 });
             }
         }
@@ -197,11 +246,13 @@ impl<'a> Syscall<'a> {
         self.lproc.with_mut_memory(|m| {
             let (old_range, area) =
                 m.areas_mut().get_mut(start.into()).ok_or(LinuxError::ENOMEM)?;
+            // 有 WRITE 或 FUTURE_WRITE 封印的内存文件的共享映射不能改为可写
             if new_range == old_range {
-                area.set_perm(prot.into());
+                area.set_perm(prot.into())?;
+                m.protect_range(new_range, prot.into());
             } else {
                 // Do split and remap
-                m.remap_range(new_range, prot.into());
+                m.remap_range(new_range, prot.into())?;
             }
             Ok(0)
         })?;
@@ -209,178 +260,162 @@ impl<'a> Syscall<'a> {
         Ok(0)
     }
 
-    pub fn sys_shmget(&mut self) -> SyscallResult {
-        use ipc::*;
+    pub fn sys_mlock(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (start, len) = (args[0], args[1]);
+        info!("Syscall mlock: start={:#x} len={:#x}", start, len);
+
+        self.mlock_helper(mlock_range(start, len), MemLock::Locked)
+    }
 
+    pub fn sys_mlock2(&mut self) -> SyscallResult {
         let args = self.cx.syscall_args();
-        let (key, size, shmflg) = (args[0], args[1], args[2]);
+        let (start, len, flags) = (args[0], args[1], args[2]);
         info!(
-            "Syscall shmget: shmget key={} size={} shmflg={}",
-            key, size, shmflg
+            "Syscall mlock2: start={:#x} len={:#x} flags={:#x}",
+            start, len, flags
         );
 
-        let mgr = global_shm_mgr();
-
-        let shm = if let Some(shm) = mgr.get(key) {
-            if (shmflg & IPC_CREAT != 0) && (shmflg & IPC_EXCL != 0) {
-                return Err(SysError::EEXIST);
-            }
-            shm
-        } else {
-            if shmflg & IPC_CREAT == 0 {
-                return Err(SysError::ENOENT);
-            }
-
-            let key = if key == IPC_PRIVATE { None } else { Some(key) };
-
-            mgr.create(key, size, self.lproc.id())?
+        let mlock = match flags {
+            0 => MemLock::Locked,
+            MLOCK_ONFAULT => MemLock::OnFault,
+            _ => return Err(SysError::EINVAL),
         };
+        self.mlock_helper(mlock_range(start, len), mlock)
+    }
 
-        let id = self.lproc.with_mut_shm_table(|f| f.alloc(shm));
-        Ok(id)
+    fn mlock_helper(&self, range: VirtAddrRange, mlock: MemLock) -> SyscallResult {
+        self.lproc.with_mut_memory(|m| {
+            m.areas_mut().set_mlock(range.clone(), mlock)?;
+            m.populate_locked(range)
+        })?;
+        Ok(0)
     }
 
-    pub fn sys_shmat(&mut self) -> SyscallResult {
-        use ipc::*;
+    pub fn sys_madvise(&mut self) -> SyscallResult {
         let args = self.cx.syscall_args();
-        let (shmid, shmaddr, shmflg) = (args[0], args[1], args[2]);
+        let (start, len, advice) = (args[0], args[1], args[2]);
         info!(
-            "Syscall shmat: shmat shmid={} shmaddr={} shmflg={}",
-            shmid, shmaddr, shmflg
+            "Syscall madvise: start={:#x} len={:#x} advice={}",
+            start, len, advice
         );
 
-        let shm = self.lproc.with_shm_table(|f| f.get(shmid)).ok_or(SysError::EINVAL)?;
-        let vaddr = if shmaddr == 0 {
-            None
-        } else {
-            Some(VirtAddr::from(shmaddr))
-        };
-        let pid = self.lproc.id();
-        let perm = if shmflg & SHM_RDONLY != 0 {
-            UserAreaPerm::READ
-        } else {
-            UserAreaPerm::READ | UserAreaPerm::WRITE
-        };
+        if start & PAGE_MASK != 0 {
+            return Err(SysError::EINVAL);
+        }
+        let range = mlock_range(start, len);
+        match advice {
+            MADV_MERGEABLE | MADV_UNMERGEABLE => {
+                let mergeable = advice == MADV_MERGEABLE;
+                self.lproc.with_mut_memory(|m| m.set_mergeable(range, mergeable))?;
+                Ok(0)
+            }
+            _ => Ok(0),
+        }
+    }
 
-        let vaddr = self
-            .lproc
-            .with_mut_memory(|m| m.attach_shm(vaddr, pid, shmid as ShmId, shm, perm))?;
+    pub fn sys_munlock(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let (start, len) = (args[0], args[1]);
+        info!("Syscall munlock: start={:#x} len={:#x}", start, len);
 
-        Ok(vaddr.bits())
+        let range = mlock_range(start, len);
+        self.lproc.with_mut_memory(|m| m.areas_mut().set_mlock(range, MemLock::None))?;
+        Ok(0)
     }
 
-    pub fn sys_shmdt(&mut self) -> SyscallResult {
+    pub fn sys_mlockall(&mut self) -> SyscallResult {
         let args = self.cx.syscall_args();
-        let shmaddr = VirtAddr::from(args[0]);
-        info!("Syscall shmdt: shmdt shmaddr={:?}", shmaddr);
-        self.lproc.with_mut_memory(|m| m.detach_shm(shmaddr)).map(|()| 0)
+        let flags = MlockAllFlags::from_bits(args[0] as u32).ok_or(SysError::EINVAL)?;
+        info!("Syscall mlockall: flags=[{:?}]", flags);
+
+        if !flags.intersects(MlockAllFlags::MCL_CURRENT | MlockAllFlags::MCL_FUTURE) {
+            return Err(SysError::EINVAL);
+        }
+        let mlock = if flags.contains(MlockAllFlags::MCL_ONFAULT) {
+            MemLock::OnFault
+        } else {
+            MemLock::Locked
+        };
+
+        self.lproc.with_mut_memory(|m| {
+            // 没有 MCL_FUTURE 时, 取消之前的 MCL_FUTURE
+            m.areas_mut().set_mlock_future(if flags.contains(MlockAllFlags::MCL_FUTURE) {
+                mlock
+            } else {
+                MemLock::None
+            });
+            if flags.contains(MlockAllFlags::MCL_CURRENT) {
+                m.areas_mut().mlock_all(mlock)?;
+                m.populate_locked(VirtAddr::from(0)..VirtAddr::from(U_SEG_END))?;
+            }
+            Ok(())
+        })?;
+        Ok(0)
+    }
+
+    pub fn sys_munlockall(&mut self) -> SyscallResult {
+        info!("Syscall munlockall");
+        self.lproc.with_mut_memory(|m| m.areas_mut().munlock_all());
+        Ok(0)
     }
 
-    pub fn sys_shmctl(&mut self) -> SyscallResult {
+    pub fn sys_mincore(&mut self) -> SyscallResult {
         let args = self.cx.syscall_args();
-        let (shmid, cmd, buf) = (args[0], args[1], UserWritePtr::<ShmIdDs>::from(args[2]));
+        let (start, len, vec) = (args[0], args[1], UserWritePtr::<u8>::from(args[2]));
         info!(
-            "Syscall shmctl: shmctl shmid={} cmd={} buf={}",
-            shmid, cmd, buf
+            "Syscall mincore: start={:#x} len={:#x} vec={}",
+            start, len, vec
         );
 
-        let shm = self.lproc.with_shm_table(|f| f.get(shmid)).ok_or(SysError::EINVAL)?;
-
-        match cmd {
-            IPC_STAT => {
-                let data = ShmIdDs {
-                    shm_perm: ipc::IPCPerm {
-                        __key: shmid as _,
-                        uid: 0,
-                        gid: 0,
-                        cuid: 0,
-                        cgid: 0,
-                        mode: 0o777,
-                        __seq: 0,
-                        __pad2: 0,
-                        __glibc_reserved1: 0,
-                        __glibc_reserved2: 0,
-                    },
-                    shm_segsz: shm.size() as _,
-                    shm_atime: 0,
-                    shm_dtime: 0,
-                    shm_ctime: 0,
-                    shm_cpid: Into::<usize>::into(shm.creater()) as _,
-                    shm_lpid: Into::<usize>::into(shm.last_operater()) as _,
-                    shm_nattch: shm.attach_cnt() as _,
-                    __glibc_reserved4: 0,
-                    __glibc_reserved5: 0,
-                };
-                buf.write(&self.lproc, data)?;
-                Ok(0)
-            }
-            IPC_SET => {
-                log::info!("IPC_SET can change nothing now");
-                Ok(0)
-            }
-            IPC_RMID => {
-                let result = self.lproc.with_mut_shm_table(|f| f.remove(shmid));
-                match result {
-                    Some(_) => Ok(0),
-                    None => Err(SysError::EINVAL),
-                }
-            }
-            _ => Err(SysError::EINVAL),
+        if start & PAGE_MASK != 0 {
+            return Err(SysError::EINVAL);
         }
+        let residency = self.lproc.with_memory(|m| m.mincore(mlock_range(start, len)))?;
+        vec.write_array(&self.lproc, &residency)?;
+        Ok(0)
+    }
+
+    pub async fn sys_swapon(&mut self) -> SyscallResult {
+        const SWAP_FLAG_PREFER: usize = 0x8000;
+        const SWAP_FLAG_PRIO_MASK: usize = 0x7fff;
+
+        let args = self.cx.syscall_args();
+        let (path, flags) = (UserReadPtr::<u8>::from(args[0]), args[1]);
+        let path = path.read_cstr(&self.lproc)?;
+        info!("Syscall swapon: path={:?} flags={:#x}", path, flags);
+
+        let file = self.resolve_helper(AT_FDCWD, path.clone(), true).await?;
+        match file.attr_kind() {
+            VfsFileKind::RegularFile | VfsFileKind::BlockDevice => {}
+            _ => return Err(SysError::EINVAL),
+        }
+
+        let prio = if flags & SWAP_FLAG_PREFER != 0 {
+            Some((flags & SWAP_FLAG_PRIO_MASK) as isize)
+        } else {
+            None
+        };
+        swap::swapon(file, self.swap_path(path)?, prio).await?;
+        Ok(0)
     }
-}
 
-mod ipc {
-    /* Mode bits for `msgget', `semget', and `shmget'.  */
-    /// Create key if key does not exist.
-    pub const IPC_CREAT: usize = 0o1000;
-    /// Fail if key exists.
-    pub const IPC_EXCL: usize = 0o2000;
-    /// Return error on wait.
-    pub const IPC_NOWAIT: usize = 0o4000;
-
-    /* Control commands for `msgctl', `semctl', and `shmctl'.  */
-    /// Remove identifier.
-    pub const IPC_RMID: usize = 0;
-    /// Set `ipc_perm' options.
-    pub const IPC_SET: usize = 1;
-    /// Get `ipc_perm' options.
-    pub const IPC_STAT: usize = 2;
-    /// Get kernel structure.
-    pub const IPC_INFO: usize = 3;
-
-    /* Special key values.  */
-    /// Private key.
-    pub const IPC_PRIVATE: usize = 0;
-
-    pub const SHM_RDONLY: usize = 0o10000;
-
-    #[repr(C)]
-    #[derive(Clone, Copy)]
-    pub struct IPCPerm {
-        pub __key: u32,
-        pub uid: u32,
-        pub gid: u32,
-        pub cuid: u32,
-        pub cgid: u32,
-        pub mode: u32,
-        pub __seq: u16,
-        pub __pad2: u16,
-        pub __glibc_reserved1: u64,
-        pub __glibc_reserved2: u64,
+    pub async fn sys_swapoff(&mut self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let path = UserReadPtr::<u8>::from(args[0]).read_cstr(&self.lproc)?;
+        info!("Syscall swapoff: path={:?}", path);
+
+        swap::swapoff(&self.swap_path(path)?).await?;
+        Ok(0)
     }
-    #[repr(C)]
-    #[derive(Clone, Copy)]
-    pub struct ShmIdDs {
-        pub shm_perm: IPCPerm,
-        pub shm_segsz: u64,
-        pub shm_atime: u64,
-        pub shm_dtime: u64,
-        pub shm_ctime: u64,
-        pub shm_cpid: u32,
-        pub shm_lpid: u32,
-        pub shm_nattch: u64,
-        pub __glibc_reserved4: u64,
-        pub __glibc_reserved5: u64,
+
+    /// swap 区以绝对路径区分
+    fn swap_path(&self, path: String) -> SysResult<String> {
+        let mut path = Path::from_string(path)?;
+        if !path.is_absolute() {
+            let cwd = self.lproc.with_fsinfo(|f| f.cwd.clone());
+            path = Path::from_string(cwd.to_string() + "/" + &path.to_string())?;
+        }
+        Ok(path.to_string())
     }
 }
@@ -1,5 +1,6 @@
 mod fs;
 mod io;
+mod ipc;
 mod memory;
 mod misc;
 mod process;
@@ -64,14 +65,19 @@ impl<'a> Syscall<'a> {
             SYSCALL_NEWFSTAT => self.sys_fstat().await,
             SYSCALL_NEWFSTATAT => self.sys_fstatat().await,
             SYSCALL_GETDENTS => self.sys_getdents().await,
-            SYSCALL_LINKAT => todo!(),
+            SYSCALL_SYMLINKAT => self.sys_symlinkat().await,
+            SYSCALL_LINKAT => self.sys_linkat().await,
             SYSCALL_UNLINKAT => self.sys_unlinkat().await,
-            SYSCALL_FCNTL => self.sys_fcntl(),
+            SYSCALL_FCNTL => self.sys_fcntl().await,
+            SYSCALL_MEMFD_CREATE => self.sys_memfd_create(),
             SYSCALL_MKDIRAT => self.sys_mkdir().await,
             SYSCALL_UMOUNT => self.sys_umount().await,
             SYSCALL_MOUNT => self.sys_mount().await,
-            SYSCALL_SYNC => self.sys_do_nothing("sync"),
-            SYSCALL_FSYNC => self.sys_do_nothing("fsync"),
+            SYSCALL_SYNC => self.sys_sync().await,
+            SYSCALL_FSYNC => self.sys_fsync().await,
+            SYSCALL_FDATASYNC => self.sys_fsync().await,
+            SYSCALL_SYNC_FILE_RANGE => self.sys_sync_file_range().await,
+            SYSCALL_SYNCFS => self.sys_syncfs().await,
             SYSCALL_FTURNCATE => self.sys_fturncate().await,
             SYSCALL_READLINKAT => self.sys_readlinkat().await,
             SYSCALL_RENAMEAT2 => self.sys_renameat2().await,
@@ -93,6 +99,7 @@ impl<'a> Syscall<'a> {
             SYSCALL_GETRLIMIT => self.sys_getrlimit(),
             SYSCALL_PRLIMIT => self.sys_prlimit(),
             SYSCALL_EXIT_GROUP => self.sys_exitgroup(),
+            SYSCALL_PERSONALITY => self.sys_personality(),
             SYSCALL_GETPGID => self.sys_getpgid(),
             SYSCALL_SETPGID => self.sys_setpgid(),
             SYSCALL_FUTEX => {
@@ -112,11 +119,29 @@ impl<'a> Syscall<'a> {
             SYSCALL_MUNMAP => self.sys_munmap(),
             SYSCALL_MMAP => self.sys_mmap(),
             SYSCALL_MPROTECT => self.sys_mprotect(),
+            SYSCALL_MADVISE => self.sys_madvise(),
+            SYSCALL_MLOCK => self.sys_mlock(),
+            SYSCALL_MLOCK2 => self.sys_mlock2(),
+            SYSCALL_MUNLOCK => self.sys_munlock(),
+            SYSCALL_MLOCKALL => self.sys_mlockall(),
+            SYSCALL_MUNLOCKALL => self.sys_munlockall(),
+            SYSCALL_MINCORE => self.sys_mincore(),
+            SYSCALL_SWAPON => self.sys_swapon().await,
+            SYSCALL_SWAPOFF => self.sys_swapoff().await,
+
+            // System V IPC
             SYSCALL_SHMGET => self.sys_shmget(),
             SYSCALL_SHMCTL => self.sys_shmctl(),
             SYSCALL_SHMAT => self.sys_shmat(),
             SYSCALL_SHMDT => self.sys_shmdt(),
-            SYSCALL_MADVISE => self.sys_do_nothing("madvise"),
+            SYSCALL_MSGGET => self.sys_msgget(),
+            SYSCALL_MSGSND => self.sys_msgsnd().await,
+            SYSCALL_MSGRCV => self.sys_msgrcv().await,
+            SYSCALL_MSGCTL => self.sys_msgctl(),
+            SYSCALL_SEMGET => self.sys_semget(),
+            SYSCALL_SEMOP => self.sys_semop().await,
+            SYSCALL_SEMTIMEDOP => self.sys_semtimedop().await,
+            SYSCALL_SEMCTL => self.sys_semctl(),
 
             // Resource related
             SYSCALL_SCHED_SETSCHEDULER => self.sys_sched_setscheduler(),
@@ -189,6 +214,7 @@ pub const SYSCALL_FCNTL: usize = 25;
 pub const SYSCALL_IOCTL: usize = 29;
 pub const SYSCALL_MKDIRAT: usize = 34;
 pub const SYSCALL_UNLINKAT: usize = 35;
+pub const SYSCALL_SYMLINKAT: usize = 36;
 pub const SYSCALL_LINKAT: usize = 37;
 pub const SYSCALL_UMOUNT: usize = 39;
 pub const SYSCALL_MOUNT: usize = 40;
@@ -217,7 +243,10 @@ pub const SYSCALL_NEWFSTATAT: usize = 79;
 pub const SYSCALL_NEWFSTAT: usize = 80;
 pub const SYSCALL_SYNC: usize = 81;
 pub const SYSCALL_FSYNC: usize = 82;
+pub const SYSCALL_FDATASYNC: usize = 83;
+pub const SYSCALL_SYNC_FILE_RANGE: usize = 84;
 pub const SYSCALL_UTIMENSAT: usize = 88;
+pub const SYSCALL_PERSONALITY: usize = 92;
 pub const SYSCALL_EXIT: usize = 93;
 pub const SYSCALL_EXIT_GROUP: usize = 94;
 pub const SYSCALL_SET_TID_ADDRESS: usize = 96;
@@ -258,6 +287,14 @@ pub const SYSCALL_GETEUID: usize = 175;
 pub const SYSCALL_GETEGID: usize = 177;
 pub const SYSCALL_GETTID: usize = 178;
 pub const SYSCALL_SYSINFO: usize = 179;
+pub const SYSCALL_MSGGET: usize = 186;
+pub const SYSCALL_MSGCTL: usize = 187;
+pub const SYSCALL_MSGRCV: usize = 188;
+pub const SYSCALL_MSGSND: usize = 189;
+pub const SYSCALL_SEMGET: usize = 190;
+pub const SYSCALL_SEMCTL: usize = 191;
+pub const SYSCALL_SEMTIMEDOP: usize = 192;
+pub const SYSCALL_SEMOP: usize = 193;
 pub const SYSCALL_SHMGET: usize = 194;
 pub const SYSCALL_SHMCTL: usize = 195;
 pub const SYSCALL_SHMAT: usize = 196;
@@ -276,13 +313,23 @@ pub const SYSCALL_MUNMAP: usize = 215;
 pub const SYSCALL_CLONE: usize = 220;
 pub const SYSCALL_EXECVE: usize = 221;
 pub const SYSCALL_MMAP: usize = 222;
+pub const SYSCALL_SWAPON: usize = 224;
+pub const SYSCALL_SWAPOFF: usize = 225;
 pub const SYSCALL_MPROTECT: usize = 226;
 pub const SYSCALL_MSYNC: usize = 227;
+pub const SYSCALL_MLOCK: usize = 228;
+pub const SYSCALL_MUNLOCK: usize = 229;
+pub const SYSCALL_MLOCKALL: usize = 230;
+pub const SYSCALL_MUNLOCKALL: usize = 231;
+pub const SYSCALL_MINCORE: usize = 232;
 pub const SYSCALL_MADVISE: usize = 233;
 pub const SYSCALL_WAIT: usize = 260;
 pub const SYSCALL_PRLIMIT: usize = 261;
+pub const SYSCALL_SYNCFS: usize = 267;
 pub const SYSCALL_RENAMEAT2: usize = 276;
+pub const SYSCALL_MEMFD_CREATE: usize = 279;
 pub const SYSCALL_MEMBARRIER: usize = 283;
+pub const SYSCALL_MLOCK2: usize = 284;
 pub const SYSCALL_COPY_FILE_RANGE: usize = 285;
 pub const SYSCALL_STOP: usize = 998;
 pub const SYSCALL_SHUTDOWN: usize = 999;
@@ -213,7 +213,7 @@ impl<'a> Syscall<'a> {
         };
 
         let old_lproc = self.lproc.clone();
-        let new_lproc = old_lproc.do_clone(flags, stack_begin);
+        let new_lproc = old_lproc.do_clone(flags, stack_begin)?;
 
         if flags.contains(CloneFlags::CHILD_CLEARTID) {
             warn!("clear child tid, wait for signal subsystem");
@@ -328,7 +328,16 @@ impl<'a> Syscall<'a> {
             fs::get_root_dir().resolve(&path).await?
         };
 
-        self.lproc.do_exec(file, argv, envp).await;
+        if let Err(e) = self.lproc.do_exec(file, argv, envp).await {
+            // 旧的地址空间已经没有了, 没法把错误返回给用户, 同 Linux 只能杀掉进程.
+            // 页帧不够时还要让 OOM killer 腾出内存, 否则别的进程也会接着失败
+            warn!("execve: process {:?} killed: {:?}", self.lproc.id(), e);
+            if e == SysError::ENOMEM {
+                process::oom::out_of_memory();
+            }
+            self.lproc.send_signal(signal::SignalSet::SIGKILL.get_signum());
+            return Err(e);
+        }
         self.lproc.with_mut_procfs_info(|info| {
             info.exe_path = Some(path);
         });
@@ -417,6 +426,12 @@ impl<'a> Syscall<'a> {
         if old_limit.not_null() {
             let limit = match res {
                 RLimitResource::NOFILE => target_lproc.with_fdtable(|f| f.get_limit()) as u64,
+                RLimitResource::STACK => {
+                    target_lproc.with_memory(|m| m.areas().stack_limit()) as u64
+                }
+                RLimitResource::MEMLOCK => {
+                    target_lproc.with_memory(|m| m.areas().memlock_limit()) as u64
+                }
                 _ => {
                     // not impl yet, just return 0
                     log::warn!("prlimit(get): not impl for {:?} yet, use INF", res);
@@ -432,6 +447,11 @@ impl<'a> Syscall<'a> {
                 RLimitResource::NOFILE => {
                     target_lproc.with_mut_fdtable(|f| f.set_limit(limit as usize))
                 }
+                RLimitResource::STACK => {
+                    target_lproc.with_mut_memory(|m| m.areas_mut().set_stack_limit(limit as usize))
+                }
+                RLimitResource::MEMLOCK => target_lproc
+                    .with_mut_memory(|m| m.areas_mut().set_memlock_limit(limit as usize)),
                 _ => {
                     log::warn!("prlimit(set): not impl for {:?} yet, do nothing", res);
                 }
@@ -441,6 +461,19 @@ impl<'a> Syscall<'a> {
         Ok(0)
     }
 
+    pub fn sys_personality(&self) -> SyscallResult {
+        let args = self.cx.syscall_args();
+        let persona = args[0] as u32;
+        info!("Syscall: personality, persona: {:#x}", persona);
+
+        let old = self.lproc.personality();
+        // 0xffffffff 表示只查询
+        if persona != u32::MAX {
+            self.lproc.set_personality(persona as usize);
+        }
+        Ok(old)
+    }
+
     pub fn sys_getpgid(&self) -> SyscallResult {
         let args = self.cx.syscall_args();
         let target_lproc_pid = Pid::from(args[0]);
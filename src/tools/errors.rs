@@ -102,6 +102,12 @@ pub enum LinuxError {
     ENOSYS = 38,
     /// Directory not empty
     ENOTEMPTY = 39,
+    /// Too many symbolic links encountered
+    ELOOP = 40,
+    /// No message of desired type
+    ENOMSG = 42,
+    /// Identifier removed
+    EIDRM = 43,
     /// Transport endpoint is not connected
     ENOTCONN = 107,
     /// Connection refused
@@ -152,6 +158,9 @@ impl LinuxError {
             ENOLCK => "No record locks available",
             ENOSYS => "Invalid system call number",
             ENOTEMPTY => "Directory not empty",
+            ELOOP => "Too many symbolic links encountered",
+            ENOMSG => "No message of desired type",
+            EIDRM => "Identifier removed",
             ENOTCONN => "Transport endpoint is not connected",
             ECONNREFUSED => "Connection refused",
         }
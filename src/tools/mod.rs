@@ -3,6 +3,7 @@ pub mod errors;
 pub mod handler_pool;
 pub mod hash;
 pub mod pointers;
+pub mod random;
 pub mod sync_ptr;
 pub mod with_dirty;
 
@@ -19,7 +20,8 @@ macro_rules! when_debug {
 
 pub use when_debug;
 
-/// debug 用的, 用于在 log 之间快速判定两个 buf 的内容是否相等
+/// debug 用的, 用于在 log 之间快速判定两个 buf 的内容是否相等.
+/// KSM 也用它作为页内容的 hash
 pub fn exam_hash(buf: &[u8]) -> usize {
     let mut h: usize = 5381;
     for c in buf {
@@ -0,0 +1,37 @@
+//! 内核随机数
+//!
+//! 没有硬件随机数发生器, 熵源是设备树的 /chosen/rng-seed (QEMU 会提供) 和 mtime,
+//! 输出经过 splitmix64 打散. 只用于 ASLR 之类的场合, 不能用于密码学.
+
+use crate::timer;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+static STATE: AtomicU64 = AtomicU64::new(GAMMA);
+
+fn splitmix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// 把 bytes 混入随机数状态
+pub fn add_entropy(bytes: &[u8]) {
+    for chunk in bytes.chunks(8) {
+        let mut word = [0u8; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        STATE.fetch_xor(splitmix64(u64::from_le_bytes(word)), Ordering::Relaxed);
+    }
+}
+
+pub fn next_u64() -> u64 {
+    let state = STATE.fetch_add(GAMMA, Ordering::Relaxed).wrapping_add(GAMMA);
+    // 每次都混入当前时间, 让不同时刻启动的进程拿到不同的值
+    splitmix64(state ^ (timer::get_time() as u64).rotate_left(32))
+}
+
+/// [0, n) 中的随机数, n 不能为 0
+pub fn below(n: usize) -> usize {
+    (next_u64() % n as u64) as usize
+}
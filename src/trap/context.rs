@@ -67,6 +67,16 @@ impl UKContext {
         self.user_rx[10] = val;
     }
 
+    pub fn set_user_a1(&mut self, val: usize) {
+        // a1 == x11
+        self.user_rx[11] = val;
+    }
+
+    pub fn set_user_a2(&mut self, val: usize) {
+        // a2 == x12
+        self.user_rx[12] = val;
+    }
+
     pub fn set_user_tp(&mut self, val: usize) {
         // tp == x4
         self.user_rx[4] = val;
@@ -3,14 +3,15 @@ use riscv::register::{
     sepc, stval,
 };
 
-use log::{error, info, warn};
+use core::mem::size_of;
+use log::{debug, error, info, warn};
 
 use crate::arch::{self, get_curr_page_table_addr};
 
 #[no_mangle]
 pub fn kernel_default_exception(a0: usize) {
     let sepc = sepc::read();
-    let _stval = stval::read();
+    let bad_addr = stval::read();
 
     let exception = match scause::read().cause() {
         Trap::Exception(e) => e,
@@ -26,16 +27,46 @@ pub fn kernel_default_exception(a0: usize) {
         Exception::InstructionFault => fatal_exception_error(a0),
         Exception::IllegalInstruction => {}
         Exception::Breakpoint => breakpoint_handler(sepc),
-        Exception::LoadFault => fatal_exception_error(a0),
         Exception::StoreMisaligned => fatal_exception_error(a0),
-        Exception::StoreFault => fatal_exception_error(a0),
         Exception::UserEnvCall => todo!(),
         Exception::InstructionPageFault => fatal_exception_error(a0),
-        _e @ (Exception::LoadPageFault | Exception::StorePageFault) => fatal_exception_error(a0),
+        Exception::LoadFault
+        | Exception::StoreFault
+        | Exception::LoadPageFault
+        | Exception::StorePageFault => match search_exception_table(sepc) {
+            // 访问用户内存失败, 交给拷贝函数自己处理
+            Some(fixup) => {
+                debug!(
+                    "kernel {:?} at {:#x}, fixup to {:#x}, bad addr = {:#x}",
+                    exception, sepc, fixup, bad_addr
+                );
+                sepc::write(fixup);
+            }
+            None => fatal_exception_error(a0),
+        },
         _ => fatal_exception_error(a0),
     }
 }
 
+/// 可能在访问用户内存时出错的指令, 见 memory/user_copy.asm
+#[repr(C)]
+struct ExceptionTableEntry {
+    insn: usize,
+    fixup: usize,
+}
+
+/// 返回出错的指令对应的修复代码地址
+fn search_exception_table(sepc: usize) -> Option<usize> {
+    extern "C" {
+        fn __ex_table_start();
+        fn __ex_table_end();
+    }
+    let start = __ex_table_start as usize;
+    let len = (__ex_table_end as usize - start) / size_of::<ExceptionTableEntry>();
+    let table = unsafe { core::slice::from_raw_parts(start as *const ExceptionTableEntry, len) };
+    table.iter().find(|e| e.insn == sepc).map(|e| e.fixup)
+}
+
 fn fatal_exception_error(_a0: usize) -> ! {
     let sepc = sepc::read();
 
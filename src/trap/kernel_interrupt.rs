@@ -1,7 +1,7 @@
 use riscv::register::scause;
 
 // use super::timer;
-use crate::{drivers, timer};
+use crate::{drivers, memory::tlb, timer};
 
 #[no_mangle]
 pub fn kernel_default_interrupt() {
@@ -14,7 +14,7 @@ pub fn kernel_default_interrupt() {
 
     match interrupt {
         scause::Interrupt::UserSoft => todo!(),
-        scause::Interrupt::SupervisorSoft => todo!(),
+        scause::Interrupt::SupervisorSoft => tlb::ipi_handler(),
         scause::Interrupt::UserTimer => todo!(),
         scause::Interrupt::SupervisorTimer => timer::timer_handler(),
         scause::Interrupt::UserExternal => todo!(),